@@ -0,0 +1,533 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Offline import of blocks from an archive exported by a Substrate node.
+//!
+//! The archive is the output of the `export-blocks --binary` command of Substrate nodes: the
+//! number of blocks, as a little-endian `u64`, followed with the SCALE-encoded `SignedBlock`s,
+//! each made of a header, a list of extrinsics and optional justifications.
+//!
+//! Older versions of Substrate encode the justifications as a single `Option<Vec<u8>>`, while
+//! newer versions encode them as an `Option<Vec<(ConsensusEngineId, Vec<u8>)>>` holding one
+//! justification per consensus engine, in which case only the GrandPa one is kept. The two
+//! encodings can't be told apart by looking at a single block, and the archive is decoded with
+//! the second one if it can't be decoded with the first one.
+//!
+//! [`BlockArchive`] implements [`Network`] without any network access, by pretending to be
+//! connected to a single peer that knows all the blocks of the archive. The blocks thus go
+//! through the exact same verification as blocks downloaded from the network, and are saved
+//! through [`crate::ffi::database_save`] in the same way.
+//!
+//! Note that blocks are only finalized, and thus saved, when a justification is found. The
+//! blocks of the archive after the last justification are verified but never saved.
+//!
+//! If blocks of the archive fail verification, the fictive peer refuses to serve them again and
+//! disconnects, and the import stops.
+
+use crate::{network_service, sync_service::Network};
+
+use core::{convert::TryFrom as _, fmt};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+use smoldot::{
+    header,
+    libp2p::{
+        self,
+        connection::{established, NoiseKey},
+        peer_id::{PeerId, PublicKey},
+    },
+    network::{self, protocol},
+};
+use std::{
+    sync::{Arc, Mutex},
+    vec,
+};
+
+/// Error while decoding an archive.
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// The archive doesn't contain any block.
+    Empty,
+    /// The archive ends in the middle of a block, or contains fewer blocks than announced.
+    Truncated,
+    /// The archive contains more data after the announced number of blocks.
+    TrailingData,
+    /// The block at the given index in the archive can't be decoded.
+    InvalidBlock(usize),
+    /// The block at the given index in the archive isn't the child of the previous block.
+    NotConsecutive(usize),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Empty => write!(f, "Archive doesn't contain any block"),
+            ArchiveError::Truncated => write!(f, "Archive is truncated"),
+            ArchiveError::TrailingData => write!(f, "Archive contains trailing data"),
+            ArchiveError::InvalidBlock(n) => write!(f, "Invalid block at index {}", n),
+            ArchiveError::NotConsecutive(n) => {
+                write!(
+                    f,
+                    "Block at index {} isn't the child of the previous block",
+                    n
+                )
+            }
+        }
+    }
+}
+
+/// Implementation of [`Network`] that serves the blocks of an archive. See
+/// [the module-level documentation](self).
+pub struct BlockArchive {
+    node_key: [u8; 32],
+    /// Returned by [`Network::address_book`], so that importing an archive doesn't erase the
+    /// address book saved in the database.
+    address_book: Vec<network_service::AddressBookEntry>,
+    /// Identity of the fictive peer that serves the blocks.
+    peer_id: PeerId,
+    /// Blocks of the archive, ordered by increasing number. Each block is the child of the
+    /// previous one, and all the fields are present.
+    blocks: Vec<protocol::BlockData>,
+    /// Number of the first block of [`BlockArchive::blocks`].
+    first_block_number: u64,
+    /// For each entry of [`BlockArchive::blocks`], true if the block has already been served.
+    served: Mutex<Vec<bool>>,
+    /// Sends to the task returned by [`BlockArchive::events`] the requests for blocks that have
+    /// already been served. The task reports the disconnection of the fictive peer, then answers
+    /// through the [`oneshot::Sender`].
+    refused_requests_tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    /// Receiving side of [`BlockArchive::refused_requests_tx`]. Extracted by
+    /// [`BlockArchive::events`].
+    refused_requests_rx: Mutex<Option<mpsc::UnboundedReceiver<oneshot::Sender<()>>>>,
+}
+
+impl BlockArchive {
+    /// Decodes an archive produced by `export-blocks --binary`.
+    ///
+    /// `node_key` and `address_book` are the values returned by [`Network::node_key`] and
+    /// [`Network::address_book`].
+    pub fn decode(
+        archive: &[u8],
+        node_key: [u8; 32],
+        address_book: Vec<network_service::AddressBookEntry>,
+    ) -> Result<Self, ArchiveError> {
+        let blocks = match decode_blocks(archive, JustificationsFormat::Single) {
+            Ok(blocks) => blocks,
+            // The error of the legacy format is reported if the archive can't be decoded with
+            // either format.
+            Err(err) => decode_blocks(archive, JustificationsFormat::PerEngine).map_err(|_| err)?,
+        };
+
+        let (refused_requests_tx, refused_requests_rx) = mpsc::unbounded();
+
+        let noise_key = NoiseKey::new(&[0; 32]);
+        let peer_id =
+            PeerId::from_public_key(&PublicKey::Ed25519(*noise_key.libp2p_public_ed25519_key()));
+
+        Ok(BlockArchive {
+            node_key,
+            address_book,
+            peer_id,
+            served: Mutex::new(vec![false; blocks.blocks.len()]),
+            refused_requests_tx,
+            refused_requests_rx: Mutex::new(Some(refused_requests_rx)),
+            blocks: blocks.blocks,
+            first_block_number: blocks.first_block_number,
+        })
+    }
+
+    /// Returns the number of the first block of the archive.
+    pub fn first_block_number(&self) -> u64 {
+        self.first_block_number
+    }
+
+    /// Returns the number of the last block of the archive.
+    pub fn last_block_number(&self) -> u64 {
+        self.first_block_number + self.blocks.len() as u64 - 1
+    }
+
+    /// Returns a receiver of network events, and a task that must be spawned in order to report
+    /// the fictive peer serving the archive, and its disconnection if the import stops.
+    ///
+    /// # Panic
+    ///
+    /// Panics if called multiple times.
+    ///
+    pub fn events(
+        &self,
+    ) -> (
+        impl Future<Output = ()>,
+        mpsc::Receiver<network_service::Event>,
+    ) {
+        let event = network_service::Event::Connected {
+            peer_id: self.peer_id.clone(),
+            chain_index: 0,
+            best_block_number: self.last_block_number(),
+            best_block_hash: self.blocks.last().unwrap().hash,
+        };
+        let peer_id = self.peer_id.clone();
+        let mut refused_requests = self.refused_requests_rx.lock().unwrap().take().unwrap();
+        let (mut tx, rx) = mpsc::channel(0);
+
+        let task = async move {
+            if tx.send(event).await.is_err() {
+                return;
+            }
+
+            let mut disconnected = false;
+            while let Some(answer) = refused_requests.next().await {
+                if !disconnected {
+                    let event = network_service::Event::Disconnected {
+                        peer_id: peer_id.clone(),
+                        chain_index: 0,
+                    };
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                    disconnected = true;
+                }
+                let _ = answer.send(());
+            }
+
+            // The sync service stops if the channel is closed, which would interrupt the
+            // verification of the blocks that have already been served.
+            future::pending::<()>().await;
+            drop(tx);
+        };
+
+        (task, rx)
+    }
+
+    /// Returns the index within [`BlockArchive::blocks`] of the block designated by `start`.
+    fn block_index(&self, start: &protocol::BlocksRequestConfigStart) -> Option<usize> {
+        match start {
+            protocol::BlocksRequestConfigStart::Number(number) => {
+                let offset = number.get().checked_sub(self.first_block_number)?;
+                let index = usize::try_from(offset).ok()?;
+                if index < self.blocks.len() {
+                    Some(index)
+                } else {
+                    None
+                }
+            }
+            protocol::BlocksRequestConfigStart::Hash(hash) => {
+                self.blocks.iter().position(|b| b.hash == *hash)
+            }
+        }
+    }
+}
+
+impl Network for BlockArchive {
+    fn blocks_request(
+        self: Arc<Self>,
+        target: PeerId,
+        _: usize,
+        config: protocol::BlocksRequestConfig,
+    ) -> future::BoxFuture<
+        'static,
+        Result<Vec<protocol::BlockData>, network_service::BlocksRequestError>,
+    > {
+        if target != self.peer_id {
+            return future::ready(Err(network_service::BlocksRequestError::Refused(
+                libp2p::RequestError::Connection(established::RequestError::SubstreamClosed),
+            )))
+            .boxed();
+        }
+
+        let start = match self.block_index(&config.start) {
+            Some(index) => index,
+            None => return future::ready(Err(network_service::BlocksRequestError::Empty)).boxed(),
+        };
+
+        let desired_count = usize::try_from(config.desired_count.get()).unwrap_or(usize::MAX);
+        let indices: Vec<usize> = match config.direction {
+            protocol::BlocksRequestDirection::Ascending => {
+                (start..self.blocks.len()).take(desired_count).collect()
+            }
+            protocol::BlocksRequestDirection::Descending => {
+                (0..=start).rev().take(desired_count).collect()
+            }
+        };
+
+        // Blocks are only requested again after the sync state machine has been reset, which
+        // can only be caused by blocks of the archive failing verification. Serving them again
+        // would make the sync service verify the same blocks in a loop. Instead, the fictive
+        // peer disconnects, so that the sync service stops sending requests and reports that it
+        // is stalled, and the request fails once the disconnection has been reported.
+        {
+            let mut served = self.served.lock().unwrap();
+            if served[start] {
+                log::error!(
+                    "Import interrupted, as blocks of the archive have failed verification"
+                );
+                let (answer_tx, answer_rx) = oneshot::channel();
+                let _ = self.refused_requests_tx.unbounded_send(answer_tx);
+                return async move {
+                    let _ = answer_rx.await;
+                    Err(network_service::BlocksRequestError::Refused(
+                        libp2p::RequestError::Connection(
+                            established::RequestError::SubstreamClosed,
+                        ),
+                    ))
+                }
+                .boxed();
+            }
+            for index in &indices {
+                served[*index] = true;
+            }
+        }
+
+        let blocks = indices
+            .into_iter()
+            .map(|index| {
+                let block = &self.blocks[index];
+                protocol::BlockData {
+                    hash: block.hash,
+                    header: block.header.clone().filter(|_| config.fields.header),
+                    body: block.body.clone().filter(|_| config.fields.body),
+                    justification: block
+                        .justification
+                        .clone()
+                        .filter(|_| config.fields.justification),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if blocks
+            .last()
+            .is_some_and(|b| b.hash == self.blocks.last().unwrap().hash)
+        {
+            log::info!(
+                "All the blocks of the archive, up to #{}, have been served",
+                self.last_block_number()
+            );
+        }
+
+        future::ready(Ok(blocks)).boxed()
+    }
+
+    fn storage_proof_request(
+        self: Arc<Self>,
+        _: PeerId,
+        _: usize,
+        _: protocol::StorageProofRequestConfig<vec::IntoIter<Vec<u8>>>,
+    ) -> future::BoxFuture<'static, Result<Vec<Vec<u8>>, network::service::StorageProofRequestError>>
+    {
+        // The archive doesn't contain any storage.
+        future::ready(Err(network::service::StorageProofRequestError::Request(
+            libp2p::RequestError::Connection(established::RequestError::SubstreamClosed),
+        )))
+        .boxed()
+    }
+
+    fn set_local_grandpa_state(
+        &self,
+        _: usize,
+        _: network_service::GrandpaState,
+    ) -> future::BoxFuture<'_, ()> {
+        future::ready(()).boxed()
+    }
+
+    fn disconnect_peer<'a>(&'a self, _: &'a PeerId, _: bool) -> future::BoxFuture<'a, ()> {
+        // The archive is the only source of blocks, and disconnecting from it would stop the
+        // import entirely.
+        future::ready(()).boxed()
+    }
+
+    fn address_book(
+        &self,
+        _: usize,
+    ) -> future::BoxFuture<'_, Vec<network_service::AddressBookEntry>> {
+        future::ready(self.address_book.clone()).boxed()
+    }
+
+    fn node_key(&self) -> &[u8; 32] {
+        &self.node_key
+    }
+}
+
+/// Encoding of the justifications of the blocks of an archive. See
+/// [the module-level documentation](self).
+#[derive(Debug, Copy, Clone)]
+enum JustificationsFormat {
+    /// `Option<Vec<u8>>`.
+    Single,
+    /// `Option<Vec<(ConsensusEngineId, Vec<u8>)>>`.
+    PerEngine,
+}
+
+/// Blocks of an archive, as returned by [`decode_blocks`].
+struct DecodedBlocks {
+    /// Blocks ordered by increasing number. Each block is the child of the previous one.
+    blocks: Vec<protocol::BlockData>,
+    /// Number of the first block of [`DecodedBlocks::blocks`].
+    first_block_number: u64,
+}
+
+/// Decodes the blocks of an archive produced by `export-blocks --binary`.
+fn decode_blocks(
+    archive: &[u8],
+    justifications_format: JustificationsFormat,
+) -> Result<DecodedBlocks, ArchiveError> {
+    let (num_blocks, mut remaining) = match archive {
+        [n0, n1, n2, n3, n4, n5, n6, n7, rest @ ..] => (
+            u64::from_le_bytes([*n0, *n1, *n2, *n3, *n4, *n5, *n6, *n7]),
+            rest,
+        ),
+        _ => return Err(ArchiveError::Truncated),
+    };
+
+    if num_blocks == 0 {
+        return Err(ArchiveError::Empty);
+    }
+
+    // The number of blocks isn't trusted for the capacity, as the archive might be invalid.
+    let mut blocks = Vec::<protocol::BlockData>::new();
+    let mut first_block_number = 0;
+
+    for index in 0..num_blocks {
+        let index = usize::try_from(index).unwrap_or(usize::MAX);
+        if remaining.is_empty() {
+            return Err(ArchiveError::Truncated);
+        }
+
+        let (block, number, parent_hash) =
+            decode_signed_block(&mut remaining, justifications_format)
+                .ok_or(ArchiveError::InvalidBlock(index))?;
+
+        match blocks.last() {
+            Some(previous) => {
+                if number != first_block_number + blocks.len() as u64
+                    || parent_hash != previous.hash
+                {
+                    return Err(ArchiveError::NotConsecutive(index));
+                }
+            }
+            None => first_block_number = number,
+        }
+
+        blocks.push(block);
+    }
+
+    if !remaining.is_empty() {
+        return Err(ArchiveError::TrailingData);
+    }
+
+    Ok(DecodedBlocks {
+        blocks,
+        first_block_number,
+    })
+}
+
+/// Decodes a SCALE-encoded `SignedBlock` found at the start of `bytes`, and updates `bytes` to
+/// point after it.
+///
+/// Returns the block, its number and the hash of its parent.
+fn decode_signed_block(
+    bytes: &mut &[u8],
+    justifications_format: JustificationsFormat,
+) -> Option<(protocol::BlockData, u64, [u8; 32])> {
+    let (header, after_header) = header::decode_partial(bytes).ok()?;
+    let scale_encoded_header = bytes[..bytes.len() - after_header.len()].to_vec();
+    let number = header.number;
+    let parent_hash = *header.parent_hash;
+    let hash = header.hash();
+    *bytes = after_header;
+
+    // Each extrinsic is itself SCALE-encoded as a `Vec<u8>`. The length prefix is removed, as
+    // the bodies of the blocks decoded from blocks responses don't include it either.
+    let num_extrinsics = decode_compact(bytes)?;
+    let mut body = Vec::new();
+    for _ in 0..num_extrinsics {
+        let len = usize::try_from(decode_compact(bytes)?).ok()?;
+        body.push(take(bytes, len)?.to_vec());
+    }
+
+    let justification = match (take(bytes, 1)?, justifications_format) {
+        ([0], _) => None,
+        ([1], JustificationsFormat::Single) => {
+            let len = usize::try_from(decode_compact(bytes)?).ok()?;
+            Some(take(bytes, len)?.to_vec())
+        }
+        ([1], JustificationsFormat::PerEngine) => {
+            let mut grandpa_justification = None;
+            for _ in 0..decode_compact(bytes)? {
+                let engine_id = take(bytes, 4)?;
+                let len = usize::try_from(decode_compact(bytes)?).ok()?;
+                let justification = take(bytes, len)?;
+                if engine_id == b"FRNK" {
+                    grandpa_justification = Some(justification.to_vec());
+                }
+            }
+            grandpa_justification
+        }
+        _ => return None,
+    };
+
+    let block = protocol::BlockData {
+        hash,
+        header: Some(scale_encoded_header),
+        body: Some(body),
+        justification,
+    };
+
+    Some((block, number, parent_hash))
+}
+
+/// Decodes a SCALE-compact-encoded number found at the start of `bytes`, and updates `bytes` to
+/// point after it.
+fn decode_compact(bytes: &mut &[u8]) -> Option<u64> {
+    let first = *bytes.first()?;
+    match first & 0b11 {
+        0b00 => {
+            take(bytes, 1)?;
+            Some(u64::from(first >> 2))
+        }
+        0b01 => {
+            let data = take(bytes, 2)?;
+            Some(u64::from(u16::from_le_bytes([data[0], data[1]]) >> 2))
+        }
+        0b10 => {
+            let data = take(bytes, 4)?;
+            Some(u64::from(
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]) >> 2,
+            ))
+        }
+        _ => {
+            let len = usize::from(first >> 2) + 4;
+            if len > 8 {
+                return None;
+            }
+            let data = take(bytes, 1 + len)?;
+            let mut value = [0; 8];
+            value[..len].copy_from_slice(&data[1..]);
+            Some(u64::from_le_bytes(value))
+        }
+    }
+}
+
+/// Extracts the first `len` bytes of `bytes`, and updates `bytes` to point after them.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+    let (taken, remaining) = bytes.split_at(len);
+    *bytes = remaining;
+    Some(taken)
+}
@@ -0,0 +1,182 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Indexing of the changes to the balances of accounts.
+//!
+//! The balances are found in the `System.Account` storage map, whose keys are
+//! `twox128("System") ++ twox128("Account") ++ blake2_128(account_id) ++ account_id`. Reading
+//! the storage changes of each finalized block, rather than its events, makes it possible to
+//! follow balances that are modified without any event being emitted, for example during a
+//! runtime migration.
+//!
+//! The values of the map are SCALE-encoded `AccountInfo` structures, whose layout has changed
+//! several times over the history of Substrate. Only the layouts that end with the `AccountData`
+//! of the balances pallet made of four 128 bits integers, `free`, `reserved`, `misc_frozen` and
+//! `fee_frozen`, are supported. More recent runtimes use an `AccountData` made of `free`,
+//! `reserved`, `frozen` and `flags`, and describe it in a format of metadata that can't be
+//! decoded. See [`is_supported`].
+
+use crate::ffi;
+
+use smoldot::metadata::decode::StorageEntryTypeRef;
+use std::collections::BTreeMap;
+
+/// `twox128("System") ++ twox128("Account")`.
+const SYSTEM_ACCOUNT_PREFIX: [u8; 32] = [
+    0x26, 0xaa, 0x39, 0x4e, 0xea, 0x56, 0x30, 0xe0, 0x7c, 0x48, 0xae, 0x0c, 0x95, 0x58, 0xce, 0xf7,
+    0xb9, 0x9d, 0x88, 0x0e, 0xc6, 0x81, 0x79, 0x9c, 0x0c, 0xf3, 0x0e, 0x88, 0x86, 0x37, 0x1d, 0xa9,
+];
+
+/// Size in bytes of the `blake2_128` hash that precedes the account id in a key.
+const KEY_HASH_LEN: usize = 16;
+
+/// Size in bytes of the `AccountData` found at the end of each value.
+const ACCOUNT_DATA_LEN: usize = 64;
+
+/// Possible sizes in bytes of an `AccountInfo`. The `AccountData` is preceded with a 32 bits
+/// nonce, followed with either an 8 bits reference counter, a 32 bits reference counter, 32 bits
+/// `consumers` and `providers` counters, or 32 bits `consumers`, `providers` and `sufficients`
+/// counters.
+const ACCOUNT_INFO_LENS: [usize; 4] = [
+    4 + 1 + ACCOUNT_DATA_LEN,
+    4 + 4 + ACCOUNT_DATA_LEN,
+    4 + 8 + ACCOUNT_DATA_LEN,
+    4 + 12 + ACCOUNT_DATA_LEN,
+];
+
+/// Returns true if, according to the given metadata, the runtime stores in `System.Account` the
+/// `AccountData` that this module decodes. Balances must not be indexed otherwise.
+pub(crate) fn is_supported(metadata: &[u8]) -> bool {
+    let metadata = match smoldot::metadata::decode(metadata) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+
+    let value_type = |module_name: &str| {
+        let storage = metadata
+            .modules
+            .clone()
+            .find(|module| module.name == module_name)?
+            .storage?;
+        match storage
+            .entries
+            .clone()
+            .find(|entry| entry.name == "Account")?
+            .ty
+        {
+            StorageEntryTypeRef::Map { value, .. } => Some(value),
+            _ => None,
+        }
+    };
+
+    value_type("System") == Some("AccountInfo<T::Index, T::AccountData>")
+        && value_type("Balances") == Some("AccountData<T::Balance>")
+}
+
+/// Returns the balance changes found in the given storage changes of a block, ordered by
+/// account. `previous_storage` must be the storage of the parent of the block, and is used to
+/// ignore the changes that don't modify the balances, such as a change of nonce.
+///
+/// Accounts whose entry has been removed from the storage have been reaped, and are reported
+/// with a balance of zero.
+pub(crate) fn balance_changes<'a>(
+    previous_storage: &BTreeMap<Vec<u8>, Vec<u8>>,
+    storage_changes: impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+) -> Vec<ffi::DatabaseSaveBalanceChange> {
+    let mut changes = storage_changes
+        .filter_map(|(key, value)| {
+            let account = match account_id(key) {
+                Some(account) => account,
+                None => return None,
+            };
+
+            let previous = match previous_storage.get(key) {
+                Some(previous) => decode_account_data(previous),
+                None => Some((0, 0, 0)),
+            };
+
+            let (free, reserved, frozen) = match value {
+                Some(value) => match decode_account_data(value) {
+                    Some(data) => data,
+                    None => {
+                        log::warn!(
+                            "Failed to decode balances of account 0x{}",
+                            account
+                                .iter()
+                                .map(|b| format!("{:02x}", b))
+                                .collect::<String>()
+                        );
+                        return None;
+                    }
+                },
+                None => (0, 0, 0),
+            };
+
+            if previous == Some((free, reserved, frozen)) {
+                return None;
+            }
+
+            Some(ffi::DatabaseSaveBalanceChange {
+                account: smoldot::json_rpc::methods::HexString(account.to_vec()),
+                free: free.to_string(),
+                reserved: reserved.to_string(),
+                frozen: frozen.to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    changes.sort_by(|a, b| a.account.0.cmp(&b.account.0));
+    changes
+}
+
+/// Returns the account id found in `key`, or `None` if `key` isn't a key of `System.Account`.
+fn account_id(key: &[u8]) -> Option<&[u8]> {
+    if !key.starts_with(&SYSTEM_ACCOUNT_PREFIX) {
+        return None;
+    }
+
+    let account = key.get(SYSTEM_ACCOUNT_PREFIX.len() + KEY_HASH_LEN..)?;
+    if account.is_empty() {
+        return None;
+    }
+
+    Some(account)
+}
+
+/// Decodes the `AccountData` at the end of an `AccountInfo`, and returns the free, reserved and
+/// frozen balances. The frozen balance is the highest of `misc_frozen` and `fee_frozen`.
+///
+/// Returns `None` if the size of `value` doesn't match any of the supported layouts.
+fn decode_account_data(value: &[u8]) -> Option<(u128, u128, u128)> {
+    if !ACCOUNT_INFO_LENS.contains(&value.len()) {
+        return None;
+    }
+
+    let data = &value[value.len() - ACCOUNT_DATA_LEN..];
+
+    let mut fields = data.chunks_exact(16).map(|field| {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(field);
+        u128::from_le_bytes(bytes)
+    });
+
+    let free = fields.next()?;
+    let reserved = fields.next()?;
+    let misc_frozen = fields.next()?;
+    let fee_frozen = fields.next()?;
+    Some((free, reserved, misc_frozen.max(fee_frozen)))
+}
@@ -0,0 +1,119 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Format of the database content that is sent to the host through
+//! [`crate::ffi::database_save`] and later passed back through
+//! [`crate::ChainConfig::database_content`].
+//!
+//! The content is a JSON object containing the chain information and finalized storage, as
+//! encoded by [`smoldot::database::finalized_serialize`], plus information about the networking
+//! that is worth keeping between sessions.
+//!
+//! For backwards compatibility, the output of
+//! [`smoldot::database::finalized_serialize::encode_chain_storage`] is also accepted as is.
+
+use crate::network_service;
+
+use core::{convert::TryFrom as _, time::Duration};
+use smoldot::json_rpc::methods::HexString;
+
+/// Decoded database content.
+pub(crate) struct DatabaseContent {
+    /// Chain information and finalized storage, as encoded by
+    /// [`smoldot::database::finalized_serialize::encode_chain_storage`].
+    pub(crate) chain: String,
+
+    /// See [`network_service::ConfigChain::address_book`].
+    pub(crate) address_book: Vec<network_service::AddressBookEntry>,
+
+    /// See [`network_service::Config::node_key`]. `None` if the database was saved by a version
+    /// that didn't persist the key.
+    pub(crate) node_key: Option<[u8; 32]>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedDatabaseContent {
+    chain: String,
+    #[serde(default)]
+    address_book: Vec<SerializedAddressBookEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    node_key: Option<HexString>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedAddressBookEntry {
+    peer_id: String,
+    address: String,
+    last_seen_unix_ms: u64,
+    reliability: i32,
+}
+
+/// Encodes the given database content into a string.
+pub(crate) fn encode(content: &DatabaseContent) -> String {
+    serde_json::to_string(&SerializedDatabaseContent {
+        chain: content.chain.clone(),
+        address_book: content
+            .address_book
+            .iter()
+            .map(|entry| SerializedAddressBookEntry {
+                peer_id: entry.peer_id.to_base58(),
+                address: entry.address.to_string(),
+                last_seen_unix_ms: u64::try_from(entry.last_seen.as_millis())
+                    .unwrap_or(u64::MAX),
+                reliability: entry.reliability,
+            })
+            .collect(),
+        node_key: content.node_key.map(|key| HexString(key.to_vec())),
+    })
+    .unwrap()
+}
+
+/// Decodes a database content that has previously been encoded with [`encode`].
+///
+/// Entries of the address book that can't be decoded are silently ignored.
+pub(crate) fn decode(encoded: &str) -> DatabaseContent {
+    let decoded = match serde_json::from_str::<SerializedDatabaseContent>(encoded) {
+        Ok(d) => d,
+        // Database saved by an older version, containing only the chain information.
+        Err(_) => {
+            return DatabaseContent {
+                chain: encoded.to_owned(),
+                address_book: Vec::new(),
+                node_key: None,
+            }
+        }
+    };
+
+    DatabaseContent {
+        chain: decoded.chain,
+        address_book: decoded
+            .address_book
+            .into_iter()
+            .filter_map(|entry| {
+                Some(network_service::AddressBookEntry {
+                    peer_id: entry.peer_id.parse().ok()?,
+                    address: entry.address.parse().ok()?,
+                    last_seen: Duration::from_millis(entry.last_seen_unix_ms),
+                    reliability: entry.reliability,
+                })
+            })
+            .collect(),
+        node_key: decoded
+            .node_key
+            .and_then(|key| <[u8; 32]>::try_from(&key.0[..]).ok()),
+    }
+}
@@ -17,6 +17,8 @@
 
 // TODO: the quality of this module is sub-par
 
+use crate::network_service;
+
 use core::{
     cmp::Ordering,
     convert::TryFrom as _,
@@ -25,35 +27,38 @@ use core::{
     marker,
     ops::{Add, Sub},
     pin::Pin,
-    slice,
+    ptr, slice,
     task::{Context, Poll, Waker},
     time::Duration,
 };
-use futures::{channel::oneshot, prelude::*};
+use futures::{channel::mpsc, prelude::*};
+use smoldot::json_rpc::methods::HexString;
 use std::{
-    collections::VecDeque,
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
     sync::{atomic, Arc, Mutex},
 };
 
 pub mod bindings;
+pub mod clock;
+pub mod simulated;
+
+mod host;
+mod timers;
+
+#[cfg(test)]
+mod tests;
+
+pub use host::{set_host, Host, WasmHost};
 
 /// Stops execution, throwing a string exception with the given content.
 pub(crate) fn throw(message: String) -> ! {
-    unsafe {
-        bindings::throw(
-            u32::try_from(message.as_bytes().as_ptr() as usize).unwrap(),
-            u32::try_from(message.as_bytes().len()).unwrap(),
-        );
-
-        // Note: we could theoretically use `unreachable_unchecked` here, but this relies on the
-        // fact that `ffi::throw` is correctly implemented, which isn't 100% guaranteed.
-        unreachable!();
-    }
+    host::host().throw(&message)
 }
 
 /// Returns the duration elapsed since the UNIX epoch, ignoring leap seconds.
 pub(crate) fn unix_time() -> Duration {
-    Duration::from_secs_f64(unsafe { bindings::unix_time_ms() } / 1000.0)
+    Duration::from_secs_f64(clock::unix_time_ms() / 1000.0)
 }
 
 /// Spawn a background task that runs forever.
@@ -105,34 +110,87 @@ fn spawn_task(future: impl Future<Output = ()> + Send + 'static) {
     futures::task::ArcWake::wake(waker);
 }
 
+thread_local! {
+    /// Callbacks passed to [`start_timer_wrap`], indexed by the identifier of their host timer.
+    static TIMER_CALLBACKS: RefCell<Registry<Box<dyn FnOnce()>>> = RefCell::new(Registry::new());
+}
+
 /// Uses the environment to invoke `closure` after `duration` has elapsed.
-fn start_timer_wrap(duration: Duration, closure: impl FnOnce()) {
-    let callback: Box<Box<dyn FnOnce()>> = Box::new(Box::new(closure));
-    let timer_id = u32::try_from(Box::into_raw(callback) as usize).unwrap();
+fn start_timer_wrap(duration: Duration, closure: impl FnOnce() + 'static) {
+    let timer_id = TIMER_CALLBACKS.with(|callbacks| {
+        callbacks
+            .borrow_mut()
+            .insert(Box::new(closure), |id| id == timers::HOST_TIMER_ID)
+    });
+
     let milliseconds = u64::try_from(duration.as_millis()).unwrap_or(u64::max_value());
-    unsafe { bindings::start_timer(timer_id, (milliseconds as f64).ceil()) }
+    host::host().start_timer(timer_id, (milliseconds as f64).ceil())
+}
+
+/// Collection of values indexed by identifiers that are passed to the host.
+struct Registry<T> {
+    /// Identifier to try first for the next value.
+    next_id: u32,
+    entries: HashMap<u32, T>,
+}
+
+impl<T> Registry<T> {
+    fn new() -> Self {
+        Registry {
+            next_id: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Inserts a value and returns its identifier. Identifiers for which `reserved` returns
+    /// `true` are never used.
+    fn insert(&mut self, value: T, reserved: impl Fn(u32) -> bool) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !reserved(id) && !self.entries.contains_key(&id) {
+                self.entries.insert(id, value);
+                return id;
+            }
+        }
+    }
+
+    fn get(&self, id: u32) -> Option<&T> {
+        self.entries.get(&id)
+    }
+
+    fn remove(&mut self, id: u32) -> Option<T> {
+        self.entries.remove(&id)
+    }
 }
 
-// TODO: cancel the timer if the `Delay` is destroyed? we create and destroy a lot of `Delay`s
+/// Future that becomes ready after a certain duration has elapsed.
+///
+/// Destroying a [`Delay`] before it is ready cancels it. Multiple [`Delay`]s whose deadlines are
+/// close to each other share the same host timer.
 pub struct Delay {
-    rx: oneshot::Receiver<()>,
+    id: u64,
 }
 
 impl Delay {
     pub fn new(when: Duration) -> Self {
-        let (tx, rx) = oneshot::channel();
-        start_timer_wrap(when, move || {
-            let _ = tx.send(());
-        });
-        Delay { rx }
+        Delay {
+            id: timers::insert(Instant::now() + when),
+        }
     }
 }
 
 impl Future for Delay {
     type Output = ();
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        Future::poll(Pin::new(&mut self.rx), cx).map(|v| v.unwrap())
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        timers::poll(self.id, cx)
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        timers::remove(self.id);
     }
 }
 
@@ -165,7 +223,7 @@ impl Ord for Instant {
 impl Instant {
     pub fn now() -> Instant {
         Instant {
-            inner: unsafe { bindings::monotonic_clock_ms() },
+            inner: clock::monotonic_clock_ms(),
         }
     }
 
@@ -220,6 +278,191 @@ fn set_syncing_paused(paused: bool) {
     SYNCING_PAUSED.store(paused, atomic::Ordering::Relaxed)
 }
 
+static FOLLOW_BEST_BLOCKS: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+pub(crate) fn is_following_best_blocks() -> bool {
+    FOLLOW_BEST_BLOCKS.load(atomic::Ordering::Relaxed)
+}
+
+fn set_follow_best_blocks(follow: bool) {
+    FOLLOW_BEST_BLOCKS.store(follow, atomic::Ordering::Relaxed)
+}
+
+/// Request emitted by the host through one of the exported functions, and that must be answered
+/// by the client.
+#[derive(Debug)]
+pub(crate) enum HostRequest {
+    /// See [`bindings::network_info`]. Must be answered with [`network_info_response`].
+    NetworkInfo,
+    /// See [`bindings::add_peer`]. Contains the multiaddress passed by the host, which might be
+    /// invalid.
+    AddPeer { address: String },
+    /// See [`bindings::disconnect_peer`]. Contains the peer id passed by the host, which might
+    /// be invalid.
+    DisconnectPeer { peer_id: String, ban: bool },
+    /// See [`bindings::set_peer_reserved`]. Contains the peer id passed by the host, which might
+    /// be invalid.
+    SetPeerReserved { peer_id: String, reserved: bool },
+    /// See [`bindings::set_reserved_only`].
+    SetReservedOnly(bool),
+    /// See [`bindings::storage_query`]. Must be answered with [`storage_query_response`].
+    /// Contains the query passed by the host, which might be invalid.
+    StorageQuery(String),
+    /// See [`bindings::runtime_call`]. Must be answered with [`runtime_call_response`].
+    RuntimeCall {
+        function: String,
+        parameter: Vec<u8>,
+    },
+    /// See [`bindings::watch_storage_prefix`].
+    WatchStoragePrefix(Vec<u8>),
+    /// See [`bindings::unwatch_storage_prefix`].
+    UnwatchStoragePrefix(Vec<u8>),
+    /// See [`bindings::json_rpc_send`]. Must be answered with [`json_rpc_respond`]. Contains the
+    /// request passed by the host, which might be invalid.
+    JsonRpc(String),
+}
+
+thread_local! {
+    /// Channel where [`HostRequest`]s are sent. The receiver is extracted with
+    /// [`take_host_requests`]. Requests emitted before the client has started are buffered.
+    static HOST_REQUESTS: (
+        mpsc::UnboundedSender<HostRequest>,
+        RefCell<Option<mpsc::UnboundedReceiver<HostRequest>>>,
+    ) = {
+        let (tx, rx) = mpsc::unbounded();
+        (tx, RefCell::new(Some(rx)))
+    };
+}
+
+/// Returns the receiving side of the requests emitted by the host.
+///
+/// Returns `None` if this function has already been called before.
+pub(crate) fn take_host_requests() -> Option<mpsc::UnboundedReceiver<HostRequest>> {
+    HOST_REQUESTS.with(|(_, rx)| rx.borrow_mut().take())
+}
+
+pub(crate) fn send_host_request(request: HostRequest) {
+    HOST_REQUESTS.with(|(tx, _)| tx.unbounded_send(request).unwrap())
+}
+
+/// See [`network_info_response`].
+#[derive(serde::Serialize)]
+struct NetworkInfo {
+    local_peer_id: String,
+    peers: Vec<NetworkInfoPeer>,
+}
+
+#[derive(serde::Serialize)]
+struct NetworkInfoPeer {
+    peer_id: String,
+    address: String,
+    connection_age_ms: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    chains: Vec<NetworkInfoPeerChain>,
+    blocks_requests_successes: u32,
+    blocks_requests_failures: u32,
+    blocks_requests_average_latency_ms: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct NetworkInfoPeerChain {
+    chain_index: usize,
+    role: &'static str,
+    best_block_number: u64,
+    best_block_hash: HexString,
+}
+
+/// Sends back to the host the answer to a [`HostRequest::NetworkInfo`].
+pub(crate) fn network_info_response(
+    local_peer_id: &smoldot::libp2p::PeerId,
+    peers: &[network_service::PeerInfo],
+) {
+    let data = NetworkInfo {
+        local_peer_id: local_peer_id.to_base58(),
+        peers: peers
+            .iter()
+            .map(|peer| NetworkInfoPeer {
+                peer_id: peer.peer_id.to_base58(),
+                address: peer.address.to_string(),
+                connection_age_ms: u64::try_from(peer.connection_age.as_millis()).unwrap(),
+                bytes_received: peer.bytes_received,
+                bytes_sent: peer.bytes_sent,
+                chains: peer
+                    .chains
+                    .iter()
+                    .map(|chain| NetworkInfoPeerChain {
+                        chain_index: chain.chain_index,
+                        role: match chain.role {
+                            smoldot::network::protocol::Role::Full => "full",
+                            smoldot::network::protocol::Role::Light => "light",
+                            smoldot::network::protocol::Role::Authority => "authority",
+                        },
+                        best_block_number: chain.best_block_number,
+                        best_block_hash: HexString(chain.best_block_hash.to_vec()),
+                    })
+                    .collect(),
+                blocks_requests_successes: peer.blocks_requests_successes,
+                blocks_requests_failures: peer.blocks_requests_failures,
+                blocks_requests_average_latency_ms: peer
+                    .blocks_requests_average_latency
+                    .map(|latency| u64::try_from(latency.as_millis()).unwrap()),
+            })
+            .collect(),
+    };
+
+    host::host().network_info_response(&serde_json::to_string(&data).unwrap());
+}
+
+/// See [`index_export_response`].
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum IndexExport {
+    Archive(String),
+    Error(String),
+}
+
+/// Builds an index archive out of the content passed by the host, and sends it back. See
+/// [`bindings::export_index`].
+fn index_export(request: &str) {
+    let response = match crate::index_archive::export(request) {
+        Ok(archive) => IndexExport::Archive(archive),
+        Err(err) => IndexExport::Error(err.to_string()),
+    };
+
+    host::host().index_export_response(&serde_json::to_string(&response).unwrap());
+}
+
+/// Sends back to the host the answer to a [`HostRequest::StorageQuery`]. `answer` must be
+/// encoded in JSON.
+pub(crate) fn storage_query_response(answer: &str) {
+    host::host().storage_query_response(answer);
+}
+
+/// See [`runtime_call_response`].
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+pub(crate) enum RuntimeCallResponse {
+    Success {
+        block_number: u64,
+        block_hash: HexString,
+        output: HexString,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// Sends back to the host the answer to a [`HostRequest::RuntimeCall`].
+pub(crate) fn runtime_call_response(response: &RuntimeCallResponse) {
+    host::host().runtime_call_response(&serde_json::to_string(response).unwrap());
+}
+
+/// Sends to the host a JSON-RPC response or notification. `message` must be encoded in JSON.
+pub(crate) fn json_rpc_respond(message: &str) {
+    host::host().json_rpc_respond(message);
+}
+
 /// See [`database_save`].
 #[derive(serde::Serialize)]
 pub(crate) struct DatabaseSave<'a> {
@@ -228,36 +471,132 @@ pub(crate) struct DatabaseSave<'a> {
     pub(crate) blocks: Vec<DatabaseSaveBlock>,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub(crate) struct DatabaseSaveMetadata {
     pub runtime_spec: u32,
     pub spec_name: String,
     pub metadata: smoldot::json_rpc::methods::HexString,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub(crate) struct DatabaseSaveBlock {
     pub number: u64,
     pub runtime_spec: u32,
     pub events: smoldot::json_rpc::methods::HexString,
+    /// Changes to the balances of accounts in this block. Always empty if balances aren't
+    /// indexed. See [`crate::ChainConfig::index_balances`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub balance_changes: Vec<DatabaseSaveBalanceChange>,
+    /// Changes to the storage keys under the watched prefixes in this block, ordered by key.
+    /// See [`bindings::watch_storage_prefix`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage_changes: Vec<DatabaseSaveStorageChange>,
+}
+
+/// New value of a storage key under one of the watched prefixes. See
+/// [`bindings::watch_storage_prefix`].
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct DatabaseSaveStorageChange {
+    pub key: smoldot::json_rpc::methods::HexString,
+    /// `None` if the key has been removed from the storage.
+    pub value: Option<smoldot::json_rpc::methods::HexString>,
+}
+
+/// New balances of an account. See [`crate::balances`].
+///
+/// The balances are encoded as decimal strings, as they don't fit in a JavaScript number.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct DatabaseSaveBalanceChange {
+    pub account: smoldot::json_rpc::methods::HexString,
+    pub free: String,
+    pub reserved: String,
+    pub frozen: String,
 }
 
 /// Merges the argument into the database.
 pub(crate) fn database_save(data: &DatabaseSave) {
-    unsafe {
-        let data = serde_json::to_vec(data).unwrap();
-        bindings::database_save(
-            u32::try_from(data.as_ptr() as usize).unwrap(),
-            u32::try_from(data.len()).unwrap(),
-        );
+    host::host().database_save(&serde_json::to_string(data).unwrap());
+}
+
+/// See [`best_block_notification`].
+#[derive(serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum BestBlockNotification {
+    Tentative {
+        number: u64,
+        hash: HexString,
+        runtime_spec: u32,
+        events: HexString,
+    },
+    Retract {
+        number: u64,
+        hash: HexString,
+    },
+    Confirm {
+        number: u64,
+        hash: HexString,
+    },
+}
+
+/// Notifies the environment of a change concerning a non-finalized best block.
+pub(crate) fn best_block_notification(notification: &BestBlockNotification) {
+    host::host().best_block_notification(&serde_json::to_string(notification).unwrap());
+}
+
+/// See [`sync_status_update`].
+#[derive(serde::Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub(crate) enum SyncStatus {
+    Syncing {
+        best_block_number: u64,
+    },
+    Stalled {
+        best_block_number: u64,
+        cause: StallCause,
+        stalled_for_ms: u64,
+    },
+}
+
+/// Most likely reason why the syncing isn't making progress.
+#[derive(Debug, Copy, Clone, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StallCause {
+    /// Not connected to any peer.
+    NoSource,
+    /// The chain keeps being reset because of consensus issues.
+    RepeatedResets,
+    /// None of the peers reports a best block higher than ours.
+    StaleSources,
+    /// Peers report higher blocks, but requesting these blocks doesn't succeed.
+    RequestsFailing,
+}
+
+impl fmt::Display for StallCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StallCause::NoSource => write!(f, "not connected to any peer"),
+            StallCause::RepeatedResets => write!(f, "repeated consensus issues"),
+            StallCause::StaleSources => write!(f, "no peer reports a higher best block"),
+            StallCause::RequestsFailing => write!(f, "blocks requests are failing"),
+        }
     }
 }
 
+/// Sends to the environment a new entry of the network recording. See [`crate::recording`].
+pub(crate) fn network_recording_entry(entry: &str) {
+    host::host().network_recording_entry(entry);
+}
+
+/// Notifies the environment of a change in the status of the syncing.
+pub(crate) fn sync_status_update(status: &SyncStatus) {
+    host::host().sync_status_update(&serde_json::to_string(status).unwrap());
+}
+
 /// Notifies the environment of an update of the best block.
 pub fn best_block_update(best_block_number: u64) {
     // Since Wasm doesn't support 64bits number, any block higher than 2^32 will panic here.
     let best_block_number = u32::try_from(best_block_number).unwrap();
-    unsafe { bindings::best_block_update(best_block_number) }
+    host::host().best_block_update(best_block_number)
 }
 
 /// Implementation of [`log::Log`] that sends out logs to the FFI.
@@ -272,25 +611,27 @@ impl log::Log for Logger {
         let target = record.target();
         let message = format!("{}", record.args());
 
-        unsafe {
-            bindings::log(
-                record.level() as usize as u32,
-                u32::try_from(target.as_bytes().as_ptr() as usize).unwrap(),
-                u32::try_from(target.as_bytes().len()).unwrap(),
-                u32::try_from(message.as_bytes().as_ptr() as usize).unwrap(),
-                u32::try_from(message.as_bytes().len()).unwrap(),
-            )
-        }
+        host::host().log(record.level() as usize as u32, target, &message)
     }
 
     fn flush(&self) {}
 }
 
+thread_local! {
+    /// All the existing [`Connection`]s, indexed by the identifier passed to the host.
+    ///
+    /// Entries are inserted by [`Connection::connect`] and removed when the [`Connection`] is
+    /// destroyed, which guarantees that the pointers are always valid.
+    static CONNECTIONS: RefCell<Registry<*mut Connection>> = RefCell::new(Registry::new());
+}
+
 /// Connection connected to a target.
 pub struct Connection {
-    /// If `Some`, [`bindings::connection_close`] must be called. Set to a value after
+    /// Identifier of the connection passed to the host. Key in [`CONNECTIONS`].
+    id: u32,
+    /// If true, [`bindings::connection_close`] must be called. Set after
     /// [`bindings::connection_new`] returns success.
-    id: Option<u32>,
+    host_open: bool,
     /// True if [`bindings::connection_open`] has been called.
     open: bool,
     /// True if [`bindings::connection_closed`] has been called.
@@ -300,6 +641,12 @@ pub struct Connection {
     messages_queue: VecDeque<Box<[u8]>>,
     /// Position of the read cursor within the first element of [`Connection::messages_queue`].
     messages_queue_first_offset: usize,
+    /// Number of bytes in [`Connection::messages_queue`] that haven't been read yet.
+    messages_queue_bytes: usize,
+    /// See [`Connection::connect`].
+    max_buffered_bytes: usize,
+    /// True if [`bindings::connection_set_reading_paused`] has been called to pause reading.
+    reading_paused: bool,
     /// Waker to wake up whenever one of the fields above is modified.
     waker: Option<Waker>,
     /// Prevents the [`Connection`] from being unpinned.
@@ -308,34 +655,45 @@ pub struct Connection {
 
 impl Connection {
     /// Connects to the given URL. Returns a [`Connection`] on success.
-    pub fn connect(url: &str) -> impl Future<Output = Result<Pin<Box<Self>>, ()>> {
+    ///
+    /// `max_buffered_bytes` is the number of bytes that can be received and not read yet before
+    /// the host is asked to pause reading from the socket. If this number is exceeded by a factor
+    /// of [`CONNECTION_BUFFER_CLOSE_FACTOR`], for example because the host doesn't support
+    /// pausing, the connection is closed.
+    pub fn connect(
+        url: &str,
+        max_buffered_bytes: usize,
+    ) -> impl Future<Output = Result<Pin<Box<Self>>, ()>> {
         let mut pointer = Box::pin(Connection {
-            id: None,
+            id: 0,
+            host_open: false,
             open: false,
             closed: false,
             messages_queue: VecDeque::with_capacity(32),
             messages_queue_first_offset: 0,
+            messages_queue_bytes: 0,
+            max_buffered_bytes,
+            reading_paused: false,
             waker: None,
             _pinned: marker::PhantomPinned,
         });
 
-        let id = u32::try_from(&*pointer as *const Connection as usize).unwrap();
-
-        let ret_code = unsafe {
-            bindings::connection_new(
-                id,
-                u32::try_from(url.as_bytes().as_ptr() as usize).unwrap(),
-                u32::try_from(url.as_bytes().len()).unwrap(),
-            )
+        let id = {
+            let this = unsafe { Pin::get_unchecked_mut(pointer.as_mut()) };
+            this.id = CONNECTIONS
+                .with(|connections| connections.borrow_mut().insert(this as *mut _, |_| false));
+            this.id
         };
 
+        let success = host::host().connection_new(id, url);
+
         async move {
-            if ret_code != 0 {
+            if !success {
                 return Err(());
             }
 
             unsafe {
-                Pin::get_unchecked_mut(pointer.as_mut()).id = Some(id);
+                Pin::get_unchecked_mut(pointer.as_mut()).host_open = true;
             }
 
             future::poll_fn(|cx| {
@@ -412,6 +770,16 @@ impl Connection {
         let this = unsafe { Pin::get_unchecked_mut(self.as_mut()) };
 
         this.messages_queue_first_offset += bytes;
+        this.messages_queue_bytes -= bytes;
+
+        // Resume reading once the queue has been sufficiently emptied. Waiting for the queue to
+        // be half-empty avoids pausing and resuming continuously.
+        if this.reading_paused && this.messages_queue_bytes <= this.max_buffered_bytes / 2 {
+            this.reading_paused = false;
+            if this.host_open {
+                host::host().connection_set_reading_paused(this.id, false);
+            }
+        }
 
         if let Some(buffer) = this.messages_queue.front() {
             assert!(this.messages_queue_first_offset <= buffer.len());
@@ -426,38 +794,58 @@ impl Connection {
 
     /// Queues the given buffer. For WebSocket connections, queues it as a binary frame.
     pub fn send(self: &mut Pin<Box<Self>>, data: &[u8]) {
-        unsafe {
-            let this = Pin::get_unchecked_mut(self.as_mut());
+        // Connection might have been closed, but API user hasn't detected it yet.
+        if self.closed {
+            return;
+        }
 
-            // Connection might have been closed, but API user hasn't detected it yet.
-            if this.closed {
-                return;
-            }
+        debug_assert!(self.host_open);
+        host::host().connection_send(self.id, data);
+    }
 
-            bindings::connection_send(
-                this.id.unwrap(),
-                u32::try_from(data.as_ptr() as usize).unwrap(),
-                u32::try_from(data.len()).unwrap(),
-            );
+    /// Closes the connection. [`Connection::read_buffer`] returns `None` afterwards, and data
+    /// passed to [`Connection::send`] is discarded.
+    pub fn close(self: Pin<&mut Self>) {
+        let this = unsafe { Pin::get_unchecked_mut(self) };
+
+        if this.host_open {
+            this.host_open = false;
+            host::host().connection_close(this.id);
         }
+
+        this.closed = true;
+        this.messages_queue.clear();
+        this.messages_queue_first_offset = 0;
+        this.messages_queue_bytes = 0;
     }
 }
 
+/// See [`Connection::connect`].
+pub const CONNECTION_BUFFER_CLOSE_FACTOR: usize = 4;
+
 impl fmt::Debug for Connection {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_tuple("Connection")
-            .field(self.id.as_ref().unwrap())
-            .finish()
+        f.debug_tuple("Connection").field(&self.id).finish()
     }
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
-        if let Some(id) = self.id {
-            unsafe {
-                bindings::connection_close(id);
-            }
+        if self.host_open {
+            host::host().connection_close(self.id);
         }
+
+        CONNECTIONS.with(|connections| connections.borrow_mut().remove(self.id));
+    }
+}
+
+/// Calls `f` with the [`Connection`] of the given identifier. Does nothing if the connection
+/// no longer exists, for example if the host reports an event after it has been destroyed.
+fn with_connection(id: u32, f: impl FnOnce(&mut Connection)) {
+    // The borrow is released before calling `f`, as `f` might create or destroy connections.
+    let pointer = CONNECTIONS.with(|connections| connections.borrow().get(id).copied());
+    if let Some(pointer) = pointer {
+        f(unsafe { &mut *pointer })
     }
 }
 
@@ -471,40 +859,54 @@ fn alloc(len: u32) -> u32 {
     u32::try_from(ptr as *mut u8 as usize).unwrap()
 }
 
-fn init(
-    chain_specs_ptr: u32,
-    chain_specs_len: u32,
-    database_content_ptr: u32,
-    database_content_len: u32,
+/// Configuration passed to [`bindings::init`].
+#[derive(serde::Deserialize)]
+struct InitConfig {
+    chain_spec: String,
+    database_content: Option<String>,
+    node_key: Option<HexString>,
     max_log_level: u32,
-) {
-    let chain_specs_ptr = usize::try_from(chain_specs_ptr).unwrap();
-    let chain_specs_len = usize::try_from(chain_specs_len).unwrap();
-    let database_content_ptr = usize::try_from(database_content_ptr).unwrap();
-    let database_content_len = usize::try_from(database_content_len).unwrap();
-
-    let chain_specs: Box<[u8]> = unsafe {
-        Box::from_raw(slice::from_raw_parts_mut(
-            chain_specs_ptr as *mut u8,
-            chain_specs_len,
-        ))
-    };
-
-    let chain_specs = String::from_utf8(Vec::from(chain_specs)).expect("non-utf8 chain specs");
+    #[serde(default)]
+    record_network: bool,
+    network_replay: Option<String>,
+    blocks_archive: Option<HexString>,
+    index_archive: Option<String>,
+    snapshot: Option<String>,
+    trusted_snapshot_hash: Option<HexString>,
+    #[serde(default)]
+    index_balances: bool,
+    #[serde(default)]
+    watched_storage_prefixes: Vec<HexString>,
+}
 
-    let database_content = if database_content_ptr != 0 {
-        let data: Box<[u8]> = unsafe {
-            Box::from_raw(slice::from_raw_parts_mut(
-                database_content_ptr as *mut u8,
-                database_content_len,
-            ))
-        };
-        String::from_utf8(Vec::from(data)).ok()
-    } else {
-        None
+fn init(config_ptr: u32, config_len: u32) {
+    let config: InitConfig = match serde_json::from_slice(&take_buffer(config_ptr, config_len)) {
+        Ok(config) => config,
+        Err(err) => throw(format!("Invalid configuration: {}", err)),
     };
 
-    let max_log_level = match max_log_level {
+    let node_key = config
+        .node_key
+        .map(|key| match <[u8; 32]>::try_from(&key.0[..]) {
+            Ok(key) => key,
+            Err(_) => throw(format!(
+                "Node key must be 32 bytes long, got {} bytes",
+                key.0.len()
+            )),
+        });
+
+    let trusted_snapshot_hash =
+        config
+            .trusted_snapshot_hash
+            .map(|hash| match <[u8; 32]>::try_from(&hash.0[..]) {
+                Ok(hash) => hash,
+                Err(_) => throw(format!(
+                    "Trusted snapshot hash must be 32 bytes long, got {} bytes",
+                    hash.0.len()
+                )),
+            });
+
+    let max_log_level = match config.max_log_level {
         0 => log::LevelFilter::Off,
         1 => log::LevelFilter::Error,
         2 => log::LevelFilter::Warn,
@@ -515,51 +917,175 @@ fn init(
 
     spawn_task(super::start_client(
         super::ChainConfig {
-            specification: chain_specs,
-            database_content,
+            specification: config.chain_spec,
+            database_content: config.database_content,
+            node_key,
+            record_network: config.record_network,
+            network_replay: config.network_replay,
+            blocks_archive: config.blocks_archive.map(|archive| archive.0),
+            index_archive: config.index_archive,
+            snapshot: config.snapshot,
+            trusted_snapshot_hash,
+            index_balances: config.index_balances,
+            watched_storage_prefixes: config
+                .watched_storage_prefixes
+                .into_iter()
+                .map(|prefix| prefix.0)
+                .collect(),
         },
         max_log_level,
     ));
 }
 
+fn network_info() {
+    send_host_request(HostRequest::NetworkInfo);
+}
+
+/// Takes ownership of a buffer allocated with [`alloc`] and passed by the host.
+fn take_buffer(ptr: u32, len: u32) -> Box<[u8]> {
+    let ptr = usize::try_from(ptr).unwrap();
+    let len = usize::try_from(len).unwrap();
+    unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr as *mut u8, len)) }
+}
+
+/// Turns a buffer allocated with [`alloc`] and passed by the host into a `String`. Invalid UTF-8
+/// sequences are replaced.
+fn take_string(ptr: u32, len: u32) -> String {
+    String::from_utf8_lossy(&take_buffer(ptr, len)).into_owned()
+}
+
+fn export_index(ptr: u32, len: u32) {
+    index_export(&take_string(ptr, len));
+}
+
+fn watch_storage_prefix(ptr: u32, len: u32) {
+    send_host_request(HostRequest::WatchStoragePrefix(
+        take_buffer(ptr, len).into_vec(),
+    ));
+}
+
+fn unwatch_storage_prefix(ptr: u32, len: u32) {
+    send_host_request(HostRequest::UnwatchStoragePrefix(
+        take_buffer(ptr, len).into_vec(),
+    ));
+}
+
+fn storage_query(ptr: u32, len: u32) {
+    send_host_request(HostRequest::StorageQuery(take_string(ptr, len)));
+}
+
+fn runtime_call(function_ptr: u32, function_len: u32, parameter_ptr: u32, parameter_len: u32) {
+    send_host_request(HostRequest::RuntimeCall {
+        function: take_string(function_ptr, function_len),
+        parameter: take_buffer(parameter_ptr, parameter_len).into_vec(),
+    });
+}
+
+fn json_rpc_send(ptr: u32, len: u32) {
+    send_host_request(HostRequest::JsonRpc(take_string(ptr, len)));
+}
+
+fn add_peer(addr_ptr: u32, addr_len: u32) {
+    send_host_request(HostRequest::AddPeer {
+        address: take_string(addr_ptr, addr_len),
+    });
+}
+
+fn disconnect_peer(peer_id_ptr: u32, peer_id_len: u32, ban: bool) {
+    send_host_request(HostRequest::DisconnectPeer {
+        peer_id: take_string(peer_id_ptr, peer_id_len),
+        ban,
+    });
+}
+
+fn set_peer_reserved(peer_id_ptr: u32, peer_id_len: u32, reserved: bool) {
+    send_host_request(HostRequest::SetPeerReserved {
+        peer_id: take_string(peer_id_ptr, peer_id_len),
+        reserved,
+    });
+}
+
+fn set_reserved_only(reserved_only: bool) {
+    send_host_request(HostRequest::SetReservedOnly(reserved_only));
+}
+
 fn timer_finished(timer_id: u32) {
-    let callback = {
-        let ptr = timer_id as *mut Box<dyn FnOnce()>;
-        unsafe { Box::from_raw(ptr) }
-    };
+    if timer_id == timers::HOST_TIMER_ID {
+        timers::host_timer_finished();
+        return;
+    }
 
-    callback();
+    // The borrow is released before calling the callback, as it might start new timers.
+    let callback = TIMER_CALLBACKS.with(|callbacks| callbacks.borrow_mut().remove(timer_id));
+    if let Some(callback) = callback {
+        callback();
+    }
 }
 
 fn connection_open(id: u32) {
-    let connection = unsafe { &mut *(usize::try_from(id).unwrap() as *mut Connection) };
-    connection.open = true;
-    if let Some(waker) = connection.waker.take() {
-        waker.wake();
-    }
+    with_connection(id, |connection| {
+        connection.open = true;
+        if let Some(waker) = connection.waker.take() {
+            waker.wake();
+        }
+    })
 }
 
 fn connection_message(id: u32, ptr: u32, len: u32) {
-    let connection = unsafe { &mut *(usize::try_from(id).unwrap() as *mut Connection) };
-
     let ptr = usize::try_from(ptr).unwrap();
     let len = usize::try_from(len).unwrap();
 
     let message: Box<[u8]> =
         unsafe { Box::from_raw(slice::from_raw_parts_mut(ptr as *mut u8, len)) };
 
+    with_connection(id, |connection| {
+        connection_message_received(connection, message)
+    })
+}
+
+/// Processes a message received by the host on the given connection. See [`connection_message`].
+fn connection_message_received(connection: &mut Connection, message: Box<[u8]>) {
     // Ignore empty message to avoid all sorts of problems.
     if message.is_empty() {
         return;
     }
 
+    // The connection might have been closed because of a previous message, but the host might
+    // have already queued more messages.
+    if connection.closed {
+        return;
+    }
+
     if connection.messages_queue.is_empty() {
         connection.messages_queue_first_offset = 0;
     }
 
-    // TODO: add some limit to `messages_queue`, to avoid DoS attacks?
+    connection.messages_queue_bytes += message.len();
+
+    if connection.messages_queue_bytes
+        > connection
+            .max_buffered_bytes
+            .saturating_mul(CONNECTION_BUFFER_CLOSE_FACTOR)
+    {
+        // The host keeps sending data despite being asked to pause. Closing the connection
+        // prevents the memory usage from growing without limit.
+        log::warn!(
+            target: "connections",
+            "Closing connection with more than {} bytes of unprocessed data",
+            connection.messages_queue_bytes
+        );
+        // The connection is pinned, as it is owned by a `Pin<Box<Connection>>`.
+        Connection::close(unsafe { Pin::new_unchecked(&mut *connection) });
+    } else {
+        if connection.messages_queue_bytes > connection.max_buffered_bytes
+            && !connection.reading_paused
+        {
+            connection.reading_paused = true;
+            host::host().connection_set_reading_paused(connection.id, true);
+        }
 
-    connection.messages_queue.push_back(message);
+        connection.messages_queue.push_back(message);
+    }
 
     if let Some(waker) = connection.waker.take() {
         waker.wake();
@@ -567,9 +1093,10 @@ fn connection_message(id: u32, ptr: u32, len: u32) {
 }
 
 fn connection_closed(id: u32) {
-    let connection = unsafe { &mut *(usize::try_from(id).unwrap() as *mut Connection) };
-    connection.closed = true;
-    if let Some(waker) = connection.waker.take() {
-        waker.wake();
-    }
+    with_connection(id, |connection| {
+        connection.closed = true;
+        if let Some(waker) = connection.waker.take() {
+            waker.wake();
+        }
+    })
 }
@@ -112,11 +112,34 @@ extern "C" {
     /// by at least the given number of `milliseconds`.
     ///
     /// If `milliseconds` is 0, [`timer_finished`] should be called as soon as possible.
+    ///
+    /// The same `id` can be reused after [`timer_finished`] has been called for it or after it
+    /// has been passed to [`cancel_timer`].
     pub fn start_timer(id: u32, milliseconds: f64);
 
+    /// Must prevent [`timer_finished`] from being called for the timer with the given `id`,
+    /// previously started with [`start_timer`].
+    ///
+    /// Has no effect if [`timer_finished`] has already been called for this timer.
+    pub fn cancel_timer(id: u32);
+
     /// Client wants to report that the best block is the one passed as parameter.
     pub fn best_block_update(best_block_number: u32);
 
+    /// Client wants to report a change in the status of the syncing. The data is a UTF-8 string
+    /// found at offset `ptr` and with length `len`, in one of the following formats:
+    ///
+    /// ```notrust
+    /// {"state": "stalled", "best_block_number": 100000, "cause": "stale_sources", "stalled_for_ms": 60000}
+    /// {"state": "syncing", "best_block_number": 100001}
+    /// ```
+    ///
+    /// A `stalled` notification is sent if the best block hasn't advanced for a long time, and
+    /// is sent again periodically as long as this is the case. `cause` is one of `"no_source"`,
+    /// `"repeated_resets"`, `"stale_sources"` or `"requests_failing"`. A `syncing` notification
+    /// is sent once the best block advances again after a stall.
+    pub fn sync_status_update(ptr: u32, len: u32);
+
     /// Client wants to merge to the database the data found at offset `ptr` and with length
     /// `len`.
     ///
@@ -131,11 +154,157 @@ extern "C" {
     /// ```
     // TODO: finish ^
     ///
+    /// Blocks that modify storage keys under the prefixes passed to [`watch_storage_prefix`]
+    /// also contain a `storage_changes` field, ordered by key, where `value` is `null` if the key
+    /// has been removed:
+    ///
+    /// ```notrust
+    /// "storage_changes": [{"key": "0xffffff...", "value": "0xffffff..."}, ...]
+    /// ```
+    ///
     /// The value of `chain` is meant to later be passed to [`init`] when restarting the client.
     ///
     /// Saving the database is entirely optional, and it is legal to simply do nothing.
     pub fn database_save(ptr: u32, len: u32);
 
+    /// Client wants to report a change concerning a block that is the best block or used to be
+    /// the best block, but that isn't finalized yet. Only ever called after
+    /// [`set_follow_best_blocks`] has been called with a non-zero value.
+    ///
+    /// The data is a UTF-8 string found at offset `ptr` and with length `len`, in one of the
+    /// following formats:
+    ///
+    /// ```notrust
+    /// {"kind": "tentative", "number": 100000, "hash": "0xffffff...", "runtime_spec": 28, "events": "0xffffff..."}
+    /// {"kind": "retract", "number": 100000, "hash": "0xffffff..."}
+    /// {"kind": "confirm", "number": 100000, "hash": "0xffffff..."}
+    /// ```
+    ///
+    /// A `tentative` notification reports the events of a new best block. Every `tentative`
+    /// notification is later followed with either a `retract` notification, if the block is no
+    /// longer part of the best chain, or a `confirm` notification, if the block has been
+    /// finalized. Confirmed blocks are also found in the next call to [`database_save`].
+    ///
+    /// Not all best blocks are reported, as the client only fetches the events of the best block
+    /// when it is close to the head of the chain.
+    pub fn best_block_notification(ptr: u32, len: u32);
+
+    /// Client emits a new entry of the recording of the network activity. The entry is a UTF-8
+    /// string found in the memory of the WebAssembly virtual machine at offset `ptr` and with
+    /// length `len`.
+    ///
+    /// Only called if `record_network` has been passed to [`init`]. Entries are JSON objects
+    /// that never contain any line break. The recording, which can later be passed to [`init`]
+    /// in order to replay it, is the concatenation of all the entries in the order in which they
+    /// have been emitted, each followed with a line break. The format of the entries is
+    /// otherwise unspecified.
+    ///
+    /// Entries are emitted as soon as the activity they describe happens, and the client doesn't
+    /// keep them in memory. The recording is thus complete even if the client panics.
+    pub fn network_recording_entry(ptr: u32, len: u32);
+
+    /// Client answers a call to [`network_info`]. The answer is a UTF-8 string found in the
+    /// memory of the WebAssembly virtual machine at offset `ptr` and with length `len`.
+    ///
+    /// The answer is a JSON object in the following format:
+    ///
+    /// ```notrust
+    /// {
+    ///     "local_peer_id": "12D3KooW...",
+    ///     "peers": [{
+    ///         "peer_id": "12D3KooW...",
+    ///         "address": "/dns/example.com/tcp/443/wss",
+    ///         "connection_age_ms": 12000,
+    ///         "bytes_received": 1048576,
+    ///         "bytes_sent": 4096,
+    ///         "chains": [{
+    ///             "chain_index": 0,
+    ///             "role": "full",
+    ///             "best_block_number": 100000,
+    ///             "best_block_hash": "0xffffff..."
+    ///         }, ...],
+    ///         "blocks_requests_successes": 12,
+    ///         "blocks_requests_failures": 1,
+    ///         "blocks_requests_average_latency_ms": 850
+    ///     }, ...]
+    /// }
+    /// ```
+    ///
+    /// `role` is one of `"full"`, `"light"` or `"authority"`. `blocks_requests_average_latency_ms`
+    /// is `null` if no blocks request towards this peer has succeeded yet.
+    pub fn network_info_response(ptr: u32, len: u32);
+
+    /// Client answers a call to [`export_index`]. The answer is a UTF-8 string found in the
+    /// memory of the WebAssembly virtual machine at offset `ptr` and with length `len`.
+    ///
+    /// The answer is a JSON object in one of the following formats:
+    ///
+    /// ```notrust
+    /// {"archive": "..."}
+    /// {"error": "Checksum mismatch"}
+    /// ```
+    ///
+    /// The value of `archive` is a string that can later be passed to [`init`] in order to
+    /// resume from this index. The format of the archive is unspecified.
+    pub fn index_export_response(ptr: u32, len: u32);
+
+    /// Client answers a call to [`storage_query`]. The answer is a UTF-8 string found in the
+    /// memory of the WebAssembly virtual machine at offset `ptr` and with length `len`.
+    ///
+    /// The answer is a JSON object. It contains an `error` field if the query has failed, and
+    /// otherwise the number and hash of the finalized block alongside with the result, in one
+    /// of the following formats depending on the method of the query:
+    ///
+    /// ```notrust
+    /// {"block_number": 100000, "block_hash": "0xffffff...", "value": "0xffffff...", "decoded": {...}}
+    /// {"block_number": 100000, "block_hash": "0xffffff...", "keys": ["0xffffff...", ...]}
+    /// {"block_number": 100000, "block_hash": "0xffffff...", "next_key": "0xffffff..."}
+    /// {"error": "Invalid storage query: ..."}
+    /// ```
+    ///
+    /// `value` and `next_key` are `null` if there is no such value or key. `decoded` is only
+    /// present if requested, and if the key belongs to a storage item of the metadata of the
+    /// finalized runtime, in the following format:
+    ///
+    /// ```notrust
+    /// {
+    ///     "pallet": "System",
+    ///     "item": "Account",
+    ///     "value_type": "AccountInfo<T::Index, T::AccountData>",
+    ///     "keys": ["0xffffff...", null, ...],
+    ///     "is_default": false,
+    ///     "value": null
+    /// }
+    /// ```
+    ///
+    /// `keys` contains the keys of the map, or `null` for the keys that can't be recovered from
+    /// their hash. `is_default` is true if the key is absent and the value is the default value
+    /// of the storage item. `value` is the decoded value if its type is a well-known primitive
+    /// type, and `null` otherwise. Integers of more than 32 bits are encoded as decimal strings.
+    pub fn storage_query_response(ptr: u32, len: u32);
+
+    /// Client answers a call to [`runtime_call`]. The answer is a UTF-8 string found in the
+    /// memory of the WebAssembly virtual machine at offset `ptr` and with length `len`.
+    ///
+    /// The answer is a JSON object in one of the following formats:
+    ///
+    /// ```notrust
+    /// {"block_number": 100000, "block_hash": "0xffffff...", "output": "0xffffff..."}
+    /// {"error": "..."}
+    /// ```
+    ///
+    /// `output` is the SCALE-encoded value returned by the runtime function, called against the
+    /// storage of the finalized block whose number and hash are indicated.
+    pub fn runtime_call_response(ptr: u32, len: u32);
+
+    /// Client sends a JSON-RPC response or notification. The message is a UTF-8 string found in
+    /// the memory of the WebAssembly virtual machine at offset `ptr` and with length `len`.
+    ///
+    /// Responses answer the requests passed to [`json_rpc_send`], and are sent in the same
+    /// order as the requests. Notifications, such as `chain_finalizedHead`, can be sent at any
+    /// time after the corresponding subscription has been answered.
+    pub fn json_rpc_respond(ptr: u32, len: u32);
+
     /// Must initialize a new connection that tries to connect to the given multiaddress.
     ///
     /// The multiaddress is a UTF-8 string found in the WebAssembly memory at offset `addr_ptr`
@@ -188,6 +357,20 @@ extern "C" {
     /// The connection must currently be in the `Open` state. See the documentation of
     /// [`connection_new`] for details.
     pub fn connection_send(id: u32, ptr: u32, len: u32);
+
+    /// Asks the host to stop (if `paused` is non-zero) or resume (if `paused` is zero) reading
+    /// data from the given connection. Called when the client doesn't process incoming data
+    /// quickly enough.
+    ///
+    /// While reading is paused, the host should no longer call [`connection_message`] with this
+    /// connection, and let the data accumulate in the operating system's buffers or be subject
+    /// to the flow control of the underlying protocol. Hosts that can't pause reading can ignore
+    /// this call, but the connection is then closed by the client if the amount of unprocessed
+    /// data grows too much.
+    ///
+    /// The connection must currently be in the `Open` state. See the documentation of
+    /// [`connection_new`] for details.
+    pub fn connection_set_reading_paused(id: u32, paused: u32);
 }
 
 /// Allocates a buffer of the given length, with an alignment of 1.
@@ -200,32 +383,94 @@ pub extern "C" fn alloc(len: u32) -> u32 {
 
 /// Initializes the client.
 ///
-/// Use [`alloc`] to allocate either one to two buffers: one for the chain specs, and an optional
-/// one for the database content.
-/// The buffers **must** have been allocated with [`alloc`]. They are freed when this function is
-/// called.
+/// Use [`alloc`] to allocate a buffer, and write in it the configuration of the client, encoded
+/// in JSON. Then, pass the pointer and length of this buffer to this function. The buffer
+/// **must** have been allocated with [`alloc`]. It is freed when this function is called.
+///
+/// The configuration has the following format, where all the fields but `chain_spec` and
+/// `max_log_level` are optional:
 ///
-/// Write the chain specs and the database content in these two buffers.
-/// Then, pass the pointer and length of these buffers to this function.
-/// Pass `0` for `database_content_ptr` and `database_content_len` if the database is empty.
+/// ```notrust
+/// {
+///     "chain_spec": "...",
+///     "database_content": "...",
+///     "node_key": "0xffffff...",
+///     "max_log_level": 3,
+///     "record_network": false,
+///     "network_replay": "...",
+///     "blocks_archive": "0xffffff...",
+///     "index_archive": "...",
+///     "snapshot": "...",
+///     "trusted_snapshot_hash": "0xffffff...",
+///     "index_balances": false,
+///     "watched_storage_prefixes": ["0xffffff...", ...]
+/// }
+/// ```
+///
+/// `database_content` must be absent or `null` if the database is empty.
+///
+/// The node key, if provided, must be a 32 bytes ed25519 private key that determines the identity
+/// of the node on the network. If absent, the key stored in the database is used, or a new one is
+/// generated if the database doesn't contain any.
 ///
 /// The client will emit log messages by calling the [`log()`] function, provided the log level is
-/// inferior or equal to the value of `max_log_level` passed here.
+/// inferior or equal to `max_log_level`.
+///
+/// If `record_network` is `true`, the network activity is recorded, and sent to the host
+/// through [`network_recording_entry`].
+///
+/// A recording previously obtained through [`network_recording_entry`] can be passed as
+/// `network_replay`. The client then doesn't access the network, and instead replays the
+/// recording. The virtual clock (see [`set_virtual_clock`]) is then enabled and set to the time
+/// of the start of the recording, and [`advance_virtual_clock`] must be called in order for the
+/// replay to progress.
+///
+/// Blocks exported by a Substrate node with `export-blocks --binary` can be passed as
+/// `blocks_archive`. The client then doesn't access the network, and instead verifies the blocks
+/// of the archive and reports them through [`database_save`] as if they had been downloaded. The
+/// archive must contain the block following the finalized block of the database, and can't be
+/// combined with a network replay.
+///
+/// An index archive previously obtained through [`index_export_response`] can be passed as
+/// `index_archive`. The database content is then ignored, the client resumes from the state
+/// found in the archive, and the content of the archive is passed to [`database_save`] before
+/// anything else.
+///
+/// A snapshot of a recent finalized block can be passed as `snapshot`, in the following format:
+///
+/// ```notrust
+/// {
+///     "chain": <chain information and storage, as found in the database content>,
+///     "justification": "0xffffff...",
+///     "authority_set_changes": [{"header": "0xffffff...", "justification": "0xffffff..."}, ...]
+/// }
+/// ```
+///
+/// The client verifies the snapshot, and starts from it if it is more recent than the database
+/// content. Initialization fails if the snapshot can't be verified.
+///
+/// The hash of a trusted block can be passed as `trusted_snapshot_hash`, and must then be 32
+/// bytes long. If provided, the snapshot is accepted if its finalized block has this hash.
+/// Otherwise, the GrandPa justification of the finalized block is verified against the
+/// authorities of the genesis block. If the authorities have changed since then,
+/// `authority_set_changes` must contain the header and justification of each block that changes
+/// the authorities, in order.
+///
+/// If `index_balances` is `true`, each block passed to [`database_save`] contains a
+/// `balance_changes` field, listing the accounts whose balances have been modified by this
+/// block and their new free, reserved and frozen balances, as decimal strings:
+///
+/// ```notrust
+/// [{"account": "0xffffff...", "free": "1000", "reserved": "0", "frozen": "0"}, ...]
+/// ```
+///
+/// The field is absent if no balance has changed.
+///
+/// The storage prefixes of `watched_storage_prefixes` are watched as if passed to
+/// [`watch_storage_prefix`].
 #[no_mangle]
-pub extern "C" fn init(
-    chain_specs_ptr: u32,
-    chain_specs_len: u32,
-    database_content_ptr: u32,
-    database_content_len: u32,
-    max_log_level: u32,
-) {
-    super::init(
-        chain_specs_ptr,
-        chain_specs_len,
-        database_content_ptr,
-        database_content_len,
-        max_log_level,
-    )
+pub extern "C" fn init(config_ptr: u32, config_len: u32) {
+    super::init(config_ptr, config_len)
 }
 
 /// Must be called in response to [`start_timer`] after the given duration has passed.
@@ -268,6 +513,197 @@ pub extern "C" fn connection_closed(id: u32) {
     super::connection_closed(id)
 }
 
+/// Requests information about the peers the client is connected to. The client later answers by
+/// calling [`network_info_response`].
+///
+/// Can be called before [`init`], in which case the answer is sent after the initialization is
+/// complete. Answers are always sent in the same order as the requests.
+#[no_mangle]
+pub extern "C" fn network_info() {
+    super::network_info()
+}
+
+/// Builds an index archive, that can be moved to a different machine. The client later answers
+/// by calling [`index_export_response`].
+///
+/// The content of the index is a UTF-8 string found in the WebAssembly memory at offset `ptr`
+/// and with `len` bytes, in the following format:
+///
+/// ```notrust
+/// {
+///     "database_content": <opaque>,
+///     "metadata": [{"runtime_spec":28,"spec_name":"foo","metadata":"0xffffffff..."}, ...],
+///     "blocks": [{"number": 100000, "runtime_spec": 28, "events":"0xffffff..."}, ...]
+/// }
+/// ```
+///
+/// `database_content` is the latest value of `chain` passed to [`database_save`], and
+/// `metadata` and `blocks` are all the entries of `new_metadata` and `blocks` passed to
+/// [`database_save`].
+///
+/// The buffer **must** have been allocated with [`alloc`]. It is freed when this function is
+/// called.
+///
+/// Contrary to the other functions, the answer is sent immediately, and this function can be
+/// called at any time, including before [`init`].
+#[no_mangle]
+pub extern "C" fn export_index(ptr: u32, len: u32) {
+    super::export_index(ptr, len)
+}
+
+/// Adds a peer to the network. The address is a UTF-8 string found in the WebAssembly memory at
+/// offset `addr_ptr` and with `addr_len` bytes, and must be a multiaddress ending with
+/// `/p2p/<peer id>`, such as `/dns/example.com/tcp/443/wss/p2p/12D3KooW...`.
+///
+/// The buffer **must** have been allocated with [`alloc`]. It is freed when this function is
+/// called.
+///
+/// The peer is saved in the address book that is part of the database content, and is thus
+/// dialed starting from the next time [`init`] is called. Invalid addresses are ignored.
+#[no_mangle]
+pub extern "C" fn add_peer(addr_ptr: u32, addr_len: u32) {
+    super::add_peer(addr_ptr, addr_len)
+}
+
+/// Closes the connection with the given peer, if any. The peer id is a UTF-8 string found in the
+/// WebAssembly memory at offset `peer_id_ptr` and with `peer_id_len` bytes.
+///
+/// The buffer **must** have been allocated with [`alloc`]. It is freed when this function is
+/// called.
+///
+/// Pass a non-zero value for `ban` in order to also forget about this peer and never dial it
+/// again, unless it is later added again with [`add_peer`] or [`set_peer_reserved`].
+#[no_mangle]
+pub extern "C" fn disconnect_peer(peer_id_ptr: u32, peer_id_len: u32, ban: u32) {
+    super::disconnect_peer(peer_id_ptr, peer_id_len, ban != 0)
+}
+
+/// Marks or unmarks a peer as reserved. The peer id is a UTF-8 string found in the WebAssembly
+/// memory at offset `peer_id_ptr` and with `peer_id_len` bytes.
+///
+/// The buffer **must** have been allocated with [`alloc`]. It is freed when this function is
+/// called.
+///
+/// Reaching a reserved peer is attempted again after a failure or after the connection has been
+/// closed. Pass a non-zero value for `reserved` for true.
+#[no_mangle]
+pub extern "C" fn set_peer_reserved(peer_id_ptr: u32, peer_id_len: u32, reserved: u32) {
+    super::set_peer_reserved(peer_id_ptr, peer_id_len, reserved != 0)
+}
+
+/// Sets whether the node should only connect to reserved peers. See [`set_peer_reserved`].
+///
+/// When enabled, connections to non-reserved peers are closed. Pass a non-zero value for true.
+#[no_mangle]
+pub extern "C" fn set_reserved_only(reserved_only: u32) {
+    super::set_reserved_only(reserved_only != 0)
+}
+
+/// Sets whether to report the events of new best blocks before they are finalized, through
+/// [`best_block_notification`]. Disabled by default.
+///
+/// Pass a non-zero value for true.
+#[no_mangle]
+pub extern "C" fn set_follow_best_blocks(boolean: u32) {
+    super::set_follow_best_blocks(boolean != 0)
+}
+
+/// Queries the storage of the current finalized block. The client later answers by calling
+/// [`storage_query_response`].
+///
+/// The query is a UTF-8 string found in the WebAssembly memory at offset `ptr` and with `len`
+/// bytes, in one of the following formats:
+///
+/// ```notrust
+/// {"method": "get", "key": "0xffffff...", "decode": true}
+/// {"method": "keys", "prefix": "0xffffff...", "start_key": "0xffffff...", "count": 100}
+/// {"method": "next_key", "key": "0xffffff..."}
+/// ```
+///
+/// `get` returns the value of a key, and, if `decode` is true, decodes it with the help of the
+/// metadata of the finalized runtime. `decode` is optional and defaults to false. `keys` returns
+/// at most `count` keys starting with `prefix`, in lexicographic order, and strictly after
+/// `start_key`. `start_key` is optional, and can be set to the last key of the previous answer
+/// in order to paginate. At most 1000 keys are returned. `next_key` returns the first key
+/// strictly after `key`.
+///
+/// The buffer **must** have been allocated with [`alloc`]. It is freed when this function is
+/// called.
+///
+/// Can be called before [`init`], in which case the answer is sent after the initialization is
+/// complete. Answers are always sent in the same order as the requests.
+#[no_mangle]
+pub extern "C" fn storage_query(ptr: u32, len: u32) {
+    super::storage_query(ptr, len)
+}
+
+/// Calls a function of the runtime of the current finalized block, such as
+/// `AccountNonceApi_account_nonce` or `Metadata_metadata`, against the storage of this block.
+/// The client later answers by calling [`runtime_call_response`].
+///
+/// The name of the function is a UTF-8 string found in the WebAssembly memory at offset
+/// `function_ptr` and with `function_len` bytes. The SCALE-encoded parameter of the function is
+/// found at offset `parameter_ptr` and with `parameter_len` bytes.
+///
+/// Both buffers **must** have been allocated with [`alloc`], including when the parameter is
+/// empty. They are freed when this function is called.
+///
+/// The call can't modify the storage. Can be called before [`init`], in which case the answer
+/// is sent after the initialization is complete. Answers are always sent in the same order as
+/// the requests.
+#[no_mangle]
+pub extern "C" fn runtime_call(
+    function_ptr: u32,
+    function_len: u32,
+    parameter_ptr: u32,
+    parameter_len: u32,
+) {
+    super::runtime_call(function_ptr, function_len, parameter_ptr, parameter_len)
+}
+
+/// Sends a request following the standard Substrate JSON-RPC API. The client later answers by
+/// calling [`json_rpc_respond`].
+///
+/// The request is a UTF-8 string found in the WebAssembly memory at offset `ptr` and with `len`
+/// bytes. The following methods are supported: `chain_getBlockHash`, `chain_getFinalizedHead`,
+/// `chain_getHeader`, `chain_subscribeFinalizedHeads`, `chain_unsubscribeFinalizedHeads`,
+/// `rpc_methods`, `state_getMetadata`, `state_getRuntimeVersion`, `state_getStorage`,
+/// `system_chain`, `system_health`, `system_name`, `system_properties` and `system_version`.
+///
+/// Requests are answered from the state verified by the client. The storage, metadata and
+/// runtime version are only available for the current finalized block, and headers only for the
+/// current finalized and best blocks.
+///
+/// The buffer **must** have been allocated with [`alloc`]. It is freed when this function is
+/// called.
+///
+/// Can be called before [`init`], in which case the answer is sent after the initialization is
+/// complete.
+#[no_mangle]
+pub extern "C" fn json_rpc_send(ptr: u32, len: u32) {
+    super::json_rpc_send(ptr, len)
+}
+
+/// Starts reporting the changes to the storage keys that start with the given prefix, through
+/// the `storage_changes` field of the blocks passed to [`database_save`].
+///
+/// The prefix is found in a buffer allocated with [`alloc`], at offset `ptr` and with length
+/// `len`. Only blocks finalized after this call are concerned. Can be called before [`init`].
+#[no_mangle]
+pub extern "C" fn watch_storage_prefix(ptr: u32, len: u32) {
+    super::watch_storage_prefix(ptr, len)
+}
+
+/// Stops reporting the changes to the storage keys that start with the given prefix, which
+/// must have been passed to [`watch_storage_prefix`] before.
+///
+/// The prefix is found in a buffer allocated with [`alloc`], at offset `ptr` and with length
+/// `len`.
+#[no_mangle]
+pub extern "C" fn unwatch_storage_prefix(ptr: u32, len: u32) {
+    super::unwatch_storage_prefix(ptr, len)
+}
+
 /// Sets whether to pause the syncing process of the node.
 ///
 /// Pass a non-zero value for true.
@@ -275,3 +711,26 @@ pub extern "C" fn connection_closed(id: u32) {
 pub extern "C" fn set_syncing_paused(boolean: u32) {
     super::set_syncing_paused(boolean != 0)
 }
+
+/// Switches the client to a virtual clock whose UNIX time is `unix_time_ms` milliseconds, and
+/// that only advances when [`advance_virtual_clock`] is called.
+///
+/// Afterwards, [`unix_time_ms`] and [`monotonic_clock_ms`] are no longer called, and
+/// [`start_timer`] is only used to schedule tasks. Can be called again to reset the UNIX time of
+/// the virtual clock.
+#[no_mangle]
+pub extern "C" fn set_virtual_clock(unix_time_ms: f64) {
+    super::clock::set_virtual(core::time::Duration::from_secs_f64(
+        unix_time_ms.max(0.0) / 1000.0,
+    ))
+}
+
+/// Advances the virtual clock by the given number of milliseconds.
+///
+/// Must only be called after [`set_virtual_clock`].
+#[no_mangle]
+pub extern "C" fn advance_virtual_clock(milliseconds: f64) {
+    super::clock::advance(core::time::Duration::from_secs_f64(
+        milliseconds.max(0.0) / 1000.0,
+    ))
+}
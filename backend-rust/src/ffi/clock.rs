@@ -0,0 +1,119 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Clock used by [`super::Instant`], [`super::unix_time`] and [`super::Delay`].
+//!
+//! By default, the clocks of the host are used. Calling [`set_virtual`] switches to a virtual
+//! clock that only advances when [`advance`] is called. The [`super::Delay`]s then no longer
+//! rely on host timers, and are instead woken up by [`advance`] when their deadline is reached.
+//!
+//! This makes it possible to control time regardless of the host, for example in order to test
+//! timeouts deterministically, or to replay a recording faster or slower than real time.
+//!
+//! Once the virtual clock is enabled, it stays enabled. The virtual monotonic clock starts at
+//! the value of the host monotonic clock, so that [`super::Instant`]s created beforehand remain
+//! valid.
+
+use super::{host, timers};
+
+use core::{cell::RefCell, time::Duration};
+
+thread_local! {
+    /// `Some` if the virtual clock is enabled.
+    static VIRTUAL: RefCell<Option<VirtualClock>> = const { RefCell::new(None) };
+}
+
+struct VirtualClock {
+    /// Current value of the monotonic clock, in milliseconds.
+    monotonic_ms: f64,
+    /// Value of the UNIX time, in milliseconds, minus [`VirtualClock::monotonic_ms`].
+    unix_time_offset_ms: f64,
+}
+
+/// Switches to the virtual clock, or resets the UNIX time of the virtual clock if it is already
+/// enabled. The UNIX time is set to `unix_time`, and the monotonic clock is left untouched.
+pub fn set_virtual(unix_time: Duration) {
+    let monotonic_ms = monotonic_clock_ms();
+    VIRTUAL.with(|clock| {
+        *clock.borrow_mut() = Some(VirtualClock {
+            monotonic_ms,
+            unix_time_offset_ms: unix_time.as_secs_f64() * 1000.0 - monotonic_ms,
+        })
+    });
+
+    // The host timer, if any, is no longer necessary.
+    timers::update_host_timer();
+}
+
+/// Returns true if [`set_virtual`] has been called.
+pub fn is_virtual() -> bool {
+    VIRTUAL.with(|clock| clock.borrow().is_some())
+}
+
+/// Advances the virtual clock by `duration`.
+///
+/// The [`super::Delay`]s whose deadline is reached are woken up in order, with the clock set to
+/// their deadline. Note that the tasks woken up might only run after this function returns,
+/// depending on the executor.
+///
+/// # Panic
+///
+/// Panics if the virtual clock isn't enabled.
+///
+pub fn advance(duration: Duration) {
+    assert!(is_virtual(), "the virtual clock isn't enabled");
+    let target_ms = monotonic_clock_ms() + duration.as_secs_f64() * 1000.0;
+
+    while let Some(deadline) = timers::earliest_deadline() {
+        if deadline.inner > target_ms {
+            break;
+        }
+
+        set_monotonic_clock_ms(deadline.inner);
+        timers::wake_elapsed();
+    }
+
+    set_monotonic_clock_ms(target_ms);
+}
+
+/// Returns the current value of the monotonic clock, in milliseconds.
+pub(super) fn monotonic_clock_ms() -> f64 {
+    VIRTUAL
+        .with(|clock| clock.borrow().as_ref().map(|c| c.monotonic_ms))
+        .unwrap_or_else(|| host::host().monotonic_clock_ms())
+}
+
+/// Returns the number of milliseconds elapsed since the UNIX epoch, ignoring leap seconds.
+pub(super) fn unix_time_ms() -> f64 {
+    VIRTUAL
+        .with(|clock| {
+            clock
+                .borrow()
+                .as_ref()
+                .map(|c| c.unix_time_offset_ms + c.monotonic_ms)
+        })
+        .unwrap_or_else(|| host::host().unix_time_ms())
+}
+
+/// Sets the monotonic clock to the given value. Never moves the clock backwards.
+fn set_monotonic_clock_ms(value: f64) {
+    VIRTUAL.with(|clock| {
+        let mut clock = clock.borrow_mut();
+        let clock = clock.as_mut().unwrap();
+        clock.monotonic_ms = clock.monotonic_ms.max(value);
+    })
+}
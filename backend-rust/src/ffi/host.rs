@@ -0,0 +1,286 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Environment the client runs in.
+//!
+//! All the functions that the client imports from its environment go through the [`Host`]
+//! trait. When compiled for WebAssembly, the default implementation is [`WasmHost`], which
+//! forwards calls to the imports found in [`super::bindings`]. Other implementations, such as
+//! [`super::simulated::SimulatedHost`], can be installed with [`set_host`].
+
+use super::bindings;
+
+use core::convert::TryFrom as _;
+use std::{cell::RefCell, sync::Arc};
+
+/// Functions that the client imports from its environment.
+///
+/// See the documentation of the functions of the same name in [`super::bindings`] for details.
+/// Contrary to these functions, the methods of this trait use Rust types instead of pointers.
+pub trait Host {
+    /// Must stop the execution. See [`bindings::throw`].
+    fn throw(&self, message: &str) -> !;
+
+    /// See [`bindings::log`].
+    fn log(&self, level: u32, target: &str, message: &str);
+
+    /// See [`bindings::unix_time_ms`].
+    fn unix_time_ms(&self) -> f64;
+
+    /// See [`bindings::monotonic_clock_ms`].
+    fn monotonic_clock_ms(&self) -> f64;
+
+    /// Must call [`bindings::timer_finished`] after at least `milliseconds` milliseconds. See
+    /// [`bindings::start_timer`].
+    fn start_timer(&self, id: u32, milliseconds: f64);
+
+    /// See [`bindings::cancel_timer`].
+    fn cancel_timer(&self, id: u32);
+
+    /// See [`bindings::best_block_update`].
+    fn best_block_update(&self, best_block_number: u32);
+
+    /// See [`bindings::sync_status_update`]. The status is encoded in JSON.
+    fn sync_status_update(&self, status: &str);
+
+    /// See [`bindings::database_save`]. The data is encoded in JSON.
+    fn database_save(&self, data: &str);
+
+    /// See [`bindings::best_block_notification`]. The notification is encoded in JSON.
+    fn best_block_notification(&self, notification: &str);
+
+    /// See [`bindings::network_info_response`]. The information is encoded in JSON.
+    fn network_info_response(&self, info: &str);
+
+    /// See [`bindings::network_recording_entry`]. The entry is encoded in JSON.
+    fn network_recording_entry(&self, entry: &str);
+
+    /// See [`bindings::index_export_response`]. The response is encoded in JSON.
+    fn index_export_response(&self, response: &str);
+
+    /// See [`bindings::storage_query_response`]. The response is encoded in JSON.
+    fn storage_query_response(&self, response: &str);
+
+    /// See [`bindings::runtime_call_response`]. The response is encoded in JSON.
+    fn runtime_call_response(&self, response: &str);
+
+    /// See [`bindings::json_rpc_respond`]. The message is encoded in JSON.
+    fn json_rpc_respond(&self, message: &str);
+
+    /// Starts opening a connection to the given multiaddress. Returns `false` if the address
+    /// isn't supported. See [`bindings::connection_new`].
+    fn connection_new(&self, id: u32, address: &str) -> bool;
+
+    /// See [`bindings::connection_close`].
+    fn connection_close(&self, id: u32);
+
+    /// See [`bindings::connection_send`].
+    fn connection_send(&self, id: u32, data: &[u8]);
+
+    /// See [`bindings::connection_set_reading_paused`].
+    fn connection_set_reading_paused(&self, id: u32, paused: bool);
+}
+
+thread_local! {
+    /// Host used by the functions of the [`super`] module. Initialized with [`default_host`] on
+    /// first access.
+    static HOST: RefCell<Option<Arc<dyn Host>>> = RefCell::new(None);
+}
+
+/// Replaces the [`Host`] used by the current thread.
+///
+/// Must be called before the client starts, as the client keeps track of the state of its
+/// timers and connections on the host.
+pub fn set_host(host: Arc<dyn Host>) {
+    HOST.with(|h| *h.borrow_mut() = Some(host));
+}
+
+/// Returns the [`Host`] used by the current thread.
+pub(super) fn host() -> Arc<dyn Host> {
+    HOST.with(|h| h.borrow_mut().get_or_insert_with(default_host).clone())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_host() -> Arc<dyn Host> {
+    Arc::new(WasmHost)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_host() -> Arc<dyn Host> {
+    panic!("no host has been set with `ffi::set_host`")
+}
+
+/// Implementation of [`Host`] that calls the imports of the WebAssembly module.
+pub struct WasmHost;
+
+impl Host for WasmHost {
+    fn throw(&self, message: &str) -> ! {
+        unsafe {
+            bindings::throw(
+                u32::try_from(message.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(message.len()).unwrap(),
+            );
+
+            // Note: we could theoretically use `unreachable_unchecked` here, but this relies on
+            // the fact that `ffi::throw` is correctly implemented, which isn't 100% guaranteed.
+            unreachable!();
+        }
+    }
+
+    fn log(&self, level: u32, target: &str, message: &str) {
+        unsafe {
+            bindings::log(
+                level,
+                u32::try_from(target.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(target.len()).unwrap(),
+                u32::try_from(message.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(message.len()).unwrap(),
+            )
+        }
+    }
+
+    fn unix_time_ms(&self) -> f64 {
+        unsafe { bindings::unix_time_ms() }
+    }
+
+    fn monotonic_clock_ms(&self) -> f64 {
+        unsafe { bindings::monotonic_clock_ms() }
+    }
+
+    fn start_timer(&self, id: u32, milliseconds: f64) {
+        unsafe { bindings::start_timer(id, milliseconds) }
+    }
+
+    fn cancel_timer(&self, id: u32) {
+        unsafe { bindings::cancel_timer(id) }
+    }
+
+    fn best_block_update(&self, best_block_number: u32) {
+        unsafe { bindings::best_block_update(best_block_number) }
+    }
+
+    fn sync_status_update(&self, status: &str) {
+        unsafe {
+            bindings::sync_status_update(
+                u32::try_from(status.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(status.len()).unwrap(),
+            )
+        }
+    }
+
+    fn database_save(&self, data: &str) {
+        unsafe {
+            bindings::database_save(
+                u32::try_from(data.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(data.len()).unwrap(),
+            )
+        }
+    }
+
+    fn best_block_notification(&self, notification: &str) {
+        unsafe {
+            bindings::best_block_notification(
+                u32::try_from(notification.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(notification.len()).unwrap(),
+            )
+        }
+    }
+
+    fn network_info_response(&self, info: &str) {
+        unsafe {
+            bindings::network_info_response(
+                u32::try_from(info.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(info.len()).unwrap(),
+            )
+        }
+    }
+
+    fn network_recording_entry(&self, entry: &str) {
+        unsafe {
+            bindings::network_recording_entry(
+                u32::try_from(entry.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(entry.len()).unwrap(),
+            )
+        }
+    }
+
+    fn index_export_response(&self, response: &str) {
+        unsafe {
+            bindings::index_export_response(
+                u32::try_from(response.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(response.len()).unwrap(),
+            )
+        }
+    }
+
+    fn storage_query_response(&self, response: &str) {
+        unsafe {
+            bindings::storage_query_response(
+                u32::try_from(response.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(response.len()).unwrap(),
+            )
+        }
+    }
+
+    fn runtime_call_response(&self, response: &str) {
+        unsafe {
+            bindings::runtime_call_response(
+                u32::try_from(response.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(response.len()).unwrap(),
+            )
+        }
+    }
+
+    fn json_rpc_respond(&self, message: &str) {
+        unsafe {
+            bindings::json_rpc_respond(
+                u32::try_from(message.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(message.len()).unwrap(),
+            )
+        }
+    }
+
+    fn connection_new(&self, id: u32, address: &str) -> bool {
+        let ret_code = unsafe {
+            bindings::connection_new(
+                id,
+                u32::try_from(address.as_bytes().as_ptr() as usize).unwrap(),
+                u32::try_from(address.len()).unwrap(),
+            )
+        };
+
+        ret_code == 0
+    }
+
+    fn connection_close(&self, id: u32) {
+        unsafe { bindings::connection_close(id) }
+    }
+
+    fn connection_send(&self, id: u32, data: &[u8]) {
+        unsafe {
+            bindings::connection_send(
+                id,
+                u32::try_from(data.as_ptr() as usize).unwrap(),
+                u32::try_from(data.len()).unwrap(),
+            )
+        }
+    }
+
+    fn connection_set_reading_paused(&self, id: u32, paused: bool) {
+        unsafe { bindings::connection_set_reading_paused(id, if paused { 1 } else { 0 }) }
+    }
+}
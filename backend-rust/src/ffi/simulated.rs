@@ -0,0 +1,510 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Implementation of [`Host`] that doesn't depend on any environment.
+//!
+//! Time is virtual and only advances when [`SimulatedHost::advance`] is called, which makes it
+//! possible to run the client deterministically, for example in tests. All the data that the
+//! client sends to the host is captured and can later be inspected.
+//!
+//! Connections are scripted: the connections that the client opens stay pending until
+//! [`SimulatedHost::accept_connection`] or [`SimulatedHost::close_connection`] is called, and
+//! the data they receive is provided with [`SimulatedHost::send_to_client`].
+
+use super::Host;
+
+use core::{
+    sync::atomic,
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures::{future::LocalBoxFuture, prelude::*, stream::FuturesUnordered};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// See [the module-level documentation](self).
+pub struct SimulatedHost {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Value returned by [`Host::monotonic_clock_ms`].
+    now_ms: f64,
+    /// Value of [`Host::unix_time_ms`] minus [`Inner::now_ms`].
+    unix_time_offset_ms: f64,
+    /// Timers that haven't fired yet, with their deadline.
+    timers: Vec<(f64, u32)>,
+    /// Latest value passed to [`Host::best_block_update`].
+    best_block_number: Option<u32>,
+    /// Data passed to [`Host::database_save`], in order.
+    database_saves: Vec<serde_json::Value>,
+    /// Data passed to [`Host::sync_status_update`], in order.
+    sync_statuses: Vec<serde_json::Value>,
+    /// Data passed to [`Host::best_block_notification`], in order.
+    best_block_notifications: Vec<serde_json::Value>,
+    /// Data passed to [`Host::network_info_response`], in order.
+    network_info_responses: Vec<serde_json::Value>,
+    /// Data passed to [`Host::network_recording_entry`], in order.
+    network_recording_entries: Vec<String>,
+    /// Data passed to [`Host::index_export_response`], in order.
+    index_export_responses: Vec<serde_json::Value>,
+    /// Data passed to [`Host::storage_query_response`], in order.
+    storage_query_responses: Vec<serde_json::Value>,
+    /// Data passed to [`Host::runtime_call_response`], in order.
+    runtime_call_responses: Vec<serde_json::Value>,
+    /// Data passed to [`Host::json_rpc_respond`], in order.
+    json_rpc_responses: Vec<serde_json::Value>,
+    /// Connections passed to [`Host::connection_new`], including the ones that are closed.
+    connections: BTreeMap<u32, SimulatedConnection>,
+    /// Connections passed to [`Host::connection_new`] since the last call to
+    /// [`SimulatedHost::take_new_connections`].
+    new_connections: Vec<(u32, String)>,
+}
+
+/// Tasks run by [`SimulatedHost::run_for`].
+///
+/// Contrary to [`futures::executor::LocalPool::run_until_stalled`], which returns as soon as a
+/// task wakes itself up, [`Tasks::run_until_stalled`] keeps running the tasks until none of them
+/// has been woken up.
+#[derive(Default)]
+pub struct Tasks {
+    tasks: FuturesUnordered<LocalBoxFuture<'static, ()>>,
+}
+
+impl Tasks {
+    /// Adds a task to the list.
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.tasks.push(task.boxed_local());
+    }
+
+    /// Runs the tasks until they can't progress anymore without an external event.
+    pub fn run_until_stalled(&mut self) {
+        struct Woken(atomic::AtomicBool);
+        impl futures::task::ArcWake for Woken {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, atomic::Ordering::SeqCst);
+            }
+        }
+
+        loop {
+            let woken = Arc::new(Woken(atomic::AtomicBool::new(false)));
+            let waker = futures::task::waker(woken.clone());
+            let mut cx = Context::from_waker(&waker);
+            while let Poll::Ready(Some(())) = self.tasks.poll_next_unpin(&mut cx) {}
+            if !woken.0.load(atomic::Ordering::SeqCst) {
+                break;
+            }
+        }
+    }
+}
+
+/// State of a connection opened by the client.
+struct SimulatedConnection {
+    /// Data sent by the client and not taken with [`SimulatedHost::take_sent_data`] yet.
+    sent: Vec<u8>,
+    /// True if either side has closed the connection.
+    closed: bool,
+    /// Latest value passed to [`Host::connection_set_reading_paused`].
+    reading_paused: bool,
+}
+
+impl SimulatedHost {
+    /// Creates a new [`SimulatedHost`] whose UNIX time is initially `unix_time`.
+    pub fn new(unix_time: Duration) -> Arc<Self> {
+        Arc::new(SimulatedHost {
+            inner: Mutex::new(Inner {
+                now_ms: 0.0,
+                unix_time_offset_ms: unix_time.as_secs_f64() * 1000.0,
+                timers: Vec::new(),
+                best_block_number: None,
+                database_saves: Vec::new(),
+                sync_statuses: Vec::new(),
+                best_block_notifications: Vec::new(),
+                network_info_responses: Vec::new(),
+                network_recording_entries: Vec::new(),
+                index_export_responses: Vec::new(),
+                storage_query_responses: Vec::new(),
+                runtime_call_responses: Vec::new(),
+                json_rpc_responses: Vec::new(),
+                connections: BTreeMap::new(),
+                new_connections: Vec::new(),
+            }),
+        })
+    }
+
+    /// Installs this host as the host of the current thread. See [`super::set_host`].
+    pub fn install(self: &Arc<Self>) {
+        super::set_host(self.clone());
+    }
+
+    /// Returns the time elapsed since the creation of this host.
+    pub fn now(&self) -> Duration {
+        Duration::from_secs_f64(self.inner.lock().unwrap().now_ms / 1000.0)
+    }
+
+    /// Returns the duration after which the next timer fires, or `None` if no timer is running.
+    pub fn next_timer(&self) -> Option<Duration> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .timers
+            .iter()
+            .map(|(deadline, _)| *deadline)
+            .fold(None, |min: Option<f64>, d| {
+                Some(min.map_or(d, |m| m.min(d)))
+            })
+            .map(|deadline| Duration::from_secs_f64((deadline - inner.now_ms).max(0.0) / 1000.0))
+    }
+
+    /// Advances the time by `duration`, firing the timers whose deadline is reached, in order.
+    ///
+    /// Timers are fired with the clock set to their deadline, and timers started while firing
+    /// are fired as well if their deadline is reached.
+    ///
+    /// Must be called from the thread this host is installed on.
+    pub fn advance(&self, duration: Duration) {
+        let target_ms = self.inner.lock().unwrap().now_ms + duration.as_secs_f64() * 1000.0;
+
+        loop {
+            // The lock must be released before firing the timer, as the client calls back the
+            // host while processing it.
+            let timer_id = {
+                let mut inner = self.inner.lock().unwrap();
+                let earliest = inner
+                    .timers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (deadline, _))| *deadline <= target_ms)
+                    .min_by(|(_, (a, _)), (_, (b, _))| a.partial_cmp(b).unwrap())
+                    .map(|(index, _)| index);
+
+                match earliest {
+                    Some(index) => {
+                        let (deadline, id) = inner.timers.remove(index);
+                        inner.now_ms = inner.now_ms.max(deadline);
+                        id
+                    }
+                    None => {
+                        inner.now_ms = target_ms;
+                        break;
+                    }
+                }
+            };
+
+            super::timer_finished(timer_id);
+        }
+    }
+
+    /// Runs `tasks` and advances the time until `duration` has elapsed.
+    ///
+    /// The tasks are run until they can't progress anymore between each timer.
+    pub fn run_for(&self, tasks: &mut Tasks, duration: Duration) {
+        let end = self.now() + duration;
+
+        loop {
+            tasks.run_until_stalled();
+
+            let now = self.now();
+            match self.next_timer() {
+                Some(next) if now + next <= end => self.advance(next),
+                _ => {
+                    self.advance(end - now);
+                    tasks.run_until_stalled();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Returns and clears the list of connections that the client has started opening, with
+    /// their identifier and multiaddress.
+    pub fn take_new_connections(&self) -> Vec<(u32, String)> {
+        std::mem::take(&mut self.inner.lock().unwrap().new_connections)
+    }
+
+    /// Finishes opening the given connection. See [`super::bindings::connection_open`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the connection doesn't exist or is closed.
+    ///
+    pub fn accept_connection(&self, id: u32) {
+        assert!(!self.inner.lock().unwrap().connections[&id].closed);
+        super::connection_open(id);
+    }
+
+    /// Closes the given connection, or makes it fail to open. Does nothing if the connection is
+    /// already closed. See [`super::bindings::connection_closed`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the connection doesn't exist.
+    ///
+    pub fn close_connection(&self, id: u32) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let connection = inner.connections.get_mut(&id).unwrap();
+            if connection.closed {
+                return;
+            }
+            connection.closed = true;
+        }
+
+        super::connection_closed(id);
+    }
+
+    /// Delivers data to the client on the given connection. See
+    /// [`super::bindings::connection_message`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the connection doesn't exist or is closed.
+    ///
+    pub fn send_to_client(&self, id: u32, data: &[u8]) {
+        assert!(!self.inner.lock().unwrap().connections[&id].closed);
+        super::with_connection(id, |connection| {
+            super::connection_message_received(connection, data.to_vec().into_boxed_slice())
+        });
+    }
+
+    /// Returns and clears the data that the client has sent on the given connection.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the connection doesn't exist.
+    ///
+    pub fn take_sent_data(&self, id: u32) -> Vec<u8> {
+        let mut inner = self.inner.lock().unwrap();
+        std::mem::take(&mut inner.connections.get_mut(&id).unwrap().sent)
+    }
+
+    /// Returns true if the given connection has been closed by either side.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the connection doesn't exist.
+    ///
+    pub fn is_connection_closed(&self, id: u32) -> bool {
+        self.inner.lock().unwrap().connections[&id].closed
+    }
+
+    /// Returns true if the client has asked to pause reading from the given connection.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the connection doesn't exist.
+    ///
+    pub fn is_reading_paused(&self, id: u32) -> bool {
+        self.inner.lock().unwrap().connections[&id].reading_paused
+    }
+
+    /// Returns the latest best block number reported by the client.
+    pub fn best_block_number(&self) -> Option<u32> {
+        self.inner.lock().unwrap().best_block_number
+    }
+
+    /// Returns and clears the list of database saves that the client has emitted.
+    pub fn take_database_saves(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().database_saves)
+    }
+
+    /// Returns and clears the list of sync status updates that the client has emitted.
+    pub fn take_sync_statuses(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().sync_statuses)
+    }
+
+    /// Returns and clears the list of best block notifications that the client has emitted.
+    pub fn take_best_block_notifications(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().best_block_notifications)
+    }
+
+    /// Returns and clears the list of answers to network information requests.
+    pub fn take_network_info_responses(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().network_info_responses)
+    }
+
+    /// Returns and clears the list of entries of the network recording that the client has
+    /// emitted. Entries are returned as is, without being parsed.
+    pub fn take_network_recording_entries(&self) -> Vec<String> {
+        std::mem::take(&mut self.inner.lock().unwrap().network_recording_entries)
+    }
+
+    /// Returns and clears the list of answers to index export requests.
+    pub fn take_index_export_responses(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().index_export_responses)
+    }
+
+    /// Returns and clears the list of answers to storage queries.
+    pub fn take_storage_query_responses(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().storage_query_responses)
+    }
+
+    /// Returns and clears the list of answers to runtime calls.
+    pub fn take_runtime_call_responses(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().runtime_call_responses)
+    }
+
+    /// Returns and clears the list of JSON-RPC responses and notifications.
+    pub fn take_json_rpc_responses(&self) -> Vec<serde_json::Value> {
+        std::mem::take(&mut self.inner.lock().unwrap().json_rpc_responses)
+    }
+}
+
+impl Host for SimulatedHost {
+    fn throw(&self, message: &str) -> ! {
+        panic!("{}", message)
+    }
+
+    fn log(&self, _: u32, _: &str, _: &str) {}
+
+    fn unix_time_ms(&self) -> f64 {
+        let inner = self.inner.lock().unwrap();
+        inner.unix_time_offset_ms + inner.now_ms
+    }
+
+    fn monotonic_clock_ms(&self) -> f64 {
+        self.inner.lock().unwrap().now_ms
+    }
+
+    fn start_timer(&self, id: u32, milliseconds: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        let deadline = inner.now_ms + milliseconds;
+        inner.timers.push((deadline, id));
+    }
+
+    fn cancel_timer(&self, id: u32) {
+        self.inner
+            .lock()
+            .unwrap()
+            .timers
+            .retain(|(_, timer_id)| *timer_id != id);
+    }
+
+    fn best_block_update(&self, best_block_number: u32) {
+        self.inner.lock().unwrap().best_block_number = Some(best_block_number);
+    }
+
+    fn sync_status_update(&self, status: &str) {
+        let status = serde_json::from_str(status).unwrap();
+        self.inner.lock().unwrap().sync_statuses.push(status);
+    }
+
+    fn database_save(&self, data: &str) {
+        let data = serde_json::from_str(data).unwrap();
+        self.inner.lock().unwrap().database_saves.push(data);
+    }
+
+    fn best_block_notification(&self, notification: &str) {
+        let notification = serde_json::from_str(notification).unwrap();
+        self.inner
+            .lock()
+            .unwrap()
+            .best_block_notifications
+            .push(notification);
+    }
+
+    fn network_info_response(&self, info: &str) {
+        let info = serde_json::from_str(info).unwrap();
+        self.inner.lock().unwrap().network_info_responses.push(info);
+    }
+
+    fn network_recording_entry(&self, entry: &str) {
+        self.inner
+            .lock()
+            .unwrap()
+            .network_recording_entries
+            .push(entry.to_owned());
+    }
+
+    fn index_export_response(&self, response: &str) {
+        let response = serde_json::from_str(response).unwrap();
+        self.inner
+            .lock()
+            .unwrap()
+            .index_export_responses
+            .push(response);
+    }
+
+    fn storage_query_response(&self, response: &str) {
+        let response = serde_json::from_str(response).unwrap();
+        self.inner
+            .lock()
+            .unwrap()
+            .storage_query_responses
+            .push(response);
+    }
+
+    fn runtime_call_response(&self, response: &str) {
+        let response = serde_json::from_str(response).unwrap();
+        self.inner
+            .lock()
+            .unwrap()
+            .runtime_call_responses
+            .push(response);
+    }
+
+    fn json_rpc_respond(&self, message: &str) {
+        let message = serde_json::from_str(message).unwrap();
+        self.inner.lock().unwrap().json_rpc_responses.push(message);
+    }
+
+    fn connection_new(&self, id: u32, address: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        // Identifiers of closed connections can be reused by the client.
+        assert!(inner.connections.get(&id).is_none_or(|c| c.closed));
+        inner.connections.insert(
+            id,
+            SimulatedConnection {
+                sent: Vec::new(),
+                closed: false,
+                reading_paused: false,
+            },
+        );
+        inner.new_connections.push((id, address.to_owned()));
+        true
+    }
+
+    fn connection_close(&self, id: u32) {
+        self.inner
+            .lock()
+            .unwrap()
+            .connections
+            .get_mut(&id)
+            .unwrap()
+            .closed = true;
+    }
+
+    fn connection_send(&self, id: u32, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        let connection = inner.connections.get_mut(&id).unwrap();
+        // The client might not have processed the closing yet.
+        if !connection.closed {
+            connection.sent.extend_from_slice(data);
+        }
+    }
+
+    fn connection_set_reading_paused(&self, id: u32, paused: bool) {
+        self.inner
+            .lock()
+            .unwrap()
+            .connections
+            .get_mut(&id)
+            .unwrap()
+            .reading_paused = paused;
+    }
+}
@@ -0,0 +1,127 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tests of the clock, the timers and the other host-independent state of the FFI layer,
+//! running against a [`SimulatedHost`].
+
+use super::*;
+use simulated::SimulatedHost;
+use std::rc::Rc;
+
+/// UNIX time of the [`SimulatedHost`]s of the tests.
+const HOST_UNIX_TIME: Duration = Duration::from_secs(1_600_000_000);
+
+fn install_host() -> Arc<SimulatedHost> {
+    let host = SimulatedHost::new(HOST_UNIX_TIME);
+    host.install();
+    host
+}
+
+fn is_ready(delay: &mut Delay) -> bool {
+    (&mut *delay).now_or_never().is_some()
+}
+
+#[test]
+fn delay_uses_host_timer() {
+    let host = install_host();
+
+    let mut delay = Delay::new(Duration::from_secs(5));
+    assert!(!is_ready(&mut delay));
+    assert!(host.next_timer().unwrap() >= Duration::from_secs(5));
+
+    host.advance(Duration::from_secs(5));
+    assert!(is_ready(&mut delay));
+    assert_eq!(host.next_timer(), None);
+}
+
+#[test]
+fn dropped_delays_cancel_host_timer() {
+    let host = install_host();
+
+    let delay = Delay::new(Duration::from_secs(5));
+    assert!(host.next_timer().is_some());
+    drop(delay);
+    assert_eq!(host.next_timer(), None);
+}
+
+#[test]
+fn virtual_clock_ignores_host_time() {
+    let host = install_host();
+    let start = Instant::now();
+
+    let mut delay = Delay::new(Duration::from_secs(5));
+    clock::set_virtual(Duration::from_secs(1_000));
+    assert_eq!(host.next_timer(), None);
+
+    host.advance(Duration::from_secs(60));
+    assert!(!is_ready(&mut delay));
+    assert_eq!(start.elapsed(), Duration::from_secs(0));
+    assert_eq!(unix_time(), Duration::from_secs(1_000));
+
+    clock::advance(Duration::from_secs(5));
+    assert!(is_ready(&mut delay));
+    assert_eq!(start.elapsed(), Duration::from_secs(5));
+    assert_eq!(unix_time(), Duration::from_secs(1_005));
+}
+
+#[test]
+fn virtual_clock_wakes_delays_at_their_deadline() {
+    let _host = install_host();
+    clock::set_virtual(HOST_UNIX_TIME);
+    let start = Instant::now();
+
+    let mut pool = futures::executor::LocalPool::new();
+    let elapsed = Rc::new(RefCell::new(Vec::new()));
+    for secs in &[3, 1, 2] {
+        let elapsed = elapsed.clone();
+        let delay = Delay::new(Duration::from_secs(*secs));
+        futures::task::LocalSpawnExt::spawn_local(&pool.spawner(), async move {
+            delay.await;
+            elapsed.borrow_mut().push(start.elapsed());
+        })
+        .unwrap();
+    }
+    pool.run_until_stalled();
+
+    // Tasks are run between the deadlines, and observe the clock at their deadline.
+    for _ in 0..4 {
+        clock::advance(Duration::from_millis(1_000));
+        pool.run_until_stalled();
+    }
+
+    assert_eq!(
+        *elapsed.borrow(),
+        vec![
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+            Duration::from_secs(3)
+        ]
+    );
+    assert_eq!(start.elapsed(), Duration::from_secs(4));
+}
+
+#[test]
+fn virtual_clock_advances_past_all_elapsed_deadlines() {
+    let _host = install_host();
+    clock::set_virtual(HOST_UNIX_TIME);
+
+    let mut delays = (1..=10)
+        .map(|n| Delay::new(Duration::from_secs(n)))
+        .collect::<Vec<_>>();
+    clock::advance(Duration::from_secs(10));
+    assert!(delays.iter_mut().all(is_ready));
+}
@@ -0,0 +1,240 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Registry of all the [`super::Delay`]s currently alive.
+//!
+//! Instead of starting one host timer per [`super::Delay`], a single host timer is started for
+//! the earliest deadline of all the registered delays. Deadlines are rounded up to a multiple of
+//! [`GRANULARITY_MS`], so that delays whose deadlines are close to each other are woken up by the
+//! same host timer. Delays are never woken up before their deadline, but can be woken up at most
+//! [`GRANULARITY_MS`] milliseconds late.
+//!
+//! When all the delays are destroyed, the host timer is cancelled.
+//!
+//! When the virtual clock is enabled (see [`super::clock`]), no host timer is used, and the
+//! delays are woken up by [`super::clock::advance`].
+
+use super::{clock, host, Instant};
+
+use core::{
+    cell::RefCell,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use std::collections::{BTreeSet, HashMap};
+
+/// Identifier passed to [`super::Host::start_timer`] for the host timer of the registry.
+///
+/// This identifier is never used by other host timers.
+pub(super) const HOST_TIMER_ID: u32 = 0;
+
+/// Granularity, in milliseconds, of the deadlines of the host timer.
+const GRANULARITY_MS: f64 = 10.0;
+
+thread_local! {
+    static TIMERS: RefCell<Timers> = RefCell::new(Timers {
+        next_id: 0,
+        entries: HashMap::default(),
+        by_deadline: BTreeSet::new(),
+        host_timer: None,
+    });
+}
+
+struct Timers {
+    /// Identifier to assign to the next registered delay.
+    next_id: u64,
+    /// All the registered delays.
+    entries: HashMap<u64, Entry, fnv::FnvBuildHasher>,
+    /// Same entries as [`Timers::entries`], except for the ones that have already been woken up,
+    /// ordered by deadline.
+    by_deadline: BTreeSet<(Instant, u64)>,
+    /// If `Some`, a host timer is running and will fire at the given moment.
+    host_timer: Option<Instant>,
+}
+
+struct Entry {
+    deadline: Instant,
+    /// True if the deadline has been reached.
+    elapsed: bool,
+    /// Waker to wake up when the deadline is reached.
+    waker: Option<Waker>,
+}
+
+/// Registers a new delay that elapses at the given moment. Returns an identifier to later pass
+/// to [`poll`] and [`remove`].
+pub(super) fn insert(deadline: Instant) -> u64 {
+    TIMERS.with(|timers| {
+        let mut timers = timers.borrow_mut();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.entries.insert(
+            id,
+            Entry {
+                deadline,
+                elapsed: false,
+                waker: None,
+            },
+        );
+        timers.by_deadline.insert((deadline, id));
+        timers.update_host_timer();
+        id
+    })
+}
+
+/// Returns `Poll::Ready` if the deadline of the given delay has been reached. Otherwise, wakes
+/// up the task of `cx` once the deadline is reached.
+pub(super) fn poll(id: u64, cx: &mut Context) -> Poll<()> {
+    TIMERS.with(|timers| {
+        let mut timers = timers.borrow_mut();
+        let entry = timers.entries.get_mut(&id).unwrap();
+
+        if entry.elapsed {
+            return Poll::Ready(());
+        }
+
+        // The deadline might have been reached without the host timer having fired yet.
+        if entry.deadline <= Instant::now() {
+            entry.elapsed = true;
+            let key = (entry.deadline, id);
+            timers.by_deadline.remove(&key);
+            timers.update_host_timer();
+            return Poll::Ready(());
+        }
+
+        if entry
+            .waker
+            .as_ref()
+            .is_none_or(|w| !cx.waker().will_wake(w))
+        {
+            entry.waker = Some(cx.waker().clone());
+        }
+
+        Poll::Pending
+    })
+}
+
+/// Unregisters a delay. The host timer is cancelled if it is no longer needed.
+pub(super) fn remove(id: u64) {
+    TIMERS.with(|timers| {
+        let mut timers = timers.borrow_mut();
+        if let Some(entry) = timers.entries.remove(&id) {
+            timers.by_deadline.remove(&(entry.deadline, id));
+            timers.update_host_timer();
+        }
+    })
+}
+
+/// Must be called when the host timer started with [`HOST_TIMER_ID`] has fired.
+pub(super) fn host_timer_finished() {
+    TIMERS.with(|timers| timers.borrow_mut().host_timer = None);
+    wake_elapsed();
+}
+
+/// Returns the earliest deadline of the delays that haven't been woken up yet.
+pub(super) fn earliest_deadline() -> Option<Instant> {
+    TIMERS.with(|timers| {
+        timers
+            .borrow()
+            .by_deadline
+            .iter()
+            .next()
+            .map(|(deadline, _)| *deadline)
+    })
+}
+
+/// Wakes up the delays whose deadline has been reached.
+pub(super) fn wake_elapsed() {
+    let wakers = TIMERS.with(|timers| {
+        let mut timers = timers.borrow_mut();
+
+        let now = Instant::now();
+        let mut wakers = Vec::new();
+        while let Some((deadline, id)) = timers.by_deadline.iter().next().cloned() {
+            if deadline > now {
+                break;
+            }
+
+            timers.by_deadline.remove(&(deadline, id));
+            let entry = timers.entries.get_mut(&id).unwrap();
+            entry.elapsed = true;
+            wakers.extend(entry.waker.take());
+        }
+
+        timers.update_host_timer();
+        wakers
+    });
+
+    // Wakers are invoked outside of the borrow, as waking up a task might poll it immediately.
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// Starts, restarts or cancels the host timer depending on the earliest deadline.
+pub(super) fn update_host_timer() {
+    TIMERS.with(|timers| timers.borrow_mut().update_host_timer())
+}
+
+impl Timers {
+    /// Starts, restarts or cancels the host timer depending on the earliest deadline.
+    fn update_host_timer(&mut self) {
+        // With the virtual clock, delays are woken up by `clock::advance`.
+        if clock::is_virtual() {
+            if self.host_timer.take().is_some() {
+                host::host().cancel_timer(HOST_TIMER_ID);
+            }
+            return;
+        }
+
+        let earliest = self
+            .by_deadline
+            .iter()
+            .next()
+            .map(|(deadline, _)| *deadline);
+
+        match (earliest, self.host_timer) {
+            (None, None) => {}
+            (None, Some(_)) => {
+                self.host_timer = None;
+                host::host().cancel_timer(HOST_TIMER_ID)
+            }
+            // The host timer fires before the earliest deadline. It is restarted when it fires.
+            (Some(earliest), Some(host_timer)) if host_timer <= earliest => {}
+            // The host timer fires slightly after the earliest deadline, which is acceptable.
+            (Some(earliest), Some(host_timer))
+                if host_timer.inner <= earliest.inner + GRANULARITY_MS => {}
+            (Some(earliest), previous) => {
+                if previous.is_some() {
+                    host::host().cancel_timer(HOST_TIMER_ID)
+                }
+
+                let host_timer = Instant {
+                    inner: (earliest.inner / GRANULARITY_MS).ceil() * GRANULARITY_MS,
+                };
+                let now = Instant::now();
+                let duration = if host_timer > now {
+                    host_timer - now
+                } else {
+                    Duration::new(0, 0)
+                };
+
+                self.host_timer = Some(host_timer);
+                host::host().start_timer(HOST_TIMER_ID, (duration.as_millis() as f64).ceil())
+            }
+        }
+    }
+}
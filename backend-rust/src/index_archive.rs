@@ -0,0 +1,302 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Portable archive of the index built by the client.
+//!
+//! Everything that the client reports through [`crate::ffi::database_save`] is stored by the
+//! host in separate places: the database content, which is opaque to the host, the metadata of
+//! each runtime, and the events of each block. An index archive groups all of them in a single
+//! JSON document, alongside with a checksum, so that an index can be moved between machines or
+//! shared.
+//!
+//! Archives are built with [`export`], from the data that the host has accumulated, and loaded
+//! with [`IndexArchive::from_json`] when passed through [`crate::ChainConfig::index_archive`].
+//!
+//! The node key is removed from the database content when building an archive, as peers that
+//! share the same identity can't be connected at the same time.
+
+use crate::{database, ffi};
+
+use core::fmt;
+use smoldot::{database::finalized_serialize, json_rpc::methods::HexString};
+
+/// Version of the format of [`SerializedIndexArchive`].
+const INDEX_ARCHIVE_VERSION: u32 = 1;
+
+/// Decoded index archive.
+pub(crate) struct IndexArchive {
+    /// Database content, as passed to [`crate::ChainConfig::database_content`]. Never contains
+    /// a node key.
+    pub(crate) database_content: String,
+    /// Metadata of each runtime, ordered by increasing spec version.
+    pub(crate) metadata: Vec<ffi::DatabaseSaveMetadata>,
+    /// Events of each block, ordered by increasing block number.
+    pub(crate) blocks: Vec<ffi::DatabaseSaveBlock>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedIndexArchive {
+    version: u32,
+    database_content: String,
+    metadata: Vec<ffi::DatabaseSaveMetadata>,
+    blocks: Vec<ffi::DatabaseSaveBlock>,
+    /// Blake2b-256 hash of the other fields. See [`IndexArchive::checksum`].
+    checksum: HexString,
+}
+
+/// Same as [`SerializedIndexArchive`], but borrowing its content.
+#[derive(serde::Serialize)]
+struct SerializedIndexArchiveRef<'a> {
+    version: u32,
+    database_content: &'a str,
+    metadata: &'a [ffi::DatabaseSaveMetadata],
+    blocks: &'a [ffi::DatabaseSaveBlock],
+    checksum: HexString,
+}
+
+/// Content passed by the host to [`export`].
+#[derive(serde::Deserialize)]
+struct ExportRequest {
+    database_content: String,
+    #[serde(default)]
+    metadata: Vec<ffi::DatabaseSaveMetadata>,
+    #[serde(default)]
+    blocks: Vec<ffi::DatabaseSaveBlock>,
+}
+
+/// Error while building or loading an index archive.
+#[derive(Debug)]
+pub(crate) enum IndexArchiveError {
+    /// The content isn't valid JSON, or doesn't have the expected format.
+    Json(serde_json::Error),
+    /// The archive has been produced by an incompatible version of the client.
+    UnsupportedVersion(u32),
+    /// The checksum doesn't match the content of the archive.
+    ChecksumMismatch,
+    /// The database content doesn't contain the chain information and finalized storage.
+    InvalidDatabaseContent,
+    /// The metadata entries aren't ordered by strictly increasing spec version, for example
+    /// because of a duplicate.
+    UnorderedMetadata(u32),
+    /// The blocks aren't ordered by strictly increasing number, for example because of a
+    /// duplicate.
+    UnorderedBlocks(u64),
+    /// A block refers to a spec version whose metadata is missing.
+    MissingMetadata {
+        block_number: u64,
+        runtime_spec: u32,
+    },
+    /// A block is above the finalized block of the database content.
+    BlockNotFinalized(u64),
+}
+
+impl fmt::Display for IndexArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexArchiveError::Json(err) => write!(f, "{}", err),
+            IndexArchiveError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported index archive version: {}", v)
+            }
+            IndexArchiveError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            IndexArchiveError::InvalidDatabaseContent => write!(f, "Invalid database content"),
+            IndexArchiveError::UnorderedMetadata(spec) => {
+                write!(
+                    f,
+                    "Duplicate or unordered metadata of runtime spec {}",
+                    spec
+                )
+            }
+            IndexArchiveError::UnorderedBlocks(number) => {
+                write!(f, "Duplicate or unordered block #{}", number)
+            }
+            IndexArchiveError::MissingMetadata {
+                block_number,
+                runtime_spec,
+            } => write!(
+                f,
+                "Missing metadata of runtime spec {} used by block #{}",
+                runtime_spec, block_number
+            ),
+            IndexArchiveError::BlockNotFinalized(number) => {
+                write!(f, "Block #{} is above the finalized block", number)
+            }
+        }
+    }
+}
+
+impl IndexArchive {
+    /// Builds an archive from its components, after sorting and checking them.
+    ///
+    /// The node key, if any, is removed from `database_content`.
+    pub(crate) fn new(
+        database_content: &str,
+        mut metadata: Vec<ffi::DatabaseSaveMetadata>,
+        mut blocks: Vec<ffi::DatabaseSaveBlock>,
+    ) -> Result<Self, IndexArchiveError> {
+        let database_content = database::encode(&database::DatabaseContent {
+            node_key: None,
+            ..database::decode(database_content)
+        });
+
+        metadata.sort_by_key(|m| m.runtime_spec);
+        blocks.sort_by_key(|b| b.number);
+
+        let archive = IndexArchive {
+            database_content,
+            metadata,
+            blocks,
+        };
+        archive.check()?;
+        Ok(archive)
+    }
+
+    /// Loads an archive produced by [`IndexArchive::to_json`], and verifies its checksum and
+    /// consistency.
+    pub(crate) fn from_json(archive: &str) -> Result<Self, IndexArchiveError> {
+        let archive: SerializedIndexArchive =
+            serde_json::from_str(archive).map_err(IndexArchiveError::Json)?;
+        if archive.version != INDEX_ARCHIVE_VERSION {
+            return Err(IndexArchiveError::UnsupportedVersion(archive.version));
+        }
+
+        let decoded = IndexArchive {
+            database_content: archive.database_content,
+            metadata: archive.metadata,
+            blocks: archive.blocks,
+        };
+
+        if archive.checksum.0[..] != decoded.checksum()[..] {
+            return Err(IndexArchiveError::ChecksumMismatch);
+        }
+
+        // An archive with a valid checksum can still have been built by hand.
+        decoded.check()?;
+        Ok(decoded)
+    }
+
+    /// Encodes the archive in JSON.
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(&SerializedIndexArchiveRef {
+            version: INDEX_ARCHIVE_VERSION,
+            database_content: &self.database_content,
+            metadata: &self.metadata,
+            blocks: &self.blocks,
+            checksum: HexString(self.checksum().to_vec()),
+        })
+        .unwrap()
+    }
+
+    /// Verifies that the entries are ordered and consistent with each other.
+    fn check(&self) -> Result<(), IndexArchiveError> {
+        let finalized_block_number = match finalized_serialize::decode_chain(
+            &database::decode(&self.database_content).chain,
+        ) {
+            Ok((chain_information, Some(_))) => chain_information.finalized_block_header.number,
+            _ => return Err(IndexArchiveError::InvalidDatabaseContent),
+        };
+
+        for pair in self.metadata.windows(2) {
+            if pair[0].runtime_spec >= pair[1].runtime_spec {
+                return Err(IndexArchiveError::UnorderedMetadata(pair[1].runtime_spec));
+            }
+        }
+
+        for (index, block) in self.blocks.iter().enumerate() {
+            if index != 0 && self.blocks[index - 1].number >= block.number {
+                return Err(IndexArchiveError::UnorderedBlocks(block.number));
+            }
+
+            if block.number > finalized_block_number {
+                return Err(IndexArchiveError::BlockNotFinalized(block.number));
+            }
+
+            if self
+                .metadata
+                .binary_search_by_key(&block.runtime_spec, |m| m.runtime_spec)
+                .is_err()
+            {
+                return Err(IndexArchiveError::MissingMetadata {
+                    block_number: block.number,
+                    runtime_spec: block.runtime_spec,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the Blake2b-256 hash of the content of the archive.
+    ///
+    /// Every variable-length field is prefixed with its length, in order for the hash to be
+    /// unambiguous.
+    fn checksum(&self) -> [u8; 32] {
+        fn update_bytes(hasher: &mut blake2_rfc::blake2b::Blake2b, bytes: &[u8]) {
+            hasher.update(&(bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+
+        let mut hasher = blake2_rfc::blake2b::Blake2b::new(32);
+        hasher.update(&INDEX_ARCHIVE_VERSION.to_le_bytes());
+        update_bytes(&mut hasher, self.database_content.as_bytes());
+
+        hasher.update(&(self.metadata.len() as u64).to_le_bytes());
+        for metadata in &self.metadata {
+            hasher.update(&metadata.runtime_spec.to_le_bytes());
+            update_bytes(&mut hasher, metadata.spec_name.as_bytes());
+            update_bytes(&mut hasher, &metadata.metadata.0);
+        }
+
+        hasher.update(&(self.blocks.len() as u64).to_le_bytes());
+        for block in &self.blocks {
+            hasher.update(&block.number.to_le_bytes());
+            hasher.update(&block.runtime_spec.to_le_bytes());
+            update_bytes(&mut hasher, &block.events.0);
+
+            hasher.update(&(block.balance_changes.len() as u64).to_le_bytes());
+            for change in &block.balance_changes {
+                update_bytes(&mut hasher, &change.account.0);
+                update_bytes(&mut hasher, change.free.as_bytes());
+                update_bytes(&mut hasher, change.reserved.as_bytes());
+                update_bytes(&mut hasher, change.frozen.as_bytes());
+            }
+
+            hasher.update(&(block.storage_changes.len() as u64).to_le_bytes());
+            for change in &block.storage_changes {
+                update_bytes(&mut hasher, &change.key.0);
+                match &change.value {
+                    Some(value) => {
+                        hasher.update(&[1]);
+                        update_bytes(&mut hasher, &value.0);
+                    }
+                    None => hasher.update(&[0]),
+                }
+            }
+        }
+
+        let mut out = [0; 32];
+        out.copy_from_slice(hasher.finalize().as_bytes());
+        out
+    }
+}
+
+/// Builds an archive from the JSON-encoded content passed by the host to
+/// [`crate::ffi::bindings::export_index`], and returns it encoded in JSON.
+pub(crate) fn export(request: &str) -> Result<String, IndexArchiveError> {
+    let request: ExportRequest = serde_json::from_str(request).map_err(IndexArchiveError::Json)?;
+    let archive = IndexArchive::new(&request.database_content, request.metadata, request.blocks)?;
+    Ok(archive.to_json())
+}
@@ -0,0 +1,382 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Minimal JSON-RPC server, answering the requests passed to
+//! [`crate::ffi::bindings::json_rpc_send`] with the standard Substrate JSON-RPC API.
+//!
+//! Only the subset of the API needed by libraries such as PolkadotJS is supported. Requests are
+//! answered from the state verified by the sync service, which owns the [`JsonRpcService`]:
+//!
+//! - The storage, metadata and runtime version are only available for the finalized block.
+//! - Headers are only available for the finalized block and the best block.
+//!
+//! Answers and subscription notifications are sent to the host through
+//! [`crate::ffi::json_rpc_respond`].
+
+use crate::ffi;
+
+use core::fmt::Write as _;
+use smoldot::{executor, header, json_rpc::methods::HexString};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// JSON-RPC methods supported by [`JsonRpcService`], as reported by `rpc_methods`.
+const METHODS: &[&str] = &[
+    "chain_getBlockHash",
+    "chain_getFinalizedHead",
+    "chain_getHeader",
+    "chain_subscribeFinalizedHeads",
+    "chain_unsubscribeFinalizedHeads",
+    "rpc_methods",
+    "state_getMetadata",
+    "state_getRuntimeVersion",
+    "state_getStorage",
+    "system_chain",
+    "system_health",
+    "system_name",
+    "system_properties",
+    "system_version",
+];
+
+/// Configuration for a [`JsonRpcService`].
+pub struct Config {
+    /// Name of the chain, as found in the chain specification.
+    pub chain_name: String,
+    /// JSON-encoded properties of the chain, as found in the chain specification.
+    pub chain_properties: String,
+    /// Hash of the genesis block of the chain.
+    pub genesis_block_hash: [u8; 32],
+}
+
+/// State of the JSON-RPC server. See [the module-level documentation](self).
+pub struct JsonRpcService {
+    config: Config,
+    /// Identifiers of the active `chain_subscribeFinalizedHeads` subscriptions.
+    finalized_heads_subscriptions: BTreeSet<String>,
+    /// Identifier to assign to the next subscription.
+    next_subscription_id: u64,
+}
+
+/// State of the chain, as known by the sync service, against which requests are answered.
+pub(crate) struct ChainState<'a> {
+    pub(crate) finalized_header: header::HeaderRef<'a>,
+    pub(crate) best_header: header::HeaderRef<'a>,
+    pub(crate) finalized_storage: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+    /// SCALE-encoded metadata of the runtime of the finalized block.
+    pub(crate) finalized_metadata: &'a [u8],
+    pub(crate) finalized_runtime_version: &'a executor::CoreVersion,
+    /// Number of peers the blocks are downloaded from.
+    pub(crate) peers: usize,
+    /// True if the best block is far from the highest block announced by the peers.
+    pub(crate) is_syncing: bool,
+}
+
+/// Parsed JSON-RPC request.
+#[derive(serde::Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: serde_json::Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<serde_json::Value>,
+}
+
+/// Error answered to a JSON-RPC request.
+struct Error {
+    code: i64,
+    message: String,
+}
+
+impl Error {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Error {
+            code: -32602,
+            message: message.into(),
+        }
+    }
+}
+
+impl JsonRpcService {
+    /// Initializes a new [`JsonRpcService`], without any subscription.
+    pub fn new(config: Config) -> Self {
+        JsonRpcService {
+            config,
+            finalized_heads_subscriptions: BTreeSet::new(),
+            next_subscription_id: 0,
+        }
+    }
+
+    /// Answers the given JSON-encoded request through [`ffi::json_rpc_respond`].
+    pub(crate) fn handle_request(&mut self, request: &str, state: &ChainState) {
+        let request: Request = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(err) => {
+                // Requests that are valid JSON but not valid JSON-RPC have a different code.
+                let error = if err.is_syntax() || err.is_eof() {
+                    Error {
+                        code: -32700,
+                        message: format!("Parse error: {}", err),
+                    }
+                } else {
+                    Error {
+                        code: -32600,
+                        message: format!("Invalid request: {}", err),
+                    }
+                };
+                ffi::json_rpc_respond(&error_response(&serde_json::Value::Null, error));
+                return;
+            }
+        };
+
+        let result = self.answer(&request, state);
+        let new_subscription = match (&request.method[..], &result) {
+            ("chain_subscribeFinalizedHeads", Ok(serde_json::Value::String(id))) => {
+                Some(id.clone())
+            }
+            _ => None,
+        };
+
+        let response = match result {
+            Ok(result) => serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request.id,
+                "result": result,
+            })
+            .to_string(),
+            Err(error) => error_response(&request.id, error),
+        };
+        ffi::json_rpc_respond(&response);
+
+        // Substrate nodes immediately report the current finalized block to new subscriptions.
+        if let Some(subscription) = new_subscription {
+            ffi::json_rpc_respond(&finalized_head_notification(
+                &subscription,
+                &state.finalized_header,
+            ));
+        }
+    }
+
+    /// Notifies the subscriptions of a new finalized block.
+    pub(crate) fn finalized_block(&self, finalized_header: &header::HeaderRef) {
+        for subscription in &self.finalized_heads_subscriptions {
+            ffi::json_rpc_respond(&finalized_head_notification(subscription, finalized_header));
+        }
+    }
+
+    fn answer(
+        &mut self,
+        request: &Request,
+        state: &ChainState,
+    ) -> Result<serde_json::Value, Error> {
+        let param = |n: usize| request.params.get(n).filter(|p| !p.is_null());
+
+        Ok(match &request.method[..] {
+            "chain_getBlockHash" => {
+                let number = match param(0) {
+                    Some(number) => Some(parse_block_number(number)?),
+                    None => None,
+                };
+                let hash = match number {
+                    None => Some(state.best_header.hash()),
+                    Some(0) => Some(self.config.genesis_block_hash),
+                    Some(n) if n == state.finalized_header.number => {
+                        Some(state.finalized_header.hash())
+                    }
+                    Some(n) if n == state.best_header.number => Some(state.best_header.hash()),
+                    Some(_) => None,
+                };
+                serde_json::to_value(hash.map(|h| HexString(h.to_vec()))).unwrap()
+            }
+            "chain_getFinalizedHead" => {
+                serde_json::to_value(HexString(state.finalized_header.hash().to_vec())).unwrap()
+            }
+            "chain_getHeader" => {
+                let header = match param(0) {
+                    None => Some(&state.best_header),
+                    Some(hash) => {
+                        let hash = parse_hash(hash)?;
+                        if hash == state.best_header.hash() {
+                            Some(&state.best_header)
+                        } else if hash == state.finalized_header.hash() {
+                            Some(&state.finalized_header)
+                        } else {
+                            None
+                        }
+                    }
+                };
+                header.map_or(serde_json::Value::Null, header_to_json)
+            }
+            "chain_subscribeFinalizedHeads" => {
+                let subscription = self.next_subscription_id.to_string();
+                self.next_subscription_id += 1;
+                self.finalized_heads_subscriptions
+                    .insert(subscription.clone());
+                serde_json::Value::String(subscription)
+            }
+            "chain_unsubscribeFinalizedHeads" => {
+                let subscription = match param(0) {
+                    Some(serde_json::Value::String(s)) => s.clone(),
+                    Some(serde_json::Value::Number(n)) => n.to_string(),
+                    _ => return Err(Error::invalid_params("Invalid subscription id")),
+                };
+                serde_json::Value::Bool(self.finalized_heads_subscriptions.remove(&subscription))
+            }
+            "rpc_methods" => serde_json::json!({ "version": 1, "methods": METHODS }),
+            "state_getMetadata" => {
+                check_finalized_hash(param(0), state)?;
+                serde_json::to_value(HexString(state.finalized_metadata.to_vec())).unwrap()
+            }
+            "state_getRuntimeVersion" => {
+                check_finalized_hash(param(0), state)?;
+                let version = state.finalized_runtime_version.decode();
+                serde_json::json!({
+                    "specName": version.spec_name,
+                    "implName": version.impl_name,
+                    "authoringVersion": version.authoring_version,
+                    "specVersion": version.spec_version,
+                    "implVersion": version.impl_version,
+                    "transactionVersion": version.transaction_version,
+                    "apis": version
+                        .apis
+                        .iter()
+                        .map(|(name, version)| serde_json::json!([
+                            HexString(name.to_vec()),
+                            version
+                        ]))
+                        .collect::<Vec<_>>(),
+                })
+            }
+            "state_getStorage" => {
+                let key = match param(0) {
+                    Some(key) => parse_hex(key)?,
+                    None => return Err(Error::invalid_params("Missing storage key")),
+                };
+                check_finalized_hash(param(1), state)?;
+                serde_json::to_value(
+                    state
+                        .finalized_storage
+                        .get(&key)
+                        .map(|v| HexString(v.clone())),
+                )
+                .unwrap()
+            }
+            "system_chain" => serde_json::Value::String(self.config.chain_name.clone()),
+            "system_health" => serde_json::json!({
+                "peers": state.peers,
+                "isSyncing": state.is_syncing,
+                "shouldHavePeers": true,
+            }),
+            "system_name" => serde_json::Value::String(env!("CARGO_PKG_NAME").to_owned()),
+            "system_properties" => serde_json::from_str(&self.config.chain_properties)
+                .unwrap_or_else(|_| serde_json::json!({})),
+            "system_version" => serde_json::Value::String(env!("CARGO_PKG_VERSION").to_owned()),
+            _ => {
+                return Err(Error {
+                    code: -32601,
+                    message: format!("Method not found: {}", request.method),
+                })
+            }
+        })
+    }
+}
+
+/// Returns an error if `hash` is neither absent nor the hash of the finalized block.
+fn check_finalized_hash(hash: Option<&serde_json::Value>, state: &ChainState) -> Result<(), Error> {
+    match hash {
+        None => Ok(()),
+        Some(hash) if parse_hash(hash)? == state.finalized_header.hash() => Ok(()),
+        Some(_) => Err(Error {
+            code: -32000,
+            message: "State is only available for the finalized block".to_owned(),
+        }),
+    }
+}
+
+fn parse_hex(value: &serde_json::Value) -> Result<Vec<u8>, Error> {
+    serde_json::from_value::<HexString>(value.clone())
+        .map(|h| h.0)
+        .map_err(|_| Error::invalid_params("Invalid hexadecimal string"))
+}
+
+fn parse_hash(value: &serde_json::Value) -> Result<[u8; 32], Error> {
+    let bytes = parse_hex(value)?;
+    if bytes.len() != 32 {
+        return Err(Error::invalid_params("Invalid block hash"));
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&bytes);
+    Ok(hash)
+}
+
+/// Parses a block number, passed either as a number or as an hexadecimal string starting with
+/// `0x`.
+fn parse_block_number(value: &serde_json::Value) -> Result<u64, Error> {
+    match value {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) if s.starts_with("0x") => {
+            u64::from_str_radix(&s[2..], 16).ok()
+        }
+        _ => None,
+    }
+    .ok_or_else(|| Error::invalid_params("Invalid block number"))
+}
+
+/// Encodes a header in the format of the Substrate JSON-RPC API.
+fn header_to_json(header: &header::HeaderRef) -> serde_json::Value {
+    let logs = header
+        .digest
+        .logs()
+        .map(|log| {
+            let encoded = log.scale_encoding().fold(Vec::new(), |mut encoded, chunk| {
+                encoded.extend_from_slice(chunk.as_ref());
+                encoded
+            });
+            HexString(encoded)
+        })
+        .collect::<Vec<_>>();
+
+    let mut number = String::new();
+    let _ = write!(number, "0x{:x}", header.number);
+
+    serde_json::json!({
+        "parentHash": HexString(header.parent_hash.to_vec()),
+        "number": number,
+        "stateRoot": HexString(header.state_root.to_vec()),
+        "extrinsicsRoot": HexString(header.extrinsics_root.to_vec()),
+        "digest": { "logs": logs },
+    })
+}
+
+fn finalized_head_notification(subscription: &str, header: &header::HeaderRef) -> String {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "chain_finalizedHead",
+        "params": {
+            "subscription": subscription,
+            "result": header_to_json(header),
+        },
+    })
+    .to_string()
+}
+
+fn error_response(id: &serde_json::Value, error: Error) -> String {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+    .to_string()
+}
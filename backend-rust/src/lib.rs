@@ -19,18 +19,36 @@
 #![deny(broken_intra_doc_links)]
 #![deny(unused_crate_dependencies)]
 
+use core::{num::NonZeroU32, time::Duration};
 use futures::{channel::mpsc, prelude::*};
 use smoldot::{
     chain, chain_spec,
-    libp2p::{multiaddr, peer_id::PeerId},
+    libp2p::{
+        connection, multiaddr,
+        peer_id::{PeerId, PublicKey},
+    },
 };
 use std::{collections::BTreeMap, iter, sync::Arc};
 
 pub mod ffi;
 
+mod archive;
+mod balances;
+mod database;
+mod index_archive;
+mod json_rpc_service;
 mod network_service;
+mod recording;
+mod runtime_call;
+mod snapshot;
+mod storage_query;
 mod sync_service;
 
+#[cfg(test)]
+mod test_chain;
+#[cfg(test)]
+mod tests;
+
 // Use the default "system" allocator. In the context of Wasm, this uses the `dlmalloc` library.
 // See <https://github.com/rust-lang/rust/tree/1.47.0/library/std/src/sys/wasm>.
 //
@@ -43,6 +61,37 @@ static ALLOC: std::alloc::System = std::alloc::System;
 pub struct ChainConfig {
     pub specification: String,
     pub database_content: Option<String>,
+    /// Ed25519 private key that determines the identity of the node on the network. If `None`,
+    /// the key found in the database is used, or a new one is generated if the database doesn't
+    /// contain any.
+    pub node_key: Option<[u8; 32]>,
+    /// If true, the activity of the network is recorded, and sent to the host as it happens.
+    pub record_network: bool,
+    /// Network activity previously recorded. If `Some`, the client doesn't connect to the
+    /// network and replays this recording instead, using the virtual clock.
+    pub network_replay: Option<String>,
+    /// Blocks exported by a Substrate node with `export-blocks --binary`. If `Some`, the client
+    /// doesn't connect to the network and imports the blocks of this archive instead. The
+    /// archive must contain the block following the finalized block of the database.
+    pub blocks_archive: Option<Vec<u8>>,
+    /// Index archive previously built with [`ffi::bindings::export_index`]. If `Some`, the
+    /// client resumes from the state found in the archive instead of
+    /// [`ChainConfig::database_content`], and reports the content of the archive through
+    /// [`ffi::bindings::database_save`].
+    pub index_archive: Option<String>,
+    /// Snapshot of a recent finalized block. If `Some`, and if the snapshot is more recent than
+    /// the database content, the client starts from this block after having verified the
+    /// snapshot. See the [`snapshot`] module.
+    pub snapshot: Option<String>,
+    /// If `Some`, the snapshot is verified by comparing the hash of its finalized block with
+    /// this hash, rather than by verifying GrandPa justifications.
+    pub trusted_snapshot_hash: Option<[u8; 32]>,
+    /// If true, the changes to the balances of accounts are reported alongside with the events
+    /// of each finalized block. See the [`balances`] module.
+    pub index_balances: bool,
+    /// Storage prefixes whose changes are reported for each finalized block, in addition to
+    /// the ones passed to [`ffi::bindings::watch_storage_prefix`].
+    pub watched_storage_prefixes: Vec<Vec<u8>>,
 }
 
 /// Starts a client running the given chain specifications.
@@ -52,6 +101,9 @@ pub async fn start_client(chain: ChainConfig, max_log_level: log::LevelFilter) {
     // calls shouldn't panic if reached multiple times.
     let _ =
         log::set_boxed_logger(Box::new(ffi::Logger)).map(|()| log::set_max_level(max_log_level));
+    // Outside of WebAssembly, the default hook already reports panics, and unwinding is
+    // supported.
+    #[cfg(target_arch = "wasm32")]
     std::panic::set_hook(Box::new(|info| {
         ffi::throw(info.to_string());
     }));
@@ -78,10 +130,26 @@ pub async fn start_client(chain: ChainConfig, max_log_level: log::LevelFilter) {
         )
         .unwrap();
 
+    // An imported index archive replaces the database content.
+    let index_archive = chain.index_archive.as_ref().map(|archive| {
+        match index_archive::IndexArchive::from_json(archive) {
+            Ok(archive) => archive,
+            Err(err) => ffi::throw(format!("Error while loading index archive: {}", err)),
+        }
+    });
+
+    let database_content = match &index_archive {
+        Some(archive) => Some(database::decode(&archive.database_content)),
+        None => chain
+            .database_content
+            .as_ref()
+            .map(|content| database::decode(content)),
+    };
+
     // Any error while decoding is treated as if there was no database.
     let (chain_information, finalized_storage) =
-        if let Some(database_content) = &chain.database_content {
-            match smoldot::database::finalized_serialize::decode_chain(database_content) {
+        if let Some(database_content) = &database_content {
+            match smoldot::database::finalized_serialize::decode_chain(&database_content.chain) {
                 Ok((parsed, Some(finalized_storage))) => ((parsed, Some(finalized_storage))),
                 Ok((_, None)) => (genesis_chain_information.clone(), None),
                 Err(error) => {
@@ -104,6 +172,124 @@ pub async fn start_client(chain: ChainConfig, max_log_level: log::LevelFilter) {
         finalized_block_storage
     };
 
+    // A snapshot is only useful if it is more recent than the database.
+    let snapshot = chain.snapshot.as_ref().map(|snapshot| {
+        match snapshot::verify(
+            snapshot,
+            &genesis_chain_information,
+            chain.trusted_snapshot_hash,
+        ) {
+            Ok(snapshot) => snapshot,
+            Err(err) => ffi::throw(format!("Error while verifying snapshot: {}", err)),
+        }
+    });
+    let (chain_information, finalized_storage, from_snapshot) = match snapshot {
+        Some(snapshot)
+            if snapshot.chain_information.finalized_block_header.number
+                > chain_information.finalized_block_header.number =>
+        {
+            log::info!(
+                "Starting from snapshot of block #{}",
+                snapshot.chain_information.finalized_block_header.number
+            );
+            (snapshot.chain_information, snapshot.finalized_storage, true)
+        }
+        Some(_) => {
+            log::info!("Ignoring snapshot older than the database");
+            (chain_information, finalized_storage, false)
+        }
+        None => (chain_information, finalized_storage, false),
+    };
+
+    // The environment hasn't been told about the runtime of the finalized block yet if the
+    // database is empty or has been replaced with the snapshot.
+    let report_finalized_metadata =
+        from_snapshot || chain_information.finalized_block_header.number == 0;
+
+    let node_key = chain
+        .node_key
+        .or_else(|| database_content.as_ref().and_then(|c| c.node_key))
+        .unwrap_or_else(rand::random);
+
+    let address_book = database_content
+        .map(|content| content.address_book)
+        .unwrap_or_default();
+
+    // When replaying a recording, the recording plays the role of the network service. The
+    // recorded events are reported using the virtual clock, so that the host controls the pace
+    // of the replay.
+    let network_replay = chain.network_replay.as_ref().map(|recording| {
+        match recording::Replay::from_recording(recording, node_key) {
+            Ok(replay) => Arc::new(replay),
+            Err(err) => ffi::throw(format!("Error while loading network recording: {}", err)),
+        }
+    });
+    if let Some(replay) = &network_replay {
+        ffi::clock::set_virtual(replay.unix_time());
+    }
+
+    // When importing an archive, the archive plays the role of the network service.
+    let blocks_archive = chain.blocks_archive.as_ref().map(|archive| {
+        if network_replay.is_some() {
+            ffi::throw("Can't both replay a network recording and import blocks".to_owned());
+        }
+
+        let archive = match archive::BlockArchive::decode(archive, node_key, address_book.clone()) {
+            Ok(archive) => archive,
+            Err(err) => ffi::throw(format!("Error while decoding blocks archive: {}", err)),
+        };
+
+        // The blocks are downloaded starting from the one following the finalized block.
+        let finalized_block_number = chain_information.finalized_block_header.number;
+        if archive.first_block_number() > finalized_block_number + 1 {
+            ffi::throw(format!(
+                "Blocks archive starts at block #{}, while the finalized block is #{}",
+                archive.first_block_number(),
+                finalized_block_number
+            ));
+        }
+        if archive.last_block_number() <= finalized_block_number {
+            log::warn!(
+                "Blocks archive ends at block #{}, which is already finalized",
+                archive.last_block_number()
+            );
+        } else {
+            log::info!(
+                "Importing blocks #{} to #{} from archive",
+                finalized_block_number + 1,
+                archive.last_block_number()
+            );
+        }
+
+        Arc::new(archive)
+    });
+
+    // The seed is part of the recording, as it influences which peers blocks are requested
+    // from.
+    let randomness_seed = network_replay
+        .as_ref()
+        .map_or_else(rand::random, |replay| replay.randomness_seed());
+    let recorder = if chain.record_network {
+        Some(recording::Recorder::new(randomness_seed))
+    } else {
+        None
+    };
+
+    // The host is expected to merge the content of the archive into its database, exactly as
+    // if it had been downloaded.
+    if let Some(archive) = index_archive {
+        log::info!(
+            "Imported index archive with {} blocks, finalized at block #{}",
+            archive.blocks.len(),
+            chain_information.finalized_block_header.number
+        );
+        ffi::database_save(&ffi::DatabaseSave {
+            chain: &archive.database_content,
+            new_metadata: archive.metadata,
+            blocks: archive.blocks,
+        });
+    }
+
     ffi::best_block_update(chain_information.finalized_block_header.number);
 
     // Starting here, the code below initializes the various "services" that make up the node.
@@ -128,61 +314,134 @@ pub async fn start_client(chain: ChainConfig, max_log_level: log::LevelFilter) {
         .unbounded_send(
             async move {
                 // The network service is responsible for connecting to the peer-to-peer network
-                // of the chain.
-                let (network_service, mut network_event_receivers) =
-                    network_service::NetworkService::new(network_service::Config {
-                        tasks_executor: Box::new({
-                            let new_task_tx = new_task_tx.clone();
-                            move |fut| new_task_tx.unbounded_send(fut).unwrap()
-                        }),
-                        num_events_receivers: 1, // Configures the length of `network_event_receivers`
-                        chains: iter::once(network_service::ConfigChain {
-                            bootstrap_nodes: {
-                                let mut list = Vec::with_capacity(chain_spec.boot_nodes().len());
-                                for node in chain_spec.boot_nodes() {
-                                    let mut address: multiaddr::Multiaddr = node.parse().unwrap(); // TODO: don't unwrap?
-                                    if let Some(multiaddr::Protocol::P2p(peer_id)) = address.pop() {
-                                        let peer_id = PeerId::from_multihash(peer_id).unwrap(); // TODO: don't unwrap
-                                        list.push((peer_id, address));
-                                    } else {
-                                        panic!() // TODO:
+                // of the chain. It isn't started when replaying a recording or importing an
+                // archive.
+                let (network_service, network, network_events_receiver): (
+                    _,
+                    Arc<dyn sync_service::Network>,
+                    _,
+                ) = if let Some(replay) = network_replay {
+                    let (task, events) = replay.events();
+                    new_task_tx.unbounded_send(task.boxed()).unwrap();
+                    (None, replay, events)
+                } else if let Some(archive) = blocks_archive {
+                    let (task, events) = archive.events();
+                    new_task_tx.unbounded_send(task.boxed()).unwrap();
+                    (None, archive, events)
+                } else {
+                    let (network_service, mut network_event_receivers) =
+                        network_service::NetworkService::new(network_service::Config {
+                            tasks_executor: Box::new({
+                                let new_task_tx = new_task_tx.clone();
+                                move |fut| new_task_tx.unbounded_send(fut).unwrap()
+                            }),
+                            num_events_receivers: 1, // Configures the length of `network_event_receivers`
+                            node_key,
+                            blocks_request_timeout: Duration::from_secs(20),
+                            connection_buffer_limit: 8 * 1024 * 1024,
+                            chains: iter::once(network_service::ConfigChain {
+                                bootstrap_nodes: {
+                                    let mut list =
+                                        Vec::with_capacity(chain_spec.boot_nodes().len());
+                                    for node in chain_spec.boot_nodes() {
+                                        let mut address: multiaddr::Multiaddr =
+                                            node.parse().unwrap(); // TODO: don't unwrap?
+                                        if let Some(multiaddr::Protocol::P2p(peer_id)) =
+                                            address.pop()
+                                        {
+                                            let peer_id = PeerId::from_multihash(peer_id).unwrap(); // TODO: don't unwrap
+                                            list.push((peer_id, address));
+                                        } else {
+                                            panic!() // TODO:
+                                        }
                                     }
-                                }
-                                list
-                            },
-                            has_grandpa_protocol: matches!(
-                                chain_information.finality,
-                                chain::chain_information::ChainInformationFinality::Grandpa { .. }
-                            ),
-                            genesis_block_hash: genesis_chain_information
-                                .finalized_block_header
-                                .hash(),
-                            best_block: (
-                                chain_information.finalized_block_header.number,
-                                chain_information.finalized_block_header.hash(),
-                            ),
-                            protocol_id: chain_spec.protocol_id().to_string(),
+                                    list
+                                },
+                                address_book,
+                                grandpa_protocol_state: sync_service::local_grandpa_state(
+                                    (&chain_information).into(),
+                                ),
+                                genesis_block_hash: genesis_chain_information
+                                    .finalized_block_header
+                                    .hash(),
+                                best_block: (
+                                    chain_information.finalized_block_header.number,
+                                    chain_information.finalized_block_header.hash(),
+                                ),
+                                protocol_id: chain_spec.protocol_id().to_string(),
+                            })
+                            .collect(),
                         })
-                        .collect(),
-                    })
-                    .await;
+                        .await;
+                    let events = network_event_receivers.pop().unwrap();
+                    (Some(network_service.clone()), network_service, events)
+                };
+
+                // The recording wraps around the network, and records what the sync service
+                // sees of it.
+                let (network, network_events_receiver) = match &recorder {
+                    Some(recorder) => {
+                        let (task, events) = recorder.record_events(network_events_receiver);
+                        new_task_tx.unbounded_send(task.boxed()).unwrap();
+                        let network: Arc<dyn sync_service::Network> =
+                            Arc::new(recording::RecordingNetwork {
+                                inner: network,
+                                recorder: recorder.clone(),
+                            });
+                        (network, events)
+                    }
+                    None => (network, network_events_receiver),
+                };
 
                 // The sync service is leveraging the network service, downloads block headers,
                 // and verifies them, to determine what are the best and finalized blocks of the
                 // chain.
-                let sync_service = Arc::new(
-                    sync_service::SyncService::new(sync_service::Config {
-                        chain_information,
-                        finalized_storage,
-                        tasks_executor: Box::new({
-                            let new_task_tx = new_task_tx.clone();
-                            move |fut| new_task_tx.unbounded_send(fut).unwrap()
-                        }),
-                        network_service: (network_service.clone(), 0),
-                        network_events_receiver: network_event_receivers.pop().unwrap(),
-                    })
-                    .await,
-                );
+                let sync_service = sync_service::SyncService::new(sync_service::Config {
+                    chain_information,
+                    finalized_storage,
+                    report_finalized_metadata,
+                    index_balances: chain.index_balances,
+                    watched_storage_prefixes: chain
+                        .watched_storage_prefixes
+                        .iter()
+                        .cloned()
+                        .collect(),
+                    json_rpc_service: json_rpc_service::JsonRpcService::new(
+                        json_rpc_service::Config {
+                            chain_name: chain_spec.name().to_owned(),
+                            chain_properties: chain_spec.properties().to_owned(),
+                            genesis_block_hash: genesis_chain_information
+                                .finalized_block_header
+                                .hash(),
+                        },
+                    ),
+                    tasks_executor: Box::new({
+                        let new_task_tx = new_task_tx.clone();
+                        move |fut| new_task_tx.unbounded_send(fut).unwrap()
+                    }),
+                    network_service: (network, 0),
+                    min_blocks_request_size: NonZeroU32::new(16).unwrap(),
+                    max_blocks_request_size: NonZeroU32::new(128).unwrap(),
+                    stall_timeout: Duration::from_secs(90),
+                    randomness_seed,
+                    network_events_receiver,
+                })
+                .await;
+
+                // Requests emitted by the host are processed by a dedicated task.
+                if let Some(host_requests) = ffi::take_host_requests() {
+                    new_task_tx
+                        .unbounded_send(
+                            process_host_requests(
+                                host_requests,
+                                network_service,
+                                sync_service,
+                                node_key,
+                            )
+                            .boxed(),
+                        )
+                        .unwrap();
+                }
 
                 log::info!("Initialization complete");
             }
@@ -201,10 +460,17 @@ pub async fn start_client(chain: ChainConfig, max_log_level: log::LevelFilter) {
         }
 
         loop {
-            match future::select(new_task_rx.select_next_some(), all_tasks.next()).await {
-                future::Either::Left((new_task, _)) => {
+            match future::select(new_task_rx.next(), all_tasks.next()).await {
+                future::Either::Left((Some(new_task), _)) => {
                     all_tasks.push(new_task);
                 }
+                // No task can be spawned anymore. This happens when replaying a network
+                // recording, as there is then no network service holding a sender.
+                future::Either::Left((None, _)) => {
+                    while all_tasks.next().await.is_some() {}
+                    log::info!("All tasks complete. Stopping client.");
+                    break;
+                }
                 future::Either::Right((Some(()), _)) => {}
                 future::Either::Right((None, _)) => {
                     log::info!("All tasks complete. Stopping client.");
@@ -216,6 +482,119 @@ pub async fn start_client(chain: ChainConfig, max_log_level: log::LevelFilter) {
     .await
 }
 
+/// Processes the requests emitted by the host through the FFI, until the channel is closed.
+async fn process_host_requests(
+    mut requests: mpsc::UnboundedReceiver<ffi::HostRequest>,
+    network_service: Option<Arc<network_service::NetworkService>>,
+    sync_service: Arc<sync_service::SyncService>,
+    node_key: [u8; 32],
+) {
+    let network_service = match network_service {
+        Some(network_service) => network_service,
+        None => return process_offline_host_requests(requests, sync_service, node_key).await,
+    };
+
+    while let Some(request) = requests.next().await {
+        match request {
+            ffi::HostRequest::NetworkInfo => {
+                let peers = network_service.peers_info().await;
+                ffi::network_info_response(network_service.local_peer_id(), &peers);
+            }
+            // The network service is configured with a single chain, whose index is 0.
+            ffi::HostRequest::AddPeer { address } => match parse_peer_address(&address) {
+                Some((peer_id, address)) => network_service.add_peer(0, peer_id, address).await,
+                None => log::warn!("Ignoring invalid peer address: {}", address),
+            },
+            ffi::HostRequest::DisconnectPeer { peer_id, ban } => match peer_id.parse() {
+                Ok(peer_id) => network_service.disconnect_peer(&peer_id, ban).await,
+                Err(_) => log::warn!("Ignoring invalid peer id: {}", peer_id),
+            },
+            ffi::HostRequest::SetPeerReserved { peer_id, reserved } => match peer_id.parse() {
+                Ok(peer_id) => network_service.set_peer_reserved(peer_id, reserved).await,
+                Err(_) => log::warn!("Ignoring invalid peer id: {}", peer_id),
+            },
+            ffi::HostRequest::SetReservedOnly(reserved_only) => {
+                network_service.set_reserved_only(reserved_only).await
+            }
+            ffi::HostRequest::StorageQuery(query) => {
+                ffi::storage_query_response(&sync_service.storage_query(query).await)
+            }
+            ffi::HostRequest::RuntimeCall {
+                function,
+                parameter,
+            } => runtime_call(&sync_service, function, parameter).await,
+            ffi::HostRequest::WatchStoragePrefix(prefix) => {
+                sync_service.watch_storage_prefix(prefix)
+            }
+            ffi::HostRequest::UnwatchStoragePrefix(prefix) => {
+                sync_service.unwatch_storage_prefix(prefix)
+            }
+            ffi::HostRequest::JsonRpc(request) => sync_service.json_rpc_request(request),
+        }
+    }
+}
+
+/// Same as [`process_host_requests`], but when replaying a network recording or importing an
+/// archive. Requests that would modify the network are ignored.
+async fn process_offline_host_requests(
+    mut requests: mpsc::UnboundedReceiver<ffi::HostRequest>,
+    sync_service: Arc<sync_service::SyncService>,
+    node_key: [u8; 32],
+) {
+    let noise_key = connection::NoiseKey::new(&node_key);
+    let local_peer_id =
+        PeerId::from_public_key(&PublicKey::Ed25519(*noise_key.libp2p_public_ed25519_key()));
+
+    while let Some(request) = requests.next().await {
+        match request {
+            ffi::HostRequest::NetworkInfo => ffi::network_info_response(&local_peer_id, &[]),
+            ffi::HostRequest::StorageQuery(query) => {
+                ffi::storage_query_response(&sync_service.storage_query(query).await)
+            }
+            ffi::HostRequest::RuntimeCall {
+                function,
+                parameter,
+            } => runtime_call(&sync_service, function, parameter).await,
+            ffi::HostRequest::WatchStoragePrefix(prefix) => {
+                sync_service.watch_storage_prefix(prefix)
+            }
+            ffi::HostRequest::UnwatchStoragePrefix(prefix) => {
+                sync_service.unwatch_storage_prefix(prefix)
+            }
+            ffi::HostRequest::JsonRpc(request) => sync_service.json_rpc_request(request),
+            _ => log::warn!("Ignoring network request while the network is disabled"),
+        }
+    }
+}
+
+/// Answers a [`ffi::HostRequest::RuntimeCall`].
+async fn runtime_call(
+    sync_service: &sync_service::SyncService,
+    function: String,
+    parameter: Vec<u8>,
+) {
+    let response = match sync_service.runtime_call(function, parameter).await {
+        Ok(output) => ffi::RuntimeCallResponse::Success {
+            block_number: output.block_number,
+            block_hash: smoldot::json_rpc::methods::HexString(output.block_hash.to_vec()),
+            output: smoldot::json_rpc::methods::HexString(output.output),
+        },
+        Err(error) => ffi::RuntimeCallResponse::Error { error },
+    };
+    ffi::runtime_call_response(&response);
+}
+
+/// Parses a multiaddress ending with `/p2p/<peer id>` into its components.
+fn parse_peer_address(address: &str) -> Option<(PeerId, multiaddr::Multiaddr)> {
+    let mut address: multiaddr::Multiaddr = address.parse().ok()?;
+    if let Some(multiaddr::Protocol::P2p(peer_id)) = address.pop() {
+        let peer_id = PeerId::from_multihash(peer_id).ok()?;
+        Some((peer_id, address))
+    } else {
+        None
+    }
+}
+
 /// Use in an asynchronous context to interrupt the current task execution and schedule it back.
 ///
 /// This function is useful in order to guarantee a fine granularity of tasks execution time in
@@ -17,14 +17,40 @@
 
 use crate::ffi;
 
-use core::{num::NonZeroUsize, pin::Pin, time::Duration};
-use futures::{channel::mpsc, lock::Mutex, prelude::*};
+use core::{
+    cmp,
+    convert::TryFrom as _,
+    fmt, mem,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::atomic::{self, AtomicU64},
+    time::Duration,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    lock::Mutex,
+    prelude::*,
+};
 use smoldot::{
     informant::HashDisplay,
-    libp2p::{connection, multiaddr::Multiaddr, peer_id::PeerId},
+    libp2p::{
+        self, connection,
+        multiaddr::Multiaddr,
+        peer_id::{PeerId, PublicKey},
+    },
     network::{protocol, service},
 };
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+pub use address_book::AddressBookEntry;
+pub use service::GrandpaState;
+pub use transport::Transport;
+
+mod address_book;
+mod transport;
 
 /// Configuration for a [`NetworkService`].
 pub struct Config {
@@ -34,6 +60,20 @@ pub struct Config {
     /// Number of event receivers returned by [`NetworkService::new`].
     pub num_events_receivers: usize,
 
+    /// Ed25519 private key of the local node. Determines the [`PeerId`] of the local node and the
+    /// key used for the Noise encryption layer.
+    ///
+    /// Should be kept between sessions, so that other peers can recognize the local node.
+    pub node_key: [u8; 32],
+
+    /// Maximum duration of a blocks request, after which it is considered as failed. See
+    /// [`NetworkService::blocks_request`].
+    pub blocks_request_timeout: Duration,
+
+    /// Maximum number of bytes received on a connection and not processed yet. When this limit
+    /// is exceeded, reading from the socket is paused. See [`transport::connect`].
+    pub connection_buffer_limit: usize,
+
     /// List of chains to connect to. Chains are later referred to by their index in this list.
     pub chains: Vec<ConfigChain>,
 }
@@ -44,6 +84,11 @@ pub struct ConfigChain {
     /// network.
     pub bootstrap_nodes: Vec<(PeerId, Multiaddr)>,
 
+    /// List of peers that have been successfully connected to during previous sessions, as
+    /// previously returned by [`NetworkService::address_book`]. Dialed in addition to the
+    /// bootstrap nodes.
+    pub address_book: Vec<AddressBookEntry>,
+
     /// Hash of the genesis block of the chain. Sent to other nodes in order to determine whether
     /// the chains match.
     pub genesis_block_hash: [u8; 32],
@@ -57,8 +102,10 @@ pub struct ConfigChain {
     /// chain, so as to not introduce conflicts in the networking messages.
     pub protocol_id: String,
 
-    /// If true, the chain uses the GrandPa networking protocol.
-    pub has_grandpa_protocol: bool,
+    /// If `Some`, the chain uses the GrandPa networking protocol, and this is the initial state
+    /// of the local node that is reported to other peers. Can later be updated with
+    /// [`NetworkService::set_local_grandpa_state`].
+    pub grandpa_protocol_state: Option<GrandpaState>,
 }
 
 pub struct NetworkService {
@@ -68,15 +115,102 @@ pub struct NetworkService {
     /// Data structure holding the entire state of the networking.
     network: service::ChainNetwork<ffi::Instant, (), ()>,
 
+    /// See [`Config::node_key`].
+    node_key: [u8; 32],
+
+    /// Identity of the local node. Derived from [`NetworkService::node_key`].
+    local_peer_id: PeerId,
+
+    /// See [`Config::blocks_request_timeout`].
+    blocks_request_timeout: Duration,
+
+    /// See [`Config::connection_buffer_limit`].
+    connection_buffer_limit: usize,
+
     /// List of nodes that are considered as important for logging purposes.
+    ///
+    /// > **Note**: Reserved peers (see [`Guarded::reserved`]) are also considered as important.
     // TODO: should also detect whenever we fail to open a block announces substream with any of these peers
     important_nodes: HashSet<PeerId, fnv::FnvBuildHasher>,
 }
 
+/// Maximum delay between two attempts at reaching a reserved peer.
+const RESERVED_PEER_MAX_RETRY_DELAY: Duration = Duration::from_secs(32);
+
 /// Fields of [`NetworkService`] behind a mutex.
 struct Guarded {
     /// See [`Config::tasks_executor`].
     tasks_executor: Box<dyn FnMut(Pin<Box<dyn Future<Output = ()> + Send>>) + Send>,
+
+    /// For each chain, list of peers that we have successfully connected to.
+    address_books: Vec<address_book::AddressBook>,
+
+    /// State of each peer we currently have a connection with.
+    connections: HashMap<PeerId, ConnectionState, fnv::FnvBuildHasher>,
+
+    /// List of peers that have been marked as reserved with [`NetworkService::set_peer_reserved`].
+    /// Each reserved peer has a [`reserved_peer_task`] dedicated to it, and the value is the
+    /// sending side of the channel of this task.
+    reserved: HashMap<PeerId, mpsc::UnboundedSender<ReservedPeerMessage>, fnv::FnvBuildHasher>,
+
+    /// See [`NetworkService::set_reserved_only`].
+    reserved_only: bool,
+
+    /// List of peers that have been banned with [`NetworkService::disconnect_peer`]. Banned
+    /// peers are never dialed.
+    banned: HashSet<PeerId, fnv::FnvBuildHasher>,
+
+    /// Dialing attempts towards non-reserved peers that have been requested while
+    /// [`Guarded::reserved_only`] was `true`, with the index of the chain that requested them.
+    /// Started when reserved-only mode is disabled.
+    ///
+    /// These attempts are kept pending rather than reported as failed, as reporting a failure
+    /// removes the address from the list of known addresses.
+    deferred_dials: HashMap<PeerId, (usize, service::StartConnect), fnv::FnvBuildHasher>,
+}
+
+/// Message sent to a [`reserved_peer_task`].
+enum ReservedPeerMessage {
+    /// Dialing attempt towards the reserved peer, with the index of the chain that requested it.
+    Dial(usize, service::StartConnect),
+    /// A connection with the reserved peer that wasn't handled by the task has been closed.
+    ConnectionClosed,
+}
+
+/// See [`Guarded::connections`].
+struct ConnectionState {
+    /// Address the connection has been established with. Used in order to fill
+    /// [`Guarded::address_books`] once the peer has connected to a chain.
+    address: Multiaddr,
+
+    /// When the connection has been established.
+    connected_since: ffi::Instant,
+
+    /// Number of bytes transferred on the connection. Updated by the connection task without
+    /// locking [`NetworkService::guarded`].
+    bandwidth: Arc<Bandwidth>,
+
+    /// For each chain index the peer is connected to, its role and best block.
+    chains: HashMap<usize, PeerChainInfo, fnv::FnvBuildHasher>,
+
+    /// Number of blocks requests towards this peer that have succeeded.
+    blocks_requests_successes: u32,
+
+    /// Number of blocks requests towards this peer that have failed.
+    blocks_requests_failures: u32,
+
+    /// Sum of the durations of all the successful blocks requests towards this peer.
+    blocks_requests_total_latency: Duration,
+
+    /// Sending on this channel instructs the connection task to close the connection.
+    disconnect: Option<oneshot::Sender<()>>,
+}
+
+/// See [`ConnectionState::bandwidth`].
+#[derive(Default)]
+struct Bandwidth {
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
 }
 
 impl NetworkService {
@@ -97,28 +231,39 @@ impl NetworkService {
             .map(|(peer_id, _)| peer_id.clone())
             .collect::<HashSet<_, _>>();
 
+        let noise_key = connection::NoiseKey::new(&config.node_key);
+        let local_peer_id =
+            PeerId::from_public_key(&PublicKey::Ed25519(*noise_key.libp2p_public_ed25519_key()));
+        log::info!(target: "network", "Local peer id: {}", local_peer_id);
+
         let num_chains = config.chains.len();
         let mut chains = Vec::with_capacity(num_chains);
         // TODO: this `bootstrap_nodes` field is weird ; should we de-duplicate entry in known_nodes?
         let mut known_nodes = Vec::new();
+        let mut address_books = Vec::with_capacity(num_chains);
 
         for chain in config.chains {
+            // Nodes from the address book that are already in the list of bootstrap nodes are
+            // ignored.
+            let previous_peers = chain
+                .address_book
+                .iter()
+                .filter(|entry| {
+                    !chain
+                        .bootstrap_nodes
+                        .iter()
+                        .any(|(peer_id, addr)| *peer_id == entry.peer_id && *addr == entry.address)
+                })
+                .map(|entry| (entry.peer_id.clone(), entry.address.clone()))
+                .collect::<Vec<_>>();
+
             chains.push(service::ChainConfig {
                 bootstrap_nodes: (known_nodes.len()
-                    ..(known_nodes.len() + chain.bootstrap_nodes.len()))
+                    ..(known_nodes.len() + chain.bootstrap_nodes.len() + previous_peers.len()))
                     .collect(),
                 in_slots: 25,
                 out_slots: 25,
-                grandpa_protocol_config: if chain.has_grandpa_protocol {
-                    // TODO: dummy values
-                    Some(service::GrandpaState {
-                        commit_finalized_height: 0,
-                        round_number: 1,
-                        set_id: 0,
-                    })
-                } else {
-                    None
-                },
+                grandpa_protocol_config: chain.grandpa_protocol_state,
                 protocol_id: chain.protocol_id.clone(),
                 best_hash: chain.best_block.1,
                 best_number: chain.best_block.0,
@@ -130,24 +275,37 @@ impl NetworkService {
                 chain
                     .bootstrap_nodes
                     .into_iter()
+                    .chain(previous_peers)
                     .map(|(peer_id, addr)| ((), peer_id, addr)),
             );
+
+            address_books.push(address_book::AddressBook::new(chain.address_book));
         }
 
         let network_service = Arc::new(NetworkService {
             guarded: Mutex::new(Guarded {
                 tasks_executor: config.tasks_executor,
+                address_books,
+                connections: HashMap::default(),
+                reserved: HashMap::default(),
+                reserved_only: false,
+                banned: HashSet::default(),
+                deferred_dials: HashMap::default(),
             }),
             network: service::ChainNetwork::new(service::Config {
                 chains,
                 known_nodes,
                 listen_addresses: Vec::new(), // TODO:
-                noise_key: connection::NoiseKey::new(&rand::random()),
+                noise_key,
                 // TODO: we use an abnormally large channel in order to by pass https://github.com/paritytech/smoldot/issues/615
                 // once the issue is solved, this should be restored to a smaller value, such as 16
                 pending_api_events_buffer_size: NonZeroUsize::new(2048).unwrap(),
                 randomness_seed: rand::random(),
             }),
+            node_key: config.node_key,
+            local_peer_id,
+            blocks_request_timeout: config.blocks_request_timeout,
+            connection_buffer_limit: config.connection_buffer_limit,
             important_nodes,
         });
 
@@ -199,10 +357,27 @@ impl NetworkService {
                                     HashDisplay(&announce.decode().header.hash()),
                                     announce.decode().is_best
                                 );
+
+                                if announce.decode().is_best {
+                                    let mut guarded = network_service.guarded.lock().await;
+                                    if let Some(chain) = guarded
+                                        .connections
+                                        .get_mut(&peer_id)
+                                        .and_then(|c| c.chains.get_mut(&chain_index))
+                                    {
+                                        chain.best_block_number = announce.decode().header.number;
+                                        chain.best_block_hash = announce.decode().header.hash();
+                                    }
+                                }
+
                                 break Event::BlockAnnounce {
                                     chain_index,
                                     peer_id,
-                                    announce,
+                                    scale_encoded_header: announce
+                                        .decode()
+                                        .header
+                                        .scale_encoding_vec(),
+                                    is_best: announce.decode().is_best,
                                 };
                             }
                             service::Event::ChainConnected {
@@ -210,7 +385,7 @@ impl NetworkService {
                                 chain_index,
                                 best_number,
                                 best_hash,
-                                ..
+                                role,
                             } => {
                                 log::debug!(
                                     target: "network",
@@ -220,6 +395,29 @@ impl NetworkService {
                                     best_number,
                                     HashDisplay(&best_hash)
                                 );
+
+                                {
+                                    let mut guarded = network_service.guarded.lock().await;
+                                    let guarded = &mut *guarded;
+                                    if let Some(connection) = guarded.connections.get_mut(&peer_id)
+                                    {
+                                        connection.chains.insert(
+                                            chain_index,
+                                            PeerChainInfo {
+                                                chain_index,
+                                                role,
+                                                best_block_number: best_number,
+                                                best_block_hash: best_hash,
+                                            },
+                                        );
+                                        guarded.address_books[chain_index].on_connected(
+                                            peer_id.clone(),
+                                            connection.address.clone(),
+                                            ffi::unix_time(),
+                                        );
+                                    }
+                                }
+
                                 break Event::Connected {
                                     peer_id,
                                     chain_index,
@@ -237,11 +435,49 @@ impl NetworkService {
                                     peer_id,
                                     chain_index,
                                 );
+
+                                if let Some(connection) = network_service
+                                    .guarded
+                                    .lock()
+                                    .await
+                                    .connections
+                                    .get_mut(&peer_id)
+                                {
+                                    connection.chains.remove(&chain_index);
+                                }
+
                                 break Event::Disconnected {
                                     peer_id,
                                     chain_index,
                                 };
                             }
+                            service::Event::GrandpaCommitMessage {
+                                chain_index,
+                                peer_id,
+                                message,
+                            } => {
+                                let commit = message.decode();
+                                log::debug!(
+                                    target: "network",
+                                    "Connection({}) => GrandpaCommit({}, {}, set_id={})",
+                                    peer_id,
+                                    chain_index,
+                                    HashDisplay(commit.message.target_hash),
+                                    commit.set_id
+                                );
+
+                                // A commit whose number of signatures doesn't match its number
+                                // of precommits can't be turned into a justification.
+                                if let Some(scale_encoded_justification) = commit.to_justification()
+                                {
+                                    break Event::GrandpaCommit {
+                                        peer_id,
+                                        chain_index,
+                                        target_number: u64::from(commit.message.target_number),
+                                        scale_encoded_justification,
+                                    };
+                                }
+                            }
                             service::Event::IdentifyRequestIn { peer_id, request } => {
                                 log::debug!(
                                     target: "network",
@@ -290,29 +526,7 @@ impl NetworkService {
                                 None => continue,
                             };
 
-                        let is_important_peer = network_service
-                            .important_nodes
-                            .contains(&start_connect.expected_peer_id);
-
-                        // Convert the `multiaddr` (typically of the form `/ip4/a.b.c.d/tcp/d/ws`)
-                        // into a `Future<dyn Output = Result<TcpStream, ...>>`.
-                        let socket = {
-                            log::debug!(target: "connections", "Pending({:?}) started: {}", start_connect.id, start_connect.multiaddr);
-                            ffi::Connection::connect(&start_connect.multiaddr.to_string())
-                        };
-
-                        // TODO: handle dialing timeout here
-
-                        let network_service2 = network_service.clone();
-                        (network_service.guarded.lock().await.tasks_executor)(Box::pin({
-                            connection_task(
-                                socket,
-                                network_service2,
-                                start_connect.id,
-                                start_connect.expected_peer_id,
-                                is_important_peer,
-                            )
-                        }));
+                        dispatch_dial(&network_service, chain_index, start_connect).await;
                     }
                 }
             }));
@@ -344,19 +558,72 @@ impl NetworkService {
     }
 
     /// Sends a blocks request to the given peer.
-    // TODO: more docs
+    ///
+    /// The request fails with [`BlocksRequestError::Timeout`] if no response has been received
+    /// after [`Config::blocks_request_timeout`], and with [`BlocksRequestError::Empty`] if the
+    /// peer doesn't know the requested blocks. On success, the response is guaranteed to contain
+    /// at least one block, and all the blocks are guaranteed to contain the fields that have been
+    /// requested in [`protocol::BlocksRequestConfig::fields`], with the exception of the
+    /// justification, which is optional.
     pub async fn blocks_request(
         self: Arc<Self>,
         target: PeerId,
         chain_index: usize,
         config: protocol::BlocksRequestConfig,
-    ) -> Result<Vec<protocol::BlockData>, service::BlocksRequestError> {
+    ) -> Result<Vec<protocol::BlockData>, BlocksRequestError> {
         log::debug!(target: "network", "Connection({}) <= BlocksRequest({:?})", target, config);
 
-        let result = self
+        let requested_header = config.fields.header;
+        let requested_body = config.fields.body;
+
+        let start = ffi::Instant::now();
+        let request = self
             .network
-            .blocks_request(ffi::Instant::now(), target.clone(), chain_index, config)
-            .await;
+            .blocks_request(start, target.clone(), chain_index, config);
+        let timeout = ffi::Delay::new(self.blocks_request_timeout);
+        futures::pin_mut!(request);
+
+        let result = match future::select(request, timeout).await {
+            future::Either::Left((Ok(blocks), _)) => {
+                if blocks.is_empty() {
+                    Err(BlocksRequestError::Empty)
+                } else if blocks.iter().any(|block| {
+                    (requested_header && block.header.is_none())
+                        || (requested_body && block.body.is_none())
+                }) {
+                    Err(BlocksRequestError::Incomplete)
+                } else {
+                    Ok(blocks)
+                }
+            }
+            future::Either::Left((Err(service::BlocksRequestError::Request(err)), _)) => {
+                match err {
+                    libp2p::RequestError::Connection(
+                        connection::established::RequestError::Timeout,
+                    ) => Err(BlocksRequestError::Timeout),
+                    libp2p::RequestError::Connection(
+                        connection::established::RequestError::SubstreamReset,
+                    )
+                    | libp2p::RequestError::Connection(
+                        connection::established::RequestError::ResponseLebError(_),
+                    ) => Err(BlocksRequestError::Protocol(
+                        service::BlocksRequestError::Request(err),
+                    )),
+                    err => Err(BlocksRequestError::Refused(err)),
+                }
+            }
+            future::Either::Left((Err(err), _)) => Err(BlocksRequestError::Protocol(err)),
+            future::Either::Right(((), _)) => Err(BlocksRequestError::Timeout),
+        };
+
+        if let Some(connection) = self.guarded.lock().await.connections.get_mut(&target) {
+            if result.is_ok() {
+                connection.blocks_requests_successes += 1;
+                connection.blocks_requests_total_latency += start.elapsed();
+            } else {
+                connection.blocks_requests_failures += 1;
+            }
+        }
 
         log::debug!(
             target: "network",
@@ -367,6 +634,257 @@ impl NetworkService {
 
         result
     }
+
+    /// Sends a storage proof request to the given peer.
+    ///
+    /// The proof isn't verified. It is the responsibility of the caller to verify it against the
+    /// state root of the block.
+    pub async fn storage_proof_request(
+        self: Arc<Self>,
+        target: PeerId,
+        chain_index: usize,
+        config: protocol::StorageProofRequestConfig<impl Iterator<Item = impl AsRef<[u8]>>>,
+    ) -> Result<Vec<Vec<u8>>, service::StorageProofRequestError> {
+        log::debug!(
+            target: "network",
+            "Connection({}) <= StorageProofRequest(block={})",
+            target,
+            HashDisplay(&config.block_hash)
+        );
+
+        let result = self
+            .network
+            .storage_proof_request(ffi::Instant::now(), target.clone(), chain_index, config)
+            .await;
+
+        log::debug!(
+            target: "network",
+            "Connection({}) => StorageProofRequest({:?})",
+            target,
+            result.as_ref().map(|p| p.len())
+        );
+
+        result
+    }
+
+    /// Updates the GrandPa state of the local node, and sends a neighbor packet to all the peers
+    /// of the given chain.
+    ///
+    /// Peers use this state in order to determine which GrandPa messages (most notably commits)
+    /// are worth gossiping to us.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `chain_index` is out of range, or if the chain doesn't use the GrandPa
+    /// protocol (see [`ConfigChain::grandpa_protocol_state`]).
+    ///
+    pub async fn set_local_grandpa_state(&self, chain_index: usize, grandpa_state: GrandpaState) {
+        log::debug!(
+            target: "network",
+            "Chain({}) <= GrandpaNeighbor(set_id={}, commit_finalized_height={})",
+            chain_index,
+            grandpa_state.set_id,
+            grandpa_state.commit_finalized_height
+        );
+
+        self.network
+            .set_local_grandpa_state(chain_index, grandpa_state)
+            .await
+    }
+
+    /// Adds a peer to the address book of the given chain, with the highest possible
+    /// reliability, and dials it unless it is already connected or being dialed.
+    ///
+    /// The peer is no longer banned (see [`NetworkService::disconnect_peer`]). In reserved-only
+    /// mode (see [`NetworkService::set_reserved_only`]), the peer is only dialed if it is
+    /// reserved or once the mode is disabled, like any other peer.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `chain_index` is out of range.
+    ///
+    pub async fn add_peer(
+        self: &Arc<Self>,
+        chain_index: usize,
+        peer_id: PeerId,
+        address: Multiaddr,
+    ) {
+        log::info!(target: "network", "Adding peer {} with address {}", peer_id, address);
+
+        {
+            let mut guarded = self.guarded.lock().await;
+            guarded.banned.remove(&peer_id);
+            guarded.address_books[chain_index].insert_trusted(
+                peer_id.clone(),
+                address.clone(),
+                ffi::unix_time(),
+            );
+        }
+
+        if let Some(start_connect) = self
+            .network
+            .add_address_and_dial(|| (), chain_index, peer_id, address)
+            .await
+        {
+            dispatch_dial(self, chain_index, start_connect).await;
+        }
+    }
+
+    /// Closes the connection with the given peer, if any.
+    ///
+    /// If `ban` is true, the peer is also removed from the address books and from the reserved
+    /// peers, and will never be dialed again for the rest of the session.
+    pub async fn disconnect_peer(&self, peer_id: &PeerId, ban: bool) {
+        log::info!(target: "network", "Disconnecting from {} (ban: {:?})", peer_id, ban);
+
+        let mut guarded = self.guarded.lock().await;
+
+        if let Some(disconnect) = guarded
+            .connections
+            .get_mut(peer_id)
+            .and_then(|c| c.disconnect.take())
+        {
+            let _ = disconnect.send(());
+        }
+
+        if ban {
+            guarded.banned.insert(peer_id.clone());
+            guarded.reserved.remove(peer_id);
+            for address_book in &mut guarded.address_books {
+                address_book.remove(peer_id);
+            }
+
+            // Note that reporting the failure removes the address from the list of known
+            // addresses.
+            if let Some((_, start_connect)) = guarded.deferred_dials.remove(peer_id) {
+                drop(guarded);
+                self.network.pending_outcome_err(start_connect.id).await;
+            }
+        }
+    }
+
+    /// Marks or unmarks the given peer as reserved.
+    ///
+    /// Reaching reserved peers is attempted again after a failure or after the connection has
+    /// been closed, and reserved peers are the only ones that are dialed when in reserved-only
+    /// mode (see [`NetworkService::set_reserved_only`]).
+    ///
+    /// > **Note**: Peers can only be dialed if their address is known to the network, for
+    /// >           example because they are part of the bootstrap nodes or of the address book
+    /// >           passed at initialization. See also [`NetworkService::add_peer`].
+    pub async fn set_peer_reserved(self: &Arc<Self>, peer_id: PeerId, reserved: bool) {
+        let mut guarded = self.guarded.lock().await;
+        let guarded = &mut *guarded;
+
+        if !reserved {
+            // Dropping the sender stops the task dedicated to the peer.
+            guarded.reserved.remove(&peer_id);
+            return;
+        }
+
+        guarded.banned.remove(&peer_id);
+        if guarded.reserved.contains_key(&peer_id) {
+            return;
+        }
+
+        let (tx, rx) = mpsc::unbounded();
+        if let Some((chain_index, start_connect)) = guarded.deferred_dials.remove(&peer_id) {
+            tx.unbounded_send(ReservedPeerMessage::Dial(chain_index, start_connect))
+                .unwrap();
+        }
+        guarded.reserved.insert(peer_id.clone(), tx);
+        (guarded.tasks_executor)(Box::pin(reserved_peer_task(self.clone(), peer_id, rx)));
+    }
+
+    /// Enables or disables reserved-only mode.
+    ///
+    /// When enabled, connections to peers that aren't reserved (see
+    /// [`NetworkService::set_peer_reserved`]) are closed, and only reserved peers are dialed.
+    pub async fn set_reserved_only(self: &Arc<Self>, reserved_only: bool) {
+        log::info!(target: "network", "Reserved-only mode: {:?}", reserved_only);
+
+        let deferred_dials = {
+            let mut guarded = self.guarded.lock().await;
+            let guarded = &mut *guarded;
+            guarded.reserved_only = reserved_only;
+
+            if reserved_only {
+                for (peer_id, connection) in &mut guarded.connections {
+                    if guarded.reserved.contains_key(peer_id) {
+                        continue;
+                    }
+
+                    if let Some(disconnect) = connection.disconnect.take() {
+                        let _ = disconnect.send(());
+                    }
+                }
+
+                return;
+            }
+
+            mem::take(&mut guarded.deferred_dials)
+        };
+
+        for (_, (chain_index, start_connect)) in deferred_dials {
+            dispatch_dial(self, chain_index, start_connect).await;
+        }
+    }
+
+    /// Returns the identity of the local node on the network.
+    pub fn local_peer_id(&self) -> &PeerId {
+        &self.local_peer_id
+    }
+
+    /// Returns information about all the peers we are currently connected to.
+    pub async fn peers_info(&self) -> Vec<PeerInfo> {
+        let guarded = self.guarded.lock().await;
+        let now = ffi::Instant::now();
+
+        guarded
+            .connections
+            .iter()
+            .map(|(peer_id, connection)| PeerInfo {
+                peer_id: peer_id.clone(),
+                address: connection.address.clone(),
+                connection_age: now - connection.connected_since,
+                bytes_received: connection
+                    .bandwidth
+                    .bytes_received
+                    .load(atomic::Ordering::Relaxed),
+                bytes_sent: connection.bandwidth.bytes_sent.load(atomic::Ordering::Relaxed),
+                chains: connection.chains.values().cloned().collect(),
+                blocks_requests_successes: connection.blocks_requests_successes,
+                blocks_requests_failures: connection.blocks_requests_failures,
+                blocks_requests_average_latency: if connection.blocks_requests_successes != 0 {
+                    Some(
+                        connection.blocks_requests_total_latency
+                            / connection.blocks_requests_successes,
+                    )
+                } else {
+                    None
+                },
+            })
+            .collect()
+    }
+
+    /// Returns the private key of the local node, as passed through [`Config::node_key`].
+    pub fn node_key(&self) -> &[u8; 32] {
+        &self.node_key
+    }
+
+    /// Returns the list of peers of the given chain that have been successfully connected to,
+    /// ordered from the most reliable to the least reliable.
+    ///
+    /// This list is meant to be saved and passed back through [`ConfigChain::address_book`] the
+    /// next time the client starts.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `chain_index` is out of range.
+    ///
+    pub async fn address_book(&self, chain_index: usize) -> Vec<AddressBookEntry> {
+        self.guarded.lock().await.address_books[chain_index].entries()
+    }
 }
 
 /// Event that can happen on the network service.
@@ -385,39 +903,434 @@ pub enum Event {
     BlockAnnounce {
         peer_id: PeerId,
         chain_index: usize,
-        announce: service::EncodedBlockAnnounce,
+        /// SCALE-encoded header of the announced block. Guaranteed to be decodable.
+        scale_encoded_header: Vec<u8>,
+        /// True if the block is the new best block of the peer.
+        is_best: bool,
+    },
+    /// A peer has gossiped a GrandPa commit. The commit hasn't been verified.
+    GrandpaCommit {
+        peer_id: PeerId,
+        chain_index: usize,
+        /// Number of the block finalized by the commit.
+        target_number: u64,
+        /// SCALE-encoded GrandPa justification equivalent to the commit. Guaranteed to be
+        /// decodable.
+        scale_encoded_justification: Vec<u8>,
     },
 }
 
+/// Error returned by [`NetworkService::blocks_request`].
+#[derive(Debug)]
+pub enum BlocksRequestError {
+    /// No response has been received in time.
+    Timeout,
+    /// The peer isn't connected, doesn't support the protocol, or has refused to answer.
+    Refused(libp2p::RequestError),
+    /// The peer has violated the networking protocol, or has sent a response that couldn't be
+    /// decoded.
+    Protocol(service::BlocksRequestError),
+    /// The response doesn't contain any block. Peers answer this way when they don't know the
+    /// requested blocks, for example because they have been pruned, or because the requested
+    /// blocks are above their best block.
+    Empty,
+    /// Some blocks of the response are missing a requested field.
+    Incomplete,
+}
+
+impl BlocksRequestError {
+    /// Returns `true` if the failure is likely caused by temporary circumstances, such as a
+    /// slow or overloaded peer, rather than by a misbehaving peer.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            BlocksRequestError::Timeout
+                | BlocksRequestError::Refused(_)
+                | BlocksRequestError::Empty
+        )
+    }
+}
+
+impl fmt::Display for BlocksRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlocksRequestError::Timeout => write!(f, "Timeout"),
+            BlocksRequestError::Refused(err) => write!(f, "Request refused: {}", err),
+            BlocksRequestError::Protocol(err) => write!(f, "Protocol error: {}", err),
+            BlocksRequestError::Empty => write!(f, "Empty response"),
+            BlocksRequestError::Incomplete => write!(f, "Incomplete response"),
+        }
+    }
+}
+
+/// Information about a peer, as returned by [`NetworkService::peers_info`].
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// Identity of the peer.
+    pub peer_id: PeerId,
+    /// Address the connection has been established with.
+    pub address: Multiaddr,
+    /// Time elapsed since the connection has been established.
+    pub connection_age: Duration,
+    /// Number of bytes received on the connection, including the encryption and multiplexing
+    /// overhead.
+    pub bytes_received: u64,
+    /// Number of bytes sent on the connection, including the encryption and multiplexing
+    /// overhead.
+    pub bytes_sent: u64,
+    /// List of chains the peer is connected to.
+    pub chains: Vec<PeerChainInfo>,
+    /// Number of blocks requests towards this peer that have succeeded.
+    pub blocks_requests_successes: u32,
+    /// Number of blocks requests towards this peer that have failed.
+    pub blocks_requests_failures: u32,
+    /// Average duration of the successful blocks requests. `None` if no request has succeeded.
+    pub blocks_requests_average_latency: Option<Duration>,
+}
+
+/// See [`PeerInfo::chains`].
+#[derive(Debug, Clone)]
+pub struct PeerChainInfo {
+    /// Index of the chain within [`Config::chains`].
+    pub chain_index: usize,
+    /// Role the peer reports playing on the network.
+    pub role: protocol::Role,
+    /// Height of the best block according to the peer.
+    pub best_block_number: u64,
+    /// Hash of the best block according to the peer.
+    pub best_block_hash: [u8; 32],
+}
+
+/// Starts, defers or rejects a dialing attempt returned by
+/// [`service::ChainNetwork::fill_out_slots`] for the given chain.
+async fn dispatch_dial(
+    network_service: &Arc<NetworkService>,
+    chain_index: usize,
+    start_connect: service::StartConnect,
+) {
+    let mut guarded = network_service.guarded.lock().await;
+
+    // Reserved peers are dialed by the task dedicated to them. This task only stops after the
+    // peer has been removed from `reserved`, and sending thus always succeeds.
+    if let Some(reserved) = guarded.reserved.get(&start_connect.expected_peer_id) {
+        reserved
+            .unbounded_send(ReservedPeerMessage::Dial(chain_index, start_connect))
+            .unwrap();
+        return;
+    }
+
+    // Banned peers are never dialed. In reserved-only mode, dialing non-reserved peers is
+    // delayed until the mode is disabled, and only one attempt is kept per peer.
+    let rejected = if guarded.banned.contains(&start_connect.expected_peer_id) {
+        true
+    } else if guarded.reserved_only {
+        if guarded
+            .deferred_dials
+            .contains_key(&start_connect.expected_peer_id)
+        {
+            true
+        } else {
+            guarded.deferred_dials.insert(
+                start_connect.expected_peer_id.clone(),
+                (chain_index, start_connect),
+            );
+            return;
+        }
+    } else {
+        false
+    };
+
+    if rejected {
+        // Note that reporting the failure removes the address from the list of known addresses.
+        drop(guarded);
+        network_service
+            .network
+            .pending_outcome_err(start_connect.id)
+            .await;
+        return;
+    }
+
+    start_dial(network_service, &mut guarded, chain_index, start_connect);
+}
+
+/// Starts dialing the given address, and spawns a [`connection_task`] dedicated to the connection.
+fn start_dial(
+    network_service: &Arc<NetworkService>,
+    guarded: &mut Guarded,
+    chain_index: usize,
+    start_connect: service::StartConnect,
+) {
+    let is_important_peer = network_service
+        .important_nodes
+        .contains(&start_connect.expected_peer_id);
+
+    // Convert the `multiaddr` (typically of the form `/ip4/a.b.c.d/tcp/d/ws`)
+    // into a `Future<dyn Output = Result<Box<dyn Transport>, ...>>`.
+    let socket = {
+        log::debug!(target: "connections", "Pending({:?}) started: {}", start_connect.id, start_connect.multiaddr);
+        transport::connect(
+            &start_connect.multiaddr,
+            network_service.connection_buffer_limit,
+        )
+    };
+
+    // TODO: handle dialing timeout here
+
+    (guarded.tasks_executor)(Box::pin({
+        connection_task(
+            socket,
+            network_service.clone(),
+            chain_index,
+            start_connect.id,
+            start_connect.expected_peer_id,
+            start_connect.multiaddr,
+            is_important_peer,
+        )
+    }));
+}
+
 /// Asynchronous task managing a specific connection.
 ///
 /// `is_important_peer` controls the log level used for problems that happen on this connection.
 async fn connection_task(
-    websocket: impl Future<Output = Result<Pin<Box<ffi::Connection>>, ()>>,
+    socket: future::BoxFuture<'static, Result<Box<dyn Transport>, ()>>,
     network_service: Arc<NetworkService>,
+    chain_index: usize,
     pending_id: service::PendingId,
     expected_peer_id: PeerId,
+    multiaddr: Multiaddr,
     is_important_peer: bool,
 ) {
     // Finishing the ongoing connection process.
-    let mut websocket = match websocket.await {
+    let socket = match socket.await {
         Ok(s) => s,
         Err(()) => {
+            let mut guarded = network_service.guarded.lock().await;
+
+            // The peer might have been marked as reserved in the meanwhile, in which case the
+            // task dedicated to it retries the dialing attempt.
+            if let Some(reserved) = guarded.reserved.get(&expected_peer_id) {
+                let start_connect = service::StartConnect {
+                    id: pending_id,
+                    multiaddr,
+                    expected_peer_id,
+                };
+                reserved
+                    .unbounded_send(ReservedPeerMessage::Dial(chain_index, start_connect))
+                    .unwrap();
+                return;
+            }
+
             log::debug!(
                 target: "connections",
                 "Pending({:?}, {}) => Failed to reach",
                 pending_id, expected_peer_id,
             );
 
+            guarded.address_books[chain_index].on_reach_failed(&expected_peer_id);
+            drop(guarded);
             network_service
                 .network
                 .pending_outcome_err(pending_id)
                 .await;
-
             return;
         }
     };
 
+    established_connection(
+        socket,
+        &network_service,
+        pending_id,
+        &expected_peer_id,
+        multiaddr,
+        is_important_peer,
+    )
+    .await;
+
+    // The peer might have been marked as reserved in the meanwhile, in which case the task
+    // dedicated to it dials it again.
+    if let Some(reserved) = network_service
+        .guarded
+        .lock()
+        .await
+        .reserved
+        .get(&expected_peer_id)
+    {
+        reserved
+            .unbounded_send(ReservedPeerMessage::ConnectionClosed)
+            .unwrap();
+    }
+}
+
+/// Asynchronous task dedicated to keeping a connection with a reserved peer. Stops once the
+/// peer is no longer reserved.
+///
+/// A dialing attempt that fails is retried at the same address, instead of being reported as a
+/// failure, as reporting a failure removes the address from the list of known addresses. When
+/// the connection with the peer is closed, a new dialing attempt is requested with
+/// [`fill_out_slots_until`].
+async fn reserved_peer_task(
+    network_service: Arc<NetworkService>,
+    peer_id: PeerId,
+    mut messages: mpsc::UnboundedReceiver<ReservedPeerMessage>,
+) {
+    let mut retry_delay = Duration::from_secs(1);
+
+    // If the peer is already connected, it is dialed once the connection closes.
+    let mut must_dial = !network_service
+        .guarded
+        .lock()
+        .await
+        .connections
+        .contains_key(&peer_id);
+
+    loop {
+        let message = if must_dial {
+            fill_out_slots_until(&network_service, &peer_id).await;
+
+            // If no attempt has been obtained, for example because the peer is already being
+            // dialed, trying again later.
+            let timeout = ffi::Delay::new(retry_delay);
+            match future::select(messages.next(), timeout).await {
+                future::Either::Left((message, _)) => message,
+                future::Either::Right(((), _)) => {
+                    retry_delay = cmp::min(retry_delay * 2, RESERVED_PEER_MAX_RETRY_DELAY);
+                    continue;
+                }
+            }
+        } else {
+            messages.next().await
+        };
+
+        let (chain_index, start_connect) = match message {
+            Some(ReservedPeerMessage::Dial(chain_index, start_connect)) => {
+                (chain_index, start_connect)
+            }
+            Some(ReservedPeerMessage::ConnectionClosed) => {
+                must_dial = true;
+                continue;
+            }
+            // The peer is no longer reserved.
+            None => break,
+        };
+
+        log::debug!(target: "connections", "Pending({:?}) started: {}", start_connect.id, start_connect.multiaddr);
+        let socket = loop {
+            let connect = transport::connect(
+                &start_connect.multiaddr,
+                network_service.connection_buffer_limit,
+            );
+            if let Ok(socket) = connect.await {
+                break Some(socket);
+            }
+
+            if !is_reserved(&network_service, &peer_id, &messages).await {
+                break None;
+            }
+
+            log::debug!(
+                target: "connections",
+                "Pending({:?}, {}) => Failed to reach reserved peer; retrying in {:?}",
+                start_connect.id, peer_id, retry_delay,
+            );
+
+            ffi::Delay::new(retry_delay).await;
+            retry_delay = cmp::min(retry_delay * 2, RESERVED_PEER_MAX_RETRY_DELAY);
+        };
+
+        let socket = match socket {
+            Some(socket) => socket,
+            None => {
+                log::debug!(
+                    target: "connections",
+                    "Pending({:?}, {}) => Failed to reach",
+                    start_connect.id, peer_id,
+                );
+
+                network_service.guarded.lock().await.address_books[chain_index]
+                    .on_reach_failed(&peer_id);
+                network_service
+                    .network
+                    .pending_outcome_err(start_connect.id)
+                    .await;
+                break;
+            }
+        };
+
+        retry_delay = Duration::from_secs(1);
+        established_connection(
+            socket,
+            &network_service,
+            start_connect.id,
+            &peer_id,
+            start_connect.multiaddr,
+            true,
+        )
+        .await;
+
+        if !is_reserved(&network_service, &peer_id, &messages).await {
+            break;
+        }
+        must_dial = true;
+    }
+
+    // Dialing attempts that have been sent in the meanwhile are handled like for any other peer.
+    messages.close();
+    while let Some(message) = messages.next().await {
+        if let ReservedPeerMessage::Dial(chain_index, start_connect) = message {
+            dispatch_dial(&network_service, chain_index, start_connect).await;
+        }
+    }
+}
+
+/// Returns true if the given peer is reserved, and if `messages` is the receiving side of the
+/// channel of the [`reserved_peer_task`] dedicated to it. The peer might have been unreserved
+/// then reserved again, in which case a different task is dedicated to it.
+async fn is_reserved(
+    network_service: &NetworkService,
+    peer_id: &PeerId,
+    messages: &mpsc::UnboundedReceiver<ReservedPeerMessage>,
+) -> bool {
+    network_service
+        .guarded
+        .lock()
+        .await
+        .reserved
+        .get(peer_id)
+        .is_some_and(|reserved| reserved.is_connected_to(messages))
+}
+
+/// Calls [`service::ChainNetwork::fill_out_slots`] and passes the returned dialing attempts to
+/// [`dispatch_dial`], until an attempt towards the given peer is returned or until no attempt is
+/// returned.
+///
+/// The network doesn't provide any way to dial a specific peer without knowing its address. Since
+/// peers that are already being dialed aren't returned again, this however always ends up
+/// returning the given peer, provided that its address is known and that it isn't already being
+/// dialed.
+async fn fill_out_slots_until(network_service: &Arc<NetworkService>, peer_id: &PeerId) {
+    for chain_index in 0..network_service.network.num_chains() {
+        while let Some(start_connect) = network_service.network.fill_out_slots(chain_index).await {
+            let found = start_connect.expected_peer_id == *peer_id;
+            dispatch_dial(network_service, chain_index, start_connect).await;
+            if found {
+                return;
+            }
+        }
+    }
+}
+
+/// Drives an established connection until it is closed.
+///
+/// `is_important_peer` controls the log level used for problems that happen on this connection.
+async fn established_connection(
+    mut socket: Box<dyn Transport>,
+    network_service: &Arc<NetworkService>,
+    pending_id: service::PendingId,
+    expected_peer_id: &PeerId,
+    multiaddr: Multiaddr,
+    is_important_peer: bool,
+) {
     let id = network_service
         .network
         .pending_outcome_ok(pending_id, ())
@@ -431,16 +1344,56 @@ async fn connection_task(
         id
     );
 
+    let bandwidth = Arc::new(Bandwidth::default());
+    let (disconnect_tx, disconnect_rx) = oneshot::channel();
+    network_service.guarded.lock().await.connections.insert(
+        expected_peer_id.clone(),
+        ConnectionState {
+            address: multiaddr,
+            connected_since: ffi::Instant::now(),
+            bandwidth: bandwidth.clone(),
+            chains: HashMap::default(),
+            blocks_requests_successes: 0,
+            blocks_requests_failures: 0,
+            blocks_requests_total_latency: Duration::new(0, 0),
+            disconnect: Some(disconnect_tx),
+        },
+    );
+
+    // If the sender is destroyed without sending anything, the fused receiver stays pending.
+    let mut disconnect_rx = disconnect_rx.fuse();
+    // If true, the connection must be shut down. This is done by reporting to the connection
+    // state machine that the socket has been closed.
+    let mut disconnect_requested = false;
+
     let mut write_buffer = vec![0; 4096];
 
     loop {
-        let read_buffer = websocket.read_buffer().now_or_never().unwrap_or(Some(&[]));
+        // If too much data is waiting to be sent, the connection state machine is given an empty
+        // write buffer, so that it doesn't produce more data until the transport is ready.
+        let mut send_ready = socket.send_ready();
+        let write_buffer_len = if (&mut send_ready).now_or_never().is_some() {
+            write_buffer.len()
+        } else {
+            0
+        };
+
+        let read_buffer = if !disconnect_requested {
+            socket.read_buffer().now_or_never().unwrap_or(Some(&[]))
+        } else {
+            None
+        };
 
         let now = ffi::Instant::now();
 
         let read_write = match network_service
             .network
-            .read_write(id, now, read_buffer, (&mut write_buffer, &mut []))
+            .read_write(
+                id,
+                now,
+                read_buffer,
+                (&mut write_buffer[..write_buffer_len], &mut []),
+            )
             .await
         {
             Ok(rw) => rw,
@@ -451,7 +1404,7 @@ async fn connection_task(
                     log::debug!(target: "connections", "Connection({:?}, {}) => Closed: {}", id, expected_peer_id, _err);
                 }
 
-                return;
+                break;
             }
         };
 
@@ -460,14 +1413,23 @@ async fn connection_task(
 
         if read_write.write_close && read_buffer_closed {
             log::debug!(target: "connections", "Connection({:?}, {}) => Closed gracefully", id, expected_peer_id);
-            return;
+            break;
         }
 
+        bandwidth.bytes_received.fetch_add(
+            u64::try_from(read_write.read_bytes).unwrap(),
+            atomic::Ordering::Relaxed,
+        );
+        bandwidth.bytes_sent.fetch_add(
+            u64::try_from(read_write.written_bytes).unwrap(),
+            atomic::Ordering::Relaxed,
+        );
+
         if read_write.written_bytes != 0 {
-            websocket.send(&write_buffer[..read_write.written_bytes]);
+            socket.send(&write_buffer[..read_write.written_bytes]);
         }
 
-        websocket.advance_read_cursor(read_write.read_bytes);
+        socket.advance_read_cursor(read_write.read_bytes);
 
         // Starting from here, we block (or not) the current task until more processing needs
         // to happen.
@@ -488,18 +1450,49 @@ async fn connection_task(
         // Future that is woken up when new data is ready on the socket.
         let read_buffer_ready =
             if !(read_buffer_has_data && read_write.read_bytes == 0) && !read_buffer_closed {
-                future::Either::Left(websocket.read_buffer())
+                future::Either::Left(socket.read_buffer())
             } else {
                 future::Either::Right(future::pending())
             };
 
-        // Wait until either some data is ready on the socket, or the connection state machine
-        // has been requested to be polled again.
+        // Future that is woken up when more data can be sent, if the write buffer has been
+        // withheld from the connection state machine.
+        let send_ready = if write_buffer_len == 0 {
+            future::Either::Left(send_ready)
+        } else {
+            future::Either::Right(future::pending())
+        };
+
+        // Wait until either some data is ready on the socket, or more data can be sent, or the
+        // connection state machine has been requested to be polled again, or a disconnection
+        // has been requested.
         futures::pin_mut!(read_buffer_ready);
-        future::select(
-            future::select(read_buffer_ready, read_write.wake_up_future),
-            poll_after,
-        )
-        .await;
+        disconnect_requested |= matches!(
+            future::select(
+                future::select(
+                    future::select(
+                        future::select(read_buffer_ready, send_ready),
+                        read_write.wake_up_future,
+                    ),
+                    poll_after,
+                ),
+                &mut disconnect_rx,
+            )
+            .await,
+            future::Either::Right((Ok(()), _))
+        );
+    }
+
+    socket.close();
+
+    // Another connection to the same peer might have been opened in the meanwhile, in which
+    // case its state must be left untouched.
+    let mut guarded = network_service.guarded.lock().await;
+    if guarded
+        .connections
+        .get(expected_peer_id)
+        .is_some_and(|c| Arc::ptr_eq(&c.bandwidth, &bandwidth))
+    {
+        guarded.connections.remove(expected_peer_id);
     }
 }
@@ -0,0 +1,154 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! List of peers that we have successfully connected to in the past.
+//!
+//! The address book is meant to be saved alongside the rest of the database and passed back to
+//! the network service when the client restarts, so that peers that behaved well during a
+//! previous session are dialed in addition to the bootnodes of the chain specification.
+
+use core::time::Duration;
+use smoldot::libp2p::{multiaddr::Multiaddr, peer_id::PeerId};
+use std::collections::HashMap;
+
+/// Maximum number of entries in an [`AddressBook`]. Entries with the lowest reliability are
+/// evicted first.
+const MAX_ENTRIES: usize = 64;
+
+/// Maximum value of [`AddressBookEntry::reliability`].
+const MAX_RELIABILITY: i32 = 20;
+
+/// Peer found in an [`AddressBook`].
+#[derive(Debug, Clone)]
+pub struct AddressBookEntry {
+    /// Identity of the peer.
+    pub peer_id: PeerId,
+
+    /// Address that was used in order to successfully reach the peer.
+    pub address: Multiaddr,
+
+    /// Duration since the UNIX epoch when the peer was last successfully connected to.
+    pub last_seen: Duration,
+
+    /// Score of the peer. Increased every time we successfully connect to this peer, and
+    /// decreased every time reaching it fails. Entries whose score becomes negative are removed.
+    pub reliability: i32,
+}
+
+/// Collection of [`AddressBookEntry`]s.
+pub(super) struct AddressBook {
+    entries: HashMap<PeerId, AddressBookEntry, fnv::FnvBuildHasher>,
+}
+
+impl AddressBook {
+    /// Builds a new [`AddressBook`] from entries that have for example been loaded from the
+    /// database.
+    pub(super) fn new(entries: impl IntoIterator<Item = AddressBookEntry>) -> Self {
+        let mut address_book = AddressBook {
+            entries: HashMap::default(),
+        };
+
+        for entry in entries {
+            address_book.entries.insert(entry.peer_id.clone(), entry);
+        }
+
+        address_book.shrink();
+        address_book
+    }
+
+    /// Reports that a connection with the given peer has been successfully established, and that
+    /// it has opened the chain-specific substreams.
+    pub(super) fn on_connected(&mut self, peer_id: PeerId, address: Multiaddr, now: Duration) {
+        let entry = self
+            .entries
+            .entry(peer_id.clone())
+            .or_insert_with(|| AddressBookEntry {
+                peer_id,
+                address: address.clone(),
+                last_seen: now,
+                reliability: 0,
+            });
+
+        entry.address = address;
+        entry.last_seen = now;
+        entry.reliability = (entry.reliability + 1).min(MAX_RELIABILITY);
+
+        self.shrink();
+    }
+
+    /// Inserts or updates an entry in the address book with the highest possible reliability.
+    ///
+    /// Used for peers that are known to be good, for example because they have been manually
+    /// added by the user.
+    pub(super) fn insert_trusted(&mut self, peer_id: PeerId, address: Multiaddr, now: Duration) {
+        self.entries.insert(
+            peer_id.clone(),
+            AddressBookEntry {
+                peer_id,
+                address,
+                last_seen: now,
+                reliability: MAX_RELIABILITY,
+            },
+        );
+
+        self.shrink();
+    }
+
+    /// Removes the given peer from the address book, if present.
+    pub(super) fn remove(&mut self, peer_id: &PeerId) {
+        self.entries.remove(peer_id);
+    }
+
+    /// Reports that reaching the given peer has failed.
+    ///
+    /// Has no effect if the peer isn't in the address book.
+    pub(super) fn on_reach_failed(&mut self, peer_id: &PeerId) {
+        let remove = if let Some(entry) = self.entries.get_mut(peer_id) {
+            entry.reliability -= 1;
+            entry.reliability < 0
+        } else {
+            false
+        };
+
+        if remove {
+            self.entries.remove(peer_id);
+        }
+    }
+
+    /// Returns the list of entries, ordered from the most reliable to the least reliable.
+    pub(super) fn entries(&self) -> Vec<AddressBookEntry> {
+        let mut list = self.entries.values().cloned().collect::<Vec<_>>();
+        list.sort_by(|a, b| {
+            b.reliability
+                .cmp(&a.reliability)
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        list
+    }
+
+    /// Removes the least reliable entries until the size of the address book is at most
+    /// [`MAX_ENTRIES`].
+    fn shrink(&mut self) {
+        if self.entries.len() <= MAX_ENTRIES {
+            return;
+        }
+
+        for entry in self.entries().into_iter().skip(MAX_ENTRIES) {
+            self.entries.remove(&entry.peer_id);
+        }
+    }
+}
@@ -0,0 +1,491 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Byte streams that connections to peers are established over.
+//!
+//! The implementation of [`Transport`] is chosen by [`connect`] depending on the protocols of
+//! the multiaddress:
+//!
+//! - `/memory/<port>` connects to a `MemoryListener` of the same thread. Listeners only exist in
+//!   tests.
+//! - `/<ip4|ip6|dns|dns4|dns6>/<host>/tcp/<port>`, when not compiling for WebAssembly, opens a
+//!   native TCP socket.
+//! - All other addresses are passed to the host through [`ffi::Connection`].
+
+use crate::ffi;
+
+use core::{cell::RefCell, pin::Pin};
+use futures::{channel::mpsc, future::BoxFuture, prelude::*};
+use smoldot::libp2p::multiaddr::{Multiaddr, Protocol};
+use std::collections::HashMap;
+
+#[cfg(test)]
+mod tests;
+
+/// Bidirectional stream of bytes.
+pub trait Transport: Send {
+    /// Returns a buffer containing data received on the connection.
+    ///
+    /// Never returns an empty buffer. If no data is available, the returned future waits until
+    /// more data arrives.
+    ///
+    /// Returns `None` if the connection has been closed.
+    fn read_buffer(&mut self) -> BoxFuture<'_, Option<&[u8]>>;
+
+    /// Advances the read cursor by the given amount of bytes. The first `bytes` will no longer
+    /// be returned by [`Transport::read_buffer`] the next time it is called.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `bytes` is larger than the size of the buffer returned by
+    /// [`Transport::read_buffer`].
+    ///
+    fn advance_read_cursor(&mut self, bytes: usize);
+
+    /// Queues the given buffer to be sent to the remote.
+    ///
+    /// The data is queued even if [`Transport::send_ready`] isn't ready, but callers should wait
+    /// for it before sending more in order to not accumulate data in memory.
+    fn send(&mut self, data: &[u8]);
+
+    /// Returns a future that is ready once the amount of data queued with [`Transport::send`]
+    /// and not sent yet is below the limit of the transport.
+    fn send_ready(&self) -> BoxFuture<'static, ()>;
+
+    /// Closes the connection. [`Transport::read_buffer`] returns `None` afterwards, and data
+    /// passed to [`Transport::send`] is discarded.
+    fn close(&mut self);
+}
+
+/// Starts connecting to the given multiaddress, using the appropriate [`Transport`].
+///
+/// `max_buffered_bytes` is the number of bytes that can be received and not read yet before
+/// reading from the socket is paused. See [`ffi::Connection::connect`].
+pub fn connect(
+    multiaddr: &Multiaddr,
+    max_buffered_bytes: usize,
+) -> BoxFuture<'static, Result<Box<dyn Transport>, ()>> {
+    let mut iter = multiaddr.iter();
+    match (iter.next(), iter.next(), iter.next(), iter.next()) {
+        (Some(Protocol::Memory(port)), None, _, _) => {
+            future::ready(connect_memory(port).map(|c| Box::new(c) as Box<_>)).boxed()
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        (Some(host), Some(Protocol::Tcp(port)), None, _) => {
+            let host = match host {
+                Protocol::Ip4(ip) => ip.to_string(),
+                Protocol::Ip6(ip) => ip.to_string(),
+                Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host) => {
+                    host.into_owned()
+                }
+                _ => return future::ready(Err(())).boxed(),
+            };
+
+            tcp::connect(host, port, max_buffered_bytes)
+                .map_ok(|c| Box::new(c) as Box<_>)
+                .boxed()
+        }
+        _ => ffi::Connection::connect(&multiaddr.to_string(), max_buffered_bytes)
+            .map_ok(|c| Box::new(c) as Box<_>)
+            .boxed(),
+    }
+}
+
+impl Transport for Pin<Box<ffi::Connection>> {
+    fn read_buffer(&mut self) -> BoxFuture<'_, Option<&[u8]>> {
+        ffi::Connection::read_buffer(self).boxed()
+    }
+
+    fn advance_read_cursor(&mut self, bytes: usize) {
+        ffi::Connection::advance_read_cursor(self, bytes)
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        ffi::Connection::send(self, data)
+    }
+
+    fn send_ready(&self) -> BoxFuture<'static, ()> {
+        // The data is queued by the host.
+        future::ready(()).boxed()
+    }
+
+    fn close(&mut self) {
+        ffi::Connection::close(self.as_mut())
+    }
+}
+
+thread_local! {
+    /// List of all the [`MemoryListener`]s, indexed by port.
+    static MEMORY_LISTENERS: RefCell<HashMap<u64, mpsc::UnboundedSender<MemoryConnection>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Accepts connections to a `/memory/<port>` multiaddress.
+///
+/// Connections can only be established from the same thread as the one the listener has been
+/// created on.
+#[cfg(test)]
+pub struct MemoryListener {
+    port: u64,
+    incoming: mpsc::UnboundedReceiver<MemoryConnection>,
+}
+
+#[cfg(test)]
+impl MemoryListener {
+    /// Starts listening on `/memory/<port>`. Returns `None` if this port is already in use.
+    pub fn new(port: u64) -> Option<Self> {
+        let (tx, incoming) = mpsc::unbounded();
+        let inserted = MEMORY_LISTENERS.with(|listeners| {
+            let mut listeners = listeners.borrow_mut();
+            if listeners.contains_key(&port) {
+                return false;
+            }
+            listeners.insert(port, tx);
+            true
+        });
+
+        if inserted {
+            Some(MemoryListener { port, incoming })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the multiaddress that connects to this listener.
+    pub fn multiaddr(&self) -> Multiaddr {
+        Multiaddr::empty().with(Protocol::Memory(self.port))
+    }
+
+    /// Waits for the next incoming connection.
+    pub async fn accept(&mut self) -> MemoryConnection {
+        // The sender is only removed when the listener is destroyed.
+        self.incoming.next().await.unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        MEMORY_LISTENERS.with(|listeners| listeners.borrow_mut().remove(&self.port));
+    }
+}
+
+fn connect_memory(port: u64) -> Result<MemoryConnection, ()> {
+    let (local, remote) = MemoryConnection::duplex();
+    MEMORY_LISTENERS.with(|listeners| {
+        listeners
+            .borrow()
+            .get(&port)
+            .ok_or(())?
+            .unbounded_send(remote)
+            .map_err(|_| ())
+    })?;
+    Ok(local)
+}
+
+/// End of an in-memory connection. See [`MemoryConnection::duplex`].
+pub struct MemoryConnection {
+    /// Data sent by the other end.
+    incoming: Chunks<mpsc::UnboundedReceiver<Vec<u8>>>,
+    /// Channel to the other end. `None` if the connection has been closed.
+    outgoing: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl MemoryConnection {
+    /// Builds two connections connected to each other.
+    pub fn duplex() -> (Self, Self) {
+        let (tx1, rx1) = mpsc::unbounded();
+        let (tx2, rx2) = mpsc::unbounded();
+        let end = |incoming, outgoing| MemoryConnection {
+            incoming: Chunks::new(incoming),
+            outgoing: Some(outgoing),
+        };
+        (end(rx1, tx2), end(rx2, tx1))
+    }
+}
+
+impl Transport for MemoryConnection {
+    fn read_buffer(&mut self) -> BoxFuture<'_, Option<&[u8]>> {
+        self.incoming.read_buffer().boxed()
+    }
+
+    fn advance_read_cursor(&mut self, bytes: usize) {
+        self.incoming.advance_read_cursor(bytes)
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        if let Some(outgoing) = self.outgoing.as_mut() {
+            let _ = outgoing.unbounded_send(data.to_vec());
+        }
+    }
+
+    fn send_ready(&self) -> BoxFuture<'static, ()> {
+        future::ready(()).boxed()
+    }
+
+    fn close(&mut self) {
+        self.incoming.close();
+        self.outgoing = None;
+    }
+}
+
+/// Stream of received buffers, read through the same API as [`Transport`].
+struct Chunks<S> {
+    /// `None` if the connection has been closed.
+    stream: Option<S>,
+    /// Buffer being read, and number of bytes of this buffer that have already been read.
+    current: Vec<u8>,
+    current_offset: usize,
+}
+
+impl<S: Stream<Item = Vec<u8>> + Unpin + Send> Chunks<S> {
+    fn new(stream: S) -> Self {
+        Chunks {
+            stream: Some(stream),
+            current: Vec::new(),
+            current_offset: 0,
+        }
+    }
+
+    /// See [`Transport::read_buffer`].
+    async fn read_buffer(&mut self) -> Option<&[u8]> {
+        while self.current_offset == self.current.len() {
+            let next = match self.stream.as_mut() {
+                Some(stream) => stream.next().await,
+                None => None,
+            };
+
+            match next {
+                Some(buffer) => {
+                    self.current = buffer;
+                    self.current_offset = 0;
+                }
+                None => {
+                    self.close();
+                    return None;
+                }
+            }
+        }
+
+        Some(&self.current[self.current_offset..])
+    }
+
+    /// See [`Transport::advance_read_cursor`].
+    fn advance_read_cursor(&mut self, bytes: usize) {
+        assert!(self.current_offset + bytes <= self.current.len());
+        self.current_offset += bytes;
+    }
+
+    /// Discards the received data and stops receiving more.
+    fn close(&mut self) {
+        self.stream = None;
+        self.current = Vec::new();
+        self.current_offset = 0;
+    }
+}
+
+/// Native TCP sockets.
+///
+/// No asynchronous I/O reactor is available, so each connection uses two background threads
+/// that respectively read from and write to the socket.
+#[cfg(not(target_arch = "wasm32"))]
+mod tcp {
+    use super::*;
+
+    use core::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Poll,
+        time::Duration,
+    };
+    use futures::{channel::oneshot, task::AtomicWaker};
+    use std::{
+        io::{Read as _, Write as _},
+        net::{Shutdown, TcpStream, ToSocketAddrs as _},
+        sync::{mpsc as sync_mpsc, Arc},
+        thread,
+        time::Instant,
+    };
+
+    /// Size of the buffers the background thread reads into.
+    const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+    /// Maximum duration of a connection attempt, for all the addresses the host resolves to.
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+    /// Number of bytes queued for sending above which [`Transport::send_ready`] waits.
+    const MAX_QUEUED_WRITE_BYTES: usize = 256 * 1024;
+
+    pub(super) struct TcpConnection {
+        /// `None` if the connection has been closed.
+        socket: Option<TcpStream>,
+        /// Data read by the background thread.
+        incoming: Chunks<mpsc::Receiver<Vec<u8>>>,
+        /// Data to write, sent to the background thread. `None` if the connection has been
+        /// closed.
+        outgoing: Option<sync_mpsc::Sender<Vec<u8>>>,
+        /// Shared with the background thread that writes to the socket.
+        write_queue: Arc<WriteQueue>,
+    }
+
+    /// State of the data queued for sending.
+    struct WriteQueue {
+        /// Number of bytes passed to [`Transport::send`] and not written to the socket yet.
+        /// Reset to 0 if writing to the socket fails.
+        queued_bytes: AtomicUsize,
+        /// Woken up whenever `queued_bytes` decreases.
+        waker: AtomicWaker,
+    }
+
+    pub(super) async fn connect(
+        host: String,
+        port: u16,
+        max_buffered_bytes: usize,
+    ) -> Result<TcpConnection, ()> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(connect_blocking(&host, port));
+        });
+
+        let socket = rx.await.map_err(|_| ())??;
+        let _ = socket.set_nodelay(true);
+        let mut reader = socket.try_clone().map_err(|_| ())?;
+        let mut writer = socket.try_clone().map_err(|_| ())?;
+
+        // The channel being bounded, the background thread stops reading from the socket when
+        // the data isn't processed quickly enough.
+        let (mut incoming_tx, incoming) = mpsc::channel(max_buffered_bytes / READ_CHUNK_SIZE);
+        thread::spawn(move || loop {
+            let mut buffer = vec![0; READ_CHUNK_SIZE];
+            let read = match reader.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            buffer.truncate(read);
+            if futures::executor::block_on(incoming_tx.send(buffer)).is_err() {
+                break;
+            }
+        });
+
+        let write_queue = Arc::new(WriteQueue {
+            queued_bytes: AtomicUsize::new(0),
+            waker: AtomicWaker::new(),
+        });
+        let (outgoing, outgoing_rx) = sync_mpsc::channel::<Vec<u8>>();
+        thread::spawn({
+            let write_queue = write_queue.clone();
+            move || {
+                // Stops when the connection is closed, as the sender is then destroyed.
+                for data in outgoing_rx {
+                    if writer.write_all(&data).is_err() {
+                        // Also interrupts the thread that reads from the socket, so that the
+                        // connection is reported as closed.
+                        let _ = writer.shutdown(Shutdown::Both);
+                        write_queue.queued_bytes.store(0, Ordering::Release);
+                        write_queue.waker.wake();
+                        break;
+                    }
+                    write_queue
+                        .queued_bytes
+                        .fetch_sub(data.len(), Ordering::AcqRel);
+                    write_queue.waker.wake();
+                }
+            }
+        });
+
+        Ok(TcpConnection {
+            socket: Some(socket),
+            incoming: Chunks::new(incoming),
+            outgoing: Some(outgoing),
+            write_queue,
+        })
+    }
+
+    /// Connects to the first address that `host` resolves to and that accepts the connection
+    /// within [`CONNECT_TIMEOUT`].
+    fn connect_blocking(host: &str, port: u16) -> Result<TcpStream, ()> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        for addr in (host, port).to_socket_addrs().map_err(|_| ())? {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout == Duration::new(0, 0) {
+                break;
+            }
+            if let Ok(socket) = TcpStream::connect_timeout(&addr, timeout) {
+                return Ok(socket);
+            }
+        }
+        Err(())
+    }
+
+    impl Transport for TcpConnection {
+        fn read_buffer(&mut self) -> BoxFuture<'_, Option<&[u8]>> {
+            self.incoming.read_buffer().boxed()
+        }
+
+        fn advance_read_cursor(&mut self, bytes: usize) {
+            self.incoming.advance_read_cursor(bytes)
+        }
+
+        fn send(&mut self, data: &[u8]) {
+            if data.is_empty() {
+                return;
+            }
+
+            if let Some(outgoing) = self.outgoing.as_mut() {
+                self.write_queue
+                    .queued_bytes
+                    .fetch_add(data.len(), Ordering::AcqRel);
+                if outgoing.send(data.to_vec()).is_err() {
+                    self.close();
+                }
+            }
+        }
+
+        fn send_ready(&self) -> BoxFuture<'static, ()> {
+            let write_queue = self.write_queue.clone();
+            future::poll_fn(move |cx| {
+                // The waker is registered before checking the counter, in order to not miss a
+                // decrease happening in between.
+                write_queue.waker.register(cx.waker());
+                if write_queue.queued_bytes.load(Ordering::Acquire) < MAX_QUEUED_WRITE_BYTES {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .boxed()
+        }
+
+        fn close(&mut self) {
+            if let Some(socket) = self.socket.take() {
+                // Also interrupts the background threads.
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            self.outgoing = None;
+            self.incoming.close();
+        }
+    }
+
+    impl Drop for TcpConnection {
+        fn drop(&mut self) {
+            self.close();
+        }
+    }
+}
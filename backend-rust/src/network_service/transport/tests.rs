@@ -0,0 +1,125 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tests of the transports that don't go through the host.
+
+use super::*;
+
+use futures::executor::block_on;
+
+/// Reads from `transport` until `len` bytes have been received.
+fn read_exact(transport: &mut dyn Transport, len: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    while data.len() < len {
+        let buffer = block_on(transport.read_buffer()).unwrap();
+        let read = buffer.len().min(len - data.len());
+        data.extend_from_slice(&buffer[..read]);
+        transport.advance_read_cursor(read);
+    }
+    data
+}
+
+#[test]
+fn memory_connections_exchange_data() {
+    let mut listener = MemoryListener::new(1).unwrap();
+    // The port is already in use.
+    assert!(MemoryListener::new(1).is_none());
+
+    let mut local = block_on(connect(&listener.multiaddr(), 1024)).unwrap();
+    let mut remote = block_on(listener.accept());
+
+    local.send(b"hello ");
+    local.send(b"world");
+    block_on(local.send_ready());
+    assert_eq!(read_exact(&mut remote, 11), b"hello world");
+
+    remote.send(b"foo");
+    let buffer = block_on(local.read_buffer()).unwrap();
+    assert_eq!(buffer, b"foo");
+    // The read cursor only advances by the given amount of bytes.
+    local.advance_read_cursor(1);
+    assert_eq!(block_on(local.read_buffer()).unwrap(), b"oo");
+}
+
+#[test]
+fn memory_connections_require_listener() {
+    let multiaddr = {
+        let listener = MemoryListener::new(2).unwrap();
+        listener.multiaddr()
+    };
+    assert!(block_on(connect(&multiaddr, 1024)).is_err());
+
+    // The port is released when the listener is destroyed.
+    let _listener = MemoryListener::new(2).unwrap();
+    assert!(block_on(connect(&multiaddr, 1024)).is_ok());
+}
+
+#[test]
+fn memory_connections_close() {
+    let (mut local, mut remote) = MemoryConnection::duplex();
+    remote.send(b"discarded");
+    local.close();
+
+    assert!(block_on(local.read_buffer()).is_none());
+    // Data sent after closing is discarded, and the remote sees the connection closed.
+    local.send(b"foo");
+    assert!(block_on(remote.read_buffer()).is_none());
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn tcp_connections_exchange_data() {
+    use std::{
+        io::{Read as _, Write as _},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let multiaddr: Multiaddr = format!(
+        "/ip4/127.0.0.1/tcp/{}",
+        listener.local_addr().unwrap().port()
+    )
+    .parse()
+    .unwrap();
+
+    let mut local = block_on(connect(&multiaddr, 64 * 1024)).unwrap();
+    let (mut remote, _) = listener.accept().unwrap();
+
+    local.send(b"hello world");
+    block_on(local.send_ready());
+    let mut received = [0; 11];
+    remote.read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"hello world");
+
+    remote.write_all(b"foo").unwrap();
+    assert_eq!(read_exact(&mut *local, 3), b"foo");
+
+    // Closing the remote is reported.
+    drop(remote);
+    assert!(block_on(local.read_buffer()).is_none());
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn tcp_connections_fail_when_refused() {
+    let port = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let multiaddr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+    assert!(block_on(connect(&multiaddr, 64 * 1024)).is_err());
+}
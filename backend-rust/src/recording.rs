@@ -0,0 +1,945 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Recording and replay of the network activity seen by the sync service.
+//!
+//! The traffic of the connections themselves can't be replayed, as it is encrypted with keys
+//! that are randomly generated for each connection. Instead, what is recorded are the events
+//! and the responses that the network service reports to the sync service through the
+//! [`Network`] trait. These are exactly the inputs that the syncing depends on, alongside with
+//! time and the seed of the sync state machine, which are recorded as well.
+//!
+//! [`RecordingNetwork`] wraps around the network service and records everything that goes
+//! through it. [`Replay`] implements [`Network`] without any network access, by reporting the
+//! events at the time they have been recorded and answering the requests with the recorded
+//! responses. Since the sync service is deterministic, it issues the same requests as during the
+//! recording, at least until the code of the client changes.
+//!
+//! The connections opened through the FFI layer (see [`ffi`]) are thus not recorded. The
+//! recording covers the [`Network`] trait, which sits between the network service and the sync
+//! service, and the network service itself isn't exercised during a replay.
+//!
+//! The recording isn't kept in memory. Each event, request and response is instead sent to the
+//! host as an entry as soon as it happens (see [`ffi::network_recording_entry`]), so that the
+//! host gets the complete recording even if the client panics. A recording is the list of these
+//! entries, each encoded in JSON and followed with a line break.
+//!
+//! Replaying is meant to be done with the virtual clock (see [`ffi::clock`]), which makes it
+//! possible to replay a recording at any speed.
+
+use crate::{ffi, network_service, sync_service::Network};
+
+use core::{convert::TryFrom as _, fmt, time::Duration};
+use futures::{channel::mpsc, prelude::*};
+use smoldot::{
+    finality::justification,
+    header,
+    json_rpc::methods::HexString,
+    libp2p::{self, connection::established, PeerId},
+    network::{self, protocol},
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc, Mutex,
+    },
+    vec,
+};
+
+/// Version of the format of [`RecordingEntry`].
+const RECORDING_VERSION: u32 = 1;
+
+/// Entry of a recording, serialized as JSON. The first entry of a recording is always a
+/// [`RecordingEntry::Start`].
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+enum RecordingEntry {
+    Start(RecordingStart),
+    Event(RecordedEvent),
+    Request(RecordedRequest),
+    Response(RecordedResponse),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecordingStart {
+    /// Always equal to [`RECORDING_VERSION`].
+    version: u32,
+    /// Number of milliseconds between the UNIX epoch and the start of the recording.
+    unix_time_ms: f64,
+    /// Seed of the randomness of the sync state machine.
+    randomness_seed: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecordedEvent {
+    /// Number of milliseconds between the start of the recording and the event.
+    time_ms: f64,
+    peer_id: String,
+    chain_index: usize,
+    #[serde(flatten)]
+    kind: RecordedEventKind,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RecordedEventKind {
+    Connected {
+        best_block_number: u64,
+        best_block_hash: HexString,
+    },
+    Disconnected,
+    BlockAnnounce {
+        header: HexString,
+        is_best: bool,
+    },
+    GrandpaCommit {
+        target_number: u64,
+        justification: HexString,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecordedRequest {
+    /// Identifier of the request, unique within the recording.
+    id: u64,
+    /// Number of milliseconds between the start of the recording and the start of the request.
+    start_ms: f64,
+    peer_id: String,
+    chain_index: usize,
+    #[serde(flatten)]
+    kind: RecordedRequestKind,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RecordedRequestKind {
+    Blocks {
+        /// Either a block number or a block hash.
+        start: RecordedBlocksStart,
+        desired_count: u32,
+        descending: bool,
+        header: bool,
+        body: bool,
+        justification: bool,
+    },
+    StorageProof {
+        block_hash: HexString,
+        keys: Vec<HexString>,
+    },
+}
+
+/// Response to a request. Missing from the recording if the response hasn't been received.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecordedResponse {
+    /// Identifier of the request, as found in [`RecordedRequest::id`].
+    id: u64,
+    /// Number of milliseconds between the start of the recording and the response.
+    end_ms: f64,
+    result: Result<RecordedResponseBody, RecordedError>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedResponseBody {
+    Blocks(Vec<RecordedBlock>),
+    StorageProof(Vec<HexString>),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedBlocksStart {
+    Number(u64),
+    Hash(HexString),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RecordedBlock {
+    hash: HexString,
+    header: Option<HexString>,
+    body: Option<Vec<HexString>>,
+    justification: Option<HexString>,
+}
+
+/// Failed request. Only the information that the sync service depends on is recorded.
+#[derive(Debug, Copy, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordedError {
+    Timeout,
+    Refused,
+    Protocol,
+    Empty,
+    Incomplete,
+}
+
+impl From<&network_service::BlocksRequestError> for RecordedError {
+    fn from(error: &network_service::BlocksRequestError) -> Self {
+        match error {
+            network_service::BlocksRequestError::Timeout => RecordedError::Timeout,
+            network_service::BlocksRequestError::Refused(_) => RecordedError::Refused,
+            network_service::BlocksRequestError::Protocol(_) => RecordedError::Protocol,
+            network_service::BlocksRequestError::Empty => RecordedError::Empty,
+            network_service::BlocksRequestError::Incomplete => RecordedError::Incomplete,
+        }
+    }
+}
+
+impl From<RecordedError> for network_service::BlocksRequestError {
+    fn from(error: RecordedError) -> Self {
+        match error {
+            RecordedError::Timeout => network_service::BlocksRequestError::Timeout,
+            RecordedError::Refused => network_service::BlocksRequestError::Refused(
+                libp2p::RequestError::Connection(established::RequestError::SubstreamClosed),
+            ),
+            RecordedError::Protocol => network_service::BlocksRequestError::Protocol(
+                network::service::BlocksRequestError::Request(libp2p::RequestError::Connection(
+                    established::RequestError::SubstreamReset,
+                )),
+            ),
+            RecordedError::Empty => network_service::BlocksRequestError::Empty,
+            RecordedError::Incomplete => network_service::BlocksRequestError::Incomplete,
+        }
+    }
+}
+
+impl From<&network::service::StorageProofRequestError> for RecordedError {
+    fn from(error: &network::service::StorageProofRequestError) -> Self {
+        match error {
+            network::service::StorageProofRequestError::Request(
+                libp2p::RequestError::Connection(established::RequestError::Timeout),
+            ) => RecordedError::Timeout,
+            network::service::StorageProofRequestError::Request(_) => RecordedError::Refused,
+            network::service::StorageProofRequestError::Decode(_) => RecordedError::Protocol,
+        }
+    }
+}
+
+impl From<RecordedError> for network::service::StorageProofRequestError {
+    fn from(error: RecordedError) -> Self {
+        network::service::StorageProofRequestError::Request(match error {
+            RecordedError::Timeout => {
+                libp2p::RequestError::Connection(established::RequestError::Timeout)
+            }
+            RecordedError::Refused => {
+                libp2p::RequestError::Connection(established::RequestError::SubstreamClosed)
+            }
+            RecordedError::Protocol | RecordedError::Empty | RecordedError::Incomplete => {
+                libp2p::RequestError::Connection(established::RequestError::SubstreamReset)
+            }
+        })
+    }
+}
+
+fn record_blocks_request(config: &protocol::BlocksRequestConfig) -> RecordedRequestKind {
+    RecordedRequestKind::Blocks {
+        start: match config.start {
+            protocol::BlocksRequestConfigStart::Number(n) => RecordedBlocksStart::Number(n.get()),
+            protocol::BlocksRequestConfigStart::Hash(h) => {
+                RecordedBlocksStart::Hash(HexString(h.to_vec()))
+            }
+        },
+        desired_count: config.desired_count.get(),
+        descending: config.direction == protocol::BlocksRequestDirection::Descending,
+        header: config.fields.header,
+        body: config.fields.body,
+        justification: config.fields.justification,
+    }
+}
+
+fn record_storage_proof_request(block_hash: &[u8; 32], keys: &[Vec<u8>]) -> RecordedRequestKind {
+    RecordedRequestKind::StorageProof {
+        block_hash: HexString(block_hash.to_vec()),
+        keys: keys.iter().map(|k| HexString(k.clone())).collect(),
+    }
+}
+
+/// Returns true if both requests have the same parameters.
+fn same_request(a: &RecordedRequestKind, b: &RecordedRequestKind) -> bool {
+    match (a, b) {
+        (
+            RecordedRequestKind::Blocks {
+                start: start_a,
+                desired_count: count_a,
+                descending: descending_a,
+                header: header_a,
+                body: body_a,
+                justification: justification_a,
+            },
+            RecordedRequestKind::Blocks {
+                start: start_b,
+                desired_count: count_b,
+                descending: descending_b,
+                header: header_b,
+                body: body_b,
+                justification: justification_b,
+            },
+        ) => {
+            let same_start = match (start_a, start_b) {
+                (RecordedBlocksStart::Number(a), RecordedBlocksStart::Number(b)) => a == b,
+                (RecordedBlocksStart::Hash(a), RecordedBlocksStart::Hash(b)) => a.0 == b.0,
+                _ => false,
+            };
+            same_start
+                && count_a == count_b
+                && descending_a == descending_b
+                && header_a == header_b
+                && body_a == body_b
+                && justification_a == justification_b
+        }
+        (
+            RecordedRequestKind::StorageProof {
+                block_hash: hash_a,
+                keys: keys_a,
+            },
+            RecordedRequestKind::StorageProof {
+                block_hash: hash_b,
+                keys: keys_b,
+            },
+        ) => {
+            hash_a.0 == hash_b.0
+                && keys_a.len() == keys_b.len()
+                && keys_a.iter().zip(keys_b).all(|(a, b)| a.0 == b.0)
+        }
+        _ => false,
+    }
+}
+
+/// Records the activity of the network. See [the module-level documentation](self).
+pub struct Recorder {
+    /// Moment when the recording started.
+    start: ffi::Instant,
+    /// Identifier to assign to the next request.
+    next_request_id: AtomicU64,
+}
+
+impl Recorder {
+    /// Starts a new recording. `randomness_seed` must be the seed passed to the sync service.
+    pub fn new(randomness_seed: u64) -> Arc<Self> {
+        let recorder = Recorder {
+            start: ffi::Instant::now(),
+            next_request_id: AtomicU64::new(0),
+        };
+        recorder.write(&RecordingEntry::Start(RecordingStart {
+            version: RECORDING_VERSION,
+            unix_time_ms: ffi::unix_time().as_secs_f64() * 1000.0,
+            randomness_seed,
+        }));
+        Arc::new(recorder)
+    }
+
+    /// Sends an entry of the recording to the host.
+    fn write(&self, entry: &RecordingEntry) {
+        // JSON strings can't contain line breaks, and `to_string` doesn't add any.
+        ffi::network_recording_entry(&serde_json::to_string(entry).unwrap());
+    }
+
+    /// Returns a receiver that yields the same events as `events`, and a task that must be
+    /// spawned in order to record and forward the events.
+    pub fn record_events(
+        self: &Arc<Self>,
+        mut events: mpsc::Receiver<network_service::Event>,
+    ) -> (
+        impl Future<Output = ()>,
+        mpsc::Receiver<network_service::Event>,
+    ) {
+        let (mut tx, rx) = mpsc::channel(0);
+        let recorder = self.clone();
+
+        let task = async move {
+            while let Some(event) = events.next().await {
+                let (peer_id, chain_index, kind) = match &event {
+                    network_service::Event::Connected {
+                        peer_id,
+                        chain_index,
+                        best_block_number,
+                        best_block_hash,
+                    } => (
+                        peer_id,
+                        *chain_index,
+                        RecordedEventKind::Connected {
+                            best_block_number: *best_block_number,
+                            best_block_hash: HexString(best_block_hash.to_vec()),
+                        },
+                    ),
+                    network_service::Event::Disconnected {
+                        peer_id,
+                        chain_index,
+                    } => (peer_id, *chain_index, RecordedEventKind::Disconnected),
+                    network_service::Event::BlockAnnounce {
+                        peer_id,
+                        chain_index,
+                        scale_encoded_header,
+                        is_best,
+                    } => (
+                        peer_id,
+                        *chain_index,
+                        RecordedEventKind::BlockAnnounce {
+                            header: HexString(scale_encoded_header.clone()),
+                            is_best: *is_best,
+                        },
+                    ),
+                    network_service::Event::GrandpaCommit {
+                        peer_id,
+                        chain_index,
+                        target_number,
+                        scale_encoded_justification,
+                    } => (
+                        peer_id,
+                        *chain_index,
+                        RecordedEventKind::GrandpaCommit {
+                            target_number: *target_number,
+                            justification: HexString(scale_encoded_justification.clone()),
+                        },
+                    ),
+                };
+
+                recorder.write(&RecordingEntry::Event(RecordedEvent {
+                    time_ms: recorder.now_ms(),
+                    peer_id: peer_id.to_base58(),
+                    chain_index,
+                    kind,
+                }));
+
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        };
+
+        (task, rx)
+    }
+
+    fn now_ms(&self) -> f64 {
+        (ffi::Instant::now() - self.start).as_secs_f64() * 1000.0
+    }
+
+    /// Records the start of a request, and returns its identifier.
+    fn request_started(
+        &self,
+        peer_id: &PeerId,
+        chain_index: usize,
+        kind: RecordedRequestKind,
+    ) -> u64 {
+        let id = self.next_request_id.fetch_add(1, atomic::Ordering::Relaxed);
+        self.write(&RecordingEntry::Request(RecordedRequest {
+            id,
+            start_ms: self.now_ms(),
+            peer_id: peer_id.to_base58(),
+            chain_index,
+            kind,
+        }));
+        id
+    }
+
+    /// Records the response to a request started with [`Recorder::request_started`].
+    fn request_finished(&self, id: u64, result: Result<RecordedResponseBody, RecordedError>) {
+        self.write(&RecordingEntry::Response(RecordedResponse {
+            id,
+            end_ms: self.now_ms(),
+            result,
+        }));
+    }
+}
+
+/// Implementation of [`Network`] that records the activity of another [`Network`].
+pub struct RecordingNetwork {
+    pub inner: Arc<dyn Network>,
+    pub recorder: Arc<Recorder>,
+}
+
+impl Network for RecordingNetwork {
+    fn blocks_request(
+        self: Arc<Self>,
+        target: PeerId,
+        chain_index: usize,
+        config: protocol::BlocksRequestConfig,
+    ) -> future::BoxFuture<
+        'static,
+        Result<Vec<protocol::BlockData>, network_service::BlocksRequestError>,
+    > {
+        let id =
+            self.recorder
+                .request_started(&target, chain_index, record_blocks_request(&config));
+        let request = self
+            .inner
+            .clone()
+            .blocks_request(target, chain_index, config);
+
+        async move {
+            let result = request.await;
+            self.recorder.request_finished(
+                id,
+                match &result {
+                    Ok(blocks) => Ok(RecordedResponseBody::Blocks(
+                        blocks
+                            .iter()
+                            .map(|block| RecordedBlock {
+                                hash: HexString(block.hash.to_vec()),
+                                header: block.header.clone().map(HexString),
+                                body: block
+                                    .body
+                                    .as_ref()
+                                    .map(|body| body.iter().cloned().map(HexString).collect()),
+                                justification: block.justification.clone().map(HexString),
+                            })
+                            .collect(),
+                    )),
+                    Err(error) => Err(error.into()),
+                },
+            );
+            result
+        }
+        .boxed()
+    }
+
+    fn storage_proof_request(
+        self: Arc<Self>,
+        target: PeerId,
+        chain_index: usize,
+        config: protocol::StorageProofRequestConfig<vec::IntoIter<Vec<u8>>>,
+    ) -> future::BoxFuture<'static, Result<Vec<Vec<u8>>, network::service::StorageProofRequestError>>
+    {
+        let keys = config.keys.collect::<Vec<_>>();
+        let id = self.recorder.request_started(
+            &target,
+            chain_index,
+            record_storage_proof_request(&config.block_hash, &keys),
+        );
+        let request = self.inner.clone().storage_proof_request(
+            target,
+            chain_index,
+            protocol::StorageProofRequestConfig {
+                block_hash: config.block_hash,
+                keys: keys.into_iter(),
+            },
+        );
+
+        async move {
+            let result = request.await;
+            self.recorder.request_finished(
+                id,
+                match &result {
+                    Ok(proof) => Ok(RecordedResponseBody::StorageProof(
+                        proof.iter().cloned().map(HexString).collect(),
+                    )),
+                    Err(error) => Err(error.into()),
+                },
+            );
+            result
+        }
+        .boxed()
+    }
+
+    fn set_local_grandpa_state(
+        &self,
+        chain_index: usize,
+        grandpa_state: network_service::GrandpaState,
+    ) -> future::BoxFuture<'_, ()> {
+        self.inner
+            .set_local_grandpa_state(chain_index, grandpa_state)
+    }
+
+    fn disconnect_peer<'a>(&'a self, peer_id: &'a PeerId, ban: bool) -> future::BoxFuture<'a, ()> {
+        self.inner.disconnect_peer(peer_id, ban)
+    }
+
+    fn address_book(
+        &self,
+        chain_index: usize,
+    ) -> future::BoxFuture<'_, Vec<network_service::AddressBookEntry>> {
+        self.inner.address_book(chain_index)
+    }
+
+    fn node_key(&self) -> &[u8; 32] {
+        self.inner.node_key()
+    }
+}
+
+/// Error while loading a recording.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// An entry of the recording isn't valid JSON, or doesn't have the expected format.
+    Json(serde_json::Error),
+    /// The recording doesn't start with the expected entry.
+    MissingStart,
+    /// The recording has been produced by an incompatible version of the client.
+    UnsupportedVersion(u32),
+    /// The recording contains an invalid peer id.
+    InvalidPeerId(String),
+    /// The recording contains an invalid value.
+    InvalidValue,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Json(err) => write!(f, "{}", err),
+            ReplayError::MissingStart => write!(f, "Recording doesn't start with a start entry"),
+            ReplayError::UnsupportedVersion(v) => write!(f, "Unsupported recording version: {}", v),
+            ReplayError::InvalidPeerId(id) => write!(f, "Invalid peer id: {}", id),
+            ReplayError::InvalidValue => write!(f, "Invalid value in recording"),
+        }
+    }
+}
+
+/// Request of a recording being replayed.
+struct ReplayedRequest {
+    peer_id: String,
+    chain_index: usize,
+    kind: RecordedRequestKind,
+    /// Duration of the request and its response. `None` if the response hasn't been recorded.
+    response: Option<(Duration, Result<RecordedResponseBody, RecordedError>)>,
+}
+
+/// Implementation of [`Network`] that replays a recording. See
+/// [the module-level documentation](self).
+pub struct Replay {
+    /// Moment when the replay started.
+    start: ffi::Instant,
+    node_key: [u8; 32],
+    unix_time: Duration,
+    randomness_seed: u64,
+    /// Events to report, with the number of milliseconds between the start and the event.
+    /// Extracted when calling [`Replay::events`].
+    events: Mutex<Vec<(f64, network_service::Event)>>,
+    /// Requests of the recording, in order. Set to `None` once they have been replayed.
+    requests: Mutex<Vec<Option<ReplayedRequest>>>,
+}
+
+impl Replay {
+    /// Loads a recording made of the entries produced by a [`Recorder`], each followed with a
+    /// line break.
+    ///
+    /// `node_key` is the value returned by [`Network::node_key`].
+    pub fn from_recording(recording: &str, node_key: [u8; 32]) -> Result<Self, ReplayError> {
+        let mut entries = recording
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str::<RecordingEntry>(line).map_err(ReplayError::Json));
+
+        let start = match entries.next() {
+            Some(Ok(RecordingEntry::Start(start))) => start,
+            Some(Ok(_)) | None => return Err(ReplayError::MissingStart),
+            Some(Err(err)) => return Err(err),
+        };
+        if start.version != RECORDING_VERSION {
+            return Err(ReplayError::UnsupportedVersion(start.version));
+        }
+
+        let parse_peer_id = |peer_id: &str| {
+            peer_id
+                .parse::<PeerId>()
+                .map_err(|_| ReplayError::InvalidPeerId(peer_id.to_owned()))
+        };
+
+        let mut events = Vec::new();
+        let mut requests = Vec::new();
+        // For each request identifier, the index in `requests` and the start of the request.
+        let mut requests_by_id = HashMap::new();
+
+        for entry in entries {
+            match entry? {
+                RecordingEntry::Start(_) => return Err(ReplayError::InvalidValue),
+                RecordingEntry::Event(event) => {
+                    let peer_id = parse_peer_id(&event.peer_id)?;
+                    let chain_index = event.chain_index;
+                    events.push((
+                        event.time_ms,
+                        match event.kind {
+                            RecordedEventKind::Connected {
+                                best_block_number,
+                                best_block_hash,
+                            } => network_service::Event::Connected {
+                                peer_id,
+                                chain_index,
+                                best_block_number,
+                                best_block_hash: <[u8; 32]>::try_from(&best_block_hash.0[..])
+                                    .map_err(|_| ReplayError::InvalidValue)?,
+                            },
+                            RecordedEventKind::Disconnected => {
+                                network_service::Event::Disconnected {
+                                    peer_id,
+                                    chain_index,
+                                }
+                            }
+                            RecordedEventKind::BlockAnnounce { header, is_best } => {
+                                header::decode(&header.0).map_err(|_| ReplayError::InvalidValue)?;
+                                network_service::Event::BlockAnnounce {
+                                    peer_id,
+                                    chain_index,
+                                    scale_encoded_header: header.0,
+                                    is_best,
+                                }
+                            }
+                            RecordedEventKind::GrandpaCommit {
+                                target_number,
+                                justification,
+                            } => {
+                                justification::decode::decode(&justification.0)
+                                    .map_err(|_| ReplayError::InvalidValue)?;
+                                network_service::Event::GrandpaCommit {
+                                    peer_id,
+                                    chain_index,
+                                    target_number,
+                                    scale_encoded_justification: justification.0,
+                                }
+                            }
+                        },
+                    ));
+                }
+                RecordingEntry::Request(request) => {
+                    parse_peer_id(&request.peer_id)?;
+                    if requests_by_id
+                        .insert(request.id, (requests.len(), request.start_ms))
+                        .is_some()
+                    {
+                        return Err(ReplayError::InvalidValue);
+                    }
+                    requests.push(Some(ReplayedRequest {
+                        peer_id: request.peer_id,
+                        chain_index: request.chain_index,
+                        kind: request.kind,
+                        response: None,
+                    }));
+                }
+                RecordingEntry::Response(response) => {
+                    let (index, start_ms) = *requests_by_id
+                        .get(&response.id)
+                        .ok_or(ReplayError::InvalidValue)?;
+                    let request = requests[index].as_mut().unwrap();
+
+                    let valid = match (&request.kind, &response.result) {
+                        (
+                            RecordedRequestKind::Blocks { .. },
+                            Ok(RecordedResponseBody::Blocks(blocks)),
+                        ) => blocks.iter().all(|b| b.hash.0.len() == 32),
+                        (
+                            RecordedRequestKind::StorageProof { .. },
+                            Ok(RecordedResponseBody::StorageProof(_)),
+                        ) => true,
+                        (_, Ok(_)) => false,
+                        (_, Err(_)) => true,
+                    };
+                    if !valid || request.response.is_some() {
+                        return Err(ReplayError::InvalidValue);
+                    }
+
+                    let duration =
+                        Duration::from_secs_f64((response.end_ms - start_ms).max(0.0) / 1000.0);
+                    request.response = Some((duration, response.result));
+                }
+            }
+        }
+
+        Ok(Replay {
+            start: ffi::Instant::now(),
+            node_key,
+            unix_time: Duration::from_secs_f64(start.unix_time_ms.max(0.0) / 1000.0),
+            randomness_seed: start.randomness_seed,
+            events: Mutex::new(events),
+            requests: Mutex::new(requests),
+        })
+    }
+
+    /// Returns the UNIX time when the recording started.
+    pub fn unix_time(&self) -> Duration {
+        self.unix_time
+    }
+
+    /// Returns the seed to pass to the sync service.
+    pub fn randomness_seed(&self) -> u64 {
+        self.randomness_seed
+    }
+
+    /// Returns a receiver of the recorded events, and a task that must be spawned in order to
+    /// send these events at the time they have been recorded.
+    ///
+    /// # Panic
+    ///
+    /// Panics if called multiple times.
+    ///
+    pub fn events(
+        &self,
+    ) -> (
+        impl Future<Output = ()>,
+        mpsc::Receiver<network_service::Event>,
+    ) {
+        let events = std::mem::take(&mut *self.events.lock().unwrap());
+        let start = self.start;
+        let (mut tx, rx) = mpsc::channel(0);
+
+        let task = async move {
+            for (time_ms, event) in events {
+                let when = start + Duration::from_secs_f64(time_ms.max(0.0) / 1000.0);
+                let now = ffi::Instant::now();
+                if when > now {
+                    ffi::Delay::new(when - now).await;
+                }
+
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+
+            log::info!("All the events of the network recording have been replayed");
+
+            // The sync service stops if the channel is closed. The network is instead considered
+            // silent after the end of the recording.
+            future::pending::<()>().await;
+            drop(tx);
+        };
+
+        (task, rx)
+    }
+
+    /// Finds the recorded request that matches the given one, and returns its response. Returns
+    /// `None` if there isn't any.
+    fn take_request(
+        &self,
+        peer_id: &PeerId,
+        chain_index: usize,
+        kind: &RecordedRequestKind,
+    ) -> Option<Option<(Duration, Result<RecordedResponseBody, RecordedError>)>> {
+        let peer_id = peer_id.to_base58();
+        let mut requests = self.requests.lock().unwrap();
+        let request = requests.iter_mut().find(|rq| {
+            rq.as_ref().is_some_and(|rq| {
+                rq.peer_id == peer_id
+                    && rq.chain_index == chain_index
+                    && same_request(&rq.kind, kind)
+            })
+        });
+
+        match request.and_then(|rq| rq.take()) {
+            Some(rq) => Some(rq.response),
+            None => {
+                log::warn!(
+                    "Replay has diverged from the recording: no request to {} matches",
+                    peer_id
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Time after which requests that aren't part of the recording fail with a timeout. Failing
+/// immediately would make the sync service retry in a loop.
+const DIVERGED_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Waits for the recorded response of a request found with [`Replay::take_request`], and returns
+/// it.
+async fn wait_response(
+    recorded: Option<Option<(Duration, Result<RecordedResponseBody, RecordedError>)>>,
+) -> Result<RecordedResponseBody, RecordedError> {
+    match recorded {
+        Some(Some((duration, result))) => {
+            ffi::Delay::new(duration).await;
+            result
+        }
+        // Requests whose response hasn't been recorded never finish.
+        Some(None) => future::pending().await,
+        None => {
+            ffi::Delay::new(DIVERGED_REQUEST_TIMEOUT).await;
+            Err(RecordedError::Timeout)
+        }
+    }
+}
+
+impl Network for Replay {
+    fn blocks_request(
+        self: Arc<Self>,
+        target: PeerId,
+        chain_index: usize,
+        config: protocol::BlocksRequestConfig,
+    ) -> future::BoxFuture<
+        'static,
+        Result<Vec<protocol::BlockData>, network_service::BlocksRequestError>,
+    > {
+        let recorded = self.take_request(&target, chain_index, &record_blocks_request(&config));
+
+        async move {
+            match wait_response(recorded).await {
+                Ok(RecordedResponseBody::Blocks(blocks)) => Ok(blocks
+                    .into_iter()
+                    .map(|block| protocol::BlockData {
+                        // The length has been checked when loading the recording.
+                        hash: <[u8; 32]>::try_from(&block.hash.0[..]).unwrap(),
+                        header: block.header.map(|h| h.0),
+                        body: block.body.map(|b| b.into_iter().map(|e| e.0).collect()),
+                        justification: block.justification.map(|j| j.0),
+                    })
+                    .collect()),
+                // The kind of the response has been checked when loading the recording.
+                Ok(RecordedResponseBody::StorageProof(_)) => unreachable!(),
+                Err(error) => Err(error.into()),
+            }
+        }
+        .boxed()
+    }
+
+    fn storage_proof_request(
+        self: Arc<Self>,
+        target: PeerId,
+        chain_index: usize,
+        config: protocol::StorageProofRequestConfig<vec::IntoIter<Vec<u8>>>,
+    ) -> future::BoxFuture<'static, Result<Vec<Vec<u8>>, network::service::StorageProofRequestError>>
+    {
+        let keys = config.keys.collect::<Vec<_>>();
+        let recorded = self.take_request(
+            &target,
+            chain_index,
+            &record_storage_proof_request(&config.block_hash, &keys),
+        );
+
+        async move {
+            match wait_response(recorded).await {
+                Ok(RecordedResponseBody::StorageProof(proof)) => {
+                    Ok(proof.into_iter().map(|p| p.0).collect())
+                }
+                // The kind of the response has been checked when loading the recording.
+                Ok(RecordedResponseBody::Blocks(_)) => unreachable!(),
+                Err(error) => Err(error.into()),
+            }
+        }
+        .boxed()
+    }
+
+    fn set_local_grandpa_state(
+        &self,
+        _: usize,
+        _: network_service::GrandpaState,
+    ) -> future::BoxFuture<'_, ()> {
+        future::ready(()).boxed()
+    }
+
+    fn disconnect_peer<'a>(&'a self, _: &'a PeerId, _: bool) -> future::BoxFuture<'a, ()> {
+        // The disconnection, if any, is part of the recorded events.
+        future::ready(()).boxed()
+    }
+
+    fn address_book(
+        &self,
+        _: usize,
+    ) -> future::BoxFuture<'_, Vec<network_service::AddressBookEntry>> {
+        future::ready(Vec::new()).boxed()
+    }
+
+    fn node_key(&self) -> &[u8; 32] {
+        &self.node_key
+    }
+}
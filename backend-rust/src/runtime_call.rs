@@ -0,0 +1,72 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Execution of runtime functions against the storage of the finalized block, on behalf of the
+//! host. See [`crate::ffi::bindings::runtime_call`].
+//!
+//! Calls are read-only: the storage modifications made by the runtime function are discarded.
+//! Each call runs in a new instance of the runtime of the finalized block, which the sync
+//! service keeps compiled.
+
+use smoldot::executor::{self, read_only_runtime_host};
+use std::collections::BTreeMap;
+
+/// Calls `function` with the SCALE-encoded `parameter`, against the given storage, and returns
+/// the SCALE-encoded output of the function.
+///
+/// `virtual_machine` must be the runtime found in `storage`, and `state_root` must be the state
+/// root of the block whose storage is `storage`.
+pub(crate) fn run(
+    virtual_machine: executor::host::HostVmPrototype,
+    storage: &BTreeMap<Vec<u8>, Vec<u8>>,
+    state_root: &[u8; 32],
+    function: &str,
+    parameter: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut call = read_only_runtime_host::run(read_only_runtime_host::Config {
+        virtual_machine,
+        function_to_call: function,
+        parameter: core::iter::once(parameter),
+    })
+    .map_err(|(err, _)| err.to_string())?;
+
+    loop {
+        match call {
+            read_only_runtime_host::RuntimeHostVm::Finished(Ok(success)) => {
+                return Ok(success.virtual_machine.value().as_ref().to_vec())
+            }
+            read_only_runtime_host::RuntimeHostVm::Finished(Err(err)) => {
+                return Err(err.to_string())
+            }
+            read_only_runtime_host::RuntimeHostVm::StorageGet(req) => {
+                let value = storage.get(&req.key_as_vec()).map(|v| &v[..]);
+                call = req.inject_value(value.map(core::iter::once));
+            }
+            read_only_runtime_host::RuntimeHostVm::StorageRoot(req) => {
+                call = req.resume(state_root);
+            }
+            read_only_runtime_host::RuntimeHostVm::NextKey(req) => {
+                let key = req.key().as_ref().to_vec();
+                let next_key = storage
+                    .range(key.clone()..)
+                    .map(|(k, _)| k)
+                    .find(|k| **k != key);
+                call = req.inject_key(next_key);
+            }
+        }
+    }
+}
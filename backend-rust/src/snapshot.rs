@@ -0,0 +1,403 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bootstrapping from a snapshot of a recent finalized block, built by a third party.
+//!
+//! A snapshot contains the chain information and storage of a finalized block, as encoded by
+//! [`finalized_serialize`], and the GrandPa justification of this block. Before being used, the
+//! snapshot is verified in one of two ways:
+//!
+//! - If the host provides a trusted block hash, the hash of the finalized block of the snapshot
+//!   must be equal to it.
+//! - Otherwise, the justification is verified against the GrandPa authorities of the genesis
+//!   block. If the authorities have changed since the genesis block, the snapshot must contain,
+//!   for each change, the header of the block that schedules it and its justification. These
+//!   are verified in order, each against the authorities that result from the previous change.
+//!
+//! In both cases, the storage is verified against the state root of the finalized block, and the
+//! GrandPa and BABE information found in the chain information is verified against the storage
+//! items the runtime keeps them in.
+
+use crate::storage_query::twox_128;
+
+use core::fmt;
+use smoldot::{
+    chain::chain_information, database::finalized_serialize, finality::justification, header,
+    json_rpc::methods::HexString, trie::calculate_root,
+};
+use std::{collections::BTreeMap, convert::TryFrom as _};
+
+#[derive(serde::Deserialize)]
+struct SerializedSnapshot {
+    /// Output of [`finalized_serialize::encode_chain_storage`].
+    chain: String,
+    /// SCALE-encoded GrandPa justification of the finalized block. Not needed if the hash of the
+    /// block is trusted.
+    #[serde(default)]
+    justification: Option<HexString>,
+    /// Changes of GrandPa authorities since the genesis block, ordered by block number.
+    #[serde(default)]
+    authority_set_changes: Vec<SerializedAuthoritySetChange>,
+}
+
+#[derive(serde::Deserialize)]
+struct SerializedAuthoritySetChange {
+    /// SCALE-encoded header of the block that schedules the change.
+    header: HexString,
+    /// SCALE-encoded GrandPa justification of this block.
+    justification: HexString,
+}
+
+/// Verified snapshot.
+pub struct Snapshot {
+    /// Information about the finalized block of the snapshot.
+    pub chain_information: chain_information::ChainInformation,
+    /// Storage of the finalized block of the snapshot.
+    pub finalized_storage: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Error while verifying a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The snapshot isn't valid JSON, or doesn't have the expected format.
+    Json(serde_json::Error),
+    /// The chain information or storage can't be decoded.
+    InvalidChain(String),
+    /// The chain doesn't use GrandPa for finality.
+    NotGrandpa,
+    /// The hash of the finalized block isn't the trusted hash.
+    UntrustedHash,
+    /// The storage doesn't match the state root of the finalized block.
+    StorageRootMismatch,
+    /// The GrandPa or BABE information of the chain information doesn't match the storage.
+    FinalityStorageMismatch,
+    /// A header of an authority set change can't be decoded. Contains the index of the change.
+    InvalidHeader(usize),
+    /// The justification of the given block can't be decoded, isn't targeting this block, or
+    /// isn't signed by the expected authorities.
+    InvalidJustification { block_number: u64, error: String },
+    /// The header of an authority set change doesn't contain a GrandPa scheduled change with a
+    /// delay of zero blocks. Contains the index of the change.
+    NoAuthoritySetChange(usize),
+    /// The GrandPa authorities of the chain information don't match the ones that result from
+    /// the authority set changes.
+    AuthoritiesMismatch,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Json(err) => write!(f, "{}", err),
+            SnapshotError::InvalidChain(err) => write!(f, "Invalid chain information: {}", err),
+            SnapshotError::NotGrandpa => write!(f, "Chain doesn't use GrandPa"),
+            SnapshotError::UntrustedHash => write!(f, "Finalized block isn't the trusted block"),
+            SnapshotError::StorageRootMismatch => write!(f, "Storage doesn't match state root"),
+            SnapshotError::FinalityStorageMismatch => {
+                write!(f, "Finality information doesn't match storage")
+            }
+            SnapshotError::InvalidHeader(n) => {
+                write!(f, "Invalid header in authority set change #{}", n)
+            }
+            SnapshotError::InvalidJustification {
+                block_number,
+                error,
+            } => write!(
+                f,
+                "Invalid justification of block #{}: {}",
+                block_number, error
+            ),
+            SnapshotError::NoAuthoritySetChange(n) => {
+                write!(
+                    f,
+                    "No supported GrandPa change in authority set change #{}",
+                    n
+                )
+            }
+            SnapshotError::AuthoritiesMismatch => {
+                write!(
+                    f,
+                    "GrandPa authorities don't match the authority set changes"
+                )
+            }
+        }
+    }
+}
+
+/// Decodes and verifies a snapshot. See [the module-level documentation](self).
+///
+/// `genesis` must be the chain information of the genesis block of the chain.
+pub fn verify(
+    snapshot: &str,
+    genesis: &chain_information::ChainInformation,
+    trusted_hash: Option<[u8; 32]>,
+) -> Result<Snapshot, SnapshotError> {
+    let snapshot: SerializedSnapshot =
+        serde_json::from_str(snapshot).map_err(SnapshotError::Json)?;
+
+    let (chain_information, finalized_storage) =
+        match finalized_serialize::decode_chain(&snapshot.chain) {
+            Ok((chain_information, Some(storage))) => (
+                chain_information,
+                storage.into_iter().collect::<BTreeMap<_, _>>(),
+            ),
+            Ok((_, None)) => return Err(SnapshotError::InvalidChain("missing storage".to_owned())),
+            Err(err) => return Err(SnapshotError::InvalidChain(err.to_string())),
+        };
+
+    let finalized_header = &chain_information.finalized_block_header;
+    if storage_root(&finalized_storage) != finalized_header.state_root {
+        return Err(SnapshotError::StorageRootMismatch);
+    }
+    verify_finality_storage(&chain_information, &finalized_storage)?;
+
+    if let Some(trusted_hash) = trusted_hash {
+        if finalized_header.hash() != trusted_hash {
+            return Err(SnapshotError::UntrustedHash);
+        }
+
+        return Ok(Snapshot {
+            chain_information,
+            finalized_storage,
+        });
+    }
+
+    // Authorities of the genesis block, updated while going through the changes.
+    let (mut set_id, mut authorities) = match &genesis.finality {
+        chain_information::ChainInformationFinality::Grandpa {
+            after_finalized_block_authorities_set_id,
+            finalized_triggered_authorities,
+            ..
+        } => (
+            *after_finalized_block_authorities_set_id,
+            finalized_triggered_authorities
+                .iter()
+                .map(|a| a.public_key)
+                .collect::<Vec<_>>(),
+        ),
+        _ => return Err(SnapshotError::NotGrandpa),
+    };
+
+    for (index, change) in snapshot.authority_set_changes.iter().enumerate() {
+        let header =
+            header::decode(&change.header.0).map_err(|_| SnapshotError::InvalidHeader(index))?;
+        verify_justification(&header, &change.justification.0, set_id, &authorities)?;
+        authorities =
+            scheduled_change(&header).ok_or(SnapshotError::NoAuthoritySetChange(index))?;
+        set_id += 1;
+    }
+
+    let justification =
+        snapshot
+            .justification
+            .ok_or_else(|| SnapshotError::InvalidJustification {
+                block_number: finalized_header.number,
+                error: "missing justification".to_owned(),
+            })?;
+    verify_justification(
+        &finalized_header.into(),
+        &justification.0,
+        set_id,
+        &authorities,
+    )?;
+
+    // The finalized block itself might enact a change.
+    if let Some(new_authorities) = scheduled_change(&finalized_header.into()) {
+        authorities = new_authorities;
+        set_id += 1;
+    }
+
+    match &chain_information.finality {
+        chain_information::ChainInformationFinality::Grandpa {
+            after_finalized_block_authorities_set_id,
+            finalized_triggered_authorities,
+            ..
+        } if *after_finalized_block_authorities_set_id == set_id
+            && finalized_triggered_authorities
+                .iter()
+                .map(|a| a.public_key)
+                .eq(authorities.iter().copied()) => {}
+        _ => return Err(SnapshotError::AuthoritiesMismatch),
+    }
+
+    Ok(Snapshot {
+        chain_information,
+        finalized_storage,
+    })
+}
+
+/// Verifies that `scale_encoded_justification` is a valid justification of `header`, signed by
+/// the given authorities.
+fn verify_justification(
+    header: &header::HeaderRef,
+    scale_encoded_justification: &[u8],
+    set_id: u64,
+    authorities: &[[u8; 32]],
+) -> Result<(), SnapshotError> {
+    let error = |error: String| SnapshotError::InvalidJustification {
+        block_number: header.number,
+        error,
+    };
+
+    let decoded = justification::decode::decode(scale_encoded_justification)
+        .map_err(|err| error(err.to_string()))?;
+    if *decoded.target_hash != header.hash() || u64::from(decoded.target_number) != header.number {
+        return Err(error("justification targets a different block".to_owned()));
+    }
+
+    justification::verify::verify(justification::verify::Config {
+        justification: decoded,
+        authorities_set_id: set_id,
+        authorities_list: authorities.iter().map(|a| &a[..]),
+    })
+    .map_err(|err| error(err.to_string()))
+}
+
+/// Verifies that the GrandPa and BABE information of `chain_information` matches the storage of
+/// its finalized block.
+fn verify_finality_storage(
+    chain_information: &chain_information::ChainInformation,
+    storage: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<(), SnapshotError> {
+    // Storage items that aren't in the storage have their default value, which is encoded as
+    // zeroes for all the items below.
+    let item = |key: &[u8], default_len: usize| {
+        storage
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| vec![0; default_len])
+    };
+    let pallet_item = |pallet: &[u8], name: &[u8], default_len: usize| {
+        item(&[twox_128(pallet), twox_128(name)].concat(), default_len)
+    };
+
+    match &chain_information.finality {
+        chain_information::ChainInformationFinality::Grandpa {
+            after_finalized_block_authorities_set_id,
+            finalized_triggered_authorities,
+            ..
+        } => {
+            let mut authorities = vec![1];
+            authorities.extend(encode_authorities(
+                finalized_triggered_authorities
+                    .iter()
+                    .map(|a| (&a.public_key, a.weight.get())),
+            ));
+            if storage.get(&b":grandpa_authorities"[..]) != Some(&authorities)
+                || pallet_item(b"Grandpa", b"CurrentSetId", 8)
+                    != after_finalized_block_authorities_set_id.to_le_bytes()
+            {
+                return Err(SnapshotError::FinalityStorageMismatch);
+            }
+        }
+        _ => return Err(SnapshotError::NotGrandpa),
+    }
+
+    if let chain_information::ChainInformationConsensus::Babe {
+        finalized_block_epoch_information,
+        finalized_next_epoch_transition,
+        ..
+    } = &chain_information.consensus
+    {
+        // At the genesis block, only epoch #0 is known.
+        let current = finalized_block_epoch_information
+            .as_ref()
+            .unwrap_or(finalized_next_epoch_transition);
+        let babe_authorities = |epoch: &chain_information::BabeEpochInformation| {
+            encode_authorities(epoch.authorities.iter().map(|a| (&a.public_key, a.weight)))
+        };
+
+        // Epoch #1 is announced with the authorities and randomness of epoch #0, while the
+        // next epochs are announced with the values stored for them when the previous epoch
+        // starts.
+        let (next_authorities, next_randomness) = if current.epoch_index == 0 {
+            (&b"Authorities"[..], &b"Randomness"[..])
+        } else {
+            (&b"NextAuthorities"[..], &b"NextRandomness"[..])
+        };
+
+        if pallet_item(b"Babe", b"EpochIndex", 8) != current.epoch_index.to_le_bytes()
+            || pallet_item(b"Babe", b"Authorities", 1) != babe_authorities(current)
+            || pallet_item(b"Babe", b"Randomness", 32) != current.randomness
+            || pallet_item(b"Babe", next_authorities, 1)
+                != babe_authorities(finalized_next_epoch_transition)
+            || pallet_item(b"Babe", next_randomness, 32)
+                != finalized_next_epoch_transition.randomness
+        {
+            return Err(SnapshotError::FinalityStorageMismatch);
+        }
+    }
+
+    Ok(())
+}
+
+/// SCALE-encodes a list of authorities as a `Vec<(PublicKey, Weight)>`.
+fn encode_authorities<'a>(
+    authorities: impl ExactSizeIterator<Item = (&'a [u8; 32], u64)>,
+) -> Vec<u8> {
+    // The length is encoded as a SCALE compact number.
+    let len = u32::try_from(authorities.len()).unwrap();
+    let mut encoded = if len < 1 << 6 {
+        vec![(len << 2) as u8]
+    } else if len < 1 << 14 {
+        ((len << 2) as u16 | 0b01).to_le_bytes().to_vec()
+    } else if len < 1 << 30 {
+        ((len << 2) | 0b10).to_le_bytes().to_vec()
+    } else {
+        let mut encoded = vec![0b11];
+        encoded.extend_from_slice(&len.to_le_bytes());
+        encoded
+    };
+
+    for (public_key, weight) in authorities {
+        encoded.extend_from_slice(public_key);
+        encoded.extend_from_slice(&weight.to_le_bytes());
+    }
+    encoded
+}
+
+/// Returns the new GrandPa authorities if the header contains a GrandPa scheduled change that
+/// takes effect immediately.
+fn scheduled_change(header: &header::HeaderRef) -> Option<Vec<[u8; 32]>> {
+    header.digest.logs().find_map(|log| match log {
+        header::DigestItemRef::GrandpaConsensus(
+            header::GrandpaConsensusLogRef::ScheduledChange(change),
+        ) if change.delay == 0 => Some(
+            change
+                .next_authorities
+                .map(|authority| *authority.public_key)
+                .collect(),
+        ),
+        _ => None,
+    })
+}
+
+/// Calculates the trie root of the given storage.
+pub(crate) fn storage_root(storage: &BTreeMap<Vec<u8>, Vec<u8>>) -> [u8; 32] {
+    let mut calculation = calculate_root::root_merkle_value(None);
+    loop {
+        match calculation {
+            calculate_root::RootMerkleValueCalculation::Finished { hash, .. } => return hash,
+            calculate_root::RootMerkleValueCalculation::AllKeys(keys) => {
+                calculation = keys.inject(storage.keys().map(|k| k.iter().copied()));
+            }
+            calculate_root::RootMerkleValueCalculation::StorageValue(value) => {
+                let key = value.key().collect::<Vec<u8>>();
+                calculation = value.inject(storage.get(&key));
+            }
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Queries of the storage of the finalized block, emitted by the host through
+//! [`crate::ffi::bindings::storage_query`].
+//!
+//! Queries are answered by the sync service, which holds the storage of the finalized block. See
+//! [`crate::sync_service::SyncService::storage_query`].
+//!
+//! When requested, values are decoded with the help of the metadata of the finalized runtime.
+//! The metadata indicates which storage item a key belongs to and the name of the type of its
+//! value, but not the layout of the types. As such, only the map keys hashed with a transparent
+//! hasher and the values of a few well-known types can be decoded. Other values are only
+//! reported in their encoded form.
+
+use core::{convert::TryFrom as _, hash::Hasher as _, ops::Bound};
+use smoldot::{
+    json_rpc::methods::HexString,
+    metadata::decode::{StorageEntryModifier, StorageEntryTypeRef, StorageHasher},
+};
+use std::collections::BTreeMap;
+
+/// Maximum number of keys returned by a single `keys` query.
+const MAX_KEYS_PER_QUERY: usize = 1000;
+
+/// Query passed by the host.
+#[derive(serde::Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Query {
+    /// Value of a key.
+    Get {
+        key: HexString,
+        #[serde(default)]
+        decode: bool,
+    },
+    /// Keys that start with `prefix`, in lexicographic order, starting after `start_key`.
+    Keys {
+        prefix: HexString,
+        #[serde(default)]
+        start_key: Option<HexString>,
+        count: usize,
+    },
+    /// First key strictly after `key`, in lexicographic order.
+    NextKey { key: HexString },
+}
+
+/// Answer to a [`Query`].
+#[derive(serde::Serialize)]
+struct Response {
+    block_number: u64,
+    block_hash: HexString,
+    #[serde(flatten)]
+    result: QueryResult,
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum QueryResult {
+    Value {
+        value: Option<HexString>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        decoded: Option<Decoded>,
+    },
+    Keys {
+        keys: Vec<HexString>,
+    },
+    NextKey {
+        next_key: Option<HexString>,
+    },
+}
+
+/// Information about a storage value, deduced from the metadata.
+#[derive(serde::Serialize)]
+struct Decoded {
+    pallet: String,
+    item: String,
+    /// Name of the type of the value, as found in the metadata.
+    value_type: String,
+    /// Keys of the map, in order. `None` for the keys that can't be recovered from the hash.
+    keys: Vec<Option<HexString>>,
+    /// True if the key is absent from the storage, and the value is the default value of the
+    /// storage item.
+    is_default: bool,
+    /// Value decoded according to `value_type`, or `None` if this type isn't supported.
+    value: Option<serde_json::Value>,
+}
+
+/// Error answered to the host.
+#[derive(serde::Serialize)]
+struct Error {
+    error: String,
+}
+
+/// State of the finalized block that queries are answered against.
+pub(crate) struct FinalizedState<'a> {
+    pub(crate) block_number: u64,
+    pub(crate) block_hash: [u8; 32],
+    pub(crate) storage: &'a BTreeMap<Vec<u8>, Vec<u8>>,
+    /// SCALE-encoded metadata of the runtime of the finalized block.
+    pub(crate) metadata: &'a [u8],
+}
+
+/// Returns the JSON-encoded answer reporting the given error.
+pub(crate) fn error_answer(error: String) -> String {
+    serde_json::to_string(&Error { error }).unwrap()
+}
+
+/// Answers the JSON-encoded `query` passed by the host. Always returns a JSON-encoded answer,
+/// including when the query is invalid.
+pub(crate) fn answer(query: &str, state: &FinalizedState) -> String {
+    let query: Query = match serde_json::from_str(query) {
+        Ok(query) => query,
+        Err(err) => return error_answer(format!("Invalid storage query: {}", err)),
+    };
+
+    let result = match query {
+        Query::Get { key, decode } => {
+            let value = state.storage.get(&key.0);
+            let decoded = if decode {
+                decode_value(state.metadata, &key.0, value.map(|v| &v[..]))
+            } else {
+                None
+            };
+            QueryResult::Value {
+                value: value.map(|v| HexString(v.clone())),
+                decoded,
+            }
+        }
+        Query::Keys {
+            prefix,
+            start_key,
+            count,
+        } => {
+            let lower_bound = match start_key {
+                Some(start_key) if start_key.0 >= prefix.0 => Bound::Excluded(start_key.0),
+                _ => Bound::Included(prefix.0.clone()),
+            };
+            QueryResult::Keys {
+                keys: state
+                    .storage
+                    .range::<Vec<u8>, _>((lower_bound, Bound::Unbounded))
+                    .map(|(k, _)| k)
+                    .take_while(|k| k.starts_with(&prefix.0))
+                    .take(count.min(MAX_KEYS_PER_QUERY))
+                    .map(|k| HexString(k.clone()))
+                    .collect(),
+            }
+        }
+        Query::NextKey { key } => QueryResult::NextKey {
+            next_key: state
+                .storage
+                .range::<Vec<u8>, _>((Bound::Excluded(key.0), Bound::Unbounded))
+                .next()
+                .map(|(k, _)| HexString(k.clone())),
+        },
+    };
+
+    serde_json::to_string(&Response {
+        block_number: state.block_number,
+        block_hash: HexString(state.block_hash.to_vec()),
+        result,
+    })
+    .unwrap()
+}
+
+/// Finds the storage item that `key` belongs to in the metadata, and decodes what can be.
+///
+/// Returns `None` if the metadata can't be decoded or if no storage item matches.
+fn decode_value(metadata: &[u8], key: &[u8], value: Option<&[u8]>) -> Option<Decoded> {
+    let metadata = smoldot::metadata::decode(metadata).ok()?;
+
+    for module in metadata.modules {
+        let storage = match module.storage {
+            Some(storage) => storage,
+            None => continue,
+        };
+
+        if !key.starts_with(&twox_128(storage.prefix.as_bytes())) {
+            continue;
+        }
+        let after_pallet = &key[16..];
+
+        for entry in storage.entries {
+            if !after_pallet.starts_with(&twox_128(entry.name.as_bytes())) {
+                continue;
+            }
+            let after_item = &after_pallet[16..];
+
+            let (value_type, keys) = match entry.ty {
+                StorageEntryTypeRef::Plain(value_type) if after_item.is_empty() => {
+                    (value_type, Vec::new())
+                }
+                StorageEntryTypeRef::Plain(_) => continue,
+                StorageEntryTypeRef::Map {
+                    hasher,
+                    key: key_type,
+                    value: value_type,
+                    ..
+                } => match split_map_key(&hasher, key_type, after_item, true) {
+                    Some((map_key, [])) => (value_type, vec![map_key]),
+                    _ => continue,
+                },
+                StorageEntryTypeRef::DoubleMap {
+                    hasher,
+                    key1,
+                    key2,
+                    value: value_type,
+                    key2_hasher,
+                } => {
+                    let (key1, rest) = match split_map_key(&hasher, key1, after_item, false) {
+                        Some(split) => split,
+                        None => continue,
+                    };
+                    match split_map_key(&key2_hasher, key2, rest, true) {
+                        Some((key2, [])) => (value_type, vec![key1, key2]),
+                        _ => continue,
+                    }
+                }
+            };
+
+            let (bytes, is_default) = match (value, &entry.modifier) {
+                (Some(value), _) => (Some(value), false),
+                (None, StorageEntryModifier::Default) => (Some(entry.default), true),
+                (None, StorageEntryModifier::Optional) => (None, false),
+            };
+
+            return Some(Decoded {
+                pallet: storage.prefix.to_owned(),
+                item: entry.name.to_owned(),
+                value_type: value_type.to_owned(),
+                keys: keys.into_iter().map(|k| k.map(HexString)).collect(),
+                is_default,
+                value: bytes.and_then(|bytes| decode_known_type(value_type, bytes)),
+            });
+        }
+    }
+
+    None
+}
+
+/// Splits the beginning of `key` into one key of a map, hashed with `hasher`, and the rest.
+///
+/// The key itself is only returned for transparent hashers. If `last` is false, the size of the
+/// key must be known in order to find where the next key starts.
+fn split_map_key<'a>(
+    hasher: &StorageHasher,
+    key_type: &str,
+    key: &'a [u8],
+    last: bool,
+) -> Option<(Option<Vec<u8>>, &'a [u8])> {
+    let (hash_len, transparent) = match hasher {
+        StorageHasher::Blake2_128 | StorageHasher::Twox128 => (16, false),
+        StorageHasher::Blake2_256 | StorageHasher::Twox256 => (32, false),
+        StorageHasher::Blake2_128Concat => (16, true),
+        StorageHasher::Twox64Concat => (8, true),
+        StorageHasher::Identity => (0, true),
+    };
+
+    if key.len() < hash_len {
+        return None;
+    }
+    let (_, after_hash) = key.split_at(hash_len);
+
+    if !transparent {
+        return Some((None, after_hash));
+    }
+
+    let key_len = if last {
+        after_hash.len()
+    } else {
+        known_type_size(key_type)?
+    };
+    if after_hash.len() < key_len {
+        return None;
+    }
+    let (map_key, rest) = after_hash.split_at(key_len);
+    Some((Some(map_key.to_vec()), rest))
+}
+
+/// Returns the size in bytes of the SCALE encoding of the given type, if it is one of the types
+/// supported by [`decode_known_type`].
+fn known_type_size(type_name: &str) -> Option<usize> {
+    match type_name {
+        "bool" | "u8" => Some(1),
+        "u16" => Some(2),
+        "u32" | "T::BlockNumber" | "BlockNumber" | "EraIndex" | "SessionIndex" | "T::Index" => {
+            Some(4)
+        }
+        "u64" | "T::Moment" | "Moment" => Some(8),
+        "u128" | "T::Balance" | "BalanceOf<T>" | "Balance" => Some(16),
+        "T::AccountId" | "AccountId" | "T::Hash" | "Hash" => Some(32),
+        _ => None,
+    }
+}
+
+/// Decodes a value of one of a few well-known types into JSON.
+///
+/// Integers that might not fit in a JavaScript number are encoded as decimal strings, and
+/// account ids and hashes as hexadecimal strings.
+fn decode_known_type(type_name: &str, bytes: &[u8]) -> Option<serde_json::Value> {
+    if bytes.len() != known_type_size(type_name)? {
+        return None;
+    }
+
+    let mut buffer = [0; 16];
+    if bytes.len() <= buffer.len() {
+        buffer[..bytes.len()].copy_from_slice(bytes);
+    }
+    let integer = u128::from_le_bytes(buffer);
+
+    Some(match bytes.len() {
+        1 if type_name == "bool" => match bytes[0] {
+            0 => serde_json::Value::Bool(false),
+            1 => serde_json::Value::Bool(true),
+            _ => return None,
+        },
+        1 | 2 | 4 => serde_json::Value::from(u32::try_from(integer).unwrap()),
+        8 | 16 => serde_json::Value::String(integer.to_string()),
+        _ => serde_json::to_value(HexString(bytes.to_vec())).unwrap(),
+    })
+}
+
+/// Returns the `twox_128` hash of `data`, as used to build the keys of storage items.
+pub(crate) fn twox_128(data: &[u8]) -> [u8; 16] {
+    let mut hash0 = twox_hash::XxHash64::with_seed(0);
+    let mut hash1 = twox_hash::XxHash64::with_seed(1);
+    hash0.write(data);
+    hash1.write(data);
+
+    let mut out = [0; 16];
+    out[..8].copy_from_slice(&hash0.finish().to_le_bytes());
+    out[8..].copy_from_slice(&hash1.finish().to_le_bytes());
+    out
+}
@@ -15,15 +15,39 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{ffi, network_service};
+use crate::{database, ffi, network_service};
 
-use core::{num::NonZeroU32, pin::Pin};
-use futures::{channel::mpsc, prelude::*};
+use core::{
+    convert::TryFrom as _,
+    mem,
+    num::{NonZeroU32, NonZeroU64},
+    pin::Pin,
+    time::Duration,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
 use smoldot::{
-    chain::chain_information, database::finalized_serialize, executor, libp2p, network,
-    sync::optimistic,
+    chain::chain_information, database::finalized_serialize, executor, header,
+    json_rpc::methods::HexString, libp2p, network, sync::optimistic, trie::proof_verify,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Mutex},
+    vec,
 };
-use std::{collections::BTreeMap, sync::Arc};
+
+mod throughput;
+mod watchdog;
+
+#[cfg(test)]
+mod tests;
+
+/// When following the best blocks (see [`ffi::is_following_best_blocks`]), the events of the best
+/// block are only fetched if the best block is at most this number of blocks behind the highest
+/// block announced by the peers.
+const FOLLOW_BEST_BLOCKS_MAX_DISTANCE: u64 = 4;
 
 /// Configuration for a [`SyncService`].
 pub struct Config {
@@ -36,42 +60,305 @@ pub struct Config {
     /// Initial storage of the finalized block. Must match [`Config::chain_information`].
     pub finalized_storage: BTreeMap<Vec<u8>, Vec<u8>>,
 
+    /// If true, the metadata of the runtime of the initial finalized block is reported alongside
+    /// with the next finalized blocks, as the environment doesn't know it yet. This is the case
+    /// when starting from the genesis block or from a snapshot.
+    pub report_finalized_metadata: bool,
+
+    /// If true, the changes to the balances of accounts are reported for each finalized block.
+    /// See [`crate::balances`].
+    pub index_balances: bool,
+
+    /// Storage prefixes whose changes are initially reported for each finalized block. See
+    /// [`SyncService::watch_storage_prefix`].
+    pub watched_storage_prefixes: BTreeSet<Vec<u8>>,
+
+    /// Server answering the JSON-RPC requests passed to [`SyncService::json_rpc_request`].
+    pub json_rpc_service: crate::json_rpc_service::JsonRpcService,
+
     /// Access to the network, and index of the chain to sync from the point of view of the
     /// network service.
-    pub network_service: (Arc<network_service::NetworkService>, usize),
+    pub network_service: (Arc<dyn Network>, usize),
+
+    /// Minimum number of blocks to request at once from a source. The actual number depends on
+    /// the observed performances of the source.
+    pub min_blocks_request_size: NonZeroU32,
+
+    /// Maximum number of blocks to request at once from a source. Must be superior or equal to
+    /// [`Config::min_blocks_request_size`].
+    pub max_blocks_request_size: NonZeroU32,
+
+    /// If the best block doesn't advance for this duration, the syncing is considered as stalled.
+    /// The cause is then logged, all the sources are replaced, and the environment is notified
+    /// through [`ffi::sync_status_update`].
+    pub stall_timeout: Duration,
+
+    /// Seed of the randomness used to choose which source to download blocks from. Recorded
+    /// alongside the network activity in order to be able to replay it.
+    pub randomness_seed: u64,
 
     /// Receiver for events coming from the network, as returned by
     /// [`network_service::NetworkService::new`].
     pub network_events_receiver: mpsc::Receiver<network_service::Event>,
 }
 
+/// Access to the network needed by the sync service.
+///
+/// Implemented on [`network_service::NetworkService`]. See the documentation of the methods of
+/// the same name on this type.
+pub trait Network: Send + Sync {
+    fn blocks_request(
+        self: Arc<Self>,
+        target: libp2p::PeerId,
+        chain_index: usize,
+        config: network::protocol::BlocksRequestConfig,
+    ) -> future::BoxFuture<
+        'static,
+        Result<Vec<network::protocol::BlockData>, network_service::BlocksRequestError>,
+    >;
+
+    fn storage_proof_request(
+        self: Arc<Self>,
+        target: libp2p::PeerId,
+        chain_index: usize,
+        config: network::protocol::StorageProofRequestConfig<vec::IntoIter<Vec<u8>>>,
+    ) -> future::BoxFuture<'static, Result<Vec<Vec<u8>>, network::service::StorageProofRequestError>>;
+
+    fn set_local_grandpa_state(
+        &self,
+        chain_index: usize,
+        grandpa_state: network_service::GrandpaState,
+    ) -> future::BoxFuture<'_, ()>;
+
+    fn disconnect_peer<'a>(
+        &'a self,
+        peer_id: &'a libp2p::PeerId,
+        ban: bool,
+    ) -> future::BoxFuture<'a, ()>;
+
+    fn address_book(
+        &self,
+        chain_index: usize,
+    ) -> future::BoxFuture<'_, Vec<network_service::AddressBookEntry>>;
+
+    fn node_key(&self) -> &[u8; 32];
+}
+
+impl Network for network_service::NetworkService {
+    fn blocks_request(
+        self: Arc<Self>,
+        target: libp2p::PeerId,
+        chain_index: usize,
+        config: network::protocol::BlocksRequestConfig,
+    ) -> future::BoxFuture<
+        'static,
+        Result<Vec<network::protocol::BlockData>, network_service::BlocksRequestError>,
+    > {
+        network_service::NetworkService::blocks_request(self, target, chain_index, config).boxed()
+    }
+
+    fn storage_proof_request(
+        self: Arc<Self>,
+        target: libp2p::PeerId,
+        chain_index: usize,
+        config: network::protocol::StorageProofRequestConfig<vec::IntoIter<Vec<u8>>>,
+    ) -> future::BoxFuture<'static, Result<Vec<Vec<u8>>, network::service::StorageProofRequestError>>
+    {
+        network_service::NetworkService::storage_proof_request(self, target, chain_index, config)
+            .boxed()
+    }
+
+    fn set_local_grandpa_state(
+        &self,
+        chain_index: usize,
+        grandpa_state: network_service::GrandpaState,
+    ) -> future::BoxFuture<'_, ()> {
+        network_service::NetworkService::set_local_grandpa_state(self, chain_index, grandpa_state)
+            .boxed()
+    }
+
+    fn disconnect_peer<'a>(
+        &'a self,
+        peer_id: &'a libp2p::PeerId,
+        ban: bool,
+    ) -> future::BoxFuture<'a, ()> {
+        network_service::NetworkService::disconnect_peer(self, peer_id, ban).boxed()
+    }
+
+    fn address_book(
+        &self,
+        chain_index: usize,
+    ) -> future::BoxFuture<'_, Vec<network_service::AddressBookEntry>> {
+        network_service::NetworkService::address_book(self, chain_index).boxed()
+    }
+
+    fn node_key(&self) -> &[u8; 32] {
+        network_service::NetworkService::node_key(self)
+    }
+}
+
 /// Background task that verifies blocks and emits requests.
-// TODO: remove or something?
-pub struct SyncService {}
+pub struct SyncService {
+    /// Sender of messages towards the background task.
+    to_background: mpsc::UnboundedSender<ToBackground>,
+}
+
+/// Message sent from the [`SyncService`] to its background task.
+enum ToBackground {
+    /// See [`SyncService::storage_query`].
+    StorageQuery {
+        query: String,
+        send_back: oneshot::Sender<String>,
+    },
+    /// See [`SyncService::runtime_call`].
+    RuntimeCall {
+        function: String,
+        parameter: Vec<u8>,
+        send_back: oneshot::Sender<Result<RuntimeCallOutput, String>>,
+    },
+    /// See [`SyncService::watch_storage_prefix`].
+    WatchStoragePrefix(Vec<u8>),
+    /// See [`SyncService::unwatch_storage_prefix`].
+    UnwatchStoragePrefix(Vec<u8>),
+    /// See [`SyncService::json_rpc_request`].
+    JsonRpc { request: String },
+}
+
+/// Successful output of [`SyncService::runtime_call`].
+pub struct RuntimeCallOutput {
+    /// Number of the finalized block the function has been called against.
+    pub block_number: u64,
+    /// Hash of the finalized block the function has been called against.
+    pub block_hash: [u8; 32],
+    /// SCALE-encoded value returned by the runtime function.
+    pub output: Vec<u8>,
+}
 
 impl SyncService {
     /// Initializes the [`SyncService`] with the given configuration.
     pub async fn new(mut config: Config) -> Arc<Self> {
-        (config.tasks_executor)(Box::pin(start_sync(
-            config.chain_information,
-            config.finalized_storage,
-            config.network_service.0,
-            config.network_service.1,
-            config.network_events_receiver,
-        )));
-
-        Arc::new(SyncService {})
+        let (to_background, from_foreground) = mpsc::unbounded();
+
+        // The executor is taken out of the configuration, as the rest of the configuration is
+        // moved to the background task.
+        let mut tasks_executor = mem::replace(&mut config.tasks_executor, Box::new(|_| {}));
+        tasks_executor(Box::pin(start_sync(config, from_foreground)));
+
+        Arc::new(SyncService { to_background })
+    }
+
+    /// Answers a query of the storage of the current finalized block, as passed to
+    /// [`ffi::bindings::storage_query`]. Both the query and the answer are encoded in JSON. See
+    /// the [`crate::storage_query`] module.
+    pub async fn storage_query(&self, query: String) -> String {
+        let (send_back, rx) = oneshot::channel();
+        let _ = self
+            .to_background
+            .unbounded_send(ToBackground::StorageQuery { query, send_back });
+
+        match rx.await {
+            Ok(answer) => answer,
+            Err(_) => crate::storage_query::error_answer("Syncing has stopped".to_owned()),
+        }
+    }
+
+    /// Calls the given runtime function, with the given SCALE-encoded parameter, against the
+    /// storage of the current finalized block. See the [`crate::runtime_call`] module.
+    pub async fn runtime_call(
+        &self,
+        function: String,
+        parameter: Vec<u8>,
+    ) -> Result<RuntimeCallOutput, String> {
+        let (send_back, rx) = oneshot::channel();
+        let _ = self
+            .to_background
+            .unbounded_send(ToBackground::RuntimeCall {
+                function,
+                parameter,
+                send_back,
+            });
+
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => Err("Syncing has stopped".to_owned()),
+        }
+    }
+
+    /// Starts reporting the changes to the storage keys that start with `prefix`, through the
+    /// `storage_changes` field of the blocks passed to [`ffi::database_save`]. Only blocks
+    /// finalized after this call are concerned.
+    pub fn watch_storage_prefix(&self, prefix: Vec<u8>) {
+        let _ = self
+            .to_background
+            .unbounded_send(ToBackground::WatchStoragePrefix(prefix));
+    }
+
+    /// Stops reporting the changes to the storage keys that start with `prefix`, which must
+    /// have been passed to [`SyncService::watch_storage_prefix`] or [`Config`] before.
+    pub fn unwatch_storage_prefix(&self, prefix: Vec<u8>) {
+        let _ = self
+            .to_background
+            .unbounded_send(ToBackground::UnwatchStoragePrefix(prefix));
+    }
+
+    /// Answers the given JSON-RPC request, as passed to [`ffi::bindings::json_rpc_send`]. The
+    /// response is sent through [`ffi::json_rpc_respond`]. See the [`crate::json_rpc_service`]
+    /// module.
+    pub fn json_rpc_request(&self, request: String) {
+        let _ = self
+            .to_background
+            .unbounded_send(ToBackground::JsonRpc { request });
+    }
+}
+
+/// Builds the GrandPa state to report to the network, given the state of the chain.
+///
+/// Returns `None` if the chain doesn't use GrandPa for finality.
+pub fn local_grandpa_state(
+    chain_information: chain_information::ChainInformationRef,
+) -> Option<network_service::GrandpaState> {
+    match chain_information.finality {
+        chain_information::ChainInformationFinalityRef::Grandpa {
+            after_finalized_block_authorities_set_id,
+            ..
+        } => Some(network_service::GrandpaState {
+            // A light client doesn't take part in GrandPa rounds, and isn't aware of the round
+            // the voters are in. Peers mostly rely on the set id and the commit height to decide
+            // which messages to gossip.
+            round_number: 1,
+            set_id: after_finalized_block_authorities_set_id,
+            commit_finalized_height: u32::try_from(
+                chain_information.finalized_block_header.number,
+            )
+            .unwrap_or(u32::MAX),
+        }),
+        _ => None,
     }
 }
 
-/// Returns the background task of the sync service.
+/// Returns the background task of the sync service. [`Config::tasks_executor`] is ignored.
 fn start_sync(
-    initial_chain_information: chain_information::ChainInformation,
-    initial_finalized_storage: BTreeMap<Vec<u8>, Vec<u8>>,
-    network_service: Arc<network_service::NetworkService>,
-    network_chain_index: usize,
-    mut from_network_service: mpsc::Receiver<network_service::Event>,
+    config: Config,
+    mut from_foreground: mpsc::UnboundedReceiver<ToBackground>,
 ) -> impl Future<Output = ()> {
+    let Config {
+        chain_information: initial_chain_information,
+        finalized_storage: initial_finalized_storage,
+        mut report_finalized_metadata,
+        index_balances,
+        mut watched_storage_prefixes,
+        mut json_rpc_service,
+        network_service: (network_service, network_chain_index),
+        min_blocks_request_size,
+        max_blocks_request_size,
+        stall_timeout,
+        randomness_seed,
+        network_events_receiver: mut from_network_service,
+        ..
+    } = config;
+    let mut throughputs =
+        throughput::Throughputs::new(min_blocks_request_size, max_blocks_request_size);
+
     // Holds, in parallel of the database, the storage of the latest finalized block.
     // At the time of writing, this state is stable around ~3MiB for Polkadot, meaning that it is
     // completely acceptable to hold it entirely in memory.
@@ -90,16 +377,21 @@ fn start_sync(
     )
     .unwrap();
     let (mut finalized_runtime_version, vm) = smoldot::executor::core_version(vm).unwrap();
-    let mut finalized_metadata = {
+    // The runtime of the finalized block is kept in order to answer runtime calls without having
+    // to compile the runtime code again for each call.
+    let (mut finalized_metadata, mut finalized_runtime) = {
         let query = smoldot::metadata::query_metadata(vm);
         loop {
             match query {
                 smoldot::metadata::Query::StorageGet(_) => todo!(),
-                smoldot::metadata::Query::Finished(Ok((metadata, _))) => break metadata,
+                smoldot::metadata::Query::Finished(Ok(out)) => break out,
                 smoldot::metadata::Query::Finished(Err(err)) => panic!("{}", err),
             }
         }
     };
+    // True if the changes to the balances are reported. See `crate::balances::is_supported`.
+    let mut balances_supported =
+        index_balances && balances_supported_by(&finalized_runtime_version, &finalized_metadata);
 
     let mut sync = optimistic::OptimisticSync::<_, libp2p::PeerId, ()>::new(optimistic::Config {
         chain_information: initial_chain_information,
@@ -108,8 +400,10 @@ fn start_sync(
             // This is the maximum number of blocks between two consecutive justifications.
             1024
         },
-        source_selection_randomness_seed: rand::random(),
-        blocks_request_granularity: NonZeroU32::new(128).unwrap(),
+        source_selection_randomness_seed: randomness_seed,
+        // Requests emitted by the state machine are later split into smaller requests. See the
+        // `throughput` module.
+        blocks_request_granularity: throughputs.max_request_size(),
         download_ahead_blocks: {
             // Assuming a verification speed of 1k blocks/sec and a 95% latency of one second,
             // the number of blocks to download ahead of time in order to not block is 1000.
@@ -138,16 +432,77 @@ fn start_sync(
     });
 
     async move {
-        let mut peers_source_id_map = hashbrown::HashMap::<_, _, fnv::FnvBuildHasher>::default();
+        let mut peers_source_id_map =
+            hashbrown::HashMap::<libp2p::PeerId, _, fnv::FnvBuildHasher>::default();
         let mut block_requests_finished = stream::FuturesUnordered::new();
 
+        // Non-finalized blocks that have been, or are being, reported through
+        // `ffi::best_block_notification`. Ordered by increasing block number.
+        let mut tentative_blocks = VecDeque::<TentativeBlock>::new();
+        // Requests for the events of the blocks of `tentative_blocks`.
+        let mut tentative_events_requests = stream::FuturesUnordered::new();
+        // Hashes of the non-finalized blocks of the chain of the current best block. A tentative
+        // block that isn't in this set belongs to a fork that has been abandoned.
+        let mut best_chain = hashbrown::HashSet::<[u8; 32], fnv::FnvBuildHasher>::default();
+        // Non-finalized blocks announced by the peers, with their number and the peers that have
+        // announced them. Used to choose the peers to request the events of these blocks from.
+        let mut announced_blocks = hashbrown::HashMap::<
+            [u8; 32],
+            (u64, Vec<libp2p::PeerId>),
+            fnv::FnvBuildHasher,
+        >::default();
+
+        // Justification built from the latest GrandPa commit gossiped by the peers, and number
+        // of the block it targets. Verified and applied once the target block has been verified.
+        // The latest commit always replaces the previous one, so that a commit targeting a block
+        // that doesn't exist can't prevent the next ones from being applied.
+        let mut pending_commit = None::<(u64, Vec<u8>)>;
+
+        let mut watchdog = watchdog::Watchdog::new(stall_timeout, sync.best_block_number());
+        let mut watchdog_timer = ffi::Delay::new(stall_timeout).fuse();
+
         loop {
             let unix_time = ffi::unix_time();
 
             // TODO: unpausing the syncing should somehow wake-up this task
             if !ffi::is_syncing_paused() {
-                // Verify blocks that have been fetched from queries.
-                let mut process = sync.process_one(unix_time);
+                // Finalize the blocks using the latest GrandPa commit, if its target has been
+                // verified. Otherwise, verify blocks that have been fetched from queries.
+                let commit = match pending_commit.take() {
+                    Some((target_number, _))
+                        if target_number <= sync.finalized_block_header().number =>
+                    {
+                        None
+                    }
+                    Some((target_number, justification))
+                        if target_number <= sync.best_block_number() =>
+                    {
+                        Some((target_number, justification))
+                    }
+                    not_verified_yet => {
+                        pending_commit = not_verified_yet;
+                        None
+                    }
+                };
+                let mut process = match commit {
+                    Some((target_number, justification)) => {
+                        match sync.finalize_with_justification(justification) {
+                            Ok(finalized_blocks) => optimistic::ProcessOne::Finalized {
+                                sync,
+                                finalized_blocks,
+                            },
+                            Err(err) => {
+                                log::debug!(
+                                    "Failed to apply GrandPa commit of block #{}: {}",
+                                    target_number,
+                                    err
+                                );
+                                sync.process_one(unix_time)
+                            }
+                        }
+                    }
+                    None => sync.process_one(unix_time),
+                };
                 let mut num_new_bests = 0;
                 loop {
                     match process {
@@ -166,9 +521,19 @@ fn start_sync(
                                 reason
                             );
 
+                            // The chain is reset to the finalized block, which drops all the
+                            // blocks that have been tentatively reported.
+                            best_chain.clear();
+                            retract_tentative_blocks(&mut tentative_blocks, &best_chain);
+                            watchdog.on_reset();
+
                             crate::yield_once().await;
                             process = s.process_one(unix_time);
                         }
+                        // Blocks are finalized either thanks to the justifications found in blocks
+                        // responses, in which case the finalized block is also the best block, or
+                        // thanks to the GrandPa commits gossiped by the peers, in which case it
+                        // can be below the best block.
                         optimistic::ProcessOne::Finalized {
                             sync: s,
                             finalized_blocks,
@@ -177,9 +542,37 @@ fn start_sync(
                                 "Finalized block #{}",
                                 finalized_blocks.last().unwrap().header.number
                             );
-                            crate::ffi::best_block_update(
-                                finalized_blocks.last().unwrap().header.number,
-                            );
+                            crate::ffi::best_block_update(s.best_block_number());
+
+                            let finalized_number = finalized_blocks.last().unwrap().header.number;
+                            for block in &finalized_blocks {
+                                best_chain.remove(&block.header.hash());
+                            }
+                            announced_blocks.retain(|_, (number, _)| *number > finalized_number);
+                            while tentative_blocks
+                                .front()
+                                .is_some_and(|b| b.number <= finalized_number)
+                            {
+                                let block = tentative_blocks.pop_front().unwrap();
+                                let confirmed = finalized_blocks.iter().any(|b| {
+                                    b.header.number == block.number
+                                        && b.header.hash() == block.hash
+                                });
+                                if !block.reported {
+                                    continue;
+                                }
+                                ffi::best_block_notification(&if confirmed {
+                                    ffi::BestBlockNotification::Confirm {
+                                        number: block.number,
+                                        hash: HexString(block.hash.to_vec()),
+                                    }
+                                } else {
+                                    ffi::BestBlockNotification::Retract {
+                                        number: block.number,
+                                        hash: HexString(block.hash.to_vec()),
+                                    }
+                                });
+                            }
 
                             crate::yield_once().await;
 
@@ -187,6 +580,21 @@ fn start_sync(
                             let mut blocks_save = Vec::with_capacity(finalized_blocks.len());
 
                             for block in finalized_blocks {
+                                // The balances are compared with the ones of the parent block,
+                                // and must thus be read before `finalized_block_storage` is
+                                // updated. A block is executed by the runtime of its parent.
+                                let balance_changes = if balances_supported {
+                                    crate::balances::balance_changes(
+                                        &finalized_block_storage,
+                                        block
+                                            .storage_top_trie_changes
+                                            .iter()
+                                            .map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..]))),
+                                    )
+                                } else {
+                                    Vec::new()
+                                };
+
                                 for (key, value) in &block.storage_top_trie_changes {
                                     if let Some(value) = value {
                                         finalized_block_storage.insert(key.clone(), value.clone());
@@ -200,30 +608,44 @@ fn start_sync(
                                 if let Some(code) =
                                     block.storage_top_trie_changes.get(&b":code"[..])
                                 {
+                                    // `finalized_block_storage` has already been updated
+                                    // with the changes of this block.
                                     let vm = smoldot::executor::host::HostVmPrototype::new(
                                         &code.as_ref().unwrap(),
-                                        smoldot::executor::DEFAULT_HEAP_PAGES, // TODO:
+                                        smoldot::executor::storage_heap_pages_to_value(
+                                            finalized_block_storage
+                                                .get(&b":heappages"[..])
+                                                .map(|v| &v[..]),
+                                        )
+                                        .unwrap(),
                                         smoldot::executor::vm::ExecHint::Oneshot,
                                     )
                                     .unwrap();
                                     let (runtime_spec, vm) =
                                         smoldot::executor::core_version(vm).unwrap();
                                     finalized_runtime_version = runtime_spec;
-                                    finalized_metadata = {
+                                    let (metadata, runtime) = {
                                         let query = smoldot::metadata::query_metadata(vm);
                                         loop {
                                             match query {
                                                 smoldot::metadata::Query::StorageGet(_) => todo!(),
-                                                smoldot::metadata::Query::Finished(Ok((
-                                                    metadata,
-                                                    _,
-                                                ))) => break metadata,
+                                                smoldot::metadata::Query::Finished(Ok(out)) => {
+                                                    break out
+                                                }
                                                 smoldot::metadata::Query::Finished(Err(err)) => {
                                                     panic!("{}", err)
                                                 }
                                             }
                                         }
                                     };
+                                    finalized_metadata = metadata;
+                                    finalized_runtime = runtime;
+
+                                    balances_supported = index_balances
+                                        && balances_supported_by(
+                                            &finalized_runtime_version,
+                                            &finalized_metadata,
+                                        );
 
                                     new_metadata.push(ffi::DatabaseSaveMetadata {
                                         runtime_spec: finalized_runtime_version
@@ -237,7 +659,7 @@ fn start_sync(
                                             finalized_metadata.clone(),
                                         ),
                                     });
-                                } else if block.header.number == 1 {
+                                } else if report_finalized_metadata {
                                     new_metadata.push(ffi::DatabaseSaveMetadata {
                                         runtime_spec: finalized_runtime_version
                                             .decode()
@@ -251,6 +673,7 @@ fn start_sync(
                                         ),
                                     });
                                 }
+                                report_finalized_metadata = false;
 
                                 let finalized_metadata =
                                     smoldot::metadata::decode(&finalized_metadata).unwrap();
@@ -267,30 +690,59 @@ fn start_sync(
                                     todo!()
                                 };
 
+                                let storage_changes = watched_storage_changes(
+                                    &watched_storage_prefixes,
+                                    block.storage_top_trie_changes.iter(),
+                                );
+
                                 blocks_save.push(ffi::DatabaseSaveBlock {
                                     number: block.header.number,
                                     runtime_spec: finalized_runtime_version.decode().spec_version,
                                     events: smoldot::json_rpc::methods::HexString(events_encoded),
+                                    balance_changes,
+                                    storage_changes,
                                 });
                             }
 
+                            let address_book =
+                                network_service.address_book(network_chain_index).await;
+
+                            // Inform the peers of our new finalized block, so that they keep
+                            // sending us the GrandPa commits that are relevant to us.
+                            if let Some(grandpa_state) =
+                                local_grandpa_state(s.as_chain_information())
+                            {
+                                network_service
+                                    .set_local_grandpa_state(network_chain_index, grandpa_state)
+                                    .await;
+                            }
+
                             ffi::database_save(&ffi::DatabaseSave {
-                                chain: &finalized_serialize::encode_chain_storage(
-                                    s.as_chain_information(),
-                                    Some(finalized_block_storage.iter()),
-                                ),
+                                chain: &database::encode(&database::DatabaseContent {
+                                    chain: finalized_serialize::encode_chain_storage(
+                                        s.as_chain_information(),
+                                        Some(finalized_block_storage.iter()),
+                                    ),
+                                    address_book,
+                                    node_key: Some(*network_service.node_key()),
+                                }),
                                 new_metadata,
                                 blocks: blocks_save,
                             });
 
+                            json_rpc_service.finalized_block(&s.finalized_block_header());
+
                             process = s.process_one(unix_time);
                         }
 
                         optimistic::ProcessOne::NewBest {
                             sync: s,
                             new_best_number,
-                            ..
+                            new_best_hash,
                         } => {
+                            // Blocks are verified on top of the current best block, so that the
+                            // non-finalized chain only ever grows until the next reset.
+                            best_chain.insert(new_best_hash);
                             num_new_bests += 1;
                             if num_new_bests % 23 == 0 {
                                 crate::yield_once().await;
@@ -328,6 +780,107 @@ fn start_sync(
                     }
                 }
 
+                if watchdog.on_best_block(sync.best_block_number()) {
+                    log::info!("Syncing has resumed at block #{}", sync.best_block_number());
+                    ffi::sync_status_update(&ffi::SyncStatus::Syncing {
+                        best_block_number: sync.best_block_number(),
+                    });
+                }
+
+                // Fetch the events of the new best block, if necessary.
+                if ffi::is_following_best_blocks()
+                    && sync.best_block_number() > sync.finalized_block_header().number
+                    && sync.best_block_number() + FOLLOW_BEST_BLOCKS_MAX_DISTANCE
+                        >= throughputs.highest_best_block().unwrap_or(0)
+                    && tentative_blocks
+                        .back()
+                        .is_none_or(|b| b.hash != sync.best_block_hash())
+                {
+                    retract_tentative_blocks(&mut tentative_blocks, &best_chain);
+
+                    let hash = sync.best_block_hash();
+                    let source = announced_blocks.get(&hash).and_then(|(_, peers)| {
+                        peers
+                            .iter()
+                            .find(|peer_id| peers_source_id_map.contains_key(*peer_id))
+                    });
+                    // If no connected peer has announced the block, its events are reported once
+                    // it is finalized, unless the block gets announced in the meanwhile.
+                    if let Some(source) = source {
+                        let number = sync.best_block_number();
+                        let state_root = *sync.best_block_header().state_root;
+                        let runtime_spec = finalized_runtime_version.decode().spec_version;
+                        let last_runtime_upgrade_key = [
+                            crate::storage_query::twox_128(b"System"),
+                            crate::storage_query::twox_128(b"LastRuntimeUpgrade"),
+                        ]
+                        .concat();
+                        let finalized_last_runtime_upgrade = finalized_block_storage
+                            .get(&last_runtime_upgrade_key)
+                            .cloned();
+                        let events_storage_key = smoldot::metadata::decode(&finalized_metadata)
+                            .map_err(|err| err.to_string())
+                            .and_then(|metadata| {
+                                smoldot::metadata::events::events_storage_key(metadata)
+                                    .map_err(|err| err.to_string())
+                            });
+                        let network_service = network_service.clone();
+                        let source = source.clone();
+
+                        tentative_blocks.push_back(TentativeBlock {
+                            number,
+                            hash,
+                            reported: false,
+                        });
+                        tentative_events_requests.push(async move {
+                            let events = async move {
+                                let events_storage_key = events_storage_key?;
+                                let proof = network_service
+                                    .storage_proof_request(
+                                        source,
+                                        network_chain_index,
+                                        network::protocol::StorageProofRequestConfig {
+                                            block_hash: hash,
+                                            keys: vec![
+                                                events_storage_key.to_vec(),
+                                                last_runtime_upgrade_key.clone(),
+                                            ]
+                                            .into_iter(),
+                                        },
+                                    )
+                                    .await
+                                    .map_err(|err| err.to_string())?;
+                                let proof_value = |key: &[u8]| {
+                                    proof_verify::verify_proof(proof_verify::VerifyProofConfig {
+                                        requested_key: key,
+                                        trie_root_hash: &state_root,
+                                        proof: proof.iter().map(|v| &v[..]),
+                                    })
+                                    .map(|value| value.map(|v| v.to_vec()))
+                                    .map_err(|err| err.to_string())
+                                };
+
+                                // The events are decoded using the runtime of the finalized
+                                // block. `LastRuntimeUpgrade` is updated by the first block
+                                // executed with a new runtime, in which case the events are
+                                // only reported once the block is finalized.
+                                if proof_value(&last_runtime_upgrade_key)?
+                                    != finalized_last_runtime_upgrade
+                                {
+                                    return Err(
+                                        "runtime upgraded since the finalized block".to_owned()
+                                    );
+                                }
+
+                                proof_value(&events_storage_key)?
+                                    .ok_or_else(|| "events not found in storage proof".to_owned())
+                            }
+                            .await;
+                            (number, hash, runtime_spec, events)
+                        });
+                    }
+                }
+
                 // Start requests that need to be started.
                 // Note that this is done after calling `process_one`, as the processing of pending
                 // blocks can result in new requests but not the contrary.
@@ -340,26 +893,27 @@ fn start_sync(
                             num_blocks,
                             ..
                         } => {
-                            let block_request = network_service.clone().blocks_request(
-                                source.clone(),
+                            // The blocks are downloaded through multiple smaller requests,
+                            // potentially towards other sources than the one chosen by the sync
+                            // state machine.
+                            let planned = throughputs.plan_requests(
+                                source,
+                                block_height.get(),
+                                num_blocks.get(),
+                            );
+                            let sources = planned
+                                .iter()
+                                .map(|rq| rq.source.clone())
+                                .collect::<Vec<_>>();
+                            let block_request = download_blocks(
+                                network_service.clone(),
                                 network_chain_index,
-                                network::protocol::BlocksRequestConfig {
-                                    start: network::protocol::BlocksRequestConfigStart::Number(
-                                        block_height,
-                                    ),
-                                    desired_count: num_blocks,
-                                    direction: network::protocol::BlocksRequestDirection::Ascending,
-                                    fields: network::protocol::BlocksRequestFields {
-                                        header: true,
-                                        body: true,
-                                        justification: true,
-                                    },
-                                },
+                                planned,
                             );
 
                             let (rx, abort) = future::abortable(block_request);
                             let request_id = start.start(abort);
-                            block_requests_finished.push(rx.map(move |r| (request_id, r)));
+                            block_requests_finished.push(rx.map(move |r| (request_id, sources, r)));
                         }
                         optimistic::RequestAction::Cancel { user_data, .. } => {
                             user_data.abort();
@@ -369,6 +923,41 @@ fn start_sync(
             }
 
             futures::select! {
+                () = watchdog_timer => {
+                    if ffi::is_syncing_paused() {
+                        // Not advancing is expected while paused.
+                        watchdog.reset_timer();
+                    } else if let Some((cause, stalled_for)) =
+                        watchdog.check(
+                            peers_source_id_map.len(),
+                            throughputs.highest_best_block().unwrap_or(0),
+                        )
+                    {
+                        log::warn!(
+                            "Syncing stalled at block #{} for {}s: {}",
+                            sync.best_block_number(),
+                            stalled_for.as_secs(),
+                            cause
+                        );
+                        ffi::sync_status_update(&ffi::SyncStatus::Stalled {
+                            best_block_number: sync.best_block_number(),
+                            cause,
+                            stalled_for_ms: u64::try_from(stalled_for.as_millis())
+                                .unwrap_or(u64::MAX),
+                        });
+
+                        // Disconnect from all the current sources, so that the network service
+                        // connects to other peers instead. The sources are removed from the
+                        // sync state machine when the disconnection is reported.
+                        let sources = peers_source_id_map.keys().cloned().collect::<Vec<_>>();
+                        for source in sources {
+                            network_service.disconnect_peer(&source, false).await;
+                        }
+                    }
+
+                    watchdog_timer = ffi::Delay::new(watchdog.next_check()).fuse();
+                },
+
                 network_event = from_network_service.next() => {
                     // Something happened on the network.
 
@@ -382,47 +971,390 @@ fn start_sync(
                     };
 
                     match network_event {
-                        network_service::Event::Connected { peer_id, chain_index, best_block_number, .. }
+                        network_service::Event::Connected { peer_id, chain_index, best_block_number, best_block_hash }
                             if chain_index == network_chain_index =>
                         {
+                            if best_block_number > sync.finalized_block_header().number {
+                                announced_blocks
+                                    .entry(best_block_hash)
+                                    .or_insert_with(|| (best_block_number, Vec::new()))
+                                    .1
+                                    .push(peer_id.clone());
+                            }
+                            throughputs.add_source(peer_id.clone(), best_block_number);
                             let id = sync.add_source(peer_id.clone(), best_block_number);
                             peers_source_id_map.insert(peer_id.clone(), id);
                         },
                         network_service::Event::Disconnected { peer_id, chain_index }
                             if chain_index == network_chain_index =>
                         {
+                            throughputs.remove_source(&peer_id);
+                            announced_blocks.retain(|_, (_, peers)| {
+                                peers.retain(|p| *p != peer_id);
+                                !peers.is_empty()
+                            });
                             let id = peers_source_id_map.remove(&peer_id).unwrap();
                             let (_, rq_list) = sync.remove_source(id);
                             for (_, rq) in rq_list {
                                 rq.abort();
                             }
                         },
-                        network_service::Event::BlockAnnounce { chain_index, peer_id, announce }
+                        network_service::Event::BlockAnnounce { chain_index, peer_id, scale_encoded_header, .. }
                             if chain_index == network_chain_index =>
                         {
+                            let number = header::decode(&scale_encoded_header).unwrap().number;
+                            if number > sync.finalized_block_header().number {
+                                let peers = &mut announced_blocks
+                                    .entry(header::hash_from_scale_encoded_header(&scale_encoded_header))
+                                    .or_insert_with(|| (number, Vec::new()))
+                                    .1;
+                                if !peers.contains(&peer_id) {
+                                    peers.push(peer_id.clone());
+                                }
+                            }
+                            throughputs.raise_source_best_block(&peer_id, number);
                             let id = *peers_source_id_map.get(&peer_id).unwrap();
-                            sync.raise_source_best_block(id, announce.decode().header.number);
+                            sync.raise_source_best_block(id, number);
+                        },
+                        network_service::Event::GrandpaCommit { chain_index, target_number, scale_encoded_justification, .. }
+                            if chain_index == network_chain_index
+                                && target_number > sync.finalized_block_header().number =>
+                        {
+                            pending_commit = Some((target_number, scale_encoded_justification));
                         },
                         // Different chain index.
                         _ => {}
                     }
                 },
 
-                (request_id, result) = block_requests_finished.select_next_some() => {
-                    // `result` is an error if the block request got cancelled by the sync state
+                message = from_foreground.select_next_some() => {
+                    match message {
+                        ToBackground::StorageQuery { query, send_back } => {
+                            let finalized_header = sync.finalized_block_header();
+                            let answer = crate::storage_query::answer(
+                                &query,
+                                &crate::storage_query::FinalizedState {
+                                    block_number: finalized_header.number,
+                                    block_hash: finalized_header.hash(),
+                                    storage: &finalized_block_storage,
+                                    metadata: &finalized_metadata,
+                                },
+                            );
+                            let _ = send_back.send(answer);
+                        }
+                        ToBackground::RuntimeCall { function, parameter, send_back } => {
+                            let finalized_header = sync.finalized_block_header();
+                            let result = crate::runtime_call::run(
+                                finalized_runtime.clone(),
+                                &finalized_block_storage,
+                                finalized_header.state_root,
+                                &function,
+                                &parameter,
+                            )
+                            .map(|output| RuntimeCallOutput {
+                                block_number: finalized_header.number,
+                                block_hash: finalized_header.hash(),
+                                output,
+                            });
+                            let _ = send_back.send(result);
+                        }
+                        ToBackground::WatchStoragePrefix(prefix) => {
+                            watched_storage_prefixes.insert(prefix);
+                        }
+                        ToBackground::UnwatchStoragePrefix(prefix) => {
+                            watched_storage_prefixes.remove(&prefix);
+                        }
+                        ToBackground::JsonRpc { request } => {
+                            json_rpc_service.handle_request(
+                                &request,
+                                &crate::json_rpc_service::ChainState {
+                                    finalized_header: sync.finalized_block_header(),
+                                    best_header: sync.best_block_header(),
+                                    finalized_storage: &finalized_block_storage,
+                                    finalized_metadata: &finalized_metadata,
+                                    finalized_runtime_version: &finalized_runtime_version,
+                                    peers: peers_source_id_map.len(),
+                                    is_syncing: sync.best_block_number()
+                                        < throughputs.highest_best_block().unwrap_or(0),
+                                },
+                            );
+                        }
+                    }
+                },
+
+                (request_id, sources, download) = block_requests_finished.select_next_some() => {
+                    // `download` is an error if the block request got cancelled by the sync state
                     // machine.
-                    // TODO: clarify this piece of code
-                    if let Ok(result) = result {
-                        let result = result.map_err(|_| ());
-                        let _ = sync.finish_request(request_id, result.map(|v| v.into_iter().map(|block| optimistic::RequestSuccessBlock {
-                            scale_encoded_header: block.header.unwrap(), // TODO: don't unwrap
-                            scale_encoded_extrinsics: block.body.unwrap(), // TODO: don't unwrap
-                            scale_encoded_justification: block.justification,
-                            user_data: (),
-                        })).map_err(|()| optimistic::RequestFail::BlocksUnavailable));
+                    let download = match download {
+                        Ok(d) => d,
+                        Err(future::Aborted) => {
+                            for source in &sources {
+                                throughputs.request_cancelled(source);
+                            }
+                            continue;
+                        }
+                    };
+
+                    for (source, report) in sources.iter().zip(download.reports) {
+                        throughputs.request_finished(source, report);
+                    }
+
+                    for (source, err) in &download.failures {
+                        if err.is_recoverable() {
+                            log::debug!("Blocks request to {} failed: {}", source, err);
+                        } else {
+                            // The source is misbehaving. Disconnecting from it frees a slot for a
+                            // more useful peer.
+                            log::warn!("Blocks request to {} failed: {}", source, err);
+                            network_service.disconnect_peer(source, false).await;
+                        }
+                    }
+
+                    // In case of failure, the sync state machine stops using the source it has
+                    // chosen until all other sources have failed as well, and the same blocks are
+                    // requested again from a different source.
+                    // `download_blocks` guarantees that the headers and bodies are present.
+                    let _ = sync.finish_request(request_id, download.blocks.map(|v| v.into_iter().map(|block| optimistic::RequestSuccessBlock {
+                        scale_encoded_header: block.header.unwrap(),
+                        scale_encoded_extrinsics: block.body.unwrap(),
+                        scale_encoded_justification: block.justification,
+                        user_data: (),
+                    })).ok_or(optimistic::RequestFail::BlocksUnavailable));
+                },
+
+                (number, hash, runtime_spec, events) = tentative_events_requests.select_next_some() => {
+                    // The block might have been finalized or retracted in the meanwhile.
+                    let block = match tentative_blocks.iter_mut().find(|b| b.hash == hash) {
+                        Some(b) => b,
+                        None => continue,
+                    };
+
+                    match events {
+                        Ok(events) => {
+                            block.reported = true;
+                            ffi::best_block_notification(&ffi::BestBlockNotification::Tentative {
+                                number,
+                                hash: HexString(hash.to_vec()),
+                                runtime_spec,
+                                events: HexString(events),
+                            });
+                        }
+                        Err(err) => {
+                            // The events will be reported once the block is finalized.
+                            log::warn!("Failed to fetch events of block #{}: {}", number, err);
+                        }
                     }
                 },
             }
         }
     }
 }
+
+/// Returns the changes among `changes` to the storage keys that start with one of the
+/// `prefixes`, ordered by key.
+fn watched_storage_changes<'a>(
+    prefixes: &BTreeSet<Vec<u8>>,
+    changes: impl Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)>,
+) -> Vec<ffi::DatabaseSaveStorageChange> {
+    let mut storage_changes = changes
+        .filter(|(key, _)| prefixes.iter().any(|prefix| key.starts_with(prefix)))
+        .map(|(key, value)| ffi::DatabaseSaveStorageChange {
+            key: HexString(key.clone()),
+            value: value.clone().map(HexString),
+        })
+        .collect::<Vec<_>>();
+    storage_changes.sort_by(|a, b| a.key.0.cmp(&b.key.0));
+    storage_changes
+}
+
+/// Returns true if the changes to the balances can be decoded from the storage of the given
+/// runtime. Logs a warning otherwise.
+fn balances_supported_by(runtime_version: &executor::CoreVersion, metadata: &[u8]) -> bool {
+    let supported = crate::balances::is_supported(metadata);
+    if !supported {
+        log::warn!(
+            "The balances of runtime {} can't be decoded and aren't indexed",
+            runtime_version.decode().spec_version
+        );
+    }
+    supported
+}
+
+/// Outcome of [`download_blocks`].
+struct BlocksDownload {
+    /// Downloaded blocks, ordered by increasing height, or `None` if one of the requests has
+    /// failed, including its retry. The failures are then part of [`BlocksDownload::failures`].
+    blocks: Option<Vec<network::protocol::BlockData>>,
+    /// For each request, the number of blocks that have been received and the duration of the
+    /// request, or `None` if the request has failed.
+    reports: Vec<Option<(usize, Duration)>>,
+    /// Sources that have failed to answer a request, and the reason of the failure.
+    failures: Vec<(libp2p::PeerId, network_service::BlocksRequestError)>,
+}
+
+/// Downloads the blocks of the given requests in parallel, and concatenates them.
+///
+/// Requests that fail for a reason that is likely temporary are sent again to their
+/// [`throughput::PlannedRequest::fallback_source`], if any.
+///
+/// Only the blocks that form a chain, in other words whose parent is the previous block, are
+/// returned. The blocks that follow a block that isn't the child of the previous one are dropped.
+async fn download_blocks(
+    network_service: Arc<dyn Network>,
+    network_chain_index: usize,
+    requests: Vec<throughput::PlannedRequest>,
+) -> BlocksDownload {
+    let reports = Mutex::new(vec![None; requests.len()]);
+    let failures = Mutex::new(Vec::new());
+
+    let lists = future::try_join_all(requests.iter().enumerate().map(|(index, request)| {
+        let network_service = &network_service;
+        let (reports, failures) = (&reports, &failures);
+        async move {
+            let start = ffi::Instant::now();
+            let result = request_blocks(
+                network_service.clone(),
+                network_chain_index,
+                &request.source,
+                request.first_block_height,
+                request.num_blocks,
+            )
+            .await;
+
+            let err = match result {
+                Ok(blocks) => {
+                    reports.lock().unwrap()[index] = Some((blocks.len(), start.elapsed()));
+                    return Ok(blocks);
+                }
+                Err(err) => err,
+            };
+
+            let recoverable = err.is_recoverable();
+            failures.lock().unwrap().push((request.source.clone(), err));
+            let fallback_source = match &request.fallback_source {
+                Some(source) if recoverable => source,
+                _ => return Err(()),
+            };
+
+            request_blocks(
+                network_service.clone(),
+                network_chain_index,
+                fallback_source,
+                request.first_block_height,
+                request.num_blocks,
+            )
+            .await
+            .map_err(|err| {
+                failures
+                    .lock()
+                    .unwrap()
+                    .push((fallback_source.clone(), err))
+            })
+        }
+    }))
+    .await;
+
+    let blocks = lists.ok().map(|lists| {
+        let mut blocks = lists.into_iter().flatten().collect::<Vec<_>>();
+        blocks.truncate(linked_blocks_len(&blocks));
+        blocks
+    });
+
+    BlocksDownload {
+        blocks,
+        reports: reports.into_inner().unwrap(),
+        failures: failures.into_inner().unwrap(),
+    }
+}
+
+/// Requests `num_blocks` blocks starting at `first_block_height` from `source`.
+///
+/// On success, the headers and bodies of the returned blocks are guaranteed to be present.
+async fn request_blocks(
+    network_service: Arc<dyn Network>,
+    network_chain_index: usize,
+    source: &libp2p::PeerId,
+    first_block_height: u64,
+    num_blocks: NonZeroU32,
+) -> Result<Vec<network::protocol::BlockData>, network_service::BlocksRequestError> {
+    let num_blocks = usize::try_from(num_blocks.get()).unwrap();
+    let mut blocks = Vec::with_capacity(num_blocks);
+
+    // Sources might send back fewer blocks than requested, for example if the response would
+    // otherwise be too large. The missing blocks are requested again.
+    while blocks.len() < num_blocks {
+        let response = network_service
+            .clone()
+            .blocks_request(
+                source.clone(),
+                network_chain_index,
+                network::protocol::BlocksRequestConfig {
+                    start: network::protocol::BlocksRequestConfigStart::Number(
+                        NonZeroU64::new(first_block_height + blocks.len() as u64).unwrap(),
+                    ),
+                    desired_count: NonZeroU32::new(
+                        u32::try_from(num_blocks - blocks.len()).unwrap(),
+                    )
+                    .unwrap(),
+                    direction: network::protocol::BlocksRequestDirection::Ascending,
+                    fields: network::protocol::BlocksRequestFields {
+                        header: true,
+                        body: true,
+                        justification: true,
+                    },
+                },
+            )
+            .await?;
+        blocks.extend(response);
+    }
+
+    blocks.truncate(num_blocks);
+    Ok(blocks)
+}
+
+/// Returns the number of blocks at the start of `blocks` that form a chain, in other words where
+/// each block is the child of the previous one.
+///
+/// The requests of a download are answered by different sources, which might be on different
+/// forks or might be misbehaving.
+fn linked_blocks_len(blocks: &[network::protocol::BlockData]) -> usize {
+    blocks
+        .windows(2)
+        .position(|pair| {
+            // `request_blocks` guarantees that the headers are present.
+            let parent_hash =
+                header::hash_from_scale_encoded_header(pair[0].header.as_ref().unwrap());
+            header::decode(pair[1].header.as_ref().unwrap())
+                .map_or(true, |header| *header.parent_hash != parent_hash)
+        })
+        .map_or(blocks.len(), |position| position + 1)
+}
+
+/// Block reported, or being reported, through [`ffi::best_block_notification`].
+struct TentativeBlock {
+    number: u64,
+    hash: [u8; 32],
+    /// True if a `Tentative` notification has been sent for this block. If false, the events of
+    /// this block are still being fetched or couldn't be fetched.
+    reported: bool,
+}
+
+/// Removes from `tentative_blocks` all the blocks whose hash isn't in `best_chain`, and notifies
+/// the environment of the retraction of these blocks.
+fn retract_tentative_blocks(
+    tentative_blocks: &mut VecDeque<TentativeBlock>,
+    best_chain: &hashbrown::HashSet<[u8; 32], fnv::FnvBuildHasher>,
+) {
+    tentative_blocks.retain(|block| {
+        if best_chain.contains(&block.hash) {
+            return true;
+        }
+        if block.reported {
+            ffi::best_block_notification(&ffi::BestBlockNotification::Retract {
+                number: block.number,
+                hash: HexString(block.hash.to_vec()),
+            });
+        }
+        false
+    });
+}
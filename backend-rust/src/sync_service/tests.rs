@@ -0,0 +1,820 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tests running the sync service against a simulated network.
+//!
+//! The sync service starts at the genesis block of Westend and downloads blocks from the peers
+//! of a [`TestNetwork`], which serve blocks from memory with a configurable latency and
+//! behaviour. Time is virtual (see [`SimulatedHost`]), which makes the tests deterministic and
+//! fast regardless of the latencies and timeouts involved.
+//!
+//! Blocks are either synthetic (see [`synthetic_chain`]), in which case they fail verification,
+//! or valid Westend blocks built by [`TestChain`] on top of a modified genesis block.
+
+use super::*;
+use crate::{
+    ffi::simulated::{SimulatedHost, Tasks},
+    storage_query::twox_128,
+    test_chain::TestChain,
+};
+
+use smoldot::{chain_spec, header};
+use std::{collections::HashMap, sync::Mutex};
+
+/// Duration after which a [`Behaviour::Timeout`] request fails.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How a [`TestPeer`] answers blocks requests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Behaviour {
+    /// Answers with the requested blocks, or with an empty response if it doesn't have them.
+    Serve,
+    /// Never answers. The request fails after [`REQUEST_TIMEOUT`].
+    Timeout,
+    /// Refuses all requests after the latency.
+    Refuse,
+    /// Answers with an empty response, as if it didn't have any of the requested blocks.
+    Empty,
+    /// Answers with blocks that are missing a requested field.
+    Incomplete,
+}
+
+/// Peer of a [`TestNetwork`].
+#[derive(Clone)]
+struct TestPeer {
+    /// Chain of blocks of the peer, starting at block #1.
+    blocks: Vec<network::protocol::BlockData>,
+    /// Time it takes for the peer to answer a request.
+    latency: Duration,
+    behaviour: Behaviour,
+}
+
+impl TestPeer {
+    fn best_block(&self, genesis_hash: [u8; 32]) -> (u64, [u8; 32]) {
+        self.blocks
+            .last()
+            .map_or((0, genesis_hash), |b| (self.blocks.len() as u64, b.hash))
+    }
+}
+
+/// Implementation of [`Network`] whose peers are simulated.
+struct TestNetwork {
+    genesis_hash: [u8; 32],
+    peers: Mutex<HashMap<libp2p::PeerId, TestPeer>>,
+    events: Mutex<mpsc::Sender<network_service::Event>>,
+    /// List of blocks requests that have been started: target, first block and number of blocks.
+    requests: Mutex<Vec<(libp2p::PeerId, u64, u32)>>,
+    /// List of peers passed to [`Network::disconnect_peer`].
+    disconnected: Mutex<Vec<libp2p::PeerId>>,
+}
+
+impl TestNetwork {
+    /// Connects a new peer, and returns its identity.
+    fn add_peer(&self, peer: TestPeer) -> libp2p::PeerId {
+        let peer_id =
+            libp2p::PeerId::from_public_key(&libp2p::peer_id::PublicKey::Ed25519(rand::random()));
+        let (best_block_number, best_block_hash) = peer.best_block(self.genesis_hash);
+        self.peers.lock().unwrap().insert(peer_id.clone(), peer);
+        self.send_event(network_service::Event::Connected {
+            peer_id: peer_id.clone(),
+            chain_index: 0,
+            best_block_number,
+            best_block_hash,
+        });
+        peer_id
+    }
+
+    /// Replaces the chain of the given peer, connecting it again if it has been disconnected in
+    /// the meanwhile. The peer reconnects in order to report its new best block.
+    fn reorg(&self, peer_id: &libp2p::PeerId, peer: TestPeer) {
+        let (best_block_number, best_block_hash) = peer.best_block(self.genesis_hash);
+        let was_connected = self
+            .peers
+            .lock()
+            .unwrap()
+            .insert(peer_id.clone(), peer)
+            .is_some();
+
+        if was_connected {
+            self.send_event(network_service::Event::Disconnected {
+                peer_id: peer_id.clone(),
+                chain_index: 0,
+            });
+        }
+        self.send_event(network_service::Event::Connected {
+            peer_id: peer_id.clone(),
+            chain_index: 0,
+            best_block_number,
+            best_block_hash,
+        });
+    }
+
+    fn send_event(&self, event: network_service::Event) {
+        self.events.lock().unwrap().try_send(event).unwrap();
+    }
+
+    fn requests(&self) -> Vec<(libp2p::PeerId, u64, u32)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn disconnected(&self) -> Vec<libp2p::PeerId> {
+        self.disconnected.lock().unwrap().clone()
+    }
+}
+
+impl Network for TestNetwork {
+    fn blocks_request(
+        self: Arc<Self>,
+        target: libp2p::PeerId,
+        _: usize,
+        config: network::protocol::BlocksRequestConfig,
+    ) -> future::BoxFuture<
+        'static,
+        Result<Vec<network::protocol::BlockData>, network_service::BlocksRequestError>,
+    > {
+        let first = match config.start {
+            network::protocol::BlocksRequestConfigStart::Number(n) => n.get(),
+            network::protocol::BlocksRequestConfigStart::Hash(_) => unimplemented!(),
+        };
+        self.requests
+            .lock()
+            .unwrap()
+            .push((target.clone(), first, config.desired_count.get()));
+
+        let peer = self.peers.lock().unwrap().get(&target).cloned();
+
+        Box::pin(async move {
+            let peer = match peer {
+                Some(p) => p,
+                None => {
+                    return Err(network_service::BlocksRequestError::Refused(
+                        libp2p::RequestError::NotConnected,
+                    ))
+                }
+            };
+
+            match peer.behaviour {
+                Behaviour::Serve => {
+                    ffi::Delay::new(peer.latency).await;
+                    let blocks = peer
+                        .blocks
+                        .into_iter()
+                        .skip(usize::try_from(first - 1).unwrap())
+                        .take(usize::try_from(config.desired_count.get()).unwrap())
+                        .collect::<Vec<_>>();
+                    if blocks.is_empty() {
+                        Err(network_service::BlocksRequestError::Empty)
+                    } else {
+                        Ok(blocks)
+                    }
+                }
+                Behaviour::Timeout => {
+                    ffi::Delay::new(REQUEST_TIMEOUT).await;
+                    Err(network_service::BlocksRequestError::Timeout)
+                }
+                Behaviour::Refuse => {
+                    ffi::Delay::new(peer.latency).await;
+                    Err(network_service::BlocksRequestError::Refused(
+                        libp2p::RequestError::NotConnected,
+                    ))
+                }
+                Behaviour::Empty => {
+                    ffi::Delay::new(peer.latency).await;
+                    Err(network_service::BlocksRequestError::Empty)
+                }
+                Behaviour::Incomplete => {
+                    ffi::Delay::new(peer.latency).await;
+                    Err(network_service::BlocksRequestError::Incomplete)
+                }
+            }
+        })
+    }
+
+    fn storage_proof_request(
+        self: Arc<Self>,
+        _: libp2p::PeerId,
+        _: usize,
+        _: network::protocol::StorageProofRequestConfig<vec::IntoIter<Vec<u8>>>,
+    ) -> future::BoxFuture<'static, Result<Vec<Vec<u8>>, network::service::StorageProofRequestError>>
+    {
+        Box::pin(future::ready(Err(
+            network::service::StorageProofRequestError::Request(libp2p::RequestError::NotConnected),
+        )))
+    }
+
+    fn set_local_grandpa_state(
+        &self,
+        _: usize,
+        _: network_service::GrandpaState,
+    ) -> future::BoxFuture<'_, ()> {
+        Box::pin(future::ready(()))
+    }
+
+    fn disconnect_peer<'a>(
+        &'a self,
+        peer_id: &'a libp2p::PeerId,
+        _: bool,
+    ) -> future::BoxFuture<'a, ()> {
+        self.disconnected.lock().unwrap().push(peer_id.clone());
+        if self.peers.lock().unwrap().remove(peer_id).is_some() {
+            self.send_event(network_service::Event::Disconnected {
+                peer_id: peer_id.clone(),
+                chain_index: 0,
+            });
+        }
+        Box::pin(future::ready(()))
+    }
+
+    fn address_book(
+        &self,
+        _: usize,
+    ) -> future::BoxFuture<'_, Vec<network_service::AddressBookEntry>> {
+        Box::pin(future::ready(Vec::new()))
+    }
+
+    fn node_key(&self) -> &[u8; 32] {
+        &[0; 32]
+    }
+}
+
+/// Builds a chain of `len` blocks on top of the genesis block. Blocks don't contain any valid
+/// consensus information, and thus always fail verification.
+///
+/// `fork` is included in the blocks, so that chains built with a different `fork` differ.
+fn synthetic_chain(
+    genesis_hash: [u8; 32],
+    len: usize,
+    fork: u8,
+) -> Vec<network::protocol::BlockData> {
+    let mut blocks = Vec::<network::protocol::BlockData>::with_capacity(len);
+    for number in 1..=len as u64 {
+        let parent_hash = blocks.last().map_or(genesis_hash, |b| b.hash);
+        let header = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[fork; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        };
+
+        blocks.push(network::protocol::BlockData {
+            hash: header.hash(),
+            header: Some(header.scale_encoding_vec()),
+            body: Some(Vec::new()),
+            justification: None,
+        });
+    }
+    blocks
+}
+
+/// Sync service running against a [`TestNetwork`].
+struct Harness {
+    host: Arc<SimulatedHost>,
+    network: Arc<TestNetwork>,
+    tasks: Tasks,
+}
+
+impl Harness {
+    /// Starts a sync service at the genesis block of Westend.
+    fn new() -> Self {
+        Self::with_specification(include_str!("../../../src/westend.json"))
+    }
+
+    /// Starts a sync service at the genesis block of the given chain specification.
+    fn with_specification(specification: &str) -> Self {
+        Self::with_max_request_size(specification, NonZeroU32::new(128).unwrap())
+    }
+
+    /// Same as [`Harness::with_specification`], but blocks are requested by ranges of at most
+    /// `max_blocks_request_size` blocks.
+    fn with_max_request_size(specification: &str, max_blocks_request_size: NonZeroU32) -> Self {
+        let host = SimulatedHost::new(Duration::from_secs(1_600_000_000));
+        host.install();
+
+        let chain_spec = chain_spec::ChainSpec::from_json_bytes(specification).unwrap();
+        let chain_information =
+            chain_information::ChainInformation::from_genesis_storage(chain_spec.genesis_storage())
+                .unwrap();
+        let finalized_storage = chain_spec
+            .genesis_storage()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect();
+
+        let (events_tx, events_rx) = mpsc::channel(16);
+        let network = Arc::new(TestNetwork {
+            genesis_hash: chain_information.finalized_block_header.hash(),
+            peers: Mutex::new(HashMap::new()),
+            events: Mutex::new(events_tx),
+            requests: Mutex::new(Vec::new()),
+            disconnected: Mutex::new(Vec::new()),
+        });
+
+        let json_rpc_service =
+            crate::json_rpc_service::JsonRpcService::new(crate::json_rpc_service::Config {
+                chain_name: chain_spec.name().to_owned(),
+                chain_properties: chain_spec.properties().to_owned(),
+                genesis_block_hash: chain_information.finalized_block_header.hash(),
+            });
+
+        let (new_tasks_tx, mut new_tasks) = mpsc::unbounded();
+        futures::executor::block_on(SyncService::new(Config {
+            tasks_executor: Box::new(move |task| new_tasks_tx.unbounded_send(task).unwrap()),
+            chain_information,
+            finalized_storage,
+            report_finalized_metadata: true,
+            index_balances: false,
+            watched_storage_prefixes: BTreeSet::new(),
+            json_rpc_service,
+            network_service: (network.clone(), 0),
+            min_blocks_request_size: NonZeroU32::new(16).unwrap(),
+            max_blocks_request_size,
+            stall_timeout: Duration::from_secs(90),
+            randomness_seed: 0,
+            network_events_receiver: events_rx,
+        }));
+
+        // The sync service spawns its background task during its initialization.
+        let mut tasks = Tasks::default();
+        while let Ok(Some(task)) = new_tasks.try_next() {
+            tasks.spawn(task);
+        }
+
+        Harness {
+            host,
+            network,
+            tasks,
+        }
+    }
+
+    fn genesis_hash(&self) -> [u8; 32] {
+        self.network.genesis_hash
+    }
+
+    /// Runs the sync service until `duration` of virtual time has elapsed.
+    fn run_for(&mut self, duration: Duration) {
+        self.host.run_for(&mut self.tasks, duration)
+    }
+}
+
+#[test]
+fn invalid_blocks_are_not_saved() {
+    let mut harness = Harness::new();
+    let blocks = synthetic_chain(harness.genesis_hash(), 256, 0);
+    harness.network.add_peer(TestPeer {
+        blocks,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+
+    harness.run_for(Duration::from_secs(60));
+
+    assert!(!harness.network.requests().is_empty());
+    assert!(harness.host.take_database_saves().is_empty());
+    assert_eq!(harness.host.best_block_number().unwrap_or(0), 0);
+}
+
+#[test]
+fn misbehaving_peer_is_disconnected() {
+    let mut harness = Harness::new();
+    let blocks = synthetic_chain(harness.genesis_hash(), 64, 0);
+    let peer_id = harness.network.add_peer(TestPeer {
+        blocks,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Incomplete,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+
+    assert_eq!(harness.network.disconnected(), vec![peer_id]);
+    assert!(harness.host.take_database_saves().is_empty());
+}
+
+#[test]
+fn slow_peer_is_kept() {
+    let mut harness = Harness::new();
+    let blocks = synthetic_chain(harness.genesis_hash(), 64, 0);
+    let peer_id = harness.network.add_peer(TestPeer {
+        blocks,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Timeout,
+    });
+
+    harness.run_for(Duration::from_secs(60));
+
+    // The failed requests are started again.
+    assert!(
+        harness
+            .network
+            .requests()
+            .iter()
+            .filter(|(target, _, _)| *target == peer_id)
+            .count()
+            >= 2
+    );
+    assert!(harness.network.disconnected().is_empty());
+    assert!(harness.host.take_database_saves().is_empty());
+}
+
+#[test]
+fn peer_without_blocks_is_kept() {
+    let mut harness = Harness::new();
+    let blocks = synthetic_chain(harness.genesis_hash(), 64, 0);
+    let peer_id = harness.network.add_peer(TestPeer {
+        blocks,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Empty,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+
+    assert!(
+        harness
+            .network
+            .requests()
+            .iter()
+            .filter(|(target, _, _)| *target == peer_id)
+            .count()
+            >= 2
+    );
+    assert!(harness.network.disconnected().is_empty());
+    assert!(harness.host.take_database_saves().is_empty());
+}
+
+#[test]
+fn failure_is_attributed_to_failing_peer() {
+    let mut harness = Harness::new();
+    let blocks = synthetic_chain(harness.genesis_hash(), 256, 0);
+    let serving_peer = harness.network.add_peer(TestPeer {
+        blocks: blocks.clone(),
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+    let misbehaving_peer = harness.network.add_peer(TestPeer {
+        blocks,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Incomplete,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+
+    assert_eq!(
+        harness.network.disconnected(),
+        vec![misbehaving_peer.clone()]
+    );
+
+    // The blocks requested from the misbehaving peer are requested again from the other one.
+    let requests = harness.network.requests();
+    assert!(requests
+        .iter()
+        .any(|(target, _, _)| *target == misbehaving_peer));
+    for (_, first, _) in requests
+        .iter()
+        .filter(|(target, _, _)| *target == misbehaving_peer)
+    {
+        assert!(requests
+            .iter()
+            .any(|(target, f, _)| *target == serving_peer && f == first));
+    }
+}
+
+#[test]
+fn refused_request_is_sent_to_other_peer() {
+    let mut harness = Harness::new();
+    let blocks = synthetic_chain(harness.genesis_hash(), 256, 0);
+    let serving_peer = harness.network.add_peer(TestPeer {
+        blocks: blocks.clone(),
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+    let refusing_peer = harness.network.add_peer(TestPeer {
+        blocks,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Refuse,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+    let refused = harness.network.requests();
+    // Leaves time for the requests that are refused at the very end to be sent again.
+    harness.run_for(Duration::from_secs(1));
+    let requests = harness.network.requests();
+
+    // The refusing peer is kept, and each request it refuses is immediately sent again to the
+    // other peer.
+    assert!(harness.network.disconnected().is_empty());
+    assert!(refused
+        .iter()
+        .any(|(target, _, _)| *target == refusing_peer));
+    for (index, (_, first, num)) in refused
+        .iter()
+        .enumerate()
+        .filter(|(_, (target, _, _))| *target == refusing_peer)
+    {
+        assert!(requests[index + 1..]
+            .iter()
+            .any(|(target, f, n)| *target == serving_peer && f == first && n == num));
+    }
+}
+
+#[test]
+fn unlinked_blocks_are_dropped() {
+    let harness = Harness::new();
+    let fork_a = synthetic_chain(harness.genesis_hash(), 20, 0);
+    let fork_b = synthetic_chain(harness.genesis_hash(), 20, 1);
+
+    assert_eq!(linked_blocks_len(&fork_a), 20);
+
+    let mixed = fork_a[..10]
+        .iter()
+        .chain(&fork_b[10..])
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(linked_blocks_len(&mixed), 10);
+}
+
+#[test]
+fn refusing_peer_is_kept() {
+    let mut harness = Harness::new();
+    let blocks = synthetic_chain(harness.genesis_hash(), 64, 0);
+    harness.network.add_peer(TestPeer {
+        blocks,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Refuse,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+
+    assert!(!harness.network.requests().is_empty());
+    assert!(harness.network.disconnected().is_empty());
+    assert!(harness.host.take_database_saves().is_empty());
+}
+
+#[test]
+fn reorg_restarts_download() {
+    let mut harness = Harness::new();
+    let fork_a = synthetic_chain(harness.genesis_hash(), 64, 0);
+    let fork_b = synthetic_chain(harness.genesis_hash(), 96, 1);
+    let peer_id = harness.network.add_peer(TestPeer {
+        blocks: fork_a,
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+
+    harness.run_for(Duration::from_secs(5));
+    let num_requests_before = harness.network.requests().len();
+    assert_ne!(num_requests_before, 0);
+
+    harness.network.reorg(
+        &peer_id,
+        TestPeer {
+            blocks: fork_b,
+            latency: Duration::from_millis(200),
+            behaviour: Behaviour::Serve,
+        },
+    );
+    harness.run_for(Duration::from_secs(5));
+
+    // The blocks of the second fork are requested from the start.
+    assert!(harness
+        .network
+        .requests()
+        .iter()
+        .skip(num_requests_before)
+        .any(|(target, first, _)| *target == peer_id && *first == 1));
+    assert!(harness.host.take_database_saves().is_empty());
+}
+
+#[test]
+fn stall_is_reported_without_sources() {
+    let mut harness = Harness::new();
+
+    harness.run_for(Duration::from_secs(100));
+
+    let statuses = harness.host.take_sync_statuses();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0]["state"], "stalled");
+    assert_eq!(statuses[0]["cause"], "no_source");
+    assert_eq!(statuses[0]["best_block_number"], 0);
+    assert!(harness.host.take_database_saves().is_empty());
+
+    // The stall keeps being reported, with a duration measured from the last progress.
+    harness.run_for(Duration::from_secs(90));
+    let statuses = harness.host.take_sync_statuses();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0]["state"], "stalled");
+    assert!(statuses[0]["stalled_for_ms"].as_u64().unwrap() >= 180_000);
+}
+
+#[test]
+fn watched_storage_changes_are_filtered() {
+    let prefixes = [b"foo".to_vec(), b"bar".to_vec()]
+        .iter()
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let changes = vec![
+        (b"foobar".to_vec(), Some(b"1".to_vec())),
+        (b"baz".to_vec(), Some(b"2".to_vec())),
+        (b"bar".to_vec(), None),
+        (b"fo".to_vec(), Some(b"3".to_vec())),
+    ];
+
+    let watched = watched_storage_changes(&prefixes, changes.iter().map(|(k, v)| (k, v)));
+    assert_eq!(watched.len(), 2);
+    assert_eq!(watched[0].key.0, b"bar");
+    assert!(watched[0].value.is_none());
+    assert_eq!(watched[1].key.0, b"foobar");
+    assert_eq!(watched[1].value.as_ref().unwrap().0, b"1");
+}
+
+#[test]
+fn tentative_blocks_outside_of_best_chain_are_retracted() {
+    let host = SimulatedHost::new(Duration::from_secs(1_600_000_000));
+    host.install();
+
+    let mut tentative_blocks = [(1, [1; 32], true), (2, [2; 32], false), (2, [3; 32], true)]
+        .iter()
+        .map(|&(number, hash, reported)| TentativeBlock {
+            number,
+            hash,
+            reported,
+        })
+        .collect::<VecDeque<_>>();
+    let best_chain = [[1; 32], [3; 32]]
+        .iter()
+        .cloned()
+        .collect::<hashbrown::HashSet<_, fnv::FnvBuildHasher>>();
+
+    // Blocks are retracted according to their hash, even when they are below the best block.
+    retract_tentative_blocks(&mut tentative_blocks, &best_chain);
+    assert_eq!(
+        tentative_blocks.iter().map(|b| b.hash).collect::<Vec<_>>(),
+        vec![[1; 32], [3; 32]]
+    );
+    // Only blocks that have been reported are notified as retracted.
+    assert!(host.take_best_block_notifications().is_empty());
+
+    retract_tentative_blocks(&mut tentative_blocks, &Default::default());
+    assert!(tentative_blocks.is_empty());
+    let notifications = host.take_best_block_notifications();
+    assert_eq!(notifications.len(), 2);
+    for (notification, number) in notifications.iter().zip(&[1, 2]) {
+        assert_eq!(notification["number"], *number);
+    }
+}
+
+#[test]
+fn valid_blocks_are_saved() {
+    let chain = TestChain::build(8, 1_600_000_000 - 60, &[4, 8]);
+    let mut harness = Harness::with_specification(&chain.specification);
+    assert_eq!(harness.genesis_hash(), chain.genesis_hash);
+    harness.network.add_peer(TestPeer {
+        blocks: chain.blocks.clone(),
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+
+    // Blocks are saved when they are finalized, in other words by batches ending with a
+    // justified block.
+    let saves = harness.host.take_database_saves();
+    assert_eq!(saves.len(), 2);
+    let events_key = [twox_128(b"System"), twox_128(b"Events")].concat();
+    for (save, numbers) in saves.iter().zip(&[1..=4, 5..=8]) {
+        let blocks = save["blocks"].as_array().unwrap();
+        assert_eq!(blocks.len(), 4);
+        for (block, number) in blocks.iter().zip(numbers.clone()) {
+            assert_eq!(block["number"], number);
+            assert_eq!(block["runtime_spec"], 1);
+            let events = chain.storage_value(number, &events_key).unwrap();
+            assert_eq!(
+                block["events"],
+                serde_json::to_value(HexString(events)).unwrap()
+            );
+        }
+    }
+
+    // The metadata of the genesis runtime is only reported once.
+    assert_eq!(saves[0]["new_metadata"].as_array().unwrap().len(), 1);
+    assert!(saves[1]["new_metadata"].as_array().unwrap().is_empty());
+
+    assert_eq!(harness.host.best_block_number(), Some(8));
+    assert!(harness.network.disconnected().is_empty());
+}
+
+#[test]
+fn unfinalized_blocks_are_not_saved() {
+    let chain = TestChain::build(8, 1_600_000_000 - 60, &[]);
+    let mut harness = Harness::with_specification(&chain.specification);
+    harness.network.add_peer(TestPeer {
+        blocks: chain.blocks.clone(),
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+
+    // The blocks are downloaded, but are never finalized.
+    assert!(!harness.network.requests().is_empty());
+    assert!(harness.host.take_database_saves().is_empty());
+    assert_eq!(harness.host.best_block_number(), None);
+}
+
+#[test]
+fn grandpa_commits_finalize_blocks_below_the_best_block() {
+    // The state machine requests blocks by ranges of 16 blocks. The peer first serves exactly
+    // one range.
+    let chain = TestChain::build(20, 1_600_000_000 - 180, &[]);
+    let mut harness =
+        Harness::with_max_request_size(&chain.specification, NonZeroU32::new(16).unwrap());
+    let peer_id = harness.network.add_peer(TestPeer {
+        blocks: chain.blocks[..16].to_vec(),
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+    assert!(harness.host.take_database_saves().is_empty());
+
+    let send_commit = |harness: &mut Harness, target: u64| {
+        harness
+            .network
+            .send_event(network_service::Event::GrandpaCommit {
+                peer_id: peer_id.clone(),
+                chain_index: 0,
+                target_number: target,
+                scale_encoded_justification: chain.justification(target),
+            });
+        harness.run_for(Duration::from_secs(1));
+
+        let saves = harness.host.take_database_saves();
+        assert_eq!(saves.len(), 1);
+        saves[0]["blocks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| b["number"].as_u64().unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    // The commit finalizes the blocks up to its target, while the blocks above it remain in the
+    // best chain.
+    assert_eq!(send_commit(&mut harness, 12), (1..=12).collect::<Vec<_>>());
+    assert_eq!(harness.host.best_block_number(), Some(16));
+
+    // The next blocks are verified on top of the best block, whose storage differs from the one
+    // of the new finalized block.
+    harness.network.reorg(
+        &peer_id,
+        TestPeer {
+            blocks: chain.blocks.clone(),
+            latency: Duration::from_millis(200),
+            behaviour: Behaviour::Serve,
+        },
+    );
+    harness.run_for(Duration::from_secs(10));
+    assert_eq!(send_commit(&mut harness, 20), (13..=20).collect::<Vec<_>>());
+    assert_eq!(harness.host.best_block_number(), Some(20));
+    assert!(harness.network.disconnected().is_empty());
+}
+
+#[test]
+fn invalid_grandpa_commit_is_ignored() {
+    let chain = TestChain::build(8, 1_600_000_000 - 60, &[]);
+    let other_chain = TestChain::build(8, 1_600_000_000 - 120, &[]);
+    let mut harness = Harness::with_specification(&chain.specification);
+    let peer_id = harness.network.add_peer(TestPeer {
+        blocks: chain.blocks.clone(),
+        latency: Duration::from_millis(200),
+        behaviour: Behaviour::Serve,
+    });
+
+    harness.run_for(Duration::from_secs(10));
+
+    // The commit targets a block that isn't part of the chain.
+    harness
+        .network
+        .send_event(network_service::Event::GrandpaCommit {
+            peer_id,
+            chain_index: 0,
+            target_number: 5,
+            scale_encoded_justification: other_chain.justification(5),
+        });
+    harness.run_for(Duration::from_secs(1));
+
+    assert!(harness.host.take_database_saves().is_empty());
+    assert!(harness.network.disconnected().is_empty());
+}
@@ -0,0 +1,255 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Measurement of the speed at which sources send us blocks.
+//!
+//! Used in order to determine how many blocks to request at once from each source, and which
+//! source to request blocks from.
+
+use core::{convert::TryFrom as _, num::NonZeroU32, time::Duration};
+use smoldot::libp2p::PeerId;
+use std::collections::HashMap;
+
+/// Duration that a blocks request should ideally take. The number of blocks requested from a
+/// source is adjusted so that requests take approximately this duration.
+const TARGET_REQUEST_DURATION: Duration = Duration::from_secs(2);
+
+/// Weight of the latest measurement when updating the throughput of a source. Must be between
+/// 0.0 and 1.0.
+const MEASUREMENT_WEIGHT: f64 = 0.25;
+
+/// Throughput assumed for sources that haven't answered any request yet.
+///
+/// This value is intentionally optimistic so that new sources get the chance to be picked.
+const DEFAULT_BLOCKS_PER_SEC: f64 = 64.0;
+
+/// Collection of sources and their performances.
+pub(super) struct Throughputs {
+    sources: HashMap<PeerId, Source, fnv::FnvBuildHasher>,
+    min_request_size: NonZeroU32,
+    max_request_size: NonZeroU32,
+}
+
+struct Source {
+    /// Estimated number of blocks per second this source sends us. `None` if unknown.
+    blocks_per_sec: Option<f64>,
+    /// Height of the best block this source has reported.
+    best_block_number: u64,
+    /// Number of requests towards this source that are currently in progress.
+    in_flight: u32,
+}
+
+impl Throughputs {
+    /// Creates a new empty collection. Request sizes are always clamped between the two bounds
+    /// passed as parameter.
+    pub(super) fn new(min_request_size: NonZeroU32, max_request_size: NonZeroU32) -> Self {
+        debug_assert!(min_request_size <= max_request_size);
+        Throughputs {
+            sources: HashMap::default(),
+            min_request_size,
+            max_request_size,
+        }
+    }
+
+    /// Returns the maximum number of blocks that are requested at once from a source.
+    pub(super) fn max_request_size(&self) -> NonZeroU32 {
+        self.max_request_size
+    }
+
+    /// Adds a new source to the collection, or updates its best block if it is already present.
+    pub(super) fn add_source(&mut self, peer_id: PeerId, best_block_number: u64) {
+        self.sources
+            .entry(peer_id)
+            .or_insert(Source {
+                blocks_per_sec: None,
+                best_block_number,
+                in_flight: 0,
+            })
+            .best_block_number = best_block_number;
+    }
+
+    /// Removes a source from the collection.
+    pub(super) fn remove_source(&mut self, peer_id: &PeerId) {
+        self.sources.remove(peer_id);
+    }
+
+    /// Updates the best block of a source. Has no effect if the source is unknown or if its best
+    /// block is already higher.
+    pub(super) fn raise_source_best_block(&mut self, peer_id: &PeerId, best_block_number: u64) {
+        if let Some(source) = self.sources.get_mut(peer_id) {
+            source.best_block_number = source.best_block_number.max(best_block_number);
+        }
+    }
+
+    /// Returns the highest best block reported by the sources of the collection, or `None` if
+    /// the collection is empty.
+    pub(super) fn highest_best_block(&self) -> Option<u64> {
+        self.sources.values().map(|s| s.best_block_number).max()
+    }
+
+    /// Splits the download of `num_blocks` blocks starting at `first_block_height` into multiple
+    /// requests, and assigns each request to a source.
+    ///
+    /// `default_source` is used for the first block if no source is known to have it. The blocks
+    /// that follow a block that no source is known to have aren't requested at all, in which case
+    /// the returned requests cover fewer than `num_blocks` blocks.
+    ///
+    /// Each request returned by this function is considered as in progress until
+    /// [`Throughputs::request_finished`] or [`Throughputs::request_cancelled`] is called. Its
+    /// [`PlannedRequest::fallback_source`] isn't.
+    pub(super) fn plan_requests(
+        &mut self,
+        default_source: &PeerId,
+        first_block_height: u64,
+        num_blocks: u32,
+    ) -> Vec<PlannedRequest> {
+        let mut requests = Vec::new();
+        let mut height = first_block_height;
+        let end = first_block_height + u64::from(num_blocks);
+
+        while height < end {
+            // Pick, among the sources that have the next block, the one that is expected to
+            // answer the soonest.
+            let source = match self
+                .sources
+                .iter()
+                .filter(|(_, s)| s.best_block_number >= height)
+                .max_by(|(_, a), (_, b)| {
+                    a.score()
+                        .partial_cmp(&b.score())
+                        .unwrap_or(core::cmp::Ordering::Equal)
+                })
+                .map(|(peer_id, _)| peer_id.clone())
+            {
+                Some(source) => source,
+                None if requests.is_empty() => default_source.clone(),
+                // Requesting blocks that no source has would only result in empty responses.
+                None => break,
+            };
+
+            let num_blocks = {
+                let remaining = u32::try_from(end - height).unwrap_or(u32::MAX);
+                let available = self.sources.get(&source).map_or(u32::MAX, |s| {
+                    u32::try_from(s.best_block_number.saturating_sub(height) + 1)
+                        .unwrap_or(u32::MAX)
+                });
+                self.request_size(&source).min(remaining).min(available)
+            };
+
+            // Pick, among the other sources that have all the blocks of the request, the one that
+            // is expected to answer the soonest.
+            let last_block_height = height + u64::from(num_blocks) - 1;
+            let fallback_source = self
+                .sources
+                .iter()
+                .filter(|(peer_id, s)| {
+                    **peer_id != source && s.best_block_number >= last_block_height
+                })
+                .max_by(|(_, a), (_, b)| {
+                    a.score()
+                        .partial_cmp(&b.score())
+                        .unwrap_or(core::cmp::Ordering::Equal)
+                })
+                .map(|(peer_id, _)| peer_id.clone());
+
+            if let Some(source) = self.sources.get_mut(&source) {
+                source.in_flight += 1;
+            }
+
+            requests.push(PlannedRequest {
+                source,
+                fallback_source,
+                first_block_height: height,
+                num_blocks: NonZeroU32::new(num_blocks).unwrap(),
+            });
+
+            height += u64::from(num_blocks);
+        }
+
+        requests
+    }
+
+    /// Reports that a request previously returned by [`Throughputs::plan_requests`] is over.
+    ///
+    /// If the request has succeeded, `outcome` must contain the number of blocks that have been
+    /// received and the duration of the request. A failed request must be reported with `None`.
+    /// See also [`Throughputs::request_cancelled`].
+    pub(super) fn request_finished(
+        &mut self,
+        peer_id: &PeerId,
+        outcome: Option<(usize, Duration)>,
+    ) {
+        let source = match self.sources.get_mut(peer_id) {
+            Some(s) => s,
+            None => return,
+        };
+
+        source.in_flight = source.in_flight.saturating_sub(1);
+
+        let measurement = match outcome {
+            Some((num_blocks, duration)) => num_blocks as f64 / duration.as_secs_f64().max(0.001),
+            // Failures are counted as if the source had been twice as slow as expected.
+            None => source.blocks_per_sec.unwrap_or(DEFAULT_BLOCKS_PER_SEC) / 2.0,
+        };
+
+        source.blocks_per_sec = Some(match source.blocks_per_sec {
+            Some(previous) => {
+                previous * (1.0 - MEASUREMENT_WEIGHT) + measurement * MEASUREMENT_WEIGHT
+            }
+            None => measurement,
+        });
+    }
+
+    /// Reports that a request previously returned by [`Throughputs::plan_requests`] has been
+    /// cancelled before it could finish.
+    pub(super) fn request_cancelled(&mut self, peer_id: &PeerId) {
+        if let Some(source) = self.sources.get_mut(peer_id) {
+            source.in_flight = source.in_flight.saturating_sub(1);
+        }
+    }
+
+    /// Returns the number of blocks to request at once from the given source.
+    fn request_size(&self, peer_id: &PeerId) -> u32 {
+        let blocks_per_sec = match self.sources.get(peer_id).and_then(|s| s.blocks_per_sec) {
+            Some(b) => b,
+            None => return self.min_request_size.get(),
+        };
+
+        let ideal = (blocks_per_sec * TARGET_REQUEST_DURATION.as_secs_f64()) as u32;
+        ideal
+            .max(self.min_request_size.get())
+            .min(self.max_request_size.get())
+    }
+}
+
+impl Source {
+    /// Number that is higher for sources that are expected to answer a request quickly.
+    fn score(&self) -> f64 {
+        self.blocks_per_sec.unwrap_or(DEFAULT_BLOCKS_PER_SEC) / f64::from(1 + self.in_flight)
+    }
+}
+
+/// Request planned by [`Throughputs::plan_requests`].
+#[derive(Debug, Clone)]
+pub(super) struct PlannedRequest {
+    pub(super) source: PeerId,
+    /// Source to send the request to if [`PlannedRequest::source`] fails, or `None` if no other
+    /// source is known to have all the requested blocks.
+    pub(super) fallback_source: Option<PeerId>,
+    pub(super) first_block_height: u64,
+    pub(super) num_blocks: NonZeroU32,
+}
@@ -0,0 +1,119 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Detection of a lack of progress of the syncing.
+
+use crate::ffi;
+
+use core::time::Duration;
+
+/// Tracks the progress of the best block, and detects when it stops advancing.
+pub(super) struct Watchdog {
+    /// Duration without progress after which the syncing is considered as stalled.
+    window: Duration,
+    /// Moment when the best block has last advanced, or when the watchdog has last been reset.
+    last_progress: ffi::Instant,
+    /// Moment when the stall has last been reported by [`Watchdog::check`]. `None` if no stall
+    /// has been detected since the last progress.
+    last_report: Option<ffi::Instant>,
+    /// Highest best block number reported to [`Watchdog::on_best_block`] since the last progress.
+    best_block_number: u64,
+    /// Number of times the chain has been reset since the last report or progress.
+    resets: u32,
+}
+
+impl Watchdog {
+    /// Creates a new watchdog. `best_block_number` is the current best block.
+    pub(super) fn new(window: Duration, best_block_number: u64) -> Self {
+        Watchdog {
+            window,
+            last_progress: ffi::Instant::now(),
+            last_report: None,
+            best_block_number,
+            resets: 0,
+        }
+    }
+
+    /// Reports that the chain has been reset to the finalized block because of a consensus
+    /// issue.
+    pub(super) fn on_reset(&mut self) {
+        self.resets = self.resets.saturating_add(1);
+    }
+
+    /// Reports the current best block number.
+    ///
+    /// Returns `true` if a stall had been detected and the syncing is now making progress again.
+    pub(super) fn on_best_block(&mut self, best_block_number: u64) -> bool {
+        if best_block_number <= self.best_block_number {
+            return false;
+        }
+
+        self.best_block_number = best_block_number;
+        self.last_progress = ffi::Instant::now();
+        self.resets = 0;
+        self.last_report.take().is_some()
+    }
+
+    /// Restarts the measurement of the lack of progress from now, for example because the
+    /// syncing has been paused.
+    pub(super) fn reset_timer(&mut self) {
+        let now = ffi::Instant::now();
+        self.last_progress = now;
+        if let Some(last_report) = &mut self.last_report {
+            *last_report = now;
+        }
+    }
+
+    /// Returns the duration after which [`Watchdog::check`] should be called.
+    pub(super) fn next_check(&self) -> Duration {
+        self.window
+            .checked_sub(self.last_report.unwrap_or(self.last_progress).elapsed())
+            .unwrap_or(self.window)
+    }
+
+    /// Determines whether the syncing is stalled. If so, returns the most likely cause and the
+    /// duration since the last progress. A stall is reported again no earlier than one window
+    /// after the previous report, with a duration that keeps increasing until the best block
+    /// advances.
+    ///
+    /// `num_sources` is the number of sources the blocks can be downloaded from, and
+    /// `highest_announced_block` the highest block number reported by these sources.
+    pub(super) fn check(
+        &mut self,
+        num_sources: usize,
+        highest_announced_block: u64,
+    ) -> Option<(ffi::StallCause, Duration)> {
+        let now = ffi::Instant::now();
+        if now - self.last_report.unwrap_or(self.last_progress) < self.window {
+            return None;
+        }
+
+        let cause = if num_sources == 0 {
+            ffi::StallCause::NoSource
+        } else if self.resets >= 2 {
+            ffi::StallCause::RepeatedResets
+        } else if highest_announced_block <= self.best_block_number {
+            ffi::StallCause::StaleSources
+        } else {
+            ffi::StallCause::RequestsFailing
+        };
+
+        self.last_report = Some(now);
+        self.resets = 0;
+        Some((cause, now - self.last_progress))
+    }
+}
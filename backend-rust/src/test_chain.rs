@@ -0,0 +1,338 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Chain of valid Westend blocks, used as a fixture by the tests.
+//!
+//! The blocks of the public Westend chain can only be verified against the genesis block they
+//! descend from, and downloading them requires network access. Instead, [`TestChain::build`]
+//! authors blocks on top of a copy of the Westend genesis block whose BABE and GrandPa
+//! authorities have been replaced with a single authority whose keys are known to the tests.
+//!
+//! The blocks are built by the Westend runtime found in the genesis storage, sealed with the
+//! BABE key of the authority, and finalized with justifications signed with its GrandPa key.
+//! They thus go through the same verification as the blocks of the public chain, and their
+//! storage changes are the ones of a real Westend block. Signatures are deterministic: building
+//! the same chain twice produces the same blocks.
+
+use crate::storage_query::twox_128;
+
+use rand::{rngs::StdRng, SeedableRng as _};
+use smoldot::{author, chain::chain_information, chain_spec, executor, header, network::protocol};
+use std::{collections::BTreeMap, convert::TryFrom as _, iter};
+
+/// Duration of a BABE slot of Westend, in milliseconds.
+const SLOT_DURATION_MS: u64 = 6000;
+
+/// Seed of the ed25519 key of the only GrandPa authority of the chain.
+pub const GRANDPA_KEY_SEED: [u8; 32] = [2; 32];
+
+/// Chain of blocks authored on top of a modified Westend genesis block. See
+/// [the module-level documentation](self).
+pub struct TestChain {
+    /// Chain specification whose genesis block is the parent of the first block of
+    /// [`TestChain::blocks`]. Can be passed as [`crate::ChainConfig::specification`].
+    pub specification: String,
+    /// Storage of the genesis block.
+    pub genesis_storage: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Hash of the genesis block.
+    pub genesis_hash: [u8; 32],
+    /// Blocks of the chain, starting at block #1. Each block is the child of the previous one.
+    pub blocks: Vec<protocol::BlockData>,
+    /// For each entry of [`TestChain::blocks`], the changes to the storage made by the block.
+    pub storage_changes: Vec<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl TestChain {
+    /// Builds a chain of `len` empty blocks, starting at the slot corresponding to `timestamp`,
+    /// expressed in seconds since the UNIX epoch, and with one block per slot.
+    ///
+    /// Only the blocks whose number is in `justified` are given a GrandPa justification.
+    pub fn build(len: u64, timestamp: u64, justified: &[u64]) -> Self {
+        let babe_key = schnorrkel::MiniSecretKey::from_bytes(&[1; 32])
+            .unwrap()
+            .expand_to_keypair(schnorrkel::ExpansionMode::Ed25519);
+        let grandpa_public_key = grandpa_public_key(GRANDPA_KEY_SEED);
+
+        let mut specification =
+            serde_json::from_str::<serde_json::Value>(include_str!("../../src/westend.json"))
+                .unwrap();
+        {
+            let genesis = specification["genesis"]["raw"]["top"]
+                .as_object_mut()
+                .unwrap();
+
+            // Lists of authorities are SCALE-encoded `Vec<(PublicKey, Weight)>`s. The list of
+            // GrandPa authorities is additionally prefixed with a version number.
+            let mut babe_authorities = vec![1 << 2];
+            babe_authorities.extend_from_slice(&babe_key.public.to_bytes());
+            babe_authorities.extend_from_slice(&1u64.to_le_bytes());
+            let mut grandpa_authorities = vec![1, 1 << 2];
+            grandpa_authorities.extend_from_slice(&grandpa_public_key);
+            grandpa_authorities.extend_from_slice(&1u64.to_le_bytes());
+
+            let babe_authorities_key = [twox_128(b"Babe"), twox_128(b"Authorities")].concat();
+            genesis.insert(
+                hex_string(&babe_authorities_key)
+                    .as_str()
+                    .unwrap()
+                    .to_owned(),
+                hex_string(&babe_authorities),
+            );
+            genesis.insert(
+                hex_string(b":grandpa_authorities")
+                    .as_str()
+                    .unwrap()
+                    .to_owned(),
+                hex_string(&grandpa_authorities),
+            );
+        }
+        // The light sync state refers to blocks of the public chain.
+        specification
+            .as_object_mut()
+            .unwrap()
+            .remove("lightSyncState");
+        let specification = specification.to_string();
+
+        let chain_spec = chain_spec::ChainSpec::from_json_bytes(&specification).unwrap();
+        let genesis_storage = chain_spec
+            .genesis_storage()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect::<BTreeMap<_, _>>();
+        let genesis_header =
+            chain_information::ChainInformation::from_genesis_storage(chain_spec.genesis_storage())
+                .unwrap()
+                .finalized_block_header;
+
+        let mut storage = genesis_storage.clone();
+        let mut parent_header = genesis_header.clone();
+        let mut blocks = Vec::<protocol::BlockData>::new();
+        let mut storage_changes = Vec::new();
+        let first_slot = timestamp * 1000 / SLOT_DURATION_MS;
+
+        for slot in first_slot..first_slot + len {
+            let runtime = executor::host::HostVmPrototype::new(
+                &storage[&b":code"[..]],
+                executor::storage_heap_pages_to_value(
+                    storage.get(&b":heappages"[..]).map(|v| &v[..]),
+                )
+                .unwrap(),
+                executor::vm::ExecHint::Oneshot,
+            )
+            .unwrap();
+
+            let mut build = author::runtime::build_block(author::runtime::Config {
+                parent_hash: &parent_header.hash(),
+                parent_number: parent_header.number,
+                parent_runtime: runtime,
+                consensus_digest_log_item: author::runtime::ConfigPreRuntime::Babe(
+                    header::BabePreDigestRef::SecondaryPlain(header::BabeSecondaryPlainPreDigest {
+                        authority_index: 0,
+                        slot_number: slot,
+                    }),
+                ),
+                top_trie_root_calculation_cache: None,
+            });
+
+            let success = loop {
+                build = match build {
+                    author::runtime::BlockBuild::Finished(result) => break result.unwrap(),
+                    author::runtime::BlockBuild::InherentExtrinsics(inherents) => {
+                        // The parachains module of the genesis runtime requires the list of new
+                        // parachain heads, which is always empty here.
+                        inherents.inject_raw_inherents_list(
+                            vec![
+                                (
+                                    *b"timstap0",
+                                    (slot * SLOT_DURATION_MS).to_le_bytes().to_vec(),
+                                ),
+                                (*b"babeslot", slot.to_le_bytes().to_vec()),
+                                (*b"newheads", compact(0)),
+                            ]
+                            .into_iter(),
+                        )
+                    }
+                    author::runtime::BlockBuild::ApplyExtrinsic(apply) => apply.finish(),
+                    author::runtime::BlockBuild::ApplyExtrinsicResult { .. } => unreachable!(),
+                    author::runtime::BlockBuild::StorageGet(get) => {
+                        let value = storage.get(&get.key_as_vec()).map(|v| &v[..]);
+                        get.inject_value(value.map(iter::once))
+                    }
+                    author::runtime::BlockBuild::PrefixKeys(prefix) => {
+                        let keys = storage
+                            .range(prefix.prefix().as_ref().to_vec()..)
+                            .map(|(k, _)| k)
+                            .take_while(|k| k.starts_with(prefix.prefix().as_ref()))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        prefix.inject_keys(keys.iter())
+                    }
+                    author::runtime::BlockBuild::NextKey(next) => {
+                        let key = next.key().as_ref().to_vec();
+                        let next_key = storage
+                            .range(key.clone()..)
+                            .map(|(k, _)| k)
+                            .find(|k| **k != key)
+                            .cloned();
+                        next.inject_key(next_key)
+                    }
+                };
+            };
+
+            // Seal the block with the BABE key of the authority.
+            let mut header: header::Header = header::decode(&success.scale_encoded_header)
+                .unwrap()
+                .into();
+            let signature = babe_key.sign(schnorrkel::context::attach_rng(
+                schnorrkel::signing_context(b"substrate").bytes(&header.hash()),
+                StdRng::seed_from_u64(slot),
+            ));
+            header.digest.push_babe_seal(signature.to_bytes()).unwrap();
+
+            let hash = header.hash();
+            let justification = if justified.contains(&header.number) {
+                Some(justification(GRANDPA_KEY_SEED, 0, &hash, header.number))
+            } else {
+                None
+            };
+
+            let changes = success
+                .storage_top_trie_changes
+                .into_iter()
+                .collect::<BTreeMap<_, _>>();
+            for (key, value) in &changes {
+                match value {
+                    Some(value) => storage.insert(key.clone(), value.clone()),
+                    None => storage.remove(key),
+                };
+            }
+
+            blocks.push(protocol::BlockData {
+                hash,
+                header: Some(header.scale_encoding_vec()),
+                body: Some(success.body),
+                justification,
+            });
+            storage_changes.push(changes);
+            parent_header = header;
+        }
+
+        TestChain {
+            specification,
+            genesis_storage,
+            genesis_hash: genesis_header.hash(),
+            blocks,
+            storage_changes,
+        }
+    }
+
+    /// Returns the value of the given storage key right after the given block has been
+    /// executed.
+    pub fn storage_value(&self, block_number: u64, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage_changes[..usize::try_from(block_number).unwrap()]
+            .iter()
+            .rev()
+            .find_map(|changes| changes.get(key))
+            .cloned()
+            .unwrap_or_else(|| self.genesis_storage.get(key).cloned())
+    }
+
+    /// Builds a GrandPa justification of the given block, whether or not the block is part of
+    /// the `justified` blocks passed to [`TestChain::build`].
+    pub fn justification(&self, block_number: u64) -> Vec<u8> {
+        let block = &self.blocks[usize::try_from(block_number - 1).unwrap()];
+        justification(GRANDPA_KEY_SEED, 0, &block.hash, block_number)
+    }
+
+    /// Returns the blocks of the chain in the format of the `export-blocks --binary` command of
+    /// Substrate nodes. See the [`crate::archive`] module.
+    pub fn archive(&self) -> Vec<u8> {
+        let mut archive = (self.blocks.len() as u64).to_le_bytes().to_vec();
+        for block in &self.blocks {
+            archive.extend_from_slice(block.header.as_ref().unwrap());
+            let body = block.body.as_ref().unwrap();
+            archive.extend_from_slice(&compact(body.len()));
+            for extrinsic in body {
+                archive.extend_from_slice(&compact(extrinsic.len()));
+                archive.extend_from_slice(extrinsic);
+            }
+            match &block.justification {
+                Some(justification) => {
+                    archive.push(1);
+                    archive.extend_from_slice(&compact(justification.len()));
+                    archive.extend_from_slice(justification);
+                }
+                None => archive.push(0),
+            }
+        }
+        archive
+    }
+}
+
+/// Returns the ed25519 public key whose seed is `key_seed`.
+pub fn grandpa_public_key(key_seed: [u8; 32]) -> [u8; 32] {
+    let key = ed25519_zebra::SigningKey::from(key_seed);
+    <[u8; 32]>::from(ed25519_zebra::VerificationKeyBytes::from(&key))
+}
+
+/// Builds a GrandPa justification of the block with the given hash and number, signed by the
+/// ed25519 key whose seed is `key_seed`, as the only member of the authorities set `set_id`.
+pub fn justification(key_seed: [u8; 32], set_id: u64, hash: &[u8; 32], number: u64) -> Vec<u8> {
+    const ROUND: u64 = 1;
+
+    let key = ed25519_zebra::SigningKey::from(key_seed);
+    let number = u32::try_from(number).unwrap();
+
+    let mut message = vec![1];
+    message.extend_from_slice(hash);
+    message.extend_from_slice(&number.to_le_bytes());
+    message.extend_from_slice(&ROUND.to_le_bytes());
+    message.extend_from_slice(&set_id.to_le_bytes());
+    let signature = <[u8; 64]>::from(key.sign(&message));
+
+    let mut justification = ROUND.to_le_bytes().to_vec();
+    justification.extend_from_slice(hash);
+    justification.extend_from_slice(&number.to_le_bytes());
+    // One precommit.
+    justification.extend_from_slice(&compact(1));
+    justification.extend_from_slice(hash);
+    justification.extend_from_slice(&number.to_le_bytes());
+    justification.extend_from_slice(&signature);
+    justification.extend_from_slice(&<[u8; 32]>::from(
+        ed25519_zebra::VerificationKeyBytes::from(&key),
+    ));
+    // No votes ancestry.
+    justification.extend_from_slice(&compact(0));
+    justification
+}
+
+/// SCALE-encodes a compact number. Only supports numbers below `2^30`.
+fn compact(value: usize) -> Vec<u8> {
+    let value = u32::try_from(value).unwrap();
+    if value < 1 << 6 {
+        vec![(value << 2) as u8]
+    } else if value < 1 << 14 {
+        ((value << 2) as u16 | 0b01).to_le_bytes().to_vec()
+    } else {
+        assert!(value < 1 << 30);
+        ((value << 2) | 0b10).to_le_bytes().to_vec()
+    }
+}
+
+/// Returns the JSON representation of `bytes` found in chain specifications.
+fn hex_string(bytes: &[u8]) -> serde_json::Value {
+    serde_json::to_value(smoldot::json_rpc::methods::HexString(bytes.to_vec())).unwrap()
+}
@@ -0,0 +1,1511 @@
+// Smoldot
+// Copyright (C) 2019-2021  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! End-to-end tests running [`start_client`] against a [`SimulatedHost`].
+
+use super::*;
+use crate::{
+    ffi::simulated::{SimulatedHost, Tasks},
+    storage_query::twox_128,
+    test_chain::{self, TestChain},
+};
+
+use futures::{executor::LocalPool, task::LocalSpawnExt as _};
+
+/// Addresses of the bootnodes of the Westend chain specification, as passed to the host.
+const WESTEND_BOOTNODES: [&str; 2] = [
+    "/dns/westend-connect-0.polkadot.io/tcp/443/wss",
+    "/dns/westend-connect-1.polkadot.io/tcp/443/wss",
+];
+
+/// Client running on a [`SimulatedHost`].
+struct Client {
+    host: Arc<SimulatedHost>,
+    tasks: Tasks,
+}
+
+impl Client {
+    /// Starts a client running Westend, with the given database content.
+    fn start(database_content: Option<String>) -> Self {
+        Self::start_with_config(ChainConfig {
+            database_content,
+            ..Self::config()
+        })
+    }
+
+    /// Returns the configuration used by [`Client::start`] when there's no database.
+    fn config() -> ChainConfig {
+        ChainConfig {
+            specification: include_str!("../../src/westend.json").to_owned(),
+            database_content: None,
+            node_key: Some([1; 32]),
+            record_network: false,
+            network_replay: None,
+            blocks_archive: None,
+            index_archive: None,
+            snapshot: None,
+            trusted_snapshot_hash: None,
+            index_balances: false,
+            watched_storage_prefixes: Vec::new(),
+        }
+    }
+
+    fn start_with_config(config: ChainConfig) -> Self {
+        let host = SimulatedHost::new(Duration::from_secs(1_600_000_000));
+        host.install();
+
+        let mut tasks = Tasks::default();
+        tasks.spawn(start_client(config, log::LevelFilter::Off));
+
+        let mut client = Client { host, tasks };
+        client.run_for(Duration::from_secs(0));
+        client
+    }
+
+    fn run_for(&mut self, duration: Duration) {
+        self.host.run_for(&mut self.tasks, duration)
+    }
+}
+
+#[test]
+fn genesis_is_reported() {
+    let client = Client::start(None);
+    assert_eq!(client.host.best_block_number(), Some(0));
+    assert!(client.host.take_database_saves().is_empty());
+}
+
+#[test]
+fn invalid_database_is_ignored() {
+    let client = Client::start(Some("not a database".to_owned()));
+    assert_eq!(client.host.best_block_number(), Some(0));
+}
+
+#[test]
+fn bootnodes_are_dialed() {
+    let mut client = Client::start(None);
+    client.run_for(Duration::from_secs(5));
+
+    let mut addresses = client
+        .host
+        .take_new_connections()
+        .into_iter()
+        .map(|(_, address)| address)
+        .collect::<Vec<_>>();
+    addresses.sort();
+    assert_eq!(addresses, WESTEND_BOOTNODES);
+}
+
+#[test]
+fn handshake_starts_when_connection_opens() {
+    let mut client = Client::start(None);
+    client.run_for(Duration::from_secs(5));
+
+    let connections = client.host.take_new_connections();
+    assert!(!connections.is_empty());
+    for (id, _) in &connections {
+        assert!(client.host.take_sent_data(*id).is_empty());
+        client.host.accept_connection(*id);
+    }
+
+    client.run_for(Duration::from_millis(100));
+
+    // The client negotiates the encryption protocol with multistream-select.
+    for (id, _) in &connections {
+        let sent = client.host.take_sent_data(*id);
+        assert!(sent.starts_with(b"\x13/multistream/1.0.0\n"));
+        assert!(!client.host.is_connection_closed(*id));
+    }
+}
+
+#[test]
+fn failed_dials_are_forgotten() {
+    let mut client = Client::start(None);
+    client.run_for(Duration::from_secs(5));
+
+    let connections = client.host.take_new_connections();
+    assert!(!connections.is_empty());
+    for (id, _) in connections {
+        client.host.close_connection(id);
+    }
+
+    client.run_for(Duration::from_secs(30));
+
+    // Addresses that can't be reached are removed from the address book, and only reserved
+    // peers are dialed again.
+    assert!(client.host.take_new_connections().is_empty());
+}
+
+#[test]
+fn reserved_peers_are_dialed_again() {
+    let reserved_peer_id = "12D3KooWR3UGwwSP5wdBMk2JXXuzXoscPSudv8hmQkzfZTBzSbeE";
+    let mut client = Client::start(None);
+    ffi::send_host_request(ffi::HostRequest::SetPeerReserved {
+        peer_id: reserved_peer_id.to_owned(),
+        reserved: true,
+    });
+    client.run_for(Duration::from_secs(5));
+
+    // Dialing attempts towards the reserved peer are retried after a failure, while the other
+    // addresses are forgotten.
+    let connections = client.host.take_new_connections();
+    assert_eq!(connections.len(), 2);
+    for (id, _) in connections {
+        client.host.close_connection(id);
+    }
+
+    for _ in 0..3 {
+        client.run_for(Duration::from_secs(40));
+        let connections = client.host.take_new_connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].1, WESTEND_BOOTNODES[0]);
+        client.host.close_connection(connections[0].0);
+    }
+
+    // Peers that are no longer reserved are forgotten after a failure.
+    client.run_for(Duration::from_secs(40));
+    let connections = client.host.take_new_connections();
+    ffi::send_host_request(ffi::HostRequest::SetPeerReserved {
+        peer_id: reserved_peer_id.to_owned(),
+        reserved: false,
+    });
+    client.run_for(Duration::from_secs(1));
+    client.host.close_connection(connections[0].0);
+    client.run_for(Duration::from_secs(60));
+    assert!(client.host.take_new_connections().is_empty());
+}
+
+#[test]
+fn reserved_only_mode_defers_dials() {
+    let mut client = Client::start(None);
+    ffi::send_host_request(ffi::HostRequest::SetReservedOnly(true));
+    client.run_for(Duration::from_secs(30));
+    assert!(client.host.take_new_connections().is_empty());
+
+    // Each peer is dialed once when the mode is disabled.
+    ffi::send_host_request(ffi::HostRequest::SetReservedOnly(false));
+    client.run_for(Duration::from_secs(1));
+
+    let mut addresses = client
+        .host
+        .take_new_connections()
+        .into_iter()
+        .map(|(_, address)| address)
+        .collect::<Vec<_>>();
+    addresses.sort();
+    assert_eq!(addresses, WESTEND_BOOTNODES);
+}
+
+#[test]
+fn network_info_is_answered() {
+    let mut client = Client::start(None);
+
+    ffi::bindings::network_info();
+    client.run_for(Duration::from_millis(100));
+
+    let responses = client.host.take_network_info_responses();
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0]["local_peer_id"].is_string());
+    assert_eq!(responses[0]["peers"], serde_json::json!([]));
+}
+
+#[test]
+fn stall_is_reported_when_unreachable() {
+    let mut client = Client::start(None);
+    client.run_for(Duration::from_secs(5));
+
+    for (id, _) in client.host.take_new_connections() {
+        client.host.close_connection(id);
+    }
+
+    client.run_for(Duration::from_secs(100));
+
+    let statuses = client.host.take_sync_statuses();
+    assert!(!statuses.is_empty());
+    assert_eq!(statuses[0]["state"], "stalled");
+    assert_eq!(statuses[0]["cause"], "no_source");
+    assert_eq!(client.host.best_block_number(), Some(0));
+}
+
+#[test]
+fn virtual_clock_drives_client() {
+    let mut client = Client::start(None);
+    ffi::clock::set_virtual(Duration::from_secs(1_700_000_000));
+
+    for _ in 0..100 {
+        ffi::clock::advance(Duration::from_secs(1));
+        client.tasks.run_until_stalled();
+    }
+
+    // Time on the host side hasn't moved.
+    assert_eq!(client.host.now(), Duration::from_secs(0));
+    assert_eq!(client.host.next_timer(), None);
+
+    let statuses = client.host.take_sync_statuses();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0]["state"], "stalled");
+}
+
+/// Returns the peer id corresponding to the given node key.
+fn peer_id(node_key: [u8; 32]) -> PeerId {
+    let noise_key = connection::NoiseKey::new(&node_key);
+    PeerId::from_public_key(&PublicKey::Ed25519(*noise_key.libp2p_public_ed25519_key()))
+}
+
+/// Returns the entries of the network recording emitted so far, parsed.
+fn network_recording_entries(client: &Client) -> Vec<serde_json::Value> {
+    client
+        .host
+        .take_network_recording_entries()
+        .iter()
+        .map(|entry| {
+            assert!(!entry.contains('\n'));
+            serde_json::from_str(entry).unwrap()
+        })
+        .collect()
+}
+
+/// Builds a recording out of the given entries.
+fn network_recording(entries: &[serde_json::Value]) -> String {
+    entries.iter().map(|entry| format!("{}\n", entry)).collect()
+}
+
+#[test]
+fn network_recording_is_disabled_by_default() {
+    let mut client = Client::start(None);
+    client.run_for(Duration::from_secs(5));
+    assert!(network_recording_entries(&client).is_empty());
+}
+
+#[test]
+fn network_recording_can_be_replayed() {
+    let mut client = Client::start_with_config(ChainConfig {
+        record_network: true,
+        ..Client::config()
+    });
+    client.run_for(Duration::from_secs(5));
+
+    let entries = network_recording_entries(&client);
+    assert_eq!(entries[0]["entry"], "start");
+    assert_eq!(entries[0]["version"], 1);
+    assert_eq!(entries[0]["unix_time_ms"], 1_600_000_000_000.0);
+
+    let replay = recording::Replay::from_recording(&network_recording(&entries), [1; 32]).unwrap();
+    assert_eq!(replay.unix_time(), Duration::from_secs(1_600_000_000));
+    assert_eq!(entries[0]["randomness_seed"], replay.randomness_seed());
+}
+
+#[test]
+fn replay_drives_sync_without_network() {
+    let peer_id = peer_id([2; 32]);
+    let replayed = network_recording(&[
+        serde_json::json!({
+            "entry": "start",
+            "version": 1,
+            "unix_time_ms": 1_700_000_000_000.0,
+            "randomness_seed": 5,
+        }),
+        serde_json::json!({
+            "entry": "event",
+            "time_ms": 1_000.0,
+            "peer_id": peer_id.to_base58(),
+            "chain_index": 0,
+            "kind": "connected",
+            "best_block_number": 10,
+            "best_block_hash": format!("0x{}", "11".repeat(32)),
+        }),
+    ]);
+
+    let mut client = Client::start_with_config(ChainConfig {
+        record_network: true,
+        network_replay: Some(replayed),
+        ..Client::config()
+    });
+    assert!(ffi::clock::is_virtual());
+    assert_eq!(ffi::unix_time(), Duration::from_secs(1_700_000_000));
+
+    for _ in 0..5 {
+        ffi::clock::advance(Duration::from_secs(1));
+        client.tasks.run_until_stalled();
+    }
+
+    // The network isn't accessed.
+    assert!(client.host.take_new_connections().is_empty());
+
+    // The replayed peer is asked for blocks, and the request is sent to the host before it
+    // finishes.
+    let entries = network_recording_entries(&client);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0]["randomness_seed"], 5);
+    assert_eq!(entries[1]["entry"], "event");
+    assert_eq!(entries[1]["kind"], "connected");
+    assert_eq!(entries[2]["entry"], "request");
+    assert_eq!(entries[2]["peer_id"], peer_id.to_base58());
+    assert_eq!(entries[2]["kind"], "blocks");
+    let request_id = entries[2]["id"].clone();
+
+    // As the recording doesn't contain the request, it eventually times out.
+    for _ in 0..20 {
+        ffi::clock::advance(Duration::from_secs(1));
+        client.tasks.run_until_stalled();
+    }
+    let entries = network_recording_entries(&client);
+    assert_eq!(entries[0]["entry"], "response");
+    assert_eq!(entries[0]["id"], request_id);
+    assert_eq!(
+        entries[0]["result"],
+        serde_json::json!({ "Err": "timeout" })
+    );
+}
+
+#[test]
+fn invalid_recordings_are_rejected() {
+    // Loading a recording reads the clock.
+    SimulatedHost::new(Duration::from_secs(0)).install();
+
+    let start = |version: u32| {
+        serde_json::json!({
+            "entry": "start",
+            "version": version,
+            "unix_time_ms": 0.0,
+            "randomness_seed": 0,
+        })
+    };
+    let event = |peer_id: &str| {
+        serde_json::json!({
+            "entry": "event",
+            "time_ms": 0.0,
+            "peer_id": peer_id,
+            "chain_index": 0,
+            "kind": "disconnected",
+        })
+    };
+    let response = serde_json::json!({
+        "entry": "response",
+        "id": 0,
+        "end_ms": 0.0,
+        "result": { "Err": "timeout" },
+    });
+
+    let replay = |entries: &[serde_json::Value]| {
+        recording::Replay::from_recording(&network_recording(entries), [1; 32])
+    };
+
+    let valid_peer_id = peer_id([2; 32]).to_base58();
+    assert!(replay(&[start(1), event(&valid_peer_id)]).is_ok());
+    assert!(matches!(
+        replay(&[start(2), event(&valid_peer_id)]),
+        Err(recording::ReplayError::UnsupportedVersion(2))
+    ));
+    assert!(matches!(
+        replay(&[start(1), event("foo")]),
+        Err(recording::ReplayError::InvalidPeerId(_))
+    ));
+    assert!(matches!(
+        replay(&[event(&valid_peer_id)]),
+        Err(recording::ReplayError::MissingStart)
+    ));
+    assert!(matches!(
+        replay(&[start(1), response]),
+        Err(recording::ReplayError::InvalidValue)
+    ));
+    assert!(matches!(
+        recording::Replay::from_recording("{}", [1; 32]),
+        Err(recording::ReplayError::Json(_))
+    ));
+}
+
+/// Builds an archive, in the format of `export-blocks --binary`, containing the blocks
+/// `first..=last` of a chain built on top of the genesis block of Westend. Blocks don't contain
+/// any valid consensus information, and thus always fail verification.
+fn blocks_archive(first: u64, last: u64) -> Vec<u8> {
+    // A justification of 2 bytes, in the legacy format.
+    blocks_archive_with_justifications(first, last, &[1, 2 << 2, 4, 5])
+}
+
+/// Same as [`blocks_archive`], but with the given SCALE-encoded justifications for each block.
+fn blocks_archive_with_justifications(first: u64, last: u64, justifications: &[u8]) -> Vec<u8> {
+    let chain_spec =
+        chain_spec::ChainSpec::from_json_bytes(&include_bytes!("../../src/westend.json")[..])
+            .unwrap();
+    let genesis_hash = chain::chain_information::ChainInformation::from_genesis_storage(
+        chain_spec.genesis_storage(),
+    )
+    .unwrap()
+    .finalized_block_header
+    .hash();
+
+    let mut archive = (last - first + 1).to_le_bytes().to_vec();
+    let mut parent_hash = genesis_hash;
+    for number in 1..=last {
+        let header = smoldot::header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[0; 32],
+            extrinsics_root: &[0; 32],
+            digest: smoldot::header::DigestRef::empty(),
+        };
+        let hash = header.hash();
+        let encoded_header = header.scale_encoding_vec();
+        parent_hash = hash;
+
+        if number >= first {
+            archive.extend(encoded_header);
+            // One extrinsic of 3 bytes.
+            archive.extend_from_slice(&[1 << 2, 3 << 2, 1, 2, 3]);
+            archive.extend_from_slice(justifications);
+        }
+    }
+    archive
+}
+
+#[test]
+fn blocks_archive_serves_blocks() {
+    use crate::sync_service::Network as _;
+
+    let archive = Arc::new(
+        archive::BlockArchive::decode(&blocks_archive(1, 10), [1; 32], Vec::new()).unwrap(),
+    );
+    assert_eq!(archive.first_block_number(), 1);
+    assert_eq!(archive.last_block_number(), 10);
+
+    let (task, mut events) = archive.events();
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(task).unwrap();
+    pool.run_until_stalled();
+    let peer_id = match events.try_next() {
+        Ok(Some(network_service::Event::Connected {
+            peer_id,
+            best_block_number: 10,
+            ..
+        })) => peer_id,
+        _ => panic!(),
+    };
+
+    let request = |start: u64, desired_count: u32| {
+        archive.clone().blocks_request(
+            peer_id.clone(),
+            0,
+            smoldot::network::protocol::BlocksRequestConfig {
+                start: smoldot::network::protocol::BlocksRequestConfigStart::Number(
+                    core::num::NonZeroU64::new(start).unwrap(),
+                ),
+                desired_count: NonZeroU32::new(desired_count).unwrap(),
+                direction: smoldot::network::protocol::BlocksRequestDirection::Ascending,
+                fields: smoldot::network::protocol::BlocksRequestFields {
+                    header: true,
+                    body: true,
+                    justification: true,
+                },
+            },
+        )
+    };
+
+    let blocks = futures::executor::block_on(request(8, 16)).unwrap();
+    assert_eq!(blocks.len(), 3);
+    let header = blocks[0].header.as_ref().unwrap();
+    assert_eq!(smoldot::header::decode(header).unwrap().number, 8);
+    assert_eq!(blocks[0].body, Some(vec![vec![1, 2, 3]]));
+    assert_eq!(blocks[0].justification, Some(vec![4, 5]));
+
+    assert!(matches!(
+        futures::executor::block_on(request(11, 1)),
+        Err(network_service::BlocksRequestError::Empty)
+    ));
+
+    // Blocks are only requested again if they have failed verification, in which case the
+    // import stops.
+    let refused = request(9, 1);
+    pool.run_until_stalled();
+    assert!(matches!(
+        events.try_next(),
+        Ok(Some(network_service::Event::Disconnected { .. }))
+    ));
+    assert!(matches!(
+        pool.run_until(refused),
+        Err(network_service::BlocksRequestError::Refused(_))
+    ));
+}
+
+#[test]
+fn blocks_archive_keeps_grandpa_justification() {
+    use crate::sync_service::Network as _;
+
+    // Justifications of the BABE and GrandPa engines, as exported by newer Substrate nodes.
+    let archive = blocks_archive_with_justifications(
+        1,
+        3,
+        &[
+            1,
+            2 << 2,
+            b'B',
+            b'A',
+            b'B',
+            b'E',
+            1 << 2,
+            9,
+            b'F',
+            b'R',
+            b'N',
+            b'K',
+            2 << 2,
+            4,
+            5,
+        ],
+    );
+    let archive = Arc::new(archive::BlockArchive::decode(&archive, [1; 32], Vec::new()).unwrap());
+    assert_eq!(archive.last_block_number(), 3);
+
+    let (task, mut events) = archive.events();
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(task).unwrap();
+    pool.run_until_stalled();
+    let peer_id = match events.try_next() {
+        Ok(Some(network_service::Event::Connected { peer_id, .. })) => peer_id,
+        _ => panic!(),
+    };
+
+    let blocks = futures::executor::block_on(archive.clone().blocks_request(
+        peer_id,
+        0,
+        smoldot::network::protocol::BlocksRequestConfig {
+            start: smoldot::network::protocol::BlocksRequestConfigStart::Number(
+                core::num::NonZeroU64::new(1).unwrap(),
+            ),
+            desired_count: NonZeroU32::new(3).unwrap(),
+            direction: smoldot::network::protocol::BlocksRequestDirection::Ascending,
+            fields: smoldot::network::protocol::BlocksRequestFields {
+                header: true,
+                body: true,
+                justification: true,
+            },
+        },
+    ))
+    .unwrap();
+    assert_eq!(blocks.len(), 3);
+    assert!(blocks
+        .iter()
+        .all(|block| block.justification == Some(vec![4, 5])));
+
+    // Blocks without a GrandPa justification.
+    let archive = blocks_archive_with_justifications(1, 3, &[1, 1 << 2, b'B', b'A', b'B', b'E', 0]);
+    assert!(archive::BlockArchive::decode(&archive, [1; 32], Vec::new()).is_ok());
+}
+
+#[test]
+fn invalid_blocks_archives_are_rejected() {
+    let decode = |archive: &[u8]| archive::BlockArchive::decode(archive, [1; 32], Vec::new());
+
+    assert!(matches!(decode(&[]), Err(archive::ArchiveError::Truncated)));
+    assert!(matches!(
+        decode(&0u64.to_le_bytes()),
+        Err(archive::ArchiveError::Empty)
+    ));
+
+    let mut archive = blocks_archive(1, 3);
+    archive[0] = 4;
+    assert!(matches!(
+        decode(&archive),
+        Err(archive::ArchiveError::Truncated)
+    ));
+
+    let mut archive = blocks_archive(1, 3);
+    archive.push(0);
+    assert!(matches!(
+        decode(&archive),
+        Err(archive::ArchiveError::TrailingData)
+    ));
+
+    let mut archive = blocks_archive(1, 3);
+    archive.truncate(archive.len() - 1);
+    assert!(matches!(
+        decode(&archive),
+        Err(archive::ArchiveError::InvalidBlock(2))
+    ));
+
+    // Blocks #1 and #3, without block #2.
+    let mut archive = blocks_archive(1, 1);
+    archive.extend_from_slice(&blocks_archive(3, 3)[8..]);
+    archive[0] = 2;
+    assert!(matches!(
+        decode(&archive),
+        Err(archive::ArchiveError::NotConsecutive(1))
+    ));
+}
+
+#[test]
+fn blocks_archive_drives_sync_without_network() {
+    let mut client = Client::start_with_config(ChainConfig {
+        blocks_archive: Some(blocks_archive(1, 10)),
+        ..Client::config()
+    });
+    client.run_for(Duration::from_secs(5));
+
+    // The network isn't accessed, and the invalid blocks aren't saved.
+    assert!(client.host.take_new_connections().is_empty());
+    assert!(client.host.take_database_saves().is_empty());
+    assert_eq!(client.host.best_block_number(), Some(0));
+}
+
+#[test]
+fn blocks_archive_blocks_are_saved() {
+    let chain = TestChain::build(8, 1_600_000_000 - 60, &[4, 8]);
+    let mut client = Client::start_with_config(ChainConfig {
+        specification: chain.specification.clone(),
+        blocks_archive: Some(chain.archive()),
+        ..Client::config()
+    });
+    client.run_for(Duration::from_secs(10));
+
+    // The blocks are saved up to the last justified block.
+    let numbers = client
+        .host
+        .take_database_saves()
+        .iter()
+        .flat_map(|save| save["blocks"].as_array().unwrap().clone())
+        .map(|block| block["number"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(numbers, (1..=8).collect::<Vec<_>>());
+    assert_eq!(client.host.best_block_number(), Some(8));
+    assert!(client.host.take_new_connections().is_empty());
+}
+
+#[test]
+#[should_panic]
+fn blocks_archive_must_follow_finalized_block() {
+    Client::start_with_config(ChainConfig {
+        blocks_archive: Some(blocks_archive(5, 10)),
+        ..Client::config()
+    });
+}
+
+/// Returns a database content containing the genesis block of Westend and the given node key.
+fn genesis_database_content(node_key: Option<[u8; 32]>) -> String {
+    let chain_spec =
+        chain_spec::ChainSpec::from_json_bytes(&include_bytes!("../../src/westend.json")[..])
+            .unwrap();
+    let chain_information = chain::chain_information::ChainInformation::from_genesis_storage(
+        chain_spec.genesis_storage(),
+    )
+    .unwrap();
+    let storage = chain_spec
+        .genesis_storage()
+        .map(|(k, v)| (k.to_vec(), v.to_vec()))
+        .collect::<BTreeMap<_, _>>();
+
+    database::encode(&database::DatabaseContent {
+        chain: smoldot::database::finalized_serialize::encode_chain_storage(
+            (&chain_information).into(),
+            Some(storage.iter()),
+        ),
+        address_book: Vec::new(),
+        node_key,
+    })
+}
+
+/// Returns the content of an index, as passed by the host to `export_index`.
+fn index_content(blocks: serde_json::Value) -> String {
+    serde_json::json!({
+        "database_content": genesis_database_content(Some([3; 32])),
+        "metadata": [
+            { "runtime_spec": 2, "spec_name": "westend", "metadata": "0x0203" },
+            { "runtime_spec": 1, "spec_name": "westend", "metadata": "0x01" },
+        ],
+        "blocks": blocks,
+    })
+    .to_string()
+}
+
+#[test]
+fn index_archive_roundtrip() {
+    let exported = index_archive::export(&index_content(serde_json::json!([
+        { "number": 0, "runtime_spec": 2, "events": "0x1234" },
+    ])))
+    .unwrap();
+
+    let archive = index_archive::IndexArchive::from_json(&exported).unwrap();
+    assert_eq!(
+        archive
+            .metadata
+            .iter()
+            .map(|m| m.runtime_spec)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert_eq!(archive.blocks.len(), 1);
+    assert_eq!(archive.blocks[0].events.0, vec![0x12, 0x34]);
+
+    // The node key isn't shared.
+    assert!(database::decode(&archive.database_content)
+        .node_key
+        .is_none());
+    assert_eq!(archive.to_json(), exported);
+}
+
+#[test]
+fn invalid_index_archives_are_rejected() {
+    let exported = index_archive::export(&index_content(serde_json::json!([
+        { "number": 0, "runtime_spec": 2, "events": "0x1234" },
+    ])))
+    .unwrap();
+    let mut parsed: serde_json::Value = serde_json::from_str(&exported).unwrap();
+
+    parsed["blocks"][0]["events"] = serde_json::json!("0x1235");
+    assert!(matches!(
+        index_archive::IndexArchive::from_json(&parsed.to_string()),
+        Err(index_archive::IndexArchiveError::ChecksumMismatch)
+    ));
+
+    parsed["version"] = serde_json::json!(4);
+    assert!(matches!(
+        index_archive::IndexArchive::from_json(&parsed.to_string()),
+        Err(index_archive::IndexArchiveError::UnsupportedVersion(4))
+    ));
+
+    assert!(matches!(
+        index_archive::IndexArchive::from_json("{}"),
+        Err(index_archive::IndexArchiveError::Json(_))
+    ));
+
+    assert!(matches!(
+        index_archive::export(&index_content(serde_json::json!([
+            { "number": 0, "runtime_spec": 3, "events": "0x" },
+        ]))),
+        Err(index_archive::IndexArchiveError::MissingMetadata {
+            block_number: 0,
+            runtime_spec: 3
+        })
+    ));
+
+    // The database content is finalized at the genesis block.
+    assert!(matches!(
+        index_archive::export(&index_content(serde_json::json!([
+            { "number": 1, "runtime_spec": 1, "events": "0x" },
+        ]))),
+        Err(index_archive::IndexArchiveError::BlockNotFinalized(1))
+    ));
+
+    assert!(matches!(
+        index_archive::export(&index_content(serde_json::json!([
+            { "number": 0, "runtime_spec": 1, "events": "0x" },
+            { "number": 0, "runtime_spec": 1, "events": "0x" },
+        ]))),
+        Err(index_archive::IndexArchiveError::UnorderedBlocks(0))
+    ));
+}
+
+#[test]
+fn index_archive_is_imported() {
+    let exported = index_archive::export(&index_content(serde_json::json!([
+        { "number": 0, "runtime_spec": 2, "events": "0x1234" },
+    ])))
+    .unwrap();
+
+    let client = Client::start_with_config(ChainConfig {
+        // The database content is ignored in favour of the archive.
+        database_content: Some("foo".to_owned()),
+        index_archive: Some(exported),
+        ..Client::config()
+    });
+
+    let saves = client.host.take_database_saves();
+    assert_eq!(saves.len(), 1);
+    assert_eq!(saves[0]["new_metadata"][0]["runtime_spec"], 1);
+    assert_eq!(saves[0]["new_metadata"][1]["metadata"], "0x0203");
+    assert_eq!(saves[0]["blocks"][0]["events"], "0x1234");
+    assert_eq!(client.host.best_block_number(), Some(0));
+}
+
+/// Returns a snapshot of the genesis block of Westend.
+fn genesis_snapshot() -> serde_json::Value {
+    let database_content = database::decode(&genesis_database_content(None));
+    serde_json::json!({ "chain": database_content.chain })
+}
+
+/// Returns the hash of the genesis block of Westend.
+fn westend_genesis_hash() -> [u8; 32] {
+    let chain_spec =
+        chain_spec::ChainSpec::from_json_bytes(&include_bytes!("../../src/westend.json")[..])
+            .unwrap();
+    chain::chain_information::ChainInformation::from_genesis_storage(chain_spec.genesis_storage())
+        .unwrap()
+        .finalized_block_header
+        .hash()
+}
+
+#[test]
+fn snapshot_with_trusted_hash_is_accepted() {
+    let chain_spec =
+        chain_spec::ChainSpec::from_json_bytes(&include_bytes!("../../src/westend.json")[..])
+            .unwrap();
+    let genesis = chain::chain_information::ChainInformation::from_genesis_storage(
+        chain_spec.genesis_storage(),
+    )
+    .unwrap();
+
+    let snapshot = snapshot::verify(
+        &genesis_snapshot().to_string(),
+        &genesis,
+        Some(westend_genesis_hash()),
+    )
+    .unwrap();
+    assert_eq!(snapshot.chain_information.finalized_block_header.number, 0);
+    assert_eq!(
+        snapshot.finalized_storage.len(),
+        chain_spec.genesis_storage().count()
+    );
+
+    assert!(matches!(
+        snapshot::verify(&genesis_snapshot().to_string(), &genesis, Some([0; 32])),
+        Err(snapshot::SnapshotError::UntrustedHash)
+    ));
+}
+
+#[test]
+fn invalid_snapshots_are_rejected() {
+    let chain_spec =
+        chain_spec::ChainSpec::from_json_bytes(&include_bytes!("../../src/westend.json")[..])
+            .unwrap();
+    let genesis = chain::chain_information::ChainInformation::from_genesis_storage(
+        chain_spec.genesis_storage(),
+    )
+    .unwrap();
+
+    assert!(matches!(
+        snapshot::verify("{}", &genesis, None),
+        Err(snapshot::SnapshotError::Json(_))
+    ));
+
+    assert!(matches!(
+        snapshot::verify(r#"{"chain":"foo"}"#, &genesis, None),
+        Err(snapshot::SnapshotError::InvalidChain(_))
+    ));
+
+    // Storage that doesn't match the state root of the block.
+    let mut storage = chain_spec
+        .genesis_storage()
+        .map(|(k, v)| (k.to_vec(), v.to_vec()))
+        .collect::<BTreeMap<_, _>>();
+    storage.insert(b"foo".to_vec(), b"bar".to_vec());
+    let tampered = serde_json::json!({
+        "chain": smoldot::database::finalized_serialize::encode_chain_storage(
+            (&genesis).into(),
+            Some(storage.iter()),
+        ),
+    });
+    assert!(matches!(
+        snapshot::verify(
+            &tampered.to_string(),
+            &genesis,
+            Some(westend_genesis_hash())
+        ),
+        Err(snapshot::SnapshotError::StorageRootMismatch)
+    ));
+
+    // Without a trusted hash, a justification is required.
+    assert!(matches!(
+        snapshot::verify(&genesis_snapshot().to_string(), &genesis, None),
+        Err(snapshot::SnapshotError::InvalidJustification {
+            block_number: 0,
+            ..
+        })
+    ));
+
+    let mut with_justification = genesis_snapshot();
+    with_justification["justification"] = serde_json::json!("0x00");
+    assert!(matches!(
+        snapshot::verify(&with_justification.to_string(), &genesis, None),
+        Err(snapshot::SnapshotError::InvalidJustification {
+            block_number: 0,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn snapshot_after_authority_set_change_is_accepted() {
+    use smoldot::{header, json_rpc::methods::HexString};
+
+    let test_chain = TestChain::build(0, 0, &[]);
+    let chain_spec = chain_spec::ChainSpec::from_json_bytes(&test_chain.specification).unwrap();
+    let genesis = chain::chain_information::ChainInformation::from_genesis_storage(
+        chain_spec.genesis_storage(),
+    )
+    .unwrap();
+
+    // Block #1 replaces the GrandPa authority of the test chain with a new one. Only its header
+    // is part of the snapshot, and its state root isn't verified.
+    const NEW_KEY_SEED: [u8; 32] = [3; 32];
+    let new_authority = header::GrandpaAuthority {
+        public_key: test_chain::grandpa_public_key(NEW_KEY_SEED),
+        weight: core::num::NonZeroU64::new(1).unwrap(),
+    };
+    let change_digest = [header::DigestItem::GrandpaConsensus(
+        header::GrandpaConsensusLog::ScheduledChange(header::GrandpaScheduledChange {
+            next_authorities: vec![new_authority],
+            delay: 0,
+        }),
+    )];
+    let change = header::Header {
+        parent_hash: test_chain.genesis_hash,
+        number: 1,
+        state_root: [0; 32],
+        extrinsics_root: [0; 32],
+        digest: header::DigestRef::from_slice(&change_digest)
+            .unwrap()
+            .into(),
+    };
+
+    // Block #2 is finalized by the new authority, and its storage contains the new set.
+    let mut storage = test_chain.genesis_storage.clone();
+    let mut grandpa_authorities = vec![1, 1 << 2];
+    grandpa_authorities.extend_from_slice(&new_authority.public_key);
+    grandpa_authorities.extend_from_slice(&1u64.to_le_bytes());
+    storage.insert(b":grandpa_authorities".to_vec(), grandpa_authorities);
+    storage.insert(
+        [twox_128(b"Grandpa"), twox_128(b"CurrentSetId")].concat(),
+        1u64.to_le_bytes().to_vec(),
+    );
+    let finalized = header::Header {
+        parent_hash: change.hash(),
+        number: 2,
+        state_root: snapshot::storage_root(&storage),
+        extrinsics_root: [0; 32],
+        digest: header::DigestRef::empty().into(),
+    };
+
+    let mut chain_information = genesis.clone();
+    chain_information.finalized_block_header = finalized.clone();
+    chain_information.finality = chain::chain_information::ChainInformationFinality::Grandpa {
+        after_finalized_block_authorities_set_id: 1,
+        finalized_triggered_authorities: vec![new_authority],
+        finalized_scheduled_change: None,
+    };
+
+    let encode = |chain_information: &chain::chain_information::ChainInformation| {
+        smoldot::database::finalized_serialize::encode_chain_storage(
+            chain_information.into(),
+            Some(storage.iter()),
+        )
+    };
+    let snapshot = serde_json::json!({
+        "chain": encode(&chain_information),
+        "justification": HexString(test_chain::justification(
+            NEW_KEY_SEED,
+            1,
+            &finalized.hash(),
+            2,
+        )),
+        "authority_set_changes": [{
+            "header": HexString(change.scale_encoding_vec()),
+            "justification": HexString(test_chain::justification(
+                test_chain::GRANDPA_KEY_SEED,
+                0,
+                &change.hash(),
+                1,
+            )),
+        }],
+    });
+
+    let verified = snapshot::verify(&snapshot.to_string(), &genesis, None).unwrap();
+    assert_eq!(verified.chain_information.finalized_block_header.number, 2);
+    assert_eq!(verified.finalized_storage, storage);
+
+    // Without the change, the justification is verified against the genesis authorities.
+    let mut without_change = snapshot.clone();
+    without_change["authority_set_changes"] = serde_json::json!([]);
+    assert!(matches!(
+        snapshot::verify(&without_change.to_string(), &genesis, None),
+        Err(snapshot::SnapshotError::InvalidJustification {
+            block_number: 2,
+            ..
+        })
+    ));
+
+    // The GrandPa authorities of the chain information must match the storage, even when the
+    // hash of the block is trusted.
+    let mut outdated = chain_information.clone();
+    outdated.finality = genesis.finality.clone();
+    let mut with_outdated_authorities = snapshot;
+    with_outdated_authorities["chain"] = serde_json::json!(encode(&outdated));
+    assert!(matches!(
+        snapshot::verify(
+            &with_outdated_authorities.to_string(),
+            &genesis,
+            Some(finalized.hash())
+        ),
+        Err(snapshot::SnapshotError::FinalityStorageMismatch)
+    ));
+}
+
+#[test]
+#[should_panic]
+fn unverifiable_snapshot_is_fatal() {
+    Client::start_with_config(ChainConfig {
+        snapshot: Some(genesis_snapshot().to_string()),
+        ..Client::config()
+    });
+}
+
+#[test]
+fn snapshot_not_more_recent_than_database_is_ignored() {
+    let client = Client::start_with_config(ChainConfig {
+        snapshot: Some(genesis_snapshot().to_string()),
+        trusted_snapshot_hash: Some(westend_genesis_hash()),
+        ..Client::config()
+    });
+    assert_eq!(client.host.best_block_number(), Some(0));
+}
+
+/// Returns the `System.Account` key of the given account.
+fn system_account_key(account: [u8; 32]) -> Vec<u8> {
+    let mut key = hex_to_bytes("26aa394eea5630e07c48ae0c9558cef7b99d880ec681799c0cf30e8886371da9");
+    // The `blake2_128` hash of the account isn't verified.
+    key.extend_from_slice(&[0; 16]);
+    key.extend_from_slice(&account);
+    key
+}
+
+/// Returns an `AccountInfo` with the given balances and a nonce of 7.
+fn account_info(free: u128, reserved: u128, misc_frozen: u128, fee_frozen: u128) -> Vec<u8> {
+    // Nonce, consumers, providers and sufficients.
+    let mut value = [7u32, 0, 1, 0]
+        .iter()
+        .flat_map(|n| n.to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    for balance in &[free, reserved, misc_frozen, fee_frozen] {
+        value.extend_from_slice(&balance.to_le_bytes());
+    }
+    value
+}
+
+fn hex_to_bytes(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(&hex[n..n + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn balance_changes_are_decoded() {
+    let rich = system_account_key([2; 32]);
+    let reaped = system_account_key([1; 32]);
+    let rich_info = account_info(u128::MAX, 20, 5, 8);
+
+    let previous_storage = iter::once((reaped.clone(), account_info(1, 0, 0, 0))).collect();
+
+    let changes = balances::balance_changes(
+        &previous_storage,
+        vec![
+            (&rich[..], Some(&rich_info[..])),
+            (&reaped[..], None),
+            // Not a `System.Account` key.
+            (&b":code"[..], Some(&b"foo"[..])),
+            // Too short to contain the balances.
+            (&system_account_key([3; 32])[..], Some(&[0; 16][..])),
+            // Longer than any supported layout.
+            (&system_account_key([4; 32])[..], Some(&[0; 96][..])),
+        ]
+        .into_iter(),
+    );
+
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].account.0, vec![1; 32]);
+    assert_eq!(changes[0].free, "0");
+    assert_eq!(changes[0].frozen, "0");
+    assert_eq!(changes[1].account.0, vec![2; 32]);
+    assert_eq!(changes[1].free, u128::MAX.to_string());
+    assert_eq!(changes[1].reserved, "20");
+    assert_eq!(changes[1].frozen, "8");
+}
+
+#[test]
+fn unchanged_balances_are_ignored() {
+    let account = system_account_key([2; 32]);
+    let previous_storage = iter::once((account.clone(), account_info(10, 0, 0, 0))).collect();
+
+    // A change of nonce only.
+    let mut new_info = account_info(10, 0, 0, 0);
+    new_info[0] += 1;
+    let changes = balances::balance_changes(
+        &previous_storage,
+        iter::once((&account[..], Some(&new_info[..]))),
+    );
+    assert!(changes.is_empty());
+
+    let new_info = account_info(10, 1, 0, 0);
+    let changes = balances::balance_changes(
+        &previous_storage,
+        iter::once((&account[..], Some(&new_info[..]))),
+    );
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].reserved, "1");
+}
+
+#[test]
+fn balances_layout_is_checked() {
+    let chain_spec =
+        chain_spec::ChainSpec::from_json_bytes(&include_bytes!("../../src/westend.json")[..])
+            .unwrap();
+    let code = chain_spec
+        .genesis_storage()
+        .find(|(key, _)| *key == b":code")
+        .unwrap()
+        .1;
+    let vm = smoldot::executor::host::HostVmPrototype::new(
+        code,
+        smoldot::executor::DEFAULT_HEAP_PAGES,
+        smoldot::executor::vm::ExecHint::Oneshot,
+    )
+    .unwrap();
+    let metadata = match smoldot::metadata::query_metadata(vm) {
+        smoldot::metadata::Query::Finished(Ok((metadata, _))) => metadata,
+        _ => panic!(),
+    };
+
+    assert!(balances::is_supported(&metadata));
+    // Metadata that can't be decoded, like the one of recent runtimes.
+    assert!(!balances::is_supported(&[0x6d, 0x65, 0x74, 0x61, 14]));
+}
+
+#[test]
+fn balance_changes_are_archived() {
+    let exported = index_archive::export(&index_content(serde_json::json!([
+        {
+            "number": 0,
+            "runtime_spec": 2,
+            "events": "0x",
+            "balance_changes": [
+                { "account": "0x0101", "free": "10", "reserved": "0", "frozen": "0" },
+            ],
+        },
+    ])))
+    .unwrap();
+
+    let archive = index_archive::IndexArchive::from_json(&exported).unwrap();
+    assert_eq!(archive.blocks[0].balance_changes[0].free, "10");
+
+    let mut parsed: serde_json::Value = serde_json::from_str(&exported).unwrap();
+    parsed["blocks"][0]["balance_changes"][0]["free"] = serde_json::json!("11");
+    assert!(matches!(
+        index_archive::IndexArchive::from_json(&parsed.to_string()),
+        Err(index_archive::IndexArchiveError::ChecksumMismatch)
+    ));
+}
+
+#[test]
+fn storage_changes_are_archived() {
+    let exported = index_archive::export(&index_content(serde_json::json!([
+        {
+            "number": 0,
+            "runtime_spec": 2,
+            "events": "0x",
+            "storage_changes": [
+                { "key": "0x0102", "value": "0x03" },
+                { "key": "0x0104", "value": null },
+            ],
+        },
+    ])))
+    .unwrap();
+
+    let archive = index_archive::IndexArchive::from_json(&exported).unwrap();
+    assert_eq!(archive.blocks[0].storage_changes.len(), 2);
+    assert!(archive.blocks[0].storage_changes[1].value.is_none());
+
+    // Removing a value must change the checksum.
+    let mut parsed: serde_json::Value = serde_json::from_str(&exported).unwrap();
+    parsed["blocks"][0]["storage_changes"][0]["value"] = serde_json::Value::Null;
+    assert!(matches!(
+        index_archive::IndexArchive::from_json(&parsed.to_string()),
+        Err(index_archive::IndexArchiveError::ChecksumMismatch)
+    ));
+}
+
+/// Sends a storage query to the client, and returns its answer.
+fn storage_query(client: &mut Client, query: serde_json::Value) -> serde_json::Value {
+    ffi::send_host_request(ffi::HostRequest::StorageQuery(query.to_string()));
+    client.run_for(Duration::from_secs(0));
+    let mut responses = client.host.take_storage_query_responses();
+    assert_eq!(responses.len(), 1);
+    responses.remove(0)
+}
+
+#[test]
+fn storage_can_be_queried() {
+    let mut client = Client::start(None);
+
+    // `:code` is the only key starting with `:c`.
+    let answer = storage_query(
+        &mut client,
+        serde_json::json!({ "method": "keys", "prefix": "0x3a63", "count": 10 }),
+    );
+    assert_eq!(answer["block_number"], 0);
+    assert_eq!(
+        answer["block_hash"],
+        serde_json::to_value(smoldot::json_rpc::methods::HexString(
+            westend_genesis_hash().to_vec()
+        ))
+        .unwrap()
+    );
+    assert_eq!(answer["keys"], serde_json::json!(["0x3a636f6465"]));
+
+    let answer = storage_query(
+        &mut client,
+        serde_json::json!({ "method": "next_key", "key": "0x3a63" }),
+    );
+    assert_eq!(answer["next_key"], "0x3a636f6465");
+
+    let answer = storage_query(
+        &mut client,
+        serde_json::json!({ "method": "get", "key": "0x3a636f6465" }),
+    );
+    assert!(answer["value"].as_str().unwrap().starts_with("0x"));
+    assert!(answer.get("decoded").is_none());
+
+    let answer = storage_query(
+        &mut client,
+        serde_json::json!({ "method": "get", "key": "0x00" }),
+    );
+    assert!(answer["value"].is_null());
+
+    let answer = storage_query(&mut client, serde_json::json!({ "method": "foo" }));
+    assert!(answer["error"].is_string());
+}
+
+#[test]
+fn storage_keys_are_paginated() {
+    let mut client = Client::start(None);
+    let system_account = "0x26aa394eea5630e07c48ae0c9558cef7b99d880ec681799c0cf30e8886371da9";
+
+    let first_page = storage_query(
+        &mut client,
+        serde_json::json!({ "method": "keys", "prefix": system_account, "count": 2 }),
+    );
+    let first_page = first_page["keys"].as_array().unwrap().clone();
+    assert_eq!(first_page.len(), 2);
+
+    let second_page = storage_query(
+        &mut client,
+        serde_json::json!({
+            "method": "keys",
+            "prefix": system_account,
+            "start_key": first_page[0],
+            "count": 1,
+        }),
+    );
+    assert_eq!(second_page["keys"], serde_json::json!([first_page[1]]));
+}
+
+#[test]
+fn storage_values_are_decoded() {
+    let mut client = Client::start(None);
+
+    // `Balances.TotalIssuance`.
+    let answer = storage_query(
+        &mut client,
+        serde_json::json!({
+            "method": "get",
+            "key": "0xc2261276cc9d1f8598ea4b6a74b15c2f57c875e4cff74148e4628f264b974c80",
+            "decode": true,
+        }),
+    );
+    assert_eq!(answer["decoded"]["pallet"], "Balances");
+    assert_eq!(answer["decoded"]["item"], "TotalIssuance");
+    assert_eq!(answer["decoded"]["keys"], serde_json::json!([]));
+    let total_issuance = answer["decoded"]["value"].as_str().unwrap();
+    assert!(total_issuance.parse::<u128>().unwrap() > 0);
+
+    // The account id of `System.Account` is found at the end of the key.
+    let key = storage_query(
+        &mut client,
+        serde_json::json!({
+            "method": "keys",
+            "prefix": "0x26aa394eea5630e07c48ae0c9558cef7b99d880ec681799c0cf30e8886371da9",
+            "count": 1,
+        }),
+    )["keys"][0]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    let answer = storage_query(
+        &mut client,
+        serde_json::json!({ "method": "get", "key": key, "decode": true }),
+    );
+    assert_eq!(answer["decoded"]["item"], "Account");
+    assert_eq!(
+        answer["decoded"]["keys"][0].as_str().unwrap(),
+        format!("0x{}", &key[key.len() - 64..])
+    );
+    assert_eq!(answer["decoded"]["is_default"], false);
+}
+
+/// Calls a runtime function through the client, and returns its answer.
+fn runtime_call(client: &mut Client, function: &str, parameter: &[u8]) -> serde_json::Value {
+    ffi::send_host_request(ffi::HostRequest::RuntimeCall {
+        function: function.to_owned(),
+        parameter: parameter.to_vec(),
+    });
+    client.run_for(Duration::from_secs(0));
+    let mut responses = client.host.take_runtime_call_responses();
+    assert_eq!(responses.len(), 1);
+    responses.remove(0)
+}
+
+#[test]
+fn runtime_can_be_called() {
+    let mut client = Client::start(None);
+
+    let answer = runtime_call(&mut client, "Core_version", &[]);
+    assert_eq!(answer["block_number"], 0);
+    // The output starts with the SCALE-encoded spec name.
+    let westend = "westend"
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    assert!(answer["output"]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("0x1c{}", westend)));
+
+    let answer = runtime_call(&mut client, "Foo_bar", &[]);
+    assert!(answer["error"].is_string());
+    assert!(answer.get("output").is_none());
+}
+
+/// Sends a JSON-RPC request to the client, and returns the responses and notifications sent
+/// back.
+fn json_rpc(client: &mut Client, request: serde_json::Value) -> Vec<serde_json::Value> {
+    ffi::send_host_request(ffi::HostRequest::JsonRpc(request.to_string()));
+    client.run_for(Duration::from_secs(0));
+    client.host.take_json_rpc_responses()
+}
+
+#[test]
+fn json_rpc_requests_are_answered() {
+    let mut client = Client::start(None);
+    let genesis_hash = serde_json::to_value(smoldot::json_rpc::methods::HexString(
+        westend_genesis_hash().to_vec(),
+    ))
+    .unwrap();
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "chain_getFinalizedHead" }),
+    );
+    assert_eq!(
+        responses,
+        vec![serde_json::json!({ "jsonrpc": "2.0", "id": 1, "result": genesis_hash })]
+    );
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({ "jsonrpc": "2.0", "id": 2, "method": "chain_getHeader" }),
+    );
+    assert_eq!(responses[0]["result"]["number"], "0x0");
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({ "jsonrpc": "2.0", "id": 3, "method": "system_health", "params": [] }),
+    );
+    assert_eq!(responses[0]["result"]["peers"], 0);
+    assert_eq!(responses[0]["result"]["isSyncing"], false);
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "method": "state_getStorage",
+            "params": ["0x3a636f6465", genesis_hash],
+        }),
+    );
+    assert!(responses[0]["result"]
+        .as_str()
+        .unwrap()
+        .starts_with("0x0061736d"));
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({ "jsonrpc": "2.0", "id": 5, "method": "state_getRuntimeVersion" }),
+    );
+    assert_eq!(responses[0]["result"]["specName"], "westend");
+    assert!(responses[0]["result"]["apis"][0][1].is_number());
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 5,
+            "method": "chain_getBlockHash",
+            "params": ["0x0"],
+        }),
+    );
+    assert_eq!(responses[0]["result"], genesis_hash);
+
+    // Block numbers passed as strings must be hexadecimal.
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 5,
+            "method": "chain_getBlockHash",
+            "params": ["10"],
+        }),
+    );
+    assert_eq!(responses[0]["error"]["code"], -32602);
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({ "jsonrpc": "2.0", "id": 6, "method": "foo_bar" }),
+    );
+    assert_eq!(responses[0]["id"], 6);
+    assert_eq!(responses[0]["error"]["code"], -32601);
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({ "jsonrpc": "2.0", "id": 7 }),
+    );
+    assert_eq!(responses[0]["id"], serde_json::Value::Null);
+    assert_eq!(responses[0]["error"]["code"], -32600);
+}
+
+#[test]
+fn finalized_heads_can_be_subscribed_to() {
+    let mut client = Client::start(None);
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "chain_subscribeFinalizedHeads" }),
+    );
+    assert_eq!(responses.len(), 2);
+    let subscription = responses[0]["result"].clone();
+    assert!(subscription.is_string());
+    // The current finalized block is immediately reported.
+    assert_eq!(responses[1]["method"], "chain_finalizedHead");
+    assert_eq!(responses[1]["params"]["subscription"], subscription);
+    assert_eq!(responses[1]["params"]["result"]["number"], "0x0");
+
+    let responses = json_rpc(
+        &mut client,
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "chain_unsubscribeFinalizedHeads",
+            "params": [subscription],
+        }),
+    );
+    assert_eq!(responses[0]["result"], true);
+}
@@ -31,6 +31,10 @@ export default (config) => {
     // The indices within this array are chosen by the Rust code.
     let connections = {};
 
+    // Used below to store the list of all timers that haven't fired yet, so that they can be
+    // cancelled. The keys are the identifiers chosen by the Rust code.
+    let timers = {};
+
     // Set to `true` once `throw` has been called.
     // As documented, after the `throw` function has been called, it is forbidden to call any
     // further function of the Wasm virtual machine. This flag is used to enforce this.
@@ -101,38 +105,42 @@ export default (config) => {
 
         // Must call `timer_finished` after the given number of milliseconds has elapsed.
         start_timer: (id, ms) => {
+            const onFinished = () => {
+                delete timers[id];
+                if (!terminated) {
+                    try {
+                        config.instance.exports.timer_finished(id);
+                    } catch (error) {
+                        terminate();
+                        if (config.onTerminated)
+                            config.onTerminated();
+                        throw error;
+                    }
+                }
+            };
+
             // In browsers, `setTimeout` works as expected when `ms` equals 0. However, NodeJS
             // requires a minimum of 1 millisecond (if `0` is passed, it is automatically replaced
             // with `1`) and wants you to use `setImmediate` instead.
             if (ms == 0 && typeof setImmediate === "function") {
-                setImmediate(() => {
-                    if (!terminated) {
-                        try {
-                            config.instance.exports.timer_finished(id);
-                        } catch (error) {
-                            terminate();
-                            if (config.onTerminated)
-                                config.onTerminated();
-                            throw error;
-                        }
-                    }
-                })
+                timers[id] = { immediate: setImmediate(onFinished) };
             } else {
-                setTimeout(() => {
-                    if (!terminated) {
-                        try {
-                            config.instance.exports.timer_finished(id);
-                        } catch (error) {
-                            terminate();
-                            if (config.onTerminated)
-                                config.onTerminated();
-                            throw error;
-                        }
-                    }
-                }, ms)
+                timers[id] = { timeout: setTimeout(onFinished, ms) };
             }
         },
 
+        // Must prevent `timer_finished` from being called for a timer previously started.
+        cancel_timer: (id) => {
+            const timer = timers[id];
+            if (!timer)
+                return;
+            if (timer.immediate !== undefined)
+                clearImmediate(timer.immediate);
+            else
+                clearTimeout(timer.timeout);
+            delete timers[id];
+        },
+
         // Update about the current best block.
         best_block_update: (best_block_number) => {
           if (config.best_block_update_callback) {
@@ -140,6 +148,62 @@ export default (config) => {
           }
         },
 
+        // Change in the status of the syncing.
+        sync_status_update: (ptr, len) => {
+            if (config.sync_status_callback) {
+                let content = Buffer.from(config.instance.exports.memory.buffer).toString('utf8', ptr, ptr + len);
+                config.sync_status_callback(content);
+            }
+        },
+
+        // Change concerning a non-finalized best block.
+        best_block_notification: (ptr, len) => {
+            if (config.best_block_notification_callback) {
+                let content = Buffer.from(config.instance.exports.memory.buffer).toString('utf8', ptr, ptr + len);
+                config.best_block_notification_callback(content);
+            }
+        },
+
+        // Answer to a call to `network_info`.
+        network_info_response: (ptr, len) => {
+            if (config.network_info_callback) {
+                let content = Buffer.from(config.instance.exports.memory.buffer).toString('utf8', ptr, ptr + len);
+                config.network_info_callback(content);
+            }
+        },
+
+        // New entry of the recording of the network activity.
+        network_recording_entry: (ptr, len) => {
+            if (config.network_recording_entry_callback) {
+                let content = Buffer.from(config.instance.exports.memory.buffer).toString('utf8', ptr, ptr + len);
+                config.network_recording_entry_callback(content);
+            }
+        },
+
+        // Answer to a call to `export_index`.
+        index_export_response: (ptr, len) => {
+            if (config.index_export_callback) {
+                let content = Buffer.from(config.instance.exports.memory.buffer).toString('utf8', ptr, ptr + len);
+                config.index_export_callback(content);
+            }
+        },
+
+        // Answer to a call to `storage_query`.
+        storage_query_response: (ptr, len) => {
+            if (config.storage_query_callback) {
+                let content = Buffer.from(config.instance.exports.memory.buffer).toString('utf8', ptr, ptr + len);
+                config.storage_query_callback(content);
+            }
+        },
+
+        // Answer to a call to `runtime_call`.
+        runtime_call_response: (ptr, len) => {
+            if (config.runtime_call_callback) {
+                let content = Buffer.from(config.instance.exports.memory.buffer).toString('utf8', ptr, ptr + len);
+                config.runtime_call_callback(content);
+            }
+        },
+
         // Must set the content of the database to the given string.
         database_save: (ptr, len) => {
             if (config.database_save_callback) {
@@ -292,6 +356,21 @@ export default (config) => {
             connections[id] = undefined;
         },
 
+        // Must pause or resume reading from the given connection. Only TCP sockets can be paused.
+        // WebSockets, be it the browser ones or `w3cwebsocket`, have no API to do so, in which
+        // case the Rust code closes the connection if its unprocessed data grows too much.
+        connection_set_reading_paused: (id, paused) => {
+            let connection = connections[id];
+            if (connection.pause) {
+                // TCP
+                if (paused) {
+                    connection.pause();
+                } else {
+                    connection.resume();
+                }
+            }
+        },
+
         // Must queue the data found in the WebAssembly memory at the given pointer. It is assumed
         // that this function is called only when the connection is in an open state.
         connection_send: (id, ptr, len) => {
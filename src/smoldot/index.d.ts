@@ -21,18 +21,72 @@ declare class SmoldotError extends Error {
 
 export interface SmoldotClient {
   send_json_rpc(rpc: string): void;
+  set_syncing_paused(paused: boolean): void;
+  set_follow_best_blocks(follow: boolean): void;
+  network_info(): Promise<object>;
+  export_index(index: SmoldotIndexContent): Promise<string>;
+  storage_query(query: SmoldotStorageQuery): Promise<SmoldotStorageQueryResult>;
+  runtime_call(fn: string, parameter?: string): Promise<SmoldotRuntimeCallResult>;
+  watch_storage_prefix(prefix: string): void;
+  unwatch_storage_prefix(prefix: string): void;
+  add_peer(address: string): void;
+  disconnect_peer(peer_id: string, ban?: boolean): void;
+  set_peer_reserved(peer_id: string, reserved: boolean): void;
+  set_reserved_only(reserved_only: boolean): void;
+  set_virtual_clock(unix_time_ms: number): void;
+  advance_virtual_clock(milliseconds: number): void;
+}
+
+export interface SmoldotIndexContent {
+  database_content: string;
+  metadata: object[];
+  blocks: object[];
+}
+
+export type SmoldotStorageQuery =
+  { method: 'get'; key: string; decode?: boolean } |
+  { method: 'keys'; prefix: string; start_key?: string; count: number } |
+  { method: 'next_key'; key: string };
+
+export interface SmoldotStorageQueryResult {
+  block_number: number;
+  block_hash: string;
+  value?: string | null;
+  decoded?: object;
+  keys?: string[];
+  next_key?: string | null;
+}
+
+export interface SmoldotRuntimeCallResult {
+  block_number: number;
+  block_hash: string;
+  output: string;
 }
 
 export type SmoldotJsonRpcCallback = (response: string) => void;
 export type SmoldotDatabaseSaveCallback = (response: string) => void;
+export type SmoldotSyncStatusCallback = (status: string) => void;
+export type SmoldotBestBlockNotificationCallback = (notification: string) => void;
+export type SmoldotNetworkRecordingCallback = (entry: string) => void;
 
 export interface SmoldotOptions {
   max_log_level?: number;
   chain_spec: string;
   json_rpc_callback: SmoldotJsonRpcCallback;
   database_save_callback: SmoldotDatabaseSaveCallback;
+  best_block_notification_callback?: SmoldotBestBlockNotificationCallback;
+  sync_status_callback?: SmoldotSyncStatusCallback;
   database_content?: string;
+  node_key?: string;
   relay_chain_spec?: string;
+  network_recording_callback?: SmoldotNetworkRecordingCallback;
+  network_replay?: string;
+  blocks_archive?: Uint8Array;
+  index_archive?: string;
+  snapshot?: string;
+  trusted_snapshot_hash?: string;
+  index_balances?: boolean;
+  watched_storage_prefixes?: string[];
 }
 
 export interface Smoldot {
@@ -35,6 +35,16 @@ export async function start(config) {
   // (https://github.com/parcel-bundler/parcel/pull/5846)
   const worker = new Worker('./worker.js');
 
+  // Promises waiting for an answer to a network information request. The worker answers
+  // requests in the same order as they are sent.
+  let pending_network_info = [];
+  // Same as `pending_network_info`, but for index export requests. Contains `[resolve, reject]`.
+  let pending_index_export = [];
+  // Same as `pending_index_export`, but for storage queries.
+  let pending_storage_query = [];
+  // Same as `pending_index_export`, but for runtime calls.
+  let pending_runtime_call = [];
+
   // The worker can send us either a database save message, or a JSON-RPC answer.
   workerOnMessage(worker, (message) => {
     if (message.kind == 'jsonrpc') {
@@ -46,6 +56,38 @@ export async function start(config) {
     } else if (message.kind == 'best-block-update') {
       if (config.best_block_update_callback)
         config.best_block_update_callback(message.num);
+    } else if (message.kind == 'sync-status') {
+      if (config.sync_status_callback)
+        config.sync_status_callback(message.data);
+    } else if (message.kind == 'best-block-notification') {
+      if (config.best_block_notification_callback)
+        config.best_block_notification_callback(message.data);
+    } else if (message.kind == 'network-info') {
+      pending_network_info.shift()(JSON.parse(message.data));
+    } else if (message.kind == 'network-recording-entry') {
+      if (config.network_recording_callback)
+        config.network_recording_callback(message.data);
+    } else if (message.kind == 'index-export') {
+      const [resolve, reject] = pending_index_export.shift();
+      const response = JSON.parse(message.data);
+      if (response.archive !== undefined)
+        resolve(response.archive);
+      else
+        reject(new SmoldotError(response.error));
+    } else if (message.kind == 'runtime-call') {
+      const [resolve, reject] = pending_runtime_call.shift();
+      const response = JSON.parse(message.data);
+      if (response.error === undefined)
+        resolve(response);
+      else
+        reject(new SmoldotError(response.error));
+    } else if (message.kind == 'storage-query') {
+      const [resolve, reject] = pending_storage_query.shift();
+      const response = JSON.parse(message.data);
+      if (response.error === undefined)
+        resolve(response);
+      else
+        reject(new SmoldotError(response.error));
     } else {
       console.error('Unknown message type', message);
     }
@@ -55,18 +97,129 @@ export async function start(config) {
   worker.postMessage({
     chain_spec: config.chain_spec,
     database_content: config.database_content,
+    // Optional hexadecimal-encoded ed25519 private key that determines the identity of the node.
+    node_key: config.node_key,
     relay_chain_spec: config.relay_chain_spec,
+    // The network activity is only recorded if the user is interested in it. The entries of the
+    // recording are passed to `network_recording_callback` as they happen.
+    record_network: !!config.network_recording_callback,
+    // Optional recording made of the entries passed to `network_recording_callback`, each
+    // followed with a line break. If set, the client doesn't connect to the network and replays
+    // the recording instead. The replay only progresses when `advance_virtual_clock` is called.
+    network_replay: config.network_replay,
+    // Optional `Uint8Array` containing blocks exported by a Substrate node with
+    // `export-blocks --binary`. If set, the client doesn't connect to the network and imports
+    // these blocks instead.
+    blocks_archive: config.blocks_archive,
+    // Optional string previously returned by `export_index`. If set, the client resumes from the
+    // state found in this archive instead of `database_content`, and reports the content of the
+    // archive through `database_save_callback`.
+    index_archive: config.index_archive,
+    // Optional JSON-encoded snapshot of a recent finalized block. If set, the client verifies
+    // it and starts from this block if it is more recent than `database_content`.
+    snapshot: config.snapshot,
+    // Optional hexadecimal-encoded hash of the block of `snapshot`. If set, the snapshot is
+    // accepted if its block has this hash, instead of verifying its GrandPa justification.
+    trusted_snapshot_hash: config.trusted_snapshot_hash,
+    // If true, the blocks passed to `database_save_callback` contain the changes to the balances
+    // of accounts, in a `balance_changes` field.
+    index_balances: !!config.index_balances,
+    // Optional array of hexadecimal-encoded storage prefixes. The blocks passed to
+    // `database_save_callback` contain the changes to the storage keys under these prefixes, in
+    // a `storage_changes` field. See also `watch_storage_prefix`.
+    watched_storage_prefixes: config.watched_storage_prefixes,
     // Maximum level of log entries sent by the client.
     // 0 = Logging disabled, 1 = Error, 2 = Warn, 3 = Info, 4 = Debug, 5 = Trace
     max_log_level: config.max_log_level || 5
   });
 
-  // After the initialization message, all further messages expected by the worker are JSON-RPC
-  // requests.
+  // After the initialization message, all further messages expected by the worker are requests
+  // with a `kind` field.
+
+  // Following the non-finalized best blocks is only enabled if the user is interested in it.
+  if (config.best_block_notification_callback)
+    worker.postMessage({ kind: 'set-follow-best-blocks', follow: true });
 
   return {
     set_syncing_paused: (paused) => {
-      worker.postMessage(paused);
+      worker.postMessage({ kind: 'set-syncing-paused', paused });
+    },
+    set_follow_best_blocks: (follow) => {
+      worker.postMessage({ kind: 'set-follow-best-blocks', follow });
+    },
+    // Returns a `Promise` that yields information about the peers the client is connected to.
+    network_info: () => {
+      return new Promise((resolve) => {
+        pending_network_info.push(resolve);
+        worker.postMessage({ kind: 'network-info' });
+      });
+    },
+    // Returns a `Promise` that yields an index archive as a string, to later pass as
+    // `index_archive`. `database_content` is the latest `chain` passed to
+    // `database_save_callback`, and `metadata` and `blocks` are all the entries of `new_metadata`
+    // and `blocks` passed to `database_save_callback`.
+    export_index: ({ database_content, metadata, blocks }) => {
+      return new Promise((resolve, reject) => {
+        pending_index_export.push([resolve, reject]);
+        const content = JSON.stringify({ database_content, metadata, blocks });
+        worker.postMessage({ kind: 'export-index', content });
+      });
+    },
+    // Returns a `Promise` that yields the answer to a query of the storage of the finalized
+    // block, for example `{ method: 'get', key: '0x...', decode: true }`,
+    // `{ method: 'keys', prefix: '0x...', start_key: '0x...', count: 100 }` or
+    // `{ method: 'next_key', key: '0x...' }`. The answer contains the number and hash of the
+    // finalized block alongside with the result.
+    storage_query: (query) => {
+      return new Promise((resolve, reject) => {
+        pending_storage_query.push([resolve, reject]);
+        worker.postMessage({ kind: 'storage-query', query: JSON.stringify(query) });
+      });
+    },
+    // Returns a `Promise` that yields the output of the given runtime function, for example
+    // `AccountNonceApi_account_nonce`, called against the storage of the finalized block.
+    // `parameter` is the hexadecimal-encoded SCALE-encoded parameter of the function. The answer
+    // contains the number and hash of the finalized block, and the hexadecimal-encoded `output`.
+    runtime_call: (fn, parameter) => {
+      return new Promise((resolve, reject) => {
+        pending_runtime_call.push([resolve, reject]);
+        worker.postMessage({ kind: 'runtime-call', function: fn, parameter: parameter || '0x' });
+      });
+    },
+    // Sends a request of the standard Substrate JSON-RPC API, such as `chain_getHeader` or
+    // `chain_subscribeFinalizedHeads`. Responses and notifications are passed to
+    // `json_rpc_callback`.
+    send_json_rpc: (rpc) => {
+      worker.postMessage({ kind: 'json-rpc', rpc });
+    },
+    // Starts reporting the changes to the storage keys that start with `prefix`, an hexadecimal
+    // string, for the blocks finalized from now on.
+    watch_storage_prefix: (prefix) => {
+      worker.postMessage({ kind: 'watch-storage-prefix', prefix });
+    },
+    unwatch_storage_prefix: (prefix) => {
+      worker.postMessage({ kind: 'unwatch-storage-prefix', prefix });
+    },
+    // `address` must be a multiaddress ending with `/p2p/<peer id>`.
+    add_peer: (address) => {
+      worker.postMessage({ kind: 'add-peer', address });
+    },
+    disconnect_peer: (peer_id, ban) => {
+      worker.postMessage({ kind: 'disconnect-peer', peer_id, ban: !!ban });
+    },
+    set_peer_reserved: (peer_id, reserved) => {
+      worker.postMessage({ kind: 'set-peer-reserved', peer_id, reserved });
+    },
+    set_reserved_only: (reserved_only) => {
+      worker.postMessage({ kind: 'set-reserved-only', reserved_only });
+    },
+    // Switches the client to a virtual clock, set to `unix_time_ms` milliseconds since the UNIX
+    // epoch, that only advances when `advance_virtual_clock` is called.
+    set_virtual_clock: (unix_time_ms) => {
+      worker.postMessage({ kind: 'set-virtual-clock', unix_time_ms });
+    },
+    advance_virtual_clock: (milliseconds) => {
+      worker.postMessage({ kind: 'advance-virtual-clock', milliseconds });
     }
   }
 }
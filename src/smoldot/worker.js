@@ -26,7 +26,7 @@ import { default as wasm_base64 } from './autogen/wasm.js';
 //
 // - At initialization, it is set to `null`.
 // - Once the first message, containing the configuration, has been received from the parent, it
-//   becomes an array filled with JSON-RPC requests that are received while the Wasm VM is still
+//   becomes an array filled with requests that are received while the Wasm VM is still
 //   initializing.
 // - After the Wasm VM has finished initialization, contains the `WebAssembly.Instance` object.
 //
@@ -40,7 +40,16 @@ let has_thrown = false;
 const startInstance = async (config) => {
   const chain_spec = config.chain_spec;
   const database_content = config.database_content;
+  const node_key = config.node_key;
   const relay_chain_spec = config.relay_chain_spec;
+  const record_network = config.record_network;
+  const network_replay = config.network_replay;
+  const blocks_archive = config.blocks_archive;
+  const index_archive = config.index_archive;
+  const snapshot = config.snapshot;
+  const trusted_snapshot_hash = config.trusted_snapshot_hash;
+  const index_balances = config.index_balances;
+  const watched_storage_prefixes = config.watched_storage_prefixes;
   const max_log_level = config.max_log_level;
 
   // The actual Wasm bytecode is base64-decoded from a constant found in a different file.
@@ -62,6 +71,34 @@ const startInstance = async (config) => {
     best_block_update_callback: (num) => {
       // `compat.postMessage` is the same as `postMessage`, but works across environments.
       compat.postMessage({ kind: 'best-block-update', num });
+    },
+    sync_status_callback: (data) => {
+      // `compat.postMessage` is the same as `postMessage`, but works across environments.
+      compat.postMessage({ kind: 'sync-status', data });
+    },
+    best_block_notification_callback: (data) => {
+      // `compat.postMessage` is the same as `postMessage`, but works across environments.
+      compat.postMessage({ kind: 'best-block-notification', data });
+    },
+    network_info_callback: (data) => {
+      // `compat.postMessage` is the same as `postMessage`, but works across environments.
+      compat.postMessage({ kind: 'network-info', data });
+    },
+    network_recording_entry_callback: (data) => {
+      // `compat.postMessage` is the same as `postMessage`, but works across environments.
+      compat.postMessage({ kind: 'network-recording-entry', data });
+    },
+    index_export_callback: (data) => {
+      // `compat.postMessage` is the same as `postMessage`, but works across environments.
+      compat.postMessage({ kind: 'index-export', data });
+    },
+    storage_query_callback: (data) => {
+      // `compat.postMessage` is the same as `postMessage`, but works across environments.
+      compat.postMessage({ kind: 'storage-query', data });
+    },
+    runtime_call_callback: (data) => {
+      // `compat.postMessage` is the same as `postMessage`, but works across environments.
+      compat.postMessage({ kind: 'runtime-call', data });
     }
   };
 
@@ -88,27 +125,30 @@ const startInstance = async (config) => {
   smoldot_js_config.instance = result.instance;
   wasi_config.instance = result.instance;
 
-  let chain_spec_len = Buffer.byteLength(chain_spec, 'utf8');
-  let chain_spec_ptr = result.instance.exports.alloc(chain_spec_len);
-  Buffer.from(result.instance.exports.memory.buffer)
-    .write(chain_spec, chain_spec_ptr);
-
-  let database_len = database_content ? Buffer.byteLength(database_content, 'utf8') : 0;
-  let database_ptr = (database_len != 0) ? result.instance.exports.alloc(database_len) : 0;
-  if (database_len != 0) {
-    Buffer.from(result.instance.exports.memory.buffer)
-      .write(database_content, database_ptr);
-  }
+  // The configuration is passed as JSON. Binary data is encoded as hexadecimal strings.
+  const toHex = (hex) => '0x' + hex.replace(/^0x/, '');
+  let init_config = JSON.stringify({
+    chain_spec,
+    database_content: database_content || null,
+    node_key: node_key ? toHex(node_key) : null,
+    max_log_level,
+    record_network: !!record_network,
+    network_replay: network_replay || null,
+    blocks_archive: (blocks_archive && blocks_archive.length != 0) ?
+      '0x' + Buffer.from(blocks_archive).toString('hex') : null,
+    index_archive: index_archive || null,
+    snapshot: snapshot || null,
+    trusted_snapshot_hash: trusted_snapshot_hash ? toHex(trusted_snapshot_hash) : null,
+    index_balances: !!index_balances,
+    watched_storage_prefixes: watched_storage_prefixes || [],
+  });
+  let [init_config_ptr, init_config_len] = allocString(result.instance, init_config);
 
   try {
-    result.instance.exports.init(
-      chain_spec_ptr, chain_spec_len,
-      database_ptr, database_len,
-      max_log_level
-    );
-
-    state.forEach((paused) => {
-      result.instance.exports.set_syncing_paused(paused ? 1 : 0);
+    result.instance.exports.init(init_config_ptr, init_config_len);
+
+    state.forEach((message) => {
+      processRequest(result.instance, message);
     });
 
     state = result.instance;
@@ -120,6 +160,71 @@ const startInstance = async (config) => {
   }
 };
 
+// Copies the given string to a buffer allocated within the Wasm VM, and returns the pointer and
+// length of this buffer.
+const allocString = (instance, string) => {
+  let len = Buffer.byteLength(string, 'utf8');
+  let ptr = instance.exports.alloc(len);
+  Buffer.from(instance.exports.memory.buffer).write(string, ptr);
+  return [ptr, len];
+};
+
+// Copies the given hexadecimal string to a buffer allocated within the Wasm VM, and returns the
+// pointer and length of this buffer.
+const allocHex = (instance, hex) => {
+  const bytes = Buffer.from(hex.replace(/^0x/, ''), 'hex');
+  let ptr = instance.exports.alloc(bytes.length);
+  bytes.copy(Buffer.from(instance.exports.memory.buffer), ptr);
+  return [ptr, bytes.length];
+};
+
+// Processes a request sent by the parent after the configuration.
+const processRequest = (instance, message) => {
+  if (message.kind == 'set-syncing-paused') {
+    instance.exports.set_syncing_paused(message.paused ? 1 : 0);
+  } else if (message.kind == 'set-follow-best-blocks') {
+    instance.exports.set_follow_best_blocks(message.follow ? 1 : 0);
+  } else if (message.kind == 'network-info') {
+    instance.exports.network_info();
+  } else if (message.kind == 'export-index') {
+    let [ptr, len] = allocString(instance, message.content);
+    instance.exports.export_index(ptr, len);
+  } else if (message.kind == 'storage-query') {
+    let [ptr, len] = allocString(instance, message.query);
+    instance.exports.storage_query(ptr, len);
+  } else if (message.kind == 'runtime-call') {
+    let [function_ptr, function_len] = allocString(instance, message.function);
+    let [parameter_ptr, parameter_len] = allocHex(instance, message.parameter);
+    instance.exports.runtime_call(function_ptr, function_len, parameter_ptr, parameter_len);
+  } else if (message.kind == 'json-rpc') {
+    let [ptr, len] = allocString(instance, message.rpc);
+    instance.exports.json_rpc_send(ptr, len);
+  } else if (message.kind == 'watch-storage-prefix') {
+    let [ptr, len] = allocHex(instance, message.prefix);
+    instance.exports.watch_storage_prefix(ptr, len);
+  } else if (message.kind == 'unwatch-storage-prefix') {
+    let [ptr, len] = allocHex(instance, message.prefix);
+    instance.exports.unwatch_storage_prefix(ptr, len);
+  } else if (message.kind == 'add-peer') {
+    let [ptr, len] = allocString(instance, message.address);
+    instance.exports.add_peer(ptr, len);
+  } else if (message.kind == 'disconnect-peer') {
+    let [ptr, len] = allocString(instance, message.peer_id);
+    instance.exports.disconnect_peer(ptr, len, message.ban ? 1 : 0);
+  } else if (message.kind == 'set-peer-reserved') {
+    let [ptr, len] = allocString(instance, message.peer_id);
+    instance.exports.set_peer_reserved(ptr, len, message.reserved ? 1 : 0);
+  } else if (message.kind == 'set-reserved-only') {
+    instance.exports.set_reserved_only(message.reserved_only ? 1 : 0);
+  } else if (message.kind == 'set-virtual-clock') {
+    instance.exports.set_virtual_clock(message.unix_time_ms);
+  } else if (message.kind == 'advance-virtual-clock') {
+    instance.exports.advance_virtual_clock(message.milliseconds);
+  } else {
+    console.error('Unknown message type', message);
+  }
+};
+
 // `compat.setOnMessage` is the same as `onmessage = ...`, but works across environments.
 compat.setOnMessage((message) => {
   // See the documentation of the `state` variable for information.
@@ -128,8 +233,8 @@ compat.setOnMessage((message) => {
     startInstance(message);
 
   } else if (Array.isArray(state)) {
-    // A JSON-RPC request has been received while the Wasm VM is still initializing. Queue it
-    // for when initialization is over.
+    // A request has been received while the Wasm VM is still initializing. Queue it for when
+    // initialization is over.
     state.push(message);
 
   } else {
@@ -137,6 +242,6 @@ compat.setOnMessage((message) => {
       return;
     }
 
-    state.exports.set_syncing_paused(message ? 1 : 0);
+    processRequest(state, message);
   }
 });